@@ -31,10 +31,10 @@ use mail_parser::decoders::base64::base64_decode;
 
 use super::{
     utils::{AsKey, ParseValue},
-    ArcAuthConfig, ArcSealer, Config, ConfigContext, DkimAuthConfig, DkimCanonicalization,
-    DkimSigner, DmarcAuthConfig, DnsBlConfig, EnvelopeKey, IfBlock, IfThen, IpRevAuthConfig,
-    MailAuthConfig, SpfAuthConfig, VerifyStrategy, DNSBL_EHLO, DNSBL_FROM, DNSBL_IP, DNSBL_IPREV,
-    DNSBL_RETURN_PATH,
+    ArcAuthConfig, ArcSealer, AuthMethod, AuthPolicy, Config, ConfigContext, DkimAuthConfig,
+    DkimBodyLength, DkimCanonicalization, DkimSigner, DmarcAuthConfig, DnsBlConfig, EnvelopeKey,
+    IfBlock, IfThen, IpRevAuthConfig, MailAuthConfig, SpfAuthConfig, VerifyStrategy, DNSBL_EHLO,
+    DNSBL_FROM, DNSBL_IP, DNSBL_IPREV, DNSBL_RETURN_PATH,
 };
 
 impl Config {
@@ -63,6 +63,12 @@ impl Config {
                     .parse_if_block::<Vec<String>>("auth.dkim.sign", ctx, &envelope_sender_keys)?
                     .unwrap_or_default()
                     .map_if_block(&ctx.signers, "auth.dkim.sign", "signature")?,
+                require: self
+                    .parse_if_block("auth.dkim.require", ctx, &envelope_sender_keys)?
+                    .unwrap_or_default(),
+                body_length: self
+                    .parse_if_block("auth.dkim.body-length", ctx, &envelope_sender_keys)?
+                    .unwrap_or_default(),
             },
             arc: ArcAuthConfig {
                 verify: self
@@ -80,6 +86,9 @@ impl Config {
                 verify_mail_from: self
                     .parse_if_block("auth.spf.verify.mail-from", ctx, &envelope_conn_keys)?
                     .unwrap_or_else(|| IfBlock::new(VerifyStrategy::Relaxed)),
+                softfail_no_dkim: self
+                    .parse_if_block("auth.spf.softfail-no-dkim", ctx, &envelope_sender_keys)?
+                    .unwrap_or_default(),
             },
             dmarc: DmarcAuthConfig {
                 verify: self
@@ -92,6 +101,9 @@ impl Config {
                     .unwrap_or_else(|| IfBlock::new(VerifyStrategy::Relaxed)),
             },
             dnsbl: self.parse_dnsbl(ctx)?,
+            policy: self
+                .parse_if_block("auth.policy", ctx, &envelope_sender_keys)?
+                .unwrap_or_default(),
         })
     }
 
@@ -386,6 +398,122 @@ impl ParseValue for VerifyStrategy {
     }
 }
 
+impl ParseValue for DkimBodyLength {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "allow" => Ok(DkimBodyLength::Allow),
+            "flag" => Ok(DkimBodyLength::Flag),
+            "ignore" => Ok(DkimBodyLength::Ignore),
+            "reject" => Ok(DkimBodyLength::Reject),
+            _ => Err(format!(
+                "Invalid value {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for AuthPolicy {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        let value = value.to_ascii_lowercase();
+        let mut tokens = Vec::new();
+        let mut chars = value.char_indices().peekable();
+        while let Some((pos, ch)) = chars.next() {
+            match ch {
+                'a'..='z' => {
+                    let mut end = pos + 1;
+                    while let Some((next_pos, _)) = chars.next_if(|(_, ch)| ch.is_ascii_lowercase())
+                    {
+                        end = next_pos + 1;
+                    }
+                    tokens.push(&value[pos..end]);
+                }
+                '(' | ')' | '!' => tokens.push(&value[pos..pos + 1]),
+                '&' | '|' if chars.next_if(|(_, next)| *next == ch).is_some() => {
+                    tokens.push(if ch == '&' { "and" } else { "or" });
+                }
+                ' ' | '\t' => (),
+                _ => {
+                    return Err(format!(
+                        "Invalid character {:?} in authentication policy {:?} for key {:?}.",
+                        ch,
+                        value,
+                        key.as_key()
+                    ))
+                }
+            }
+        }
+
+        let mut tokens = tokens.into_iter().peekable();
+        match AuthPolicy::parse_or(&mut tokens) {
+            Some(policy) if tokens.next().is_none() => Ok(policy),
+            _ => Err(format!(
+                "Invalid authentication policy {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl AuthPolicy {
+    fn parse_or<'x>(
+        tokens: &mut std::iter::Peekable<impl Iterator<Item = &'x str>>,
+    ) -> Option<Self> {
+        let mut items = vec![AuthPolicy::parse_and(tokens)?];
+        while tokens.next_if_eq(&"or").is_some() {
+            items.push(AuthPolicy::parse_and(tokens)?);
+        }
+        Some(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            AuthPolicy::Or(items)
+        })
+    }
+
+    fn parse_and<'x>(
+        tokens: &mut std::iter::Peekable<impl Iterator<Item = &'x str>>,
+    ) -> Option<Self> {
+        let mut items = vec![AuthPolicy::parse_not(tokens)?];
+        while tokens.next_if_eq(&"and").is_some() {
+            items.push(AuthPolicy::parse_not(tokens)?);
+        }
+        Some(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            AuthPolicy::And(items)
+        })
+    }
+
+    fn parse_not<'x>(
+        tokens: &mut std::iter::Peekable<impl Iterator<Item = &'x str>>,
+    ) -> Option<Self> {
+        match tokens.next()? {
+            "not" | "!" => AuthPolicy::parse_not(tokens).map(|p| AuthPolicy::Not(Box::new(p))),
+            "(" => {
+                let policy = AuthPolicy::parse_or(tokens)?;
+                tokens.next_if_eq(&")").map(|_| policy)
+            }
+            "spf" => AuthPolicy::Pass(AuthMethod::Spf).into(),
+            "dkim" => AuthPolicy::Pass(AuthMethod::Dkim).into(),
+            "dmarc" => AuthPolicy::Pass(AuthMethod::Dmarc).into(),
+            "arc" => AuthPolicy::Pass(AuthMethod::Arc).into(),
+            "iprev" => AuthPolicy::Pass(AuthMethod::Iprev).into(),
+            _ => None,
+        }
+    }
+
+    pub fn eval(&self, passed: &impl Fn(AuthMethod) -> bool) -> bool {
+        match self {
+            AuthPolicy::Pass(method) => passed(*method),
+            AuthPolicy::Not(policy) => !policy.eval(passed),
+            AuthPolicy::And(policies) => policies.iter().all(|p| p.eval(passed)),
+            AuthPolicy::Or(policies) => policies.iter().any(|p| p.eval(passed)),
+        }
+    }
+}
+
 impl ParseValue for DkimCanonicalization {
     fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
         if let Some((headers, body)) = value.split_once('/') {
@@ -482,3 +610,50 @@ impl IntoDnsbl for Vec<String> {
         Ok(dns_bl)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{utils::ParseValue, AuthMethod, AuthPolicy};
+
+    #[test]
+    fn parse_auth_policy() {
+        let policy = AuthPolicy::parse_value("auth.policy", "SPF or (dkim && !arc)").unwrap();
+        assert_eq!(
+            policy,
+            AuthPolicy::Or(vec![
+                AuthPolicy::Pass(AuthMethod::Spf),
+                AuthPolicy::And(vec![
+                    AuthPolicy::Pass(AuthMethod::Dkim),
+                    AuthPolicy::Not(Box::new(AuthPolicy::Pass(AuthMethod::Arc))),
+                ]),
+            ])
+        );
+
+        for (passed, expected) in [
+            (&[AuthMethod::Spf][..], true),
+            (&[AuthMethod::Dkim][..], true),
+            (&[AuthMethod::Dkim, AuthMethod::Arc][..], false),
+            (&[AuthMethod::Dmarc, AuthMethod::Iprev][..], false),
+        ] {
+            assert_eq!(
+                policy.eval(&|method| passed.contains(&method)),
+                expected,
+                "{passed:?}"
+            );
+        }
+
+        for invalid in [
+            "",
+            "spf or",
+            "spf and (dkim",
+            "spf dkim",
+            "bimi",
+            "spf | dkim",
+        ] {
+            assert!(
+                AuthPolicy::parse_value("auth.policy", invalid).is_err(),
+                "{invalid}"
+            );
+        }
+    }
+}
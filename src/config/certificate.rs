@@ -21,15 +21,20 @@
  * for more details.
 */
 
-use std::{io::Cursor, sync::Arc};
+use std::{io::Cursor, sync::Arc, time::SystemTime};
 
 use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
     server::{ClientHello, ResolvesServerCert, ResolvesServerCertUsingSni},
     sign::CertifiedKey,
     version::{TLS12, TLS13},
-    Certificate, PrivateKey, SupportedProtocolVersion,
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+    SupportedProtocolVersion,
 };
 use rustls_pemfile::{certs, read_one, Item};
+use tokio_rustls::TlsConnector;
+
+use crate::core::TlsConnectors;
 
 use super::Config;
 
@@ -94,4 +99,57 @@ impl Config {
             )),
         }
     }
+
+    pub fn rustls_client_connectors(&self, cert_id: &str) -> super::Result<TlsConnectors> {
+        let cert = self.rustls_certificate(cert_id)?;
+        let key = self.rustls_private_key(cert_id)?;
+
+        Ok(TlsConnectors {
+            pki_verify: build_client_auth_connector(cert.clone(), key.clone(), false, cert_id)?,
+            dummy_verify: build_client_auth_connector(cert, key, true, cert_id)?,
+        })
+    }
+}
+
+fn build_client_auth_connector(
+    cert: Vec<Certificate>,
+    key: PrivateKey,
+    allow_invalid_certs: bool,
+    cert_id: &str,
+) -> super::Result<TlsConnector> {
+    let config = ClientConfig::builder().with_safe_defaults();
+    let config = if !allow_invalid_certs {
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        config.with_root_certificates(root_cert_store)
+    } else {
+        config.with_custom_certificate_verifier(Arc::new(DummyVerifier))
+    };
+
+    config
+        .with_client_auth_cert(cert, key)
+        .map(|config| TlsConnector::from(Arc::new(config)))
+        .map_err(|err| format!("Invalid client certificate \"certificate.{cert_id}\": {err}"))
+}
+
+pub(crate) struct DummyVerifier;
+
+impl ServerCertVerifier for DummyVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
 }
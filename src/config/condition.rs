@@ -173,7 +173,9 @@ impl Config {
                         | EnvelopeKey::AuthenticatedAs
                         | EnvelopeKey::Mx
                         | EnvelopeKey::LocalIp
-                        | EnvelopeKey::RemoteIp,
+                        | EnvelopeKey::RemoteIp
+                        | EnvelopeKey::Country
+                        | EnvelopeKey::Asn,
                         _,
                     ) => match op {
                         MatchType::Equal => {
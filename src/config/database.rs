@@ -131,7 +131,7 @@ impl Config {
             .property(("database", id, "cache.ttl.positive"))?
             .unwrap_or(Duration::from_secs(86400));
         let cache_ttl_negative = self
-            .property(("database", id, "cache.ttl.positive"))?
+            .property(("database", id, "cache.ttl.negative"))?
             .unwrap_or(Duration::from_secs(3600));
         let cache_enable = self
             .values(("database", id, "cache.enable"))
@@ -35,6 +35,7 @@ pub mod resolver;
 pub mod scripts;
 pub mod server;
 pub mod session;
+pub mod syslog;
 pub mod throttle;
 pub mod utils;
 
@@ -59,7 +60,9 @@ use sieve::Sieve;
 use smtp_proto::MtPriority;
 use tokio::{net::TcpSocket, sync::mpsc};
 
-use crate::lookup::{self, Lookup, SqlDatabase};
+use crate::core::{throttle::ConcurrencyLimiter, TlsConnectors};
+use crate::lookup::{self, geoip::GeoIpDatabase, imap::ImapAuthClientBuilder, Lookup, SqlDatabase};
+use crate::queue::{encryption::SpoolEncryption, spool::Spool};
 
 #[derive(Debug, Default)]
 pub struct Server {
@@ -71,6 +74,9 @@ pub struct Server {
     pub listeners: Vec<Listener>,
     pub tls: Option<ServerConfig>,
     pub tls_implicit: bool,
+    pub tls_detect: Option<Duration>,
+    pub max_connections: Option<usize>,
+    pub overflow: ConnectionOverflow,
 }
 
 #[derive(Debug)]
@@ -100,6 +106,22 @@ pub struct Host {
     pub channel_tx: mpsc::Sender<lookup::Event>,
     pub channel_rx: mpsc::Receiver<lookup::Event>,
     pub lookup: bool,
+    pub ldap: Option<LdapSettings>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LdapSettings {
+    pub base_dn: String,
+    pub filter_account: String,
+    pub filter_auth: String,
+    pub attribute_mail: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ConnectionOverflow {
+    #[default]
+    RejectNew,
+    EvictOldestIdle,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
@@ -109,6 +131,7 @@ pub enum ServerProtocol {
     Lmtp,
     Imap,
     Http,
+    Ldap,
 }
 
 #[derive(Debug, Clone)]
@@ -192,6 +215,8 @@ pub enum EnvelopeKey {
     RemoteIp,
     LocalIp,
     Priority,
+    Country,
+    Asn,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -221,6 +246,24 @@ pub struct Throttle {
     pub keys: u16,
     pub concurrency: Option<u64>,
     pub rate: Option<Rate>,
+    pub tarpit: Option<Tarpit>,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub struct Tarpit {
+    pub threshold: u64,
+    pub max_delay: Duration,
+    pub curve: TarpitCurve,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(test, derive(PartialEq, Eq))]
+pub enum TarpitCurve {
+    #[default]
+    Linear,
+    Quadratic,
+    Exponential,
 }
 
 pub const THROTTLE_RCPT: u16 = 1 << 0;
@@ -233,6 +276,22 @@ pub const THROTTLE_MX: u16 = 1 << 6;
 pub const THROTTLE_REMOTE_IP: u16 = 1 << 7;
 pub const THROTTLE_LOCAL_IP: u16 = 1 << 8;
 pub const THROTTLE_HELO_DOMAIN: u16 = 1 << 9;
+pub const THROTTLE_COUNTRY: u16 = 1 << 10;
+pub const THROTTLE_ASN: u16 = 1 << 11;
+
+#[derive(Debug, Clone)]
+pub struct ThrottleSnapshot {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadThrottle {
+    pub max_load: f64,
+    pub restore_load: f64,
+    pub min_concurrency: u64,
+    pub interval: Duration,
+}
 
 #[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub struct Rate {
@@ -248,6 +307,14 @@ pub enum IpAddrMask {
 
 pub struct Connect {
     pub script: IfBlock<Option<Arc<Sieve>>>,
+    pub abandoned: Abandoned,
+}
+
+pub struct Abandoned {
+    pub threshold: IfBlock<Option<usize>>,
+    pub window: IfBlock<Duration>,
+    pub delay: IfBlock<Duration>,
+    pub block: IfBlock<Option<usize>>,
 }
 
 pub struct Ehlo {
@@ -259,12 +326,17 @@ pub struct Ehlo {
 pub struct Extensions {
     pub pipelining: IfBlock<bool>,
     pub chunking: IfBlock<bool>,
+    pub require_chunking: IfBlock<bool>,
     pub requiretls: IfBlock<bool>,
     pub dsn: IfBlock<bool>,
     pub no_soliciting: IfBlock<Option<String>>,
     pub future_release: IfBlock<Option<Duration>>,
     pub deliver_by: IfBlock<Option<Duration>>,
     pub mt_priority: IfBlock<Option<MtPriority>>,
+    pub burl: IfBlock<Option<Arc<ImapAuthClientBuilder>>>,
+    pub rrvs: IfBlock<bool>,
+    pub atrn: IfBlock<Option<Arc<Lookup>>>,
+    pub custom: IfBlock<Vec<String>>,
 }
 
 pub struct Auth {
@@ -273,10 +345,57 @@ pub struct Auth {
     pub require: IfBlock<bool>,
     pub errors_max: IfBlock<usize>,
     pub errors_wait: IfBlock<Duration>,
+    pub travel_action: IfBlock<TravelAction>,
+    pub travel_window: IfBlock<Duration>,
+    pub max_sessions: IfBlock<Option<u64>>,
+    pub oauth_check_expiry: IfBlock<bool>,
+    pub oauth_max_age: IfBlock<Option<Duration>>,
+    pub scram_secret: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TravelAction {
+    #[default]
+    Disable,
+    Log,
+    Defer,
 }
 
 pub struct Mail {
     pub script: IfBlock<Option<Arc<Sieve>>>,
+    pub verify_domain: IfBlock<VerifyDomain>,
+    pub verify_domain_timeout: IfBlock<Duration>,
+    pub address_strictness: IfBlock<AddressStrictness>,
+    pub require_tls: IfBlock<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyDomain {
+    #[default]
+    Disable,
+    Temporary,
+    Permanent,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressStrictness {
+    #[default]
+    Lenient,
+    Strict,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LookupFailAction {
+    #[default]
+    Reject,
+    Accept,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CatchAllAction {
+    Accept,
+    #[default]
+    NoBounce,
 }
 
 pub struct Rcpt {
@@ -286,13 +405,34 @@ pub struct Rcpt {
     pub lookup_addresses: IfBlock<Option<Arc<Lookup>>>,
     pub lookup_expn: IfBlock<Option<Arc<Lookup>>>,
     pub lookup_vrfy: IfBlock<Option<Arc<Lookup>>>,
+    pub lookup_lists: IfBlock<Option<Arc<Lookup>>>,
+    pub lookup_created: IfBlock<Option<Arc<Lookup>>>,
+    pub lookup_fail_action: IfBlock<LookupFailAction>,
+    pub domain_aliases: AHashMap<String, String>,
+
+    // Catch-all detection
+    pub catch_all_detect: IfBlock<bool>,
+    pub catch_all_action: IfBlock<CatchAllAction>,
+    pub catch_all_ttl: Duration,
 
     // Errors
     pub errors_max: IfBlock<usize>,
     pub errors_wait: IfBlock<Duration>,
 
+    // Timing
+    pub min_delay: IfBlock<Option<Duration>>,
+    pub min_delay_wait: IfBlock<Duration>,
+
     // Limits
     pub max_recipients: IfBlock<usize>,
+    pub lists_max_members: IfBlock<usize>,
+    pub lists_max_depth: IfBlock<usize>,
+}
+
+pub struct RejectedStore {
+    pub path: PathBuf,
+    pub retention: Duration,
+    pub enable: IfBlock<bool>,
 }
 
 pub struct Data {
@@ -304,13 +444,39 @@ pub struct Data {
     pub max_message_size: IfBlock<usize>,
     pub max_received_headers: IfBlock<usize>,
 
+    // Content
+    pub max_urls: IfBlock<Option<usize>>,
+    pub urls_action: IfBlock<ContentAction>,
+    pub urls_max_scan_size: IfBlock<usize>,
+    pub verify_encoding: IfBlock<bool>,
+    pub encoding_action: IfBlock<ContentAction>,
+    pub verify_line_length: IfBlock<bool>,
+    pub max_line_length: IfBlock<usize>,
+    pub line_length_action: IfBlock<ContentAction>,
+    pub max_rcpt_ratio: IfBlock<Option<f64>>,
+    pub rcpt_ratio_action: IfBlock<ContentAction>,
+    pub protected_names: IfBlock<Vec<String>>,
+    pub display_name_action: IfBlock<ContentAction>,
+    pub rejected: Option<RejectedStore>,
+    pub metadata_header: IfBlock<Option<String>>,
+
     // Headers
     pub add_received: IfBlock<bool>,
+    pub add_received_cipher: IfBlock<bool>,
     pub add_received_spf: IfBlock<bool>,
     pub add_return_path: IfBlock<bool>,
     pub add_auth_results: IfBlock<bool>,
     pub add_message_id: IfBlock<bool>,
     pub add_date: IfBlock<bool>,
+    pub add_geoip: IfBlock<bool>,
+    pub add_list_unsubscribe: IfBlock<Option<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContentAction {
+    #[default]
+    Reject,
+    Tag,
 }
 
 pub struct Pipe {
@@ -323,6 +489,7 @@ pub struct SessionConfig {
     pub timeout: IfBlock<Duration>,
     pub duration: IfBlock<Duration>,
     pub transfer_limit: IfBlock<usize>,
+    pub command_limit: IfBlock<usize>,
     pub throttle: SessionThrottle,
 
     pub connect: Connect,
@@ -332,6 +499,7 @@ pub struct SessionConfig {
     pub rcpt: Rcpt,
     pub data: Data,
     pub extensions: Extensions,
+    pub geoip: Option<GeoIpDatabase>,
 }
 
 pub struct SessionThrottle {
@@ -352,19 +520,39 @@ pub struct RelayHost {
 pub struct QueueConfig {
     pub path: IfBlock<PathBuf>,
     pub hash: IfBlock<u64>,
+    pub spool: Spool,
+    pub encryption: Option<SpoolEncryption>,
+    pub compression: Option<usize>,
+    pub dedup: Option<usize>,
 
     // Schedule
     pub retry: IfBlock<Vec<Duration>>,
+    pub jitter: IfBlock<Duration>,
     pub notify: IfBlock<Vec<Duration>>,
+    pub over_quota_retry: IfBlock<Vec<Duration>>,
+    pub over_quota_notify_once: IfBlock<bool>,
     pub expire: IfBlock<Duration>,
+    pub window: IfBlock<Option<DeliveryWindow>>,
+    pub max_lifetime: Option<Duration>,
+    pub priority_aging: Option<Duration>,
+    pub max_resident: Option<usize>,
+    pub load_batch: usize,
 
     // Outbound
     pub hostname: IfBlock<String>,
     pub next_hop: IfBlock<Option<RelayHost>>,
+    pub verify_addresses: IfBlock<Option<Arc<Lookup>>>,
     pub max_mx: IfBlock<usize>,
     pub max_multihomed: IfBlock<usize>,
+    pub max_batch: IfBlock<usize>,
+    pub pipelining: IfBlock<bool>,
     pub ip_strategy: IfBlock<IpLookupStrategy>,
+    pub connect_delay: IfBlock<Option<Duration>>,
+    pub bind_interface: IfBlock<Option<String>>,
+    pub mx_selection: IfBlock<MxSelection>,
+    pub refresh_dns: IfBlock<bool>,
     pub source_ip: QueueOutboundSourceIp,
+    pub pool: QueueOutboundPool,
     pub tls: QueueOutboundTls,
     pub dsn: Dsn,
 
@@ -380,6 +568,7 @@ pub struct QueueConfig {
 pub struct QueueOutboundSourceIp {
     pub ipv4: IfBlock<Vec<Ipv4Addr>>,
     pub ipv6: IfBlock<Vec<Ipv6Addr>>,
+    pub max_connections: IfBlock<Option<u64>>,
 }
 
 pub struct ReportConfig {
@@ -387,6 +576,7 @@ pub struct ReportConfig {
     pub hash: IfBlock<u64>,
     pub submitter: IfBlock<String>,
     pub analysis: ReportAnalysis,
+    pub local_domains: Option<Arc<Lookup>>,
 
     pub dkim: Report,
     pub spf: Report,
@@ -400,6 +590,8 @@ pub struct ReportAnalysis {
     pub forward: bool,
     pub store: Option<PathBuf>,
     pub report_id: AtomicU64,
+    pub max_size: usize,
+    pub concurrency: ConcurrencyLimiter,
 }
 
 pub enum AddressMatch {
@@ -412,6 +604,28 @@ pub struct Dsn {
     pub name: IfBlock<String>,
     pub address: IfBlock<String>,
     pub sign: IfBlock<Vec<Arc<DkimSigner>>>,
+    pub format: IfBlock<DsnFormat>,
+    pub domains: AHashMap<String, DsnSender>,
+}
+
+#[derive(Debug, Default)]
+pub struct DsnSender {
+    pub name: Option<String>,
+    pub address: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MxSelection {
+    #[default]
+    Random,
+    Weighted,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DsnFormat {
+    #[default]
+    Full,
+    Simple,
 }
 
 pub struct AggregateReport {
@@ -432,10 +646,27 @@ pub struct Report {
     pub send: IfBlock<Option<Rate>>,
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryWindow {
+    pub days: u8,
+    pub start: u32,
+    pub end: u32,
+    pub offset: i32,
+}
+
 pub struct QueueOutboundTls {
     pub dane: IfBlock<RequireOptional>,
+    pub dane_fallback: IfBlock<bool>,
     pub mta_sts: IfBlock<RequireOptional>,
     pub start: IfBlock<RequireOptional>,
+    pub min_version: IfBlock<TlsVersion>,
+    pub min_cipher_strength: IfBlock<u32>,
+    pub certificate: IfBlock<Option<Arc<TlsConnectors>>>,
+}
+
+pub struct QueueOutboundPool {
+    pub idle_timeout: IfBlock<Option<Duration>>,
+    pub max_messages: IfBlock<usize>,
 }
 
 pub struct QueueOutboundTimeout {
@@ -454,6 +685,7 @@ pub struct QueueThrottle {
     pub sender: Vec<Throttle>,
     pub rcpt: Vec<Throttle>,
     pub host: Vec<Throttle>,
+    pub pacing: AHashMap<String, Throttle>,
 }
 
 pub struct QueueQuotas {
@@ -481,6 +713,7 @@ pub enum AggregateFrequency {
 #[derive(Debug, Clone, Copy, Default)]
 pub struct TlsStrategy {
     pub dane: RequireOptional,
+    pub dane_fallback: bool,
     pub mta_sts: RequireOptional,
     pub tls: RequireOptional,
 }
@@ -493,6 +726,13 @@ pub enum RequireOptional {
     Disable,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
 pub struct MailAuthConfig {
     pub dkim: DkimAuthConfig,
     pub arc: ArcAuthConfig,
@@ -500,6 +740,24 @@ pub struct MailAuthConfig {
     pub dmarc: DmarcAuthConfig,
     pub iprev: IpRevAuthConfig,
     pub dnsbl: DnsBlConfig,
+    pub policy: IfBlock<Option<AuthPolicy>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthPolicy {
+    Pass(AuthMethod),
+    Not(Box<AuthPolicy>),
+    And(Vec<AuthPolicy>),
+    Or(Vec<AuthPolicy>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Spf,
+    Dkim,
+    Dmarc,
+    Arc,
+    Iprev,
 }
 
 pub enum DkimSigner {
@@ -515,6 +773,8 @@ pub enum ArcSealer {
 pub struct DkimAuthConfig {
     pub verify: IfBlock<VerifyStrategy>,
     pub sign: IfBlock<Vec<Arc<DkimSigner>>>,
+    pub require: IfBlock<Option<String>>,
+    pub body_length: IfBlock<DkimBodyLength>,
 }
 
 pub struct ArcAuthConfig {
@@ -525,6 +785,7 @@ pub struct ArcAuthConfig {
 pub struct SpfAuthConfig {
     pub verify_ehlo: IfBlock<VerifyStrategy>,
     pub verify_mail_from: IfBlock<VerifyStrategy>,
+    pub softfail_no_dkim: IfBlock<Option<ContentAction>>,
 }
 pub struct DmarcAuthConfig {
     pub verify: IfBlock<VerifyStrategy>,
@@ -552,6 +813,15 @@ pub struct DkimCanonicalization {
     pub body: Canonicalization,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DkimBodyLength {
+    #[default]
+    Allow,
+    Flag,
+    Ignore,
+    Reject,
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub enum VerifyStrategy {
     #[default]
@@ -571,6 +841,7 @@ pub struct ConfigContext {
     pub hosts: AHashMap<String, Host>,
     pub scripts: AHashMap<String, Arc<Sieve>>,
     pub lookup: AHashMap<String, Arc<Lookup>>,
+    pub imap_hosts: AHashMap<String, Arc<ImapAuthClientBuilder>>,
     pub databases: AHashMap<String, SqlDatabase>,
     pub signers: AHashMap<String, Arc<DkimSigner>>,
     pub sealers: AHashMap<String, Arc<ArcSealer>>,
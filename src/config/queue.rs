@@ -24,6 +24,12 @@
 use std::time::Duration;
 
 use mail_send::Credentials;
+use reqwest::Url;
+
+use crate::queue::{
+    encryption::{decode_key, SpoolEncryption},
+    s3::S3Spool,
+};
 
 use super::{
     throttle::ParseTrottleKey,
@@ -74,6 +80,10 @@ impl Config {
             hash: self
                 .parse_if_block("queue.hash", ctx, &sender_envelope_keys)?
                 .unwrap_or_else(|| IfBlock::new(32)),
+            spool: self.parse_queue_spool()?,
+            encryption: self.parse_queue_encryption()?,
+            compression: self.parse_queue_compression()?,
+            dedup: self.parse_queue_dedup()?,
 
             retry: self
                 .parse_if_block("queue.schedule.retry", ctx, &host_envelope_keys)?
@@ -89,6 +99,9 @@ impl Config {
                         Duration::from_secs(2 * 3600),
                     ])
                 }),
+            jitter: self
+                .parse_if_block("queue.schedule.jitter", ctx, &host_envelope_keys)?
+                .unwrap_or_else(|| IfBlock::new(Duration::from_secs(30))),
             notify: self
                 .parse_if_block("queue.schedule.notify", ctx, &rcpt_envelope_keys)?
                 .unwrap_or_else(|| {
@@ -97,11 +110,33 @@ impl Config {
                         Duration::from_secs(3 * 86400),
                     ])
                 }),
+            over_quota_retry: self
+                .parse_if_block("queue.schedule.over-quota.retry", ctx, &host_envelope_keys)?
+                .unwrap_or_else(|| IfBlock::new(Vec::new())),
+            over_quota_notify_once: self
+                .parse_if_block(
+                    "queue.schedule.over-quota.notify-once",
+                    ctx,
+                    &rcpt_envelope_keys,
+                )?
+                .unwrap_or_default(),
             expire: self
                 .parse_if_block("queue.schedule.expire", ctx, &rcpt_envelope_keys)?
                 .unwrap_or_else(|| IfBlock::new(Duration::from_secs(5 * 86400))),
+            window: self
+                .parse_if_block("queue.schedule.window", ctx, &rcpt_envelope_keys)?
+                .unwrap_or_else(|| IfBlock::new(None)),
+            max_lifetime: self.property("queue.max-lifetime")?,
+            priority_aging: self.property("queue.schedule.priority-aging")?,
+            max_resident: self
+                .property::<usize>("queue.memory.max-messages")?
+                .filter(|v| *v > 0),
+            load_batch: self
+                .property::<usize>("queue.memory.load-batch")?
+                .filter(|v| *v > 0)
+                .unwrap_or(1024),
             hostname: self
-                .parse_if_block("queue.outbound.hostname", ctx, &sender_envelope_keys)?
+                .parse_if_block("queue.outbound.hostname", ctx, &host_envelope_keys)?
                 .unwrap_or_else(|| IfBlock::new(default_hostname.to_string())),
             max_mx: self
                 .parse_if_block("queue.outbound.limits.mx", ctx, &rcpt_envelope_keys)?
@@ -109,9 +144,27 @@ impl Config {
             max_multihomed: self
                 .parse_if_block("queue.outbound.limits.multihomed", ctx, &rcpt_envelope_keys)?
                 .unwrap_or_else(|| IfBlock::new(2)),
+            max_batch: self
+                .parse_if_block("queue.outbound.limits.batch", ctx, &rcpt_envelope_keys)?
+                .unwrap_or_else(|| IfBlock::new(1)),
+            pipelining: self
+                .parse_if_block("queue.outbound.pipelining", ctx, &mx_envelope_keys)?
+                .unwrap_or_else(|| IfBlock::new(true)),
             ip_strategy: self
                 .parse_if_block("queue.outbound.ip-strategy", ctx, &sender_envelope_keys)?
                 .unwrap_or_else(|| IfBlock::new(IpLookupStrategy::Ipv4thenIpv6)),
+            connect_delay: self
+                .parse_if_block("queue.outbound.connect-delay", ctx, &mx_envelope_keys)?
+                .unwrap_or_else(|| IfBlock::new(Some(Duration::from_millis(250)))),
+            bind_interface: self
+                .parse_if_block("queue.outbound.bind-interface", ctx, &mx_envelope_keys)?
+                .unwrap_or_default(),
+            mx_selection: self
+                .parse_if_block("queue.outbound.mx-selection", ctx, &rcpt_envelope_keys)?
+                .unwrap_or_default(),
+            refresh_dns: self
+                .parse_if_block("queue.outbound.refresh-dns", ctx, &rcpt_envelope_keys)?
+                .unwrap_or_default(),
             source_ip: QueueOutboundSourceIp {
                 ipv4: self
                     .parse_if_block("queue.outbound.source-ip.v4", ctx, &mx_envelope_keys)?
@@ -119,18 +172,63 @@ impl Config {
                 ipv6: self
                     .parse_if_block("queue.outbound.source-ip.v6", ctx, &mx_envelope_keys)?
                     .unwrap_or_else(|| IfBlock::new(Vec::new())),
+                max_connections: self
+                    .parse_if_block(
+                        "queue.outbound.source-ip.max-connections",
+                        ctx,
+                        &mx_envelope_keys,
+                    )?
+                    .unwrap_or_default(),
+            },
+            pool: QueueOutboundPool {
+                idle_timeout: self
+                    .parse_if_block("queue.outbound.pool.idle-timeout", ctx, &mx_envelope_keys)?
+                    .unwrap_or_default(),
+                max_messages: self
+                    .parse_if_block("queue.outbound.pool.max-messages", ctx, &mx_envelope_keys)?
+                    .unwrap_or_else(|| IfBlock::new(100)),
             },
             next_hop: next_hop.into_relay_host(ctx)?,
+            verify_addresses: self
+                .parse_if_block::<Option<String>>(
+                    "queue.outbound.verify-addresses",
+                    ctx,
+                    &rcpt_envelope_keys,
+                )?
+                .unwrap_or_default()
+                .map_if_block(
+                    &ctx.lookup,
+                    "queue.outbound.verify-addresses",
+                    "lookup list",
+                )?,
             tls: QueueOutboundTls {
                 dane: self
                     .parse_if_block("queue.outbound.tls.dane", ctx, &mx_envelope_keys)?
                     .unwrap_or_else(|| IfBlock::new(RequireOptional::Optional)),
+                dane_fallback: self
+                    .parse_if_block("queue.outbound.tls.dane-fallback", ctx, &mx_envelope_keys)?
+                    .unwrap_or_else(|| IfBlock::new(false)),
                 mta_sts: self
                     .parse_if_block("queue.outbound.tls.mta-sts", ctx, &rcpt_envelope_keys)?
                     .unwrap_or_else(|| IfBlock::new(RequireOptional::Optional)),
                 start: self
                     .parse_if_block("queue.outbound.tls.starttls", ctx, &mx_envelope_keys)?
                     .unwrap_or_else(|| IfBlock::new(RequireOptional::Optional)),
+                min_version: self
+                    .parse_if_block(
+                        "queue.outbound.tls.secure.min-version",
+                        ctx,
+                        &mx_envelope_keys,
+                    )?
+                    .unwrap_or_else(|| IfBlock::new(TlsVersion::Tls12)),
+                min_cipher_strength: self
+                    .parse_if_block(
+                        "queue.outbound.tls.secure.min-cipher-strength",
+                        ctx,
+                        &mx_envelope_keys,
+                    )?
+                    .unwrap_or_else(|| IfBlock::new(0)),
+                certificate: self.parse_queue_client_certificate(ctx, &mx_envelope_keys)?,
             },
             throttle: self.parse_queue_throttle(ctx)?,
             quota: self.parse_queue_quota(ctx)?,
@@ -175,6 +273,10 @@ impl Config {
                     .parse_if_block::<Vec<String>>("report.dsn.sign", ctx, &sender_envelope_keys)?
                     .unwrap_or_default()
                     .map_if_block(&ctx.signers, "report.dsn.sign", "signature")?,
+                format: self
+                    .parse_if_block("report.dsn.format", ctx, &sender_envelope_keys)?
+                    .unwrap_or_else(|| IfBlock::new(DsnFormat::Full)),
+                domains: self.parse_dsn_senders()?,
             },
             management_lookup: if let Some(lookup) = self.value("management.auth.lookup") {
                 ctx.lookup
@@ -197,12 +299,116 @@ impl Config {
         }
     }
 
+    pub fn parse_queue_spool(&self) -> super::Result<Spool> {
+        match self.value("queue.spool.type").unwrap_or("local") {
+            "local" => Ok(Spool::default()),
+            "s3" => self.parse_s3_spool().map(Spool::new),
+            other => Err(format!("Unknown spool type {other:?}.")),
+        }
+    }
+
+    pub fn parse_s3_spool(&self) -> super::Result<S3Spool> {
+        let endpoint = self.value_require("queue.spool.s3.endpoint")?;
+        Ok(S3Spool {
+            endpoint: Url::parse(endpoint).map_err(|err| {
+                format!("Invalid URL {endpoint:?} for property \"queue.spool.s3.endpoint\": {err}")
+            })?,
+            bucket: self.value_require("queue.spool.s3.bucket")?.to_string(),
+            region: self
+                .value("queue.spool.s3.region")
+                .unwrap_or("us-east-1")
+                .to_string(),
+            access_key: self.value_require("queue.spool.s3.access-key")?.to_string(),
+            secret_key: self.value_require("queue.spool.s3.secret-key")?.to_string(),
+            prefix: self
+                .value("queue.spool.s3.prefix")
+                .unwrap_or_default()
+                .to_string(),
+            client: reqwest::Client::builder()
+                .timeout(
+                    self.property("queue.spool.s3.timeout")?
+                        .unwrap_or_else(|| Duration::from_secs(30)),
+                )
+                .build()
+                .map_err(|err| format!("Failed to create S3 client: {err}"))?,
+        })
+    }
+
+    pub fn parse_queue_encryption(&self) -> super::Result<Option<SpoolEncryption>> {
+        if self.value("queue.encryption.key").is_none() {
+            return Ok(None);
+        }
+        let mut key_names = vec!["queue.encryption.key".to_string()];
+        key_names.extend(
+            self.values("queue.encryption.previous-keys")
+                .map(|(key, _)| key.to_string()),
+        );
+        let mut keys = Vec::with_capacity(key_names.len());
+        for key in key_names {
+            keys.push(
+                decode_key(&self.file_contents(key.as_str())?).ok_or_else(|| {
+                    format!("Invalid encryption key for property {key:?}: expected 64 hexadecimal characters.")
+                })?,
+            );
+        }
+        SpoolEncryption::new(keys).map(Some)
+    }
+
+    pub fn parse_queue_compression(&self) -> super::Result<Option<usize>> {
+        if self.property("queue.compression.enable")?.unwrap_or(false) {
+            self.property("queue.compression.min-size")
+                .map(|size| Some(size.unwrap_or(4096)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn parse_queue_dedup(&self) -> super::Result<Option<usize>> {
+        if self.property("queue.dedup.enable")?.unwrap_or(false) {
+            self.property::<usize>("queue.dedup.max-entries")
+                .map(|max| Some(max.filter(|v| *v > 0).unwrap_or(10000)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn parse_queue_client_certificate(
+        &self,
+        ctx: &ConfigContext,
+        available_keys: &[EnvelopeKey],
+    ) -> super::Result<IfBlock<Option<Arc<TlsConnectors>>>> {
+        let key = "queue.outbound.tls.certificate";
+        let certificate = self
+            .parse_if_block::<Option<String>>(key, ctx, available_keys)?
+            .unwrap_or_default();
+
+        // Build a client authentication connector for each referenced certificate
+        let mut connectors = AHashMap::new();
+        for cert_id in certificate
+            .if_then
+            .iter()
+            .map(|i| &i.then)
+            .chain([&certificate.default])
+            .flatten()
+        {
+            if !connectors.contains_key(cert_id) {
+                connectors.insert(
+                    cert_id.to_string(),
+                    Arc::new(self.rustls_client_connectors(cert_id)?),
+                );
+            }
+        }
+
+        certificate.map_if_block(&connectors, key, "certificate")
+    }
+
     pub fn parse_queue_throttle(&self, ctx: &ConfigContext) -> super::Result<QueueThrottle> {
         // Parse throttle
         let mut throttle = QueueThrottle {
             sender: Vec::new(),
             rcpt: Vec::new(),
             host: Vec::new(),
+            pacing: self.parse_queue_pacing()?,
         };
         let envelope_keys = [
             EnvelopeKey::RecipientDomain,
@@ -257,6 +463,79 @@ impl Config {
         Ok(throttle)
     }
 
+    fn parse_queue_pacing(&self) -> super::Result<AHashMap<String, Throttle>> {
+        let prefix = "queue.outbound.pacing";
+        let mut domains = Vec::new();
+        for (key, _) in self.values(prefix) {
+            let (domain, property) = key
+                .strip_prefix(prefix)
+                .and_then(|key| key.strip_prefix('.'))
+                .and_then(|key| key.rsplit_once('.'))
+                .filter(|(domain, _)| !domain.is_empty())
+                .ok_or_else(|| format!("Invalid pacing property {key:?}."))?;
+            if !["concurrency", "messages-per-minute"].contains(&property) {
+                return Err(format!("Invalid pacing property {key:?}."));
+            }
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+
+        let mut pacing = AHashMap::with_capacity(domains.len());
+        for domain in domains {
+            let concurrency = self.property::<u64>((prefix, domain, "concurrency"))?;
+            let rate = self
+                .property::<u64>((prefix, domain, "messages-per-minute"))?
+                .filter(|messages| *messages > 0)
+                .map(|messages| {
+                    // Spread the rate over the shortest whole-second period to avoid bursts
+                    let mut gcd = (messages, 60);
+                    while gcd.1 != 0 {
+                        gcd = (gcd.1, gcd.0 % gcd.1);
+                    }
+                    Rate {
+                        requests: messages / gcd.0,
+                        period: Duration::from_secs(60 / gcd.0),
+                    }
+                });
+            pacing.insert(
+                domain.to_lowercase(),
+                Throttle {
+                    conditions: Conditions {
+                        conditions: Vec::with_capacity(0),
+                    },
+                    keys: THROTTLE_RCPT_DOMAIN,
+                    concurrency,
+                    rate,
+                    tarpit: None,
+                },
+            );
+        }
+
+        Ok(pacing)
+    }
+
+    fn parse_dsn_senders(&self) -> super::Result<AHashMap<String, DsnSender>> {
+        let prefix = "report.dsn.domain";
+        let mut senders: AHashMap<String, DsnSender> = AHashMap::new();
+        for (key, value) in self.values(prefix) {
+            let (domain, property) = key
+                .strip_prefix(prefix)
+                .and_then(|key| key.strip_prefix('.'))
+                .and_then(|key| key.rsplit_once('.'))
+                .filter(|(domain, _)| !domain.is_empty())
+                .ok_or_else(|| format!("Invalid DSN sender property {key:?}."))?;
+            let sender = senders.entry(domain.to_lowercase()).or_default();
+            match property {
+                "from-name" => sender.name = value.to_string().into(),
+                "from-address" => sender.address = value.to_string().into(),
+                _ => return Err(format!("Invalid DSN sender property {key:?}.")),
+            }
+        }
+
+        Ok(senders)
+    }
+
     pub fn parse_queue_quota(&self, ctx: &ConfigContext) -> super::Result<QueueQuotas> {
         let mut capacities = QueueQuotas {
             sender: Vec::new(),
@@ -377,7 +656,7 @@ impl IfBlock<Option<String>> {
                                     .get(&then)
                                     .ok_or_else(|| {
                                         format!(
-                                            "Host {then:?} not found for property \"queue.next-hop\".",
+                                            "Host {then:?} not found for property \"queue.outbound.next-hop\".",
                                         )
                                     })?
                                     .into(),
@@ -396,7 +675,7 @@ impl IfBlock<Option<String>> {
                         .get(&default)
                         .ok_or_else(|| {
                             format!(
-                                "Relay host {default:?} not found for property \"queue.next-hop\".",
+                                "Relay host {default:?} not found for property \"queue.outbound.next-hop\".",
                             )
                         })?
                         .into(),
@@ -440,6 +719,132 @@ impl ParseValue for RequireOptional {
     }
 }
 
+impl ParseValue for MxSelection {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "random" => Ok(MxSelection::Random),
+            "weighted" => Ok(MxSelection::Weighted),
+            _ => Err(format!(
+                "Invalid MX selection strategy {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for DsnFormat {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "full" | "rfc3464" => Ok(DsnFormat::Full),
+            "simple" | "text" => Ok(DsnFormat::Simple),
+            _ => Err(format!(
+                "Invalid DSN format {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for TlsVersion {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "TLSv1.2" | "0x0303" => Ok(TlsVersion::Tls12),
+            "TLSv1.3" | "0x0304" => Ok(TlsVersion::Tls13),
+            _ => Err(format!(
+                "Unsupported TLS protocol {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for DeliveryWindow {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        let err = || {
+            format!(
+                "Invalid delivery window {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )
+        };
+        let mut window = DeliveryWindow {
+            days: 0x7f,
+            ..Default::default()
+        };
+        let mut has_hours = false;
+
+        for part in value.split_ascii_whitespace() {
+            if let Some(offset) = part
+                .strip_prefix("UTC")
+                .or_else(|| part.strip_prefix("utc"))
+                .or_else(|| part.starts_with(['+', '-']).then_some(part))
+            {
+                window.offset = if offset.is_empty() {
+                    0
+                } else {
+                    let (sign, offset) = match offset.split_at(1) {
+                        ("+", offset) => (1, offset),
+                        ("-", offset) => (-1, offset),
+                        _ => return Err(err()),
+                    };
+                    sign * parse_time_of_day(offset).ok_or_else(err)? as i32
+                };
+            } else if let Some((start, end)) = part.split_once('-').filter(|_| part.contains(':')) {
+                window.start = parse_time_of_day(start).ok_or_else(err)?;
+                window.end = parse_time_of_day(end).ok_or_else(err)?;
+                has_hours = true;
+            } else {
+                window.days = 0;
+                for days in part.split(',') {
+                    if let Some((from, to)) = days.split_once('-') {
+                        let from = parse_weekday(from).ok_or_else(err)?;
+                        let to = parse_weekday(to).ok_or_else(err)?;
+                        let mut day = from;
+                        loop {
+                            window.days |= 1 << day;
+                            if day == to {
+                                break;
+                            }
+                            day = (day + 1) % 7;
+                        }
+                    } else {
+                        window.days |= 1 << parse_weekday(days).ok_or_else(err)?;
+                    }
+                }
+            }
+        }
+
+        if has_hours && window.start != window.end {
+            Ok(window)
+        } else {
+            Err(err())
+        }
+    }
+}
+
+fn parse_time_of_day(value: &str) -> Option<u32> {
+    let (hour, minute) = value.split_once(':')?;
+    let hour = hour.parse::<u32>().ok().filter(|&h| h <= 24)?;
+    let minute = minute.parse::<u32>().ok().filter(|&m| m < 60)?;
+    Some(hour * 3600 + minute * 60).filter(|&secs| secs <= 86400)
+}
+
+fn parse_weekday(value: &str) -> Option<u8> {
+    match value.to_ascii_lowercase().as_str() {
+        "mon" | "monday" => Some(0),
+        "tue" | "tuesday" => Some(1),
+        "wed" | "wednesday" => Some(2),
+        "thu" | "thursday" => Some(3),
+        "fri" | "friday" => Some(4),
+        "sat" | "saturday" => Some(5),
+        "sun" | "sunday" => Some(6),
+        _ => None,
+    }
+}
+
 impl ParseValue for Ipv4Addr {
     fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
         value
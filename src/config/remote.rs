@@ -23,11 +23,12 @@
 
 use std::{sync::Arc, time::Duration};
 
+use mail_send::smtp::tls::build_tls_connector;
 use tokio::sync::mpsc;
 
-use crate::lookup::Lookup;
+use crate::lookup::{imap::ImapAuthClientBuilder, ldap::build_filter, Lookup};
 
-use super::{Config, ConfigContext, Host};
+use super::{Config, ConfigContext, Host, LdapSettings, ServerProtocol};
 
 impl Config {
     pub fn parse_remote_hosts(&self, ctx: &mut ConfigContext) -> super::Result<()> {
@@ -39,6 +40,18 @@ impl Config {
                     Arc::new(Lookup::Remote(host.channel_tx.clone().into())),
                 );
             }
+            if host.protocol == ServerProtocol::Imap {
+                ctx.imap_hosts.insert(
+                    format!("remote/{id}"),
+                    Arc::new(ImapAuthClientBuilder::new(
+                        format!("{}:{}", host.address, host.port),
+                        host.timeout,
+                        build_tls_connector(host.tls_allow_invalid_certs),
+                        host.address.clone(),
+                        host.tls_implicit,
+                    )),
+                );
+            }
             ctx.hosts.insert(id.to_string(), host);
         }
 
@@ -47,11 +60,12 @@ impl Config {
 
     fn parse_host(&self, id: &str) -> super::Result<Host> {
         let (channel_tx, channel_rx) = mpsc::channel(1024);
+        let protocol = self.property_require(("remote", id, "protocol"))?;
 
         Ok(Host {
             address: self.property_require(("remote", id, "address"))?,
             port: self.property_require(("remote", id, "port"))?,
-            protocol: self.property_require(("remote", id, "protocol"))?,
+            protocol,
             concurrency: self.property(("remote", id, "concurrency"))?.unwrap_or(10),
             tls_implicit: self
                 .property(("remote", id, "tls.implicit"))?
@@ -68,7 +82,7 @@ impl Config {
                 .property(("remote", id, "cache.ttl.positive"))?
                 .unwrap_or(Duration::from_secs(86400)),
             cache_ttl_negative: self
-                .property(("remote", id, "cache.ttl.positive"))?
+                .property(("remote", id, "cache.ttl.negative"))?
                 .unwrap_or(Duration::from_secs(3600)),
             timeout: self
                 .property(("remote", id, "timeout"))?
@@ -80,6 +94,46 @@ impl Config {
             channel_tx,
             channel_rx,
             lookup: self.property(("remote", id, "lookup"))?.unwrap_or(false),
+            ldap: if protocol == ServerProtocol::Ldap {
+                self.parse_ldap_settings(id)?.into()
+            } else {
+                None
+            },
         })
     }
+
+    fn parse_ldap_settings(&self, id: &str) -> super::Result<LdapSettings> {
+        let filter_account = self
+            .value(("remote", id, "ldap.filter.account"))
+            .unwrap_or("(mail=%s)")
+            .to_string();
+        let settings = LdapSettings {
+            base_dn: self
+                .value_require(("remote", id, "ldap.base-dn"))?
+                .to_string(),
+            filter_auth: self
+                .value(("remote", id, "ldap.filter.auth"))
+                .unwrap_or(filter_account.as_str())
+                .to_string(),
+            filter_account,
+            attribute_mail: self
+                .value(("remote", id, "ldap.attributes.mail"))
+                .unwrap_or("mail")
+                .to_string(),
+        };
+
+        for (key, filter) in [
+            ("ldap.filter.account", &settings.filter_account),
+            ("ldap.filter.auth", &settings.filter_auth),
+        ] {
+            if build_filter(filter, "test").is_err() {
+                return Err(format!(
+                    "Invalid LDAP search filter {:?} for property \"remote.{}.{}\".",
+                    filter, id, key
+                ));
+            }
+        }
+
+        Ok(settings)
+    }
 }
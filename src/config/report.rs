@@ -21,6 +21,8 @@
  * for more details.
 */
 
+use crate::core::throttle::ConcurrencyLimiter;
+
 use super::{
     utils::{AsKey, ParseValue},
     AddressMatch, AggregateFrequency, AggregateReport, Config, ConfigContext, EnvelopeKey, IfBlock,
@@ -72,11 +74,31 @@ impl Config {
             hash: self
                 .parse_if_block("report.hash", ctx, &sender_envelope_keys)?
                 .unwrap_or_else(|| IfBlock::new(32)),
+            local_domains: if let Some((key, lookup)) =
+                ["report.local-domains", "session.rcpt.lookup.domains"]
+                    .into_iter()
+                    .find_map(|key| self.value(key).map(|lookup| (key, lookup)))
+            {
+                ctx.lookup
+                    .get(lookup)
+                    .ok_or_else(|| format!("Lookup {lookup:?} not found for key {key:?}."))?
+                    .clone()
+                    .into()
+            } else {
+                None
+            },
             analysis: ReportAnalysis {
                 addresses,
                 forward: self.property("report.analysis.forward")?.unwrap_or(false),
                 store: self.property("report.analysis.store")?,
                 report_id: 0.into(),
+                max_size: self
+                    .property("report.analysis.max-size")?
+                    .unwrap_or(25 * 1024 * 1024),
+                concurrency: ConcurrencyLimiter::new(
+                    self.property("report.analysis.max-concurrent")?
+                        .unwrap_or(4),
+                ),
             },
         })
     }
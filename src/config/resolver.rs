@@ -21,11 +21,14 @@
  * for more details.
 */
 
+use std::time::Duration;
+
 use mail_auth::{
     common::lru::{DnsCache, LruCache},
     trust_dns_resolver::{
         config::{ResolverConfig, ResolverOpts},
         system_conf::read_system_conf,
+        AsyncResolver,
     },
     IpLookupStrategy, Resolver,
 };
@@ -70,6 +73,11 @@ impl Config {
         let mut opts_dnssec = opts;
         opts_dnssec.validate = true;
 
+        // Prepare uncached resolver options
+        let config_uncached = config.clone();
+        let mut opts_uncached = opts;
+        opts_uncached.cache_size = 0;
+
         let mut capacities = [1024usize; 5];
         for (pos, key) in ["txt", "mx", "ipv4", "ipv6", "ptr"].into_iter().enumerate() {
             if let Some(capacity) = self.property(("resolver.cache", key))? {
@@ -90,6 +98,8 @@ impl Config {
             .map_err(|err| format!("Failed to build DNS resolver: {err}"))?,
             dnssec: DnssecResolver::with_capacity(config_dnssec, opts_dnssec)
                 .map_err(|err| format!("Failed to build DNSSEC resolver: {err}"))?,
+            uncached: AsyncResolver::tokio(config_uncached, opts_uncached)
+                .map_err(|err| format!("Failed to build DNS resolver: {err}"))?,
             cache: crate::core::DnsCache {
                 tlsa: LruCache::with_capacity(
                     self.property("resolver.cache.tlsa")?.unwrap_or(1024),
@@ -97,6 +107,12 @@ impl Config {
                 mta_sts: LruCache::with_capacity(
                     self.property("resolver.cache.mta-sts")?.unwrap_or(1024),
                 ),
+                mx_negative: LruCache::with_capacity(
+                    self.property("resolver.cache.mx-negative")?.unwrap_or(1024),
+                ),
+                mx_negative_ttl: self
+                    .property("resolver.cache.mx-negative-ttl")?
+                    .unwrap_or_else(|| Duration::from_secs(5 * 60)),
             },
         })
     }
@@ -30,16 +30,17 @@ use rustls::{
         TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
         TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384, TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
     },
-    server::{NoClientAuth, ResolvesServerCertUsingSni},
+    server::{AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth, ResolvesServerCertUsingSni},
     sign::{any_supported_type, CertifiedKey},
-    ServerConfig, SupportedCipherSuite, ALL_CIPHER_SUITES, ALL_KX_GROUPS, ALL_VERSIONS,
+    RootCertStore, ServerConfig, SupportedCipherSuite, ALL_CIPHER_SUITES, ALL_KX_GROUPS,
+    ALL_VERSIONS,
 };
 use tokio::net::TcpSocket;
 
 use super::{
     certificate::{CertificateResolver, TLS12_VERSION, TLS13_VERSION},
     utils::{AsKey, ParseKey, ParseValue},
-    Config, ConfigContext, Listener, Server, ServerProtocol,
+    Config, ConfigContext, ConnectionOverflow, Listener, Server, ServerProtocol, TlsVersion,
 };
 
 impl Config {
@@ -63,7 +64,7 @@ impl Config {
 
     fn parse_server(&self, id: &str) -> super::Result<Server> {
         // Build TLS config
-        let (tls, tls_implicit) = if self
+        let (tls, tls_implicit, tls_detect) = if self
             .property_or_default(("server.listener", id, "tls.enable"), "server.tls.enable")?
             .unwrap_or(false)
         {
@@ -85,6 +86,20 @@ impl Config {
                 }
             }
 
+            // Enforce minimum protocol version
+            if let Some(TlsVersion::Tls13) = self.property_or_default::<TlsVersion>(
+                ("server.listener", id, "tls.min-version"),
+                "server.tls.min-version",
+            )? {
+                if tls_v2 && !tls_v3 {
+                    return Err(format!(
+                        "Listener {id:?} only allows TLSv1.2 but requires a minimum version of TLSv1.3.",
+                    ));
+                }
+                tls_v2 = false;
+                tls_v3 = true;
+            }
+
             // Parse cipher suites
             let mut ciphers = Vec::new();
             for (key, protocol) in
@@ -154,6 +169,22 @@ impl Config {
                 sct_list: None,
             }));
 
+            // Optionally request client certificates issued by a trusted CA
+            let client_cert_verifier = if let Some(ca_id) = self.value_or_default(
+                ("server.listener", id, "tls.client-auth.ca"),
+                "server.tls.client-auth.ca",
+            ) {
+                let mut roots = RootCertStore::empty();
+                for cert in self.rustls_certificate(ca_id)? {
+                    roots.add(&cert).map_err(|err| {
+                        format!("Failed to add client CA certificate {ca_id:?}: {err}")
+                    })?;
+                }
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            } else {
+                NoClientAuth::boxed()
+            };
+
             // Build server config
             let mut config = ServerConfig::builder()
                 .with_cipher_suites(if !ciphers.is_empty() {
@@ -170,7 +201,7 @@ impl Config {
                     TLS12_VERSION
                 })
                 .map_err(|err| format!("Failed to build TLS config: {err}"))?
-                .with_client_cert_verifier(NoClientAuth::boxed())
+                .with_client_cert_verifier(client_cert_verifier)
                 .with_cert_resolver(Arc::new(CertificateResolver {
                     resolver: if has_sni { resolver.into() } else { None },
                     default_cert,
@@ -183,16 +214,38 @@ impl Config {
                     "server.tls.ignore-client-order",
                 )?
                 .unwrap_or(true);
-            (
-                config.into(),
-                self.property_or_default(
+
+            // Implicit TLS can be detected by peeking at the first bytes sent by the client
+            if self
+                .value_or_default(
                     ("server.listener", id, "tls.implicit"),
                     "server.tls.implicit",
-                )?
-                .unwrap_or(true),
-            )
+                )
+                .map_or(false, |value| value.eq_ignore_ascii_case("auto"))
+            {
+                (
+                    config.into(),
+                    false,
+                    self.property_or_default(
+                        ("server.listener", id, "tls.detect-timeout"),
+                        "server.tls.detect-timeout",
+                    )?
+                    .unwrap_or_else(|| Duration::from_secs(1))
+                    .into(),
+                )
+            } else {
+                (
+                    config.into(),
+                    self.property_or_default(
+                        ("server.listener", id, "tls.implicit"),
+                        "server.tls.implicit",
+                    )?
+                    .unwrap_or(true),
+                    None,
+                )
+            }
         } else {
-            (None, false)
+            (None, false, None)
         };
 
         // Build listeners
@@ -280,10 +333,32 @@ impl Config {
             listeners,
             tls,
             tls_implicit,
+            tls_detect,
+            max_connections: self.property_or_default(
+                ("server.listener", id, "max-connections"),
+                "server.max-connections",
+            )?,
+            overflow: self
+                .property_or_default(("server.listener", id, "overflow"), "server.overflow")?
+                .unwrap_or_default(),
         })
     }
 }
 
+impl ParseValue for ConnectionOverflow {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "reject-new" => Ok(ConnectionOverflow::RejectNew),
+            "evict-oldest-idle" => Ok(ConnectionOverflow::EvictOldestIdle),
+            _ => Err(format!(
+                "Invalid connection overflow action {:?} for property {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
 impl ParseValue for ServerProtocol {
     fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
         if value.eq_ignore_ascii_case("smtp") {
@@ -294,6 +369,8 @@ impl ParseValue for ServerProtocol {
             Ok(Self::Imap)
         } else if value.eq_ignore_ascii_case("http") {
             Ok(Self::Http)
+        } else if value.eq_ignore_ascii_case("ldap") {
+            Ok(Self::Ldap)
         } else {
             Err(format!(
                 "Invalid server protocol type {:?} for property {:?}.",
@@ -359,12 +436,12 @@ impl ParseValue for tracing::Level {
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, path::PathBuf};
+    use std::{fs, path::PathBuf, time::Duration};
 
     use tokio::net::TcpSocket;
 
     use crate::{
-        config::{Config, ConfigContext, Listener, Server, ServerProtocol},
+        config::{Config, ConfigContext, ConnectionOverflow, Listener, Server, ServerProtocol},
         tests::add_test_certs,
     };
 
@@ -397,6 +474,9 @@ mod tests {
                 }],
                 tls: None,
                 tls_implicit: false,
+                tls_detect: None,
+                max_connections: None,
+                overflow: ConnectionOverflow::default(),
             },
             Server {
                 id: "smtps".to_string(),
@@ -420,6 +500,9 @@ mod tests {
                 ],
                 tls: None,
                 tls_implicit: true,
+                tls_detect: None,
+                max_connections: None,
+                overflow: ConnectionOverflow::default(),
             },
             Server {
                 id: "submission".to_string(),
@@ -435,6 +518,27 @@ mod tests {
                 }],
                 tls: None,
                 tls_implicit: true,
+                tls_detect: None,
+                max_connections: Some(100),
+                overflow: ConnectionOverflow::EvictOldestIdle,
+            },
+            Server {
+                id: "submissions".to_string(),
+                internal_id: 3,
+                hostname: "mx.example.org".to_string(),
+                greeting: "Stalwart SMTP - hi there!".to_string(),
+                protocol: ServerProtocol::Smtp,
+                listeners: vec![Listener {
+                    socket: TcpSocket::new_v4().unwrap(),
+                    addr: "127.0.0.1:9992".parse().unwrap(),
+                    ttl: 3600.into(),
+                    backlog: 1024.into(),
+                }],
+                tls: None,
+                tls_implicit: false,
+                tls_detect: Duration::from_millis(500).into(),
+                max_connections: None,
+                overflow: ConnectionOverflow::default(),
             },
         ];
 
@@ -469,6 +573,21 @@ mod tests {
                 "failed for {}",
                 expected_server.id
             );
+            assert_eq!(
+                server.tls_detect, expected_server.tls_detect,
+                "failed for {}",
+                expected_server.id
+            );
+            assert_eq!(
+                server.max_connections, expected_server.max_connections,
+                "failed for {}",
+                expected_server.id
+            );
+            assert_eq!(
+                server.overflow, expected_server.overflow,
+                "failed for {}",
+                expected_server.id
+            );
             for (listener, expected_listener) in
                 server.listeners.into_iter().zip(expected_server.listeners)
             {
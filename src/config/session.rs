@@ -23,6 +23,7 @@
 
 use std::time::Duration;
 
+use rand::RngCore;
 use smtp_proto::*;
 
 use super::{
@@ -36,6 +37,8 @@ impl Config {
             EnvelopeKey::Listener,
             EnvelopeKey::RemoteIp,
             EnvelopeKey::LocalIp,
+            EnvelopeKey::Country,
+            EnvelopeKey::Asn,
         ];
 
         Ok(SessionConfig {
@@ -45,6 +48,9 @@ impl Config {
             transfer_limit: self
                 .parse_if_block("session.transfer-limit", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(250 * 1024 * 1024)),
+            command_limit: self
+                .parse_if_block("session.command-limit", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(10000)),
             timeout: self
                 .parse_if_block::<Option<Duration>>("session.timeout", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(Some(Duration::from_secs(5 * 60))))
@@ -58,9 +64,20 @@ impl Config {
             rcpt: self.parse_session_rcpt(ctx)?,
             data: self.parse_session_data(ctx)?,
             extensions: self.parse_extensions(ctx)?,
+            geoip: self.parse_geoip_database()?,
         })
     }
 
+    fn parse_geoip_database(&self) -> super::Result<Option<GeoIpDatabase>> {
+        let country = self.value("session.geoip.country");
+        let asn = self.value("session.geoip.asn");
+        if country.is_some() || asn.is_some() {
+            GeoIpDatabase::open(country, asn).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
     fn parse_session_throttle(&self, ctx: &ConfigContext) -> super::Result<SessionThrottle> {
         // Parse throttle
         let mut throttle = SessionThrottle {
@@ -82,6 +99,8 @@ impl Config {
                 EnvelopeKey::LocalIp,
                 EnvelopeKey::Priority,
                 EnvelopeKey::HeloDomain,
+                EnvelopeKey::Country,
+                EnvelopeKey::Asn,
             ],
             THROTTLE_LISTENER
                 | THROTTLE_REMOTE_IP
@@ -91,7 +110,9 @@ impl Config {
                 | THROTTLE_RCPT
                 | THROTTLE_RCPT_DOMAIN
                 | THROTTLE_SENDER
-                | THROTTLE_SENDER_DOMAIN,
+                | THROTTLE_SENDER_DOMAIN
+                | THROTTLE_COUNTRY
+                | THROTTLE_ASN,
         )?;
         for t in all_throttles {
             if (t.keys & (THROTTLE_RCPT | THROTTLE_RCPT_DOMAIN)) != 0
@@ -139,12 +160,28 @@ impl Config {
             EnvelopeKey::Listener,
             EnvelopeKey::RemoteIp,
             EnvelopeKey::LocalIp,
+            EnvelopeKey::Country,
+            EnvelopeKey::Asn,
         ];
         Ok(Connect {
             script: self
                 .parse_if_block::<Option<String>>("session.connect.script", ctx, &available_keys)?
                 .unwrap_or_default()
                 .map_if_block(&ctx.scripts, "session.connect.script", "script")?,
+            abandoned: Abandoned {
+                threshold: self
+                    .parse_if_block("session.connect.abandoned.threshold", ctx, &available_keys)?
+                    .unwrap_or_default(),
+                window: self
+                    .parse_if_block("session.connect.abandoned.window", ctx, &available_keys)?
+                    .unwrap_or_else(|| IfBlock::new(Duration::from_secs(3600))),
+                delay: self
+                    .parse_if_block("session.connect.abandoned.delay", ctx, &available_keys)?
+                    .unwrap_or_else(|| IfBlock::new(Duration::from_secs(5))),
+                block: self
+                    .parse_if_block("session.connect.abandoned.block", ctx, &available_keys)?
+                    .unwrap_or_default(),
+            },
         })
     }
 
@@ -153,6 +190,8 @@ impl Config {
             EnvelopeKey::Listener,
             EnvelopeKey::RemoteIp,
             EnvelopeKey::LocalIp,
+            EnvelopeKey::Country,
+            EnvelopeKey::Asn,
             EnvelopeKey::Sender,
             EnvelopeKey::SenderDomain,
             EnvelopeKey::AuthenticatedAs,
@@ -168,6 +207,9 @@ impl Config {
             chunking: self
                 .parse_if_block("session.extensions.chunking", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(true)),
+            require_chunking: self
+                .parse_if_block("session.extensions.require-chunking", ctx, &available_keys)?
+                .unwrap_or_default(),
             requiretls: self
                 .parse_if_block("session.extensions.requiretls", ctx, &available_keys)?
                 .unwrap_or_default(),
@@ -183,14 +225,58 @@ impl Config {
             mt_priority: self
                 .parse_if_block("session.extensions.mt-priority", ctx, &available_keys)?
                 .unwrap_or_default(),
+            burl: self
+                .parse_if_block::<Option<String>>("session.extensions.burl", ctx, &available_keys)?
+                .unwrap_or_default()
+                .map_if_block(&ctx.imap_hosts, "session.extensions.burl", "IMAP host")?,
+            rrvs: self
+                .parse_if_block("session.extensions.rrvs", ctx, &available_keys)?
+                .unwrap_or_default(),
+            atrn: self
+                .parse_if_block::<Option<String>>("session.extensions.atrn", ctx, &available_keys)?
+                .unwrap_or_default()
+                .map_if_block(&ctx.lookup, "session.extensions.atrn", "lookup list")?,
+            custom: self.parse_ehlo_keywords("session.extensions.custom", ctx, &available_keys)?,
         })
     }
 
+    fn parse_ehlo_keywords(
+        &self,
+        key: &str,
+        ctx: &ConfigContext,
+        available_keys: &[EnvelopeKey],
+    ) -> super::Result<IfBlock<Vec<String>>> {
+        let keywords: IfBlock<Vec<String>> = self
+            .parse_if_block(key, ctx, available_keys)?
+            .unwrap_or_default();
+        for keyword in keywords
+            .if_then
+            .iter()
+            .flat_map(|if_then| if_then.then.iter())
+            .chain(keywords.default.iter())
+        {
+            let name = keyword.split(' ').next().unwrap_or_default();
+            if !name.starts_with(|ch: char| ch.is_ascii_alphanumeric())
+                || !name
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-')
+                || !keyword.chars().all(|ch| ch.is_ascii_graphic() || ch == ' ')
+            {
+                return Err(format!(
+                    "Invalid EHLO keyword {keyword:?} for property {key:?}."
+                ));
+            }
+        }
+        Ok(keywords)
+    }
+
     fn parse_session_ehlo(&self, ctx: &ConfigContext) -> super::Result<Ehlo> {
         let available_keys = [
             EnvelopeKey::Listener,
             EnvelopeKey::RemoteIp,
             EnvelopeKey::LocalIp,
+            EnvelopeKey::Country,
+            EnvelopeKey::Asn,
         ];
 
         Ok(Ehlo {
@@ -212,6 +298,8 @@ impl Config {
             EnvelopeKey::Listener,
             EnvelopeKey::RemoteIp,
             EnvelopeKey::LocalIp,
+            EnvelopeKey::Country,
+            EnvelopeKey::Asn,
             EnvelopeKey::HeloDomain,
         ];
 
@@ -247,6 +335,38 @@ impl Config {
             errors_wait: self
                 .parse_if_block("session.auth.errors.wait", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(Duration::from_secs(30))),
+            travel_action: self
+                .parse_if_block("session.auth.travel.action", ctx, &available_keys)?
+                .unwrap_or_default(),
+            max_sessions: self
+                .parse_if_block(
+                    "session.auth.max-sessions",
+                    ctx,
+                    &[
+                        EnvelopeKey::Listener,
+                        EnvelopeKey::RemoteIp,
+                        EnvelopeKey::LocalIp,
+                        EnvelopeKey::AuthenticatedAs,
+                    ],
+                )?
+                .unwrap_or_default(),
+            travel_window: self
+                .parse_if_block("session.auth.travel.window", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(Duration::from_secs(3600))),
+            oauth_check_expiry: self
+                .parse_if_block("session.auth.oauth.check-expiry", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(false)),
+            oauth_max_age: self
+                .parse_if_block("session.auth.oauth.max-age", ctx, &available_keys)?
+                .unwrap_or_default(),
+            scram_secret: self
+                .value("session.auth.scram.secret")
+                .map(|secret| secret.as_bytes().to_vec())
+                .unwrap_or_else(|| {
+                    let mut secret = vec![0u8; 32];
+                    rand::thread_rng().fill_bytes(&mut secret);
+                    secret
+                }),
         })
     }
 
@@ -256,6 +376,8 @@ impl Config {
             EnvelopeKey::Listener,
             EnvelopeKey::RemoteIp,
             EnvelopeKey::LocalIp,
+            EnvelopeKey::Country,
+            EnvelopeKey::Asn,
             EnvelopeKey::HeloDomain,
         ];
         Ok(Mail {
@@ -263,6 +385,18 @@ impl Config {
                 .parse_if_block::<Option<String>>("session.mail.script", ctx, &available_keys)?
                 .unwrap_or_default()
                 .map_if_block(&ctx.scripts, "session.mail.script", "script")?,
+            verify_domain: self
+                .parse_if_block("session.mail.sender-domain.verify", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(VerifyDomain::Disable)),
+            verify_domain_timeout: self
+                .parse_if_block("session.mail.sender-domain.timeout", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(Duration::from_secs(10))),
+            address_strictness: self
+                .parse_if_block("session.mail.address-strictness", ctx, &available_keys)?
+                .unwrap_or_default(),
+            require_tls: self
+                .parse_if_block("session.mail.require-tls", ctx, &available_keys)?
+                .unwrap_or_default(),
         })
     }
 
@@ -274,6 +408,8 @@ impl Config {
             EnvelopeKey::Listener,
             EnvelopeKey::RemoteIp,
             EnvelopeKey::LocalIp,
+            EnvelopeKey::Country,
+            EnvelopeKey::Asn,
             EnvelopeKey::HeloDomain,
         ];
         Ok(Rcpt {
@@ -309,18 +445,77 @@ impl Config {
                 .parse_if_block::<Option<String>>("session.rcpt.lookup.vrfy", ctx, &available_keys)?
                 .unwrap_or_default()
                 .map_if_block(&ctx.lookup, "session.rcpt.lookup.vrfy", "lookup list")?,
+            lookup_lists: self
+                .parse_if_block::<Option<String>>(
+                    "session.rcpt.lookup.lists",
+                    ctx,
+                    &available_keys,
+                )?
+                .unwrap_or_default()
+                .map_if_block(&ctx.lookup, "session.rcpt.lookup.lists", "lookup list")?,
+            lookup_created: self
+                .parse_if_block::<Option<String>>(
+                    "session.rcpt.lookup.created",
+                    ctx,
+                    &available_keys,
+                )?
+                .unwrap_or_default()
+                .map_if_block(&ctx.lookup, "session.rcpt.lookup.created", "lookup list")?,
+            lookup_fail_action: self
+                .parse_if_block("session.rcpt.lookup.temp-fail", ctx, &available_keys)?
+                .unwrap_or_default(),
+            domain_aliases: self.parse_domain_aliases("session.rcpt.domain-alias")?,
+            catch_all_detect: self
+                .parse_if_block("session.rcpt.catch-all.detect", ctx, &available_keys)?
+                .unwrap_or_default(),
+            catch_all_action: self
+                .parse_if_block("session.rcpt.catch-all.action", ctx, &available_keys)?
+                .unwrap_or_default(),
+            catch_all_ttl: self
+                .property("session.rcpt.catch-all.cache-ttl")?
+                .unwrap_or_else(|| Duration::from_secs(86400)),
             errors_max: self
                 .parse_if_block("session.rcpt.errors.max", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(10)),
             errors_wait: self
                 .parse_if_block("session.rcpt.errors.wait", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(Duration::from_secs(30))),
+            min_delay: self
+                .parse_if_block("session.rcpt.timing.min-delay", ctx, &available_keys)?
+                .unwrap_or_default(),
+            min_delay_wait: self
+                .parse_if_block("session.rcpt.timing.wait", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(Duration::from_secs(1))),
             max_recipients: self
                 .parse_if_block("session.rcpt.max-recipients", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(100)),
+            lists_max_members: self
+                .parse_if_block("session.rcpt.lists.max-members", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(10000)),
+            lists_max_depth: self
+                .parse_if_block("session.rcpt.lists.max-depth", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(3)),
         })
     }
 
+    fn parse_domain_aliases(&self, prefix: &str) -> super::Result<AHashMap<String, String>> {
+        let mut aliases = AHashMap::new();
+        for (key, value) in self.values(prefix) {
+            let domain = key
+                .strip_prefix(prefix)
+                .and_then(|domain| domain.strip_prefix('.'))
+                .filter(|domain| !domain.is_empty())
+                .ok_or_else(|| format!("Invalid domain alias property {key:?}."))?;
+            if value.is_empty() || value.contains('@') {
+                return Err(format!(
+                    "Invalid domain {value:?} for domain alias property {key:?}."
+                ));
+            }
+            aliases.insert(domain.to_lowercase(), value.to_lowercase());
+        }
+        Ok(aliases)
+    }
+
     fn parse_session_data(&self, ctx: &ConfigContext) -> super::Result<Data> {
         let available_keys = [
             EnvelopeKey::Sender,
@@ -329,6 +524,8 @@ impl Config {
             EnvelopeKey::Listener,
             EnvelopeKey::RemoteIp,
             EnvelopeKey::LocalIp,
+            EnvelopeKey::Country,
+            EnvelopeKey::Asn,
             EnvelopeKey::Priority,
             EnvelopeKey::HeloDomain,
         ];
@@ -346,9 +543,65 @@ impl Config {
             max_received_headers: self
                 .parse_if_block("session.data.limits.received-headers", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(50)),
+            max_urls: self
+                .parse_if_block("session.data.urls.max", ctx, &available_keys)?
+                .unwrap_or_default(),
+            urls_action: self
+                .parse_if_block("session.data.urls.action", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(ContentAction::Reject)),
+            urls_max_scan_size: self
+                .parse_if_block("session.data.urls.max-scan-size", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(1024 * 1024)),
+            verify_encoding: self
+                .parse_if_block("session.data.encoding.verify", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(false)),
+            encoding_action: self
+                .parse_if_block("session.data.encoding.action", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(ContentAction::Reject)),
+            verify_line_length: self
+                .parse_if_block("session.data.line-length.verify", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(false)),
+            max_line_length: self
+                .parse_if_block("session.data.line-length.max", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(1000)),
+            line_length_action: self
+                .parse_if_block("session.data.line-length.action", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(ContentAction::Reject)),
+            max_rcpt_ratio: self
+                .parse_if_block("session.data.recipients.max-ratio", ctx, &available_keys)?
+                .unwrap_or_default(),
+            rcpt_ratio_action: self
+                .parse_if_block("session.data.recipients.action", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(ContentAction::Tag)),
+            protected_names: self
+                .parse_if_block("session.data.display-name.protected", ctx, &available_keys)?
+                .unwrap_or_default(),
+            display_name_action: self
+                .parse_if_block("session.data.display-name.action", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(ContentAction::Reject)),
+            rejected: if let Some(path) = self.property("session.data.rejected.path")? {
+                Some(RejectedStore {
+                    path,
+                    retention: self
+                        .property("session.data.rejected.retention")?
+                        .unwrap_or_else(|| Duration::from_secs(7 * 86400)),
+                    enable: self
+                        .parse_if_block("session.data.rejected.store", ctx, &available_keys)?
+                        .unwrap_or_else(|| IfBlock::new(true)),
+                })
+            } else {
+                None
+            },
             add_received: self
                 .parse_if_block("session.data.add-headers.received", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(true)),
+            add_received_cipher: self
+                .parse_if_block(
+                    "session.data.add-headers.received-cipher",
+                    ctx,
+                    &available_keys,
+                )?
+                .unwrap_or_else(|| IfBlock::new(true)),
             add_received_spf: self
                 .parse_if_block(
                     "session.data.add-headers.received-spf",
@@ -372,6 +625,19 @@ impl Config {
             add_date: self
                 .parse_if_block("session.data.add-headers.date", ctx, &available_keys)?
                 .unwrap_or_else(|| IfBlock::new(true)),
+            add_geoip: self
+                .parse_if_block("session.data.add-headers.geoip", ctx, &available_keys)?
+                .unwrap_or_else(|| IfBlock::new(false)),
+            add_list_unsubscribe: self
+                .parse_if_block(
+                    "session.data.add-headers.list-unsubscribe",
+                    ctx,
+                    &available_keys,
+                )?
+                .unwrap_or_default(),
+            metadata_header: self
+                .parse_if_block("session.data.metadata.header", ctx, &available_keys)?
+                .unwrap_or_default(),
             pipe_commands: self.parse_pipes(ctx, &available_keys)?,
         })
     }
@@ -399,6 +665,92 @@ impl Config {
     }
 }
 
+impl ParseValue for VerifyDomain {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "temporary" | "temp" | "true" => Ok(VerifyDomain::Temporary),
+            "permanent" | "perm" => Ok(VerifyDomain::Permanent),
+            "disable" | "disabled" | "none" | "false" => Ok(VerifyDomain::Disable),
+            _ => Err(format!(
+                "Invalid value {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for AddressStrictness {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "lenient" => Ok(AddressStrictness::Lenient),
+            "strict" => Ok(AddressStrictness::Strict),
+            _ => Err(format!(
+                "Invalid value {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for LookupFailAction {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "reject" => Ok(LookupFailAction::Reject),
+            "accept" => Ok(LookupFailAction::Accept),
+            _ => Err(format!(
+                "Invalid value {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for CatchAllAction {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "accept" => Ok(CatchAllAction::Accept),
+            "no-bounce" => Ok(CatchAllAction::NoBounce),
+            _ => Err(format!(
+                "Invalid value {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for ContentAction {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "reject" => Ok(ContentAction::Reject),
+            "tag" => Ok(ContentAction::Tag),
+            _ => Err(format!(
+                "Invalid value {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
+impl ParseValue for TravelAction {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "log" => Ok(TravelAction::Log),
+            "defer" => Ok(TravelAction::Defer),
+            "disable" | "disabled" | "none" | "false" => Ok(TravelAction::Disable),
+            _ => Err(format!(
+                "Invalid value {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
 impl ParseValue for MtPriority {
     fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
         match value.to_ascii_lowercase().as_str() {
@@ -426,8 +778,10 @@ impl ParseValue for Mechanism {
                 "PLAIN" => AUTH_PLAIN,
                 "XOAUTH2" => AUTH_XOAUTH2,
                 "OAUTHBEARER" => AUTH_OAUTHBEARER,
-                /*"SCRAM-SHA-256-PLUS" => AUTH_SCRAM_SHA_256_PLUS,
                 "SCRAM-SHA-256" => AUTH_SCRAM_SHA_256,
+                "CRAM-MD5" => AUTH_CRAM_MD5,
+                "EXTERNAL" => AUTH_EXTERNAL,
+                /*"SCRAM-SHA-256-PLUS" => AUTH_SCRAM_SHA_256_PLUS,
                 "SCRAM-SHA-1-PLUS" => AUTH_SCRAM_SHA_1_PLUS,
                 "SCRAM-SHA-1" => AUTH_SCRAM_SHA_1,
                 "XOAUTH" => AUTH_XOAUTH,
@@ -441,7 +795,6 @@ impl ParseValue for Mechanism {
                 "EAP-AES128-PLUS" => AUTH_EAP_AES128_PLUS,
                 "ECDH-X25519-CHALLENGE" => AUTH_ECDH_X25519_CHALLENGE,
                 "ECDSA-NIST256P-CHALLENGE" => AUTH_ECDSA_NIST256P_CHALLENGE,
-                "EXTERNAL" => AUTH_EXTERNAL,
                 "GS2-KRB5" => AUTH_GS2_KRB5,
                 "GS2-KRB5-PLUS" => AUTH_GS2_KRB5_PLUS,
                 "GSS-SPNEGO" => AUTH_GSS_SPNEGO,
@@ -461,7 +814,6 @@ impl ParseValue for Mechanism {
                 "SPNEGO" => AUTH_SPNEGO,
                 "SPNEGO-PLUS" => AUTH_SPNEGO_PLUS,
                 "SXOVER-PLUS" => AUTH_SXOVER_PLUS,
-                "CRAM-MD5" => AUTH_CRAM_MD5,
                 "DIGEST-MD5" => AUTH_DIGEST_MD5,
                 "ANONYMOUS" => AUTH_ANONYMOUS,*/
                 _ => {
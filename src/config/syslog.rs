@@ -0,0 +1,127 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+
+use crate::core::syslog::{SyslogLayer, SyslogTransport};
+
+use super::{certificate::DummyVerifier, Config};
+
+impl Config {
+    pub fn build_syslog_layer(&self) -> super::Result<Option<SyslogLayer>> {
+        let endpoint = if let Some(endpoint) = self.value("global.tracing.syslog.endpoint") {
+            endpoint
+        } else {
+            return Ok(None);
+        };
+        let (scheme, address) = endpoint
+            .split_once("://")
+            .ok_or_else(|| format!("Invalid syslog endpoint {endpoint:?}."))?;
+        let host = address
+            .rsplit_once(':')
+            .map(|(host, _)| host.trim_start_matches('[').trim_end_matches(']'))
+            .ok_or_else(|| format!("Missing port in syslog endpoint {endpoint:?}."))?;
+
+        let transport = match scheme {
+            "udp" => SyslogTransport::Udp,
+            "tcp" => SyslogTransport::Tcp,
+            "tls" => {
+                let config = ClientConfig::builder().with_safe_defaults();
+                let config = if !self
+                    .property::<bool>("global.tracing.syslog.allow-invalid-certs")?
+                    .unwrap_or(false)
+                {
+                    let mut root_cert_store = RootCertStore::empty();
+                    root_cert_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
+                        |ta| {
+                            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                                ta.subject,
+                                ta.spki,
+                                ta.name_constraints,
+                            )
+                        },
+                    ));
+                    config.with_root_certificates(root_cert_store)
+                } else {
+                    config.with_custom_certificate_verifier(Arc::new(DummyVerifier))
+                };
+
+                SyslogTransport::Tls {
+                    config: Arc::new(config.with_no_client_auth()),
+                    server_name: ServerName::try_from(host).map_err(|_| {
+                        format!("Invalid hostname {host:?} in syslog endpoint {endpoint:?}.")
+                    })?,
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Unsupported syslog transport {scheme:?}, expected 'udp', 'tcp' or 'tls'."
+                ))
+            }
+        };
+
+        let facility = self
+            .value("global.tracing.syslog.facility")
+            .unwrap_or("mail");
+        let facility = match facility {
+            "kern" => 0,
+            "user" => 1,
+            "mail" => 2,
+            "daemon" => 3,
+            "auth" => 4,
+            "syslog" => 5,
+            "lpr" => 6,
+            "news" => 7,
+            "uucp" => 8,
+            "cron" => 9,
+            "authpriv" => 10,
+            "ftp" => 11,
+            _ => match facility
+                .strip_prefix("local")
+                .and_then(|n| n.parse::<u8>().ok())
+            {
+                Some(n @ 0..=7) => 16 + n,
+                _ => {
+                    return Err(format!(
+                        "Invalid syslog facility {facility:?} for property \"global.tracing.syslog.facility\"."
+                    ))
+                }
+            },
+        };
+
+        Ok(Some(SyslogLayer::new(
+            address.to_string(),
+            transport,
+            facility,
+            self.value("global.tracing.syslog.hostname")
+                .or_else(|| self.value("server.hostname"))
+                .unwrap_or_default()
+                .to_string(),
+            self.value("global.tracing.syslog.app-name")
+                .unwrap_or("stalwart-smtp")
+                .to_string(),
+        )))
+    }
+}
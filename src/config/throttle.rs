@@ -83,10 +83,15 @@ impl Config {
             rate: self
                 .property::<Rate>((prefix.as_str(), "rate"))?
                 .filter(|v| v.requests > 0),
+            tarpit: self.parse_tarpit((prefix.as_str(), "tarpit"))?,
         };
 
         // Validate
-        if throttle.rate.is_none() && throttle.concurrency.is_none() {
+        if throttle.tarpit.is_some() && throttle.rate.is_none() {
+            Err(format!(
+                "Throttle {prefix:?} needs to define a 'rate' property in order to use a tarpit."
+            ))
+        } else if throttle.rate.is_none() && throttle.concurrency.is_none() {
             Err(format!(
                 concat!(
                     "Throttle {:?} needs to define a ",
@@ -98,6 +103,65 @@ impl Config {
             Ok(throttle)
         }
     }
+
+    fn parse_tarpit(&self, prefix: impl AsKey) -> super::Result<Option<Tarpit>> {
+        let prefix = prefix.as_key();
+        if let Some(max_delay) = self
+            .property::<Duration>((prefix.as_str(), "max-delay"))?
+            .filter(|d| !d.is_zero())
+        {
+            let threshold = self
+                .property::<u64>((prefix.as_str(), "threshold"))?
+                .unwrap_or(50);
+            if threshold >= 100 {
+                return Err(format!(
+                    "Tarpit threshold for {prefix:?} must be a percentage below 100."
+                ));
+            }
+            Ok(Some(Tarpit {
+                threshold,
+                max_delay,
+                curve: self
+                    .property::<TarpitCurve>((prefix.as_str(), "curve"))?
+                    .unwrap_or_default(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn parse_throttle_snapshot(&self) -> super::Result<Option<ThrottleSnapshot>> {
+        if let Some(path) = self.value("global.shared-map.snapshot.path") {
+            Ok(Some(ThrottleSnapshot {
+                path: PathBuf::from(path),
+                interval: self
+                    .property("global.shared-map.snapshot.interval")?
+                    .unwrap_or_else(|| Duration::from_secs(5 * 60)),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn parse_load_throttle(&self) -> super::Result<Option<LoadThrottle>> {
+        if let Some(max_load) = self.property::<f64>("queue.outbound.load.max-load")? {
+            Ok(Some(LoadThrottle {
+                max_load,
+                restore_load: self
+                    .property("queue.outbound.load.restore-load")?
+                    .unwrap_or(max_load * 0.75),
+                min_concurrency: self
+                    .property::<u64>("queue.outbound.load.min-concurrency")?
+                    .unwrap_or(1)
+                    .max(1),
+                interval: self
+                    .property("queue.outbound.load.interval")?
+                    .unwrap_or_else(|| Duration::from_secs(10)),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
 }
 
 impl ParseValue for Rate {
@@ -130,6 +194,21 @@ impl ParseValue for Rate {
     }
 }
 
+impl ParseValue for TarpitCurve {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        match value {
+            "linear" => Ok(TarpitCurve::Linear),
+            "quadratic" => Ok(TarpitCurve::Quadratic),
+            "exponential" => Ok(TarpitCurve::Exponential),
+            _ => Err(format!(
+                "Invalid tarpit curve value {:?} for key {:?}.",
+                value,
+                key.as_key()
+            )),
+        }
+    }
+}
+
 impl ParseValue for EnvelopeKey {
     fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
         Ok(match value {
@@ -143,6 +222,8 @@ impl ParseValue for EnvelopeKey {
             "priority" => EnvelopeKey::Priority,
             "authenticated-as" => EnvelopeKey::AuthenticatedAs,
             "mx" => EnvelopeKey::Mx,
+            "country" => EnvelopeKey::Country,
+            "asn" => EnvelopeKey::Asn,
             _ => {
                 return Err(format!(
                     "Invalid context key {:?} for property {:?}.",
@@ -171,6 +252,8 @@ impl ParseTrottleKey for &str {
             "remote-ip" => Ok(THROTTLE_REMOTE_IP),
             "local-ip" => Ok(THROTTLE_LOCAL_IP),
             "helo-domain" => Ok(THROTTLE_HELO_DOMAIN),
+            "country" => Ok(THROTTLE_COUNTRY),
+            "asn" => Ok(THROTTLE_ASN),
             _ => Err(format!("Invalid throttle key {self:?} found in {key:?}")),
         }
     }
@@ -182,7 +265,8 @@ mod tests {
 
     use crate::config::{
         Condition, ConditionMatch, Conditions, Config, ConfigContext, EnvelopeKey, IpAddrMask,
-        Rate, Throttle, THROTTLE_AUTH_AS, THROTTLE_REMOTE_IP, THROTTLE_SENDER_DOMAIN,
+        Rate, Tarpit, TarpitCurve, Throttle, THROTTLE_AUTH_AS, THROTTLE_REMOTE_IP,
+        THROTTLE_SENDER_DOMAIN,
     };
 
     #[test]
@@ -231,13 +315,20 @@ mod tests {
                         requests: 50,
                         period: Duration::from_secs(30)
                     }
+                    .into(),
+                    tarpit: Tarpit {
+                        threshold: 75,
+                        max_delay: Duration::from_secs(5),
+                        curve: TarpitCurve::Quadratic
+                    }
                     .into()
                 },
                 Throttle {
                     conditions: Conditions { conditions: vec![] },
                     keys: THROTTLE_SENDER_DOMAIN,
                     concurrency: 10000.into(),
-                    rate: None
+                    rate: None,
+                    tarpit: None
                 }
             ]
         );
@@ -149,6 +149,10 @@ impl Config {
                 std::fs::read(value).map_err(|err| {
                     format!("Failed to read file {value:?} for property {key:?}: {err}")
                 })
+            } else if let Some(value) = value.strip_prefix("env://") {
+                std::env::var(value).map(|value| value.into_bytes()).map_err(|err| {
+                    format!("Failed to read environment variable {value:?} for property {key:?}: {err}")
+                })
             } else {
                 Ok(value.to_string().into_bytes())
             }
@@ -296,6 +300,22 @@ impl ParseValue for u32 {
     }
 }
 
+impl ParseValue for f64 {
+    fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
+        value
+            .parse()
+            .ok()
+            .filter(|v: &f64| v.is_finite())
+            .ok_or_else(|| {
+                format!(
+                    "Invalid floating point value {:?} for property {:?}.",
+                    value,
+                    key.as_key()
+                )
+            })
+    }
+}
+
 impl ParseValue for IpAddr {
     fn parse_value(key: impl AsKey, value: &str) -> super::Result<Self> {
         value.parse().map_err(|_| {
@@ -39,6 +39,23 @@ impl<T: Default> IfBlock<T> {
 
         &self.default
     }
+
+    pub fn has_keys(&self, keys: &[EnvelopeKey]) -> bool {
+        self.if_then.iter().any(|if_then| {
+            if_then.conditions.conditions.iter().any(
+                |condition| matches!(condition, Condition::Match { key, .. } if keys.contains(key)),
+            )
+        })
+    }
+}
+
+impl<T: Default + Ord> IfBlock<T> {
+    pub fn max_value(&self) -> &T {
+        self.if_then
+            .iter()
+            .map(|if_then| &if_then.then)
+            .fold(&self.default, std::cmp::max)
+    }
 }
 
 impl Conditions {
@@ -232,6 +249,14 @@ mod tests {
         fn priority(&self) -> i16 {
             self.priority
         }
+
+        fn country(&self) -> &str {
+            ""
+        }
+
+        fn asn(&self) -> u32 {
+            0
+        }
     }
 
     #[tokio::test]
@@ -21,7 +21,13 @@
  * for more details.
 */
 
-use std::{borrow::Cow, fmt::Display, net::IpAddr, sync::Arc, time::Instant};
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    net::IpAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
 
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::{
@@ -42,6 +48,7 @@ use tokio_rustls::TlsAcceptor;
 
 use crate::{
     config::Server,
+    inbound::spawn::is_version_rejected,
     lookup::{Item, LookupResult},
     queue::{self, instant_to_timestamp, InstantFromTimestamp, QueueId, Status},
     reporting::{
@@ -76,9 +83,23 @@ pub enum QueueRequest {
     Retry {
         queue_ids: Vec<QueueId>,
         item: Option<String>,
+        error: Option<String>,
         time: Instant,
         result_tx: oneshot::Sender<Vec<bool>>,
     },
+    Bounce {
+        queue_ids: Vec<QueueId>,
+        item: Option<String>,
+        result_tx: oneshot::Sender<Vec<bool>>,
+    },
+    Edit {
+        queue_id: QueueId,
+        edit: RecipientEdit,
+        result_tx: oneshot::Sender<Result<Message, String>>,
+    },
+    Stats {
+        result_tx: oneshot::Sender<QueueStats>,
+    },
 }
 
 #[derive(Debug)]
@@ -116,6 +137,9 @@ pub struct Message {
     pub priority: i16,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub env_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub metadata: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -144,6 +168,37 @@ pub struct Recipient {
     pub orcpt: Option<String>,
 }
 
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QueueStats {
+    pub total: usize,
+    pub on_hold: usize,
+    pub retry: RetryStats,
+    pub size: usize,
+}
+
+// Scheduled messages grouped by how soon their next delivery attempt is due
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetryStats {
+    pub now: usize,
+    pub hour: usize,
+    pub day: usize,
+    pub later: usize,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RecipientEdit {
+    #[serde(default)]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    pub rewrite: Vec<RecipientRewrite>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecipientRewrite {
+    pub from: String,
+    pub to: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Report {
     pub domain: String,
@@ -218,6 +273,15 @@ impl Server {
                                                 Ok(stream) => {
                                                     handle_request(stream, core, remote_addr.ip(), in_flight).await;
                                                 }
+                                                Err(err) if is_version_rejected(&err) => {
+                                                    tracing::info!(
+                                                        context = "tls",
+                                                        event = "version-rejected",
+                                                        remote.ip = remote_addr.ip().to_string(),
+                                                        "Rejected TLS management connection below the minimum protocol version: {}",
+                                                        err
+                                                    );
+                                                }
                                                 Err(err) => {
                                                     tracing::debug!(
                                                         context = "tls",
@@ -269,13 +333,14 @@ async fn handle_request(
                 let core = core.clone();
 
                 async move {
-                    let response = core.parse_request(&req).await;
+                    let uri = req.uri().to_string();
+                    let response = core.parse_request(req).await;
 
                     tracing::debug!(
                         context = "management",
                         event = "request",
                         remote.ip = remote_addr.to_string(),
-                        uri = req.uri().to_string(),
+                        uri = uri,
                         status = match &response {
                             Ok(response) => response.status().to_string(),
                             Err(error) => error.to_string(),
@@ -300,8 +365,31 @@ async fn handle_request(
 impl Core {
     async fn parse_request(
         &self,
-        req: &hyper::Request<hyper::body::Incoming>,
+        req: hyper::Request<hyper::body::Incoming>,
     ) -> Result<hyper::Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        // Health checks do not require authentication
+        if req.method() == Method::GET && matches!(req.uri().path(), "/health" | "/ready") {
+            let draining = self.draining.load(Ordering::Relaxed);
+            let status = if draining && req.uri().path() == "/ready" {
+                StatusCode::SERVICE_UNAVAILABLE
+            } else {
+                StatusCode::OK
+            };
+            return Ok(hyper::Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+                .body(
+                    Full::new(Bytes::from(if draining {
+                        "{\"status\": \"draining\"}"
+                    } else {
+                        "{\"status\": \"ok\"}"
+                    }))
+                    .map_err(|never| match never {})
+                    .boxed(),
+                )
+                .unwrap());
+        }
+
         // Authenticate request
         let mut is_authenticated = false;
         if let Some((mechanism, payload)) = req
@@ -479,6 +567,7 @@ impl Core {
                 let mut queue_ids = Vec::new();
                 let mut time = Instant::now();
                 let mut item = None;
+                let mut error_filter = None;
                 let mut error = None;
 
                 if let Some(query) = req.uri().query() {
@@ -493,6 +582,9 @@ impl Core {
                                     break;
                                 }
                             },
+                            "error" => {
+                                error_filter = value.into_owned().into();
+                            }
                             "at" => match value.parse_timestamp() {
                                 Ok(dt) => {
                                     time = dt;
@@ -520,6 +612,7 @@ impl Core {
                             QueueRequest::Retry {
                                 queue_ids,
                                 item,
+                                error: error_filter,
                                 time,
                                 result_tx,
                             },
@@ -574,6 +667,125 @@ impl Core {
                     Some(error) => error.into_bad_request(),
                 }
             }
+            (&Method::GET, Some("queue"), Some("bounce")) => {
+                let mut queue_ids = Vec::new();
+                let mut item = None;
+                let mut error = None;
+
+                if let Some(query) = req.uri().query() {
+                    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                        match key.as_ref() {
+                            "id" | "ids" => match value.parse_queue_ids() {
+                                Ok(ids) => {
+                                    queue_ids = ids;
+                                }
+                                Err(reason) => {
+                                    error = reason.into();
+                                    break;
+                                }
+                            },
+                            "filter" => {
+                                item = value.into_owned().into();
+                            }
+                            _ => {
+                                error = format!("Invalid parameter {key:?}.").into();
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                match error {
+                    None => {
+                        let (result_tx, result_rx) = oneshot::channel();
+                        self.send_queue_event(
+                            QueueRequest::Bounce {
+                                queue_ids,
+                                item,
+                                result_tx,
+                            },
+                            result_rx,
+                        )
+                        .await
+                    }
+                    Some(error) => error.into_bad_request(),
+                }
+            }
+            (&Method::POST, Some("queue"), Some("edit")) => {
+                let mut queue_id = None;
+                let mut error = None;
+
+                if let Some(query) = req.uri().query() {
+                    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                        match key.as_ref() {
+                            "id" => match value.parse() {
+                                Ok(id) => {
+                                    queue_id = Some(id);
+                                }
+                                Err(_) => {
+                                    error = format!("Failed to parse id {value:?}.").into();
+                                    break;
+                                }
+                            },
+                            _ => {
+                                error = format!("Invalid parameter {key:?}.").into();
+                                break;
+                            }
+                        }
+                    }
+                }
+                let edit = match (error, queue_id) {
+                    (None, Some(queue_id)) => {
+                        let body = req.into_body().collect().await?.to_bytes();
+                        serde_json::from_slice::<RecipientEdit>(&body)
+                            .map(|edit| (queue_id, edit))
+                            .map_err(|err| format!("Invalid request body: {err}"))
+                    }
+                    (None, None) => Err("Missing parameter \"id\".".to_string()),
+                    (Some(error), _) => Err(error),
+                };
+
+                match edit {
+                    Ok((queue_id, edit)) => {
+                        let (result_tx, result_rx) = oneshot::channel();
+                        let result = if self
+                            .queue
+                            .tx
+                            .send(queue::Event::Manage(QueueRequest::Edit {
+                                queue_id,
+                                edit,
+                                result_tx,
+                            }))
+                            .await
+                            .is_ok()
+                        {
+                            result_rx.await.ok()
+                        } else {
+                            None
+                        };
+
+                        match result {
+                            Some(Ok(message)) => (
+                                StatusCode::OK,
+                                serde_json::to_string(&Response { data: message })
+                                    .unwrap_or_default(),
+                            ),
+                            Some(Err(reason)) => reason.into_bad_request(),
+                            None => (
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                "{\"error\": \"internal-error\", \"details\": \"Resource unavailable, try again later.\"}"
+                                    .to_string(),
+                            ),
+                        }
+                    }
+                    Err(error) => error.into_bad_request(),
+                }
+            }
+            (&Method::GET, Some("queue"), Some("stats")) => {
+                let (result_tx, result_rx) = oneshot::channel();
+                self.send_queue_event(QueueRequest::Stats { result_tx }, result_rx)
+                    .await
+            }
             (&Method::GET, Some("report"), Some("list")) => {
                 let mut domain = None;
                 let mut type_ = None;
@@ -699,6 +911,61 @@ impl Core {
                     Some(error) => error.into_bad_request(),
                 }
             }
+            (&Method::GET, Some("rejected"), Some("list")) => (
+                StatusCode::OK,
+                serde_json::to_string(&Response {
+                    data: self.list_rejected().await,
+                })
+                .unwrap_or_default(),
+            ),
+            (&Method::GET, Some("rejected"), Some(action @ ("status" | "replay"))) => {
+                let mut ids = Vec::new();
+                let mut error = None;
+
+                if let Some(query) = req.uri().query() {
+                    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+                        match key.as_ref() {
+                            "id" | "ids" => match value.parse_queue_ids() {
+                                Ok(ids_) => {
+                                    ids = ids_;
+                                }
+                                Err(reason) => {
+                                    error = reason.into();
+                                    break;
+                                }
+                            },
+                            _ => {
+                                error = format!("Invalid parameter {key:?}.").into();
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                match error {
+                    None if action == "status" => {
+                        let mut result = Vec::with_capacity(ids.len());
+                        for id in ids {
+                            result.push(self.rejected_status(id).await);
+                        }
+                        (
+                            StatusCode::OK,
+                            serde_json::to_string(&Response { data: result }).unwrap_or_default(),
+                        )
+                    }
+                    None => {
+                        let mut result = Vec::with_capacity(ids.len());
+                        for id in ids {
+                            result.push(self.replay_rejected(id).await);
+                        }
+                        (
+                            StatusCode::OK,
+                            serde_json::to_string(&Response { data: result }).unwrap_or_default(),
+                        )
+                    }
+                    Some(error) => error.into_bad_request(),
+                }
+            }
             _ => (
                 StatusCode::NOT_FOUND,
                 format!(
@@ -804,6 +1071,7 @@ impl From<&queue::Message> for Message {
             size: message.size,
             priority: message.priority,
             env_id: message.env_id.clone(),
+            metadata: message.metadata.clone(),
             domains: message
                 .domains
                 .iter()
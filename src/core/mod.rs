@@ -25,58 +25,71 @@ use std::{
     borrow::Cow,
     hash::Hash,
     net::IpAddr,
-    sync::{atomic::AtomicU32, Arc},
+    sync::{
+        atomic::{AtomicBool, AtomicU32},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use ahash::AHashMap;
 use dashmap::DashMap;
-use mail_auth::{common::lru::LruCache, IprevOutput, Resolver, SpfOutput};
+use mail_auth::{
+    common::lru::LruCache,
+    trust_dns_resolver::{proto::op::ResponseCode, TokioAsyncResolver},
+    IprevOutput, Resolver, SpfOutput,
+};
+use mail_send::Credentials;
 use sieve::{Runtime, Sieve};
 use smtp_proto::request::receiver::{
     BdatReceiver, DataReceiver, DummyDataReceiver, DummyLineReceiver, LineReceiver, RequestReceiver,
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    sync::mpsc,
+    sync::{mpsc, watch},
 };
 use tokio_rustls::TlsConnector;
 use tracing::Span;
 
 use crate::{
     config::{
-        DkimSigner, EnvelopeKey, MailAuthConfig, QueueConfig, ReportConfig, SessionConfig,
-        VerifyStrategy,
+        AddressStrictness, CatchAllAction, DkimSigner, EnvelopeKey, LookupFailAction,
+        MailAuthConfig, QueueConfig, ReportConfig, SessionConfig, VerifyStrategy,
     },
     inbound::auth::SaslToken,
-    lookup::{Lookup, SqlDatabase},
+    lookup::{geoip::GeoIp, Lookup, SqlDatabase},
     outbound::{
         dane::{DnssecResolver, Tlsa},
         mta_sts,
+        pool::{ConnectionKey, PooledConnection},
     },
     queue::{self, QuotaLimiter},
     reporting,
 };
 
 use self::throttle::{
-    ConcurrencyLimiter, InFlight, Limiter, ThrottleKey, ThrottleKeyHasherBuilder,
+    ConcurrencyLimiter, Connection, ConnectionLimiter, HandshakeLimiter, InFlight, Limiter,
+    LogLimiter, ThrottleKey, ThrottleKeyHasherBuilder,
 };
 
 pub mod if_block;
 pub mod management;
 pub mod params;
 pub mod scripts;
+pub mod syslog;
 pub mod throttle;
 pub mod worker;
 
 pub struct Core {
     pub worker_pool: rayon::ThreadPool,
+    pub tls_handshakes: HandshakeLimiter,
     pub session: SessionCore,
     pub queue: QueueCore,
     pub resolvers: Resolvers,
     pub mail_auth: MailAuthConfig,
     pub report: ReportCore,
     pub sieve: SieveCore,
+    pub draining: AtomicBool,
 }
 
 pub struct SieveCore {
@@ -97,24 +110,60 @@ pub struct SieveConfig {
 pub struct Resolvers {
     pub dns: Resolver,
     pub dnssec: DnssecResolver,
+    pub uncached: TokioAsyncResolver,
     pub cache: DnsCache,
 }
 
 pub struct DnsCache {
     pub tlsa: LruCache<String, Arc<Tlsa>>,
     pub mta_sts: LruCache<String, Arc<mta_sts::Policy>>,
+    pub mx_negative: LruCache<String, ResponseCode>,
+    pub mx_negative_ttl: Duration,
 }
 
 pub struct SessionCore {
     pub config: SessionConfig,
     pub concurrency: ConcurrencyLimiter,
     pub throttle: DashMap<ThrottleKey, Limiter, ThrottleKeyHasherBuilder>,
+    pub auth_origins: DashMap<String, AuthOrigin>,
+    pub auth_sessions: DashMap<String, ConcurrencyLimiter>,
+    pub abandoned: DashMap<IpAddr, AbandonedSessions>,
+    pub catch_all: DashMap<String, CatchAllDomain>,
+    pub log_limiter: LogLimiter,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthOrigin {
+    pub remote_ip: IpAddr,
+    pub country: String,
+    pub expires: Instant,
+}
+
+pub struct AbandonedSessions {
+    pub count: usize,
+    pub expires: Instant,
+}
+
+pub struct CatchAllDomain {
+    pub is_catch_all: bool,
+    pub expires: Instant,
+}
+
+pub struct MxStats {
+    pub success_rate: f64,
+    pub latency: f64,
+    pub expires: Instant,
 }
 
 pub struct QueueCore {
     pub config: QueueConfig,
+    pub concurrency: ConcurrencyLimiter,
     pub throttle: DashMap<ThrottleKey, Limiter, ThrottleKeyHasherBuilder>,
     pub quota: DashMap<ThrottleKey, Arc<QuotaLimiter>, ThrottleKeyHasherBuilder>,
+    pub mx_stats: DashMap<String, MxStats>,
+    pub no_pipelining: DashMap<String, Instant>,
+    pub source_ips: DashMap<IpAddr, ConcurrencyLimiter>,
+    pub connections: DashMap<ConnectionKey, Vec<PooledConnection>>,
     pub tx: mpsc::Sender<queue::Event>,
     pub id_seq: AtomicU32,
     pub connectors: TlsConnectors,
@@ -136,16 +185,25 @@ pub enum State {
     Data(DataReceiver),
     Sasl(LineReceiver<SaslToken>),
     DataTooLarge(DummyDataReceiver),
+    BdatTooLarge(DummyDataReceiver),
+    BdatRejected(DummyDataReceiver),
     RequestTooLarge(DummyLineReceiver),
     None,
 }
 
+pub enum SessionEnd {
+    StartTls(watch::Receiver<bool>),
+    ClosedByPeer,
+    ClosedByServer,
+}
+
 pub struct ServerInstance {
     pub id: String,
     pub listener_id: u16,
     pub is_smtp: bool,
     pub hostname: String,
     pub greeting: Vec<u8>,
+    pub connections: Option<Arc<ConnectionLimiter>>,
 }
 
 pub struct Session<T: AsyncWrite + AsyncRead> {
@@ -157,6 +215,7 @@ pub struct Session<T: AsyncWrite + AsyncRead> {
     pub data: SessionData,
     pub params: SessionParameters,
     pub in_flight: Vec<InFlight>,
+    pub connection: Option<Connection>,
 }
 
 pub struct SessionData {
@@ -166,11 +225,14 @@ pub struct SessionData {
 
     pub mail_from: Option<SessionAddress>,
     pub rcpt_to: Vec<SessionAddress>,
+    pub rcpt_lists: Vec<String>,
     pub rcpt_errors: usize,
     pub message: Vec<u8>,
 
     pub authenticated_as: String,
+    pub auth_credentials: Option<Credentials<String>>,
     pub auth_errors: usize,
+    pub peer_certificate: Option<rustls::Certificate>,
 
     pub priority: i16,
     pub delivery_by: i64,
@@ -178,12 +240,16 @@ pub struct SessionData {
 
     pub valid_until: Instant,
     pub bytes_left: usize,
+    pub commands: usize,
     pub messages_sent: usize,
+    pub pipelining: bool,
+    pub mail_from_at: Option<Instant>,
 
     pub iprev: Option<IprevOutput>,
     pub spf_ehlo: Option<SpfOutput>,
     pub spf_mail_from: Option<SpfOutput>,
     pub dnsbl_error: Option<Vec<u8>>,
+    pub geoip: Option<GeoIp>,
 }
 
 #[derive(Clone)]
@@ -199,11 +265,15 @@ pub struct SessionAddress {
 pub struct SessionParameters {
     // Global parameters
     pub timeout: Duration,
+    pub command_limit: usize,
 
     // Ehlo parameters
     pub ehlo_require: bool,
     pub ehlo_reject_non_fqdn: bool,
 
+    // Mail parameters
+    pub address_strictness: AddressStrictness,
+
     // Auth parameters
     pub auth_lookup: Option<Arc<Lookup>>,
     pub auth_require: bool,
@@ -217,9 +287,14 @@ pub struct SessionParameters {
     pub rcpt_errors_wait: Duration,
     pub rcpt_max: usize,
     pub rcpt_dsn: bool,
+    pub rcpt_rrvs: bool,
     pub rcpt_lookup_domain: Option<Arc<Lookup>>,
     pub rcpt_lookup_addresses: Option<Arc<Lookup>>,
     pub rcpt_lookup_expn: Option<Arc<Lookup>>,
+    pub rcpt_lookup_lists: Option<Arc<Lookup>>,
+    pub rcpt_lookup_created: Option<Arc<Lookup>>,
+    pub rcpt_lookup_fail_action: LookupFailAction,
+    pub rcpt_catch_all: Option<CatchAllAction>,
     pub rcpt_lookup_vrfy: Option<Arc<Lookup>>,
     pub max_message_size: usize,
 
@@ -238,20 +313,27 @@ impl SessionData {
             helo_domain: String::new(),
             mail_from: None,
             rcpt_to: Vec::new(),
+            rcpt_lists: Vec::new(),
             authenticated_as: String::new(),
+            auth_credentials: None,
             priority: 0,
             valid_until: Instant::now(),
             rcpt_errors: 0,
             message: Vec::with_capacity(0),
             auth_errors: 0,
+            peer_certificate: None,
             messages_sent: 0,
+            pipelining: false,
+            mail_from_at: None,
             bytes_left: 0,
+            commands: 0,
             delivery_by: 0,
             future_release: 0,
             iprev: None,
             spf_ehlo: None,
             spf_mail_from: None,
             dnsbl_error: None,
+            geoip: None,
         }
     }
 }
@@ -274,6 +356,8 @@ pub trait Envelope {
     fn mx(&self) -> &str;
     fn listener_id(&self) -> u16;
     fn priority(&self) -> i16;
+    fn country(&self) -> &str;
+    fn asn(&self) -> u32;
 
     #[inline(always)]
     fn key_to_string(&self, key: &EnvelopeKey) -> Cow<'_, str> {
@@ -289,6 +373,11 @@ pub trait Envelope {
             EnvelopeKey::RemoteIp => self.remote_ip().to_string().into(),
             EnvelopeKey::LocalIp => self.local_ip().to_string().into(),
             EnvelopeKey::Priority => self.priority().to_string().into(),
+            EnvelopeKey::Country => self.country().into(),
+            EnvelopeKey::Asn => match self.asn() {
+                0 => "".into(),
+                asn => asn.to_string().into(),
+            },
         }
     }
 }
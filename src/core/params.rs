@@ -32,6 +32,7 @@ impl<T: AsyncRead + AsyncWrite> Session<T> {
         self.data.valid_until += *c.duration.eval(self).await;
 
         self.params.timeout = *c.timeout.eval(self).await;
+        self.params.command_limit = *c.command_limit.eval(self).await;
         self.params.spf_ehlo = *self.core.mail_auth.spf.verify_ehlo.eval(self).await;
         self.params.spf_mail_from = *self.core.mail_auth.spf.verify_mail_from.eval(self).await;
         self.params.iprev = *self.core.mail_auth.iprev.verify.eval(self).await;
@@ -42,6 +43,16 @@ impl<T: AsyncRead + AsyncWrite> Session<T> {
         self.params.ehlo_require = *ec.require.eval(self).await;
         self.params.ehlo_reject_non_fqdn = *ec.reject_non_fqdn.eval(self).await;
 
+        // Mail parameters
+        self.params.address_strictness = *self
+            .core
+            .session
+            .config
+            .mail
+            .address_strictness
+            .eval(self)
+            .await;
+
         // Auth parameters
         let ac = &self.core.session.config.auth;
         self.params.auth_lookup = ac.lookup.eval(self).await.clone();
@@ -71,7 +82,17 @@ impl<T: AsyncRead + AsyncWrite> Session<T> {
         self.params.rcpt_max = *rc.max_recipients.eval(self).await;
         self.params.rcpt_lookup_domain = rc.lookup_domains.eval(self).await.clone();
         self.params.rcpt_lookup_addresses = rc.lookup_addresses.eval(self).await.clone();
+        self.params.rcpt_lookup_lists = rc.lookup_lists.eval(self).await.clone();
+        self.params.rcpt_lookup_created = rc.lookup_created.eval(self).await.clone();
+        self.params.rcpt_lookup_fail_action = *rc.lookup_fail_action.eval(self).await;
+        self.params.rcpt_catch_all = if *rc.catch_all_detect.eval(self).await {
+            Some(*rc.catch_all_action.eval(self).await)
+        } else {
+            None
+        };
         self.params.rcpt_dsn = *self.core.session.config.extensions.dsn.eval(self).await;
+        self.params.rcpt_rrvs = *self.core.session.config.extensions.rrvs.eval(self).await
+            && self.params.rcpt_lookup_created.is_some();
 
         self.params.max_message_size = *self
             .core
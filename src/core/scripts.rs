@@ -75,6 +75,10 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
             "authenticated_as".to_string(),
             self.data.authenticated_as.clone().into(),
         );
+        if let Some(geoip) = &self.data.geoip {
+            vars_env.insert("country".to_string(), geoip.country.clone().into());
+            vars_env.insert("asn".to_string(), geoip.asn.to_string().into());
+        }
 
         // Set envelope
         let envelope = if let Some(mail_from) = &self.data.mail_from {
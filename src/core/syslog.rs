@@ -0,0 +1,329 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fmt::{Debug, Write as _},
+    io::Write,
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use mail_parser::DateTime;
+use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+const SD_ID: &str = "stalwart@32473";
+const QUEUE_SIZE: usize = 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct SyslogLayer {
+    tx: SyncSender<Vec<u8>>,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    proc_id: u32,
+}
+
+pub enum SyslogTransport {
+    Udp,
+    Tcp,
+    Tls {
+        config: Arc<ClientConfig>,
+        server_name: ServerName,
+    },
+}
+
+struct SpanFields(String);
+
+#[derive(Default)]
+struct EventVisitor {
+    message: String,
+    msg_id: String,
+    params: String,
+}
+
+impl SyslogLayer {
+    pub fn new(
+        address: String,
+        transport: SyslogTransport,
+        facility: u8,
+        hostname: String,
+        app_name: String,
+    ) -> Self {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("syslog".to_string())
+            .spawn(move || {
+                SyslogWriter {
+                    address,
+                    transport,
+                    udp: None,
+                    stream: None,
+                }
+                .run(rx)
+            })
+            .expect("Failed to spawn syslog thread");
+
+        SyslogLayer {
+            tx,
+            facility,
+            hostname,
+            app_name,
+            proc_id: std::process::id(),
+        }
+    }
+
+    pub fn format(&self, level: &Level, msg_id: &str, params: &str, message: &str) -> String {
+        let severity = match *level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let timestamp = DateTime::from_timestamp(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()) as i64,
+        )
+        .to_rfc3339();
+
+        let mut result = String::with_capacity(128 + params.len() + message.len());
+        let _ = write!(
+            result,
+            "<{}>1 {} {} {} {} {} ",
+            (self.facility as u32) * 8 + severity,
+            timestamp,
+            header_field(&self.hostname, 255),
+            header_field(&self.app_name, 48),
+            self.proc_id,
+            header_field(msg_id, 32),
+        );
+        if !params.is_empty() {
+            let _ = write!(result, "[{SD_ID}{params}]");
+        } else {
+            result.push('-');
+        }
+        if !message.is_empty() {
+            result.push(' ');
+            result.push_str(message);
+        }
+        result
+    }
+}
+
+impl<S> Layer<S> for SyslogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut visitor = EventVisitor::default();
+            attrs.record(&mut visitor);
+            span.extensions_mut().insert(SpanFields(visitor.params));
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut visitor = EventVisitor::default();
+            values.record(&mut visitor);
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                fields.0.push_str(&visitor.params);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    visitor.params.push_str(&fields.0);
+                }
+            }
+        }
+
+        let _ = self.tx.try_send(
+            self.format(
+                event.metadata().level(),
+                &visitor.msg_id,
+                &visitor.params,
+                &visitor.message,
+            )
+            .into_bytes(),
+        );
+    }
+}
+
+impl EventVisitor {
+    fn add_param(&mut self, name: &str, value: &str) {
+        self.params.push(' ');
+        for ch in name.chars().take(32) {
+            if ch.is_ascii_graphic() && !matches!(ch, '=' | ']' | '"') {
+                self.params.push(ch);
+            } else {
+                self.params.push('_');
+            }
+        }
+        self.params.push_str("=\"");
+        for ch in value.chars() {
+            match ch {
+                '"' | '\\' | ']' => {
+                    self.params.push('\\');
+                    self.params.push(ch);
+                }
+                '\r' | '\n' => self.params.push(' '),
+                _ => self.params.push(ch),
+            }
+        }
+        self.params.push('"');
+    }
+}
+
+impl Visit for EventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.replace(['\r', '\n'], " "),
+            "event" => {
+                self.msg_id = value.to_string();
+                self.add_param("event", value);
+            }
+            name => self.add_param(name, value),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}").replace(['\r', '\n'], " "),
+            name => self.add_param(name, &format!("{value:?}")),
+        }
+    }
+}
+
+fn header_field(value: &str, max_len: usize) -> String {
+    let value = value
+        .chars()
+        .filter(|ch| ch.is_ascii_graphic())
+        .take(max_len)
+        .collect::<String>();
+    if !value.is_empty() {
+        value
+    } else {
+        "-".to_string()
+    }
+}
+
+struct SyslogWriter {
+    address: String,
+    transport: SyslogTransport,
+    udp: Option<UdpSocket>,
+    stream: Option<Box<dyn Write + Send>>,
+}
+
+impl SyslogWriter {
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        let mut is_failing = false;
+        while let Ok(message) = rx.recv() {
+            match self.send(&message) {
+                Ok(_) => is_failing = false,
+                Err(err) if !is_failing => {
+                    eprintln!("Failed to send syslog message to {}: {}", self.address, err);
+                    is_failing = true;
+                }
+                Err(_) => (),
+            }
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) -> std::io::Result<()> {
+        if let SyslogTransport::Udp = &self.transport {
+            if self.udp.is_none() {
+                let socket = UdpSocket::bind(if self.address.starts_with('[') {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                })?;
+                socket.connect(&self.address)?;
+                self.udp = socket.into();
+            }
+            return self.udp.as_ref().unwrap().send(message).map(|_| ());
+        }
+
+        // Stream transports use octet-counting framing (RFC 6587)
+        let mut frame = Vec::with_capacity(message.len() + 8);
+        let _ = write!(frame, "{} ", message.len());
+        frame.extend_from_slice(message);
+
+        for _ in 0..2 {
+            if self.stream.is_none() {
+                self.stream = self.connect()?.into();
+            }
+            match self
+                .stream
+                .as_mut()
+                .unwrap()
+                .write_all(&frame)
+                .and_then(|_| self.stream.as_mut().unwrap().flush())
+            {
+                Ok(_) => return Ok(()),
+                Err(_) => {
+                    self.stream = None;
+                }
+            }
+        }
+
+        Err(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "connection lost",
+        ))
+    }
+
+    fn connect(&self) -> std::io::Result<Box<dyn Write + Send>> {
+        let addr = self.address.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "failed to resolve address")
+        })?;
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_write_timeout(CONNECT_TIMEOUT.into())?;
+
+        match &self.transport {
+            SyslogTransport::Tls {
+                config,
+                server_name,
+            } => Ok(Box::new(StreamOwned::new(
+                ClientConnection::new(config.clone(), server_name.clone())
+                    .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?,
+                stream,
+            ))),
+            _ => Ok(Box::new(stream)),
+        }
+    }
+}
@@ -21,8 +21,13 @@
  * for more details.
 */
 
+use ahash::AHashMap;
 use dashmap::mapref::entry::Entry;
-use tokio::io::{AsyncRead, AsyncWrite};
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{watch, Notify, OwnedSemaphorePermit, Semaphore},
+};
 
 use std::{
     hash::{BuildHasher, Hash, Hasher},
@@ -31,12 +36,12 @@ use std::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::config::*;
 
-use super::{Envelope, Session};
+use super::{Core, Envelope, Session};
 
 #[derive(Debug)]
 pub struct Limiter {
@@ -61,6 +66,39 @@ pub struct InFlight {
     concurrent: Arc<AtomicU64>,
 }
 
+#[derive(Debug, Default)]
+pub struct LogLimiter {
+    pub rate: Option<Rate>,
+    events: DashMap<&'static str, (RateLimiter, u64)>,
+}
+
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    pub max_connections: usize,
+    pub overflow: ConnectionOverflow,
+    next_id: AtomicU64,
+    connections: Mutex<AHashMap<u64, ConnectionState>>,
+}
+
+#[derive(Debug)]
+struct ConnectionState {
+    idle_since: Option<Instant>,
+    evict: Arc<Notify>,
+}
+
+pub struct Connection {
+    id: u64,
+    limiter: Arc<ConnectionLimiter>,
+    pub evict: Arc<Notify>,
+}
+
+#[derive(Debug)]
+pub struct HandshakeLimiter {
+    pub max_concurrent: usize,
+    pub max_wait: Duration,
+    semaphore: Arc<Semaphore>,
+}
+
 impl Drop for InFlight {
     fn drop(&mut self) {
         self.concurrent.fetch_sub(1, Ordering::Relaxed);
@@ -106,6 +144,34 @@ impl RateLimiter {
     pub fn reset(&mut self) {
         self.limiter = (Instant::now(), self.max_requests);
     }
+
+    pub fn available(&self) -> f64 {
+        (self.limiter.1
+            + self.limiter.0.elapsed().as_secs_f64() * (self.max_requests / self.max_interval))
+            .min(self.max_requests)
+    }
+
+    pub fn utilization(&self) -> f64 {
+        (1.0 - self.available() / self.max_requests).clamp(0.0, 1.0)
+    }
+
+    pub fn restore(
+        max_requests: f64,
+        max_interval: f64,
+        available: f64,
+        elapsed: Duration,
+    ) -> Self {
+        RateLimiter {
+            max_requests,
+            max_interval,
+            limiter: (
+                Instant::now()
+                    .checked_sub(elapsed)
+                    .unwrap_or_else(Instant::now),
+                available,
+            ),
+        }
+    }
 }
 
 impl ConcurrencyLimiter {
@@ -133,6 +199,126 @@ impl ConcurrencyLimiter {
     }
 }
 
+impl HandshakeLimiter {
+    pub fn new(max_concurrent: usize, max_wait: Duration) -> Self {
+        HandshakeLimiter {
+            max_concurrent,
+            max_wait,
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+        }
+    }
+
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        // Wait briefly for a handshake slot, otherwise defer the connection
+        tokio::time::timeout(self.max_wait, self.semaphore.clone().acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize, overflow: ConnectionOverflow) -> Self {
+        ConnectionLimiter {
+            max_connections,
+            overflow,
+            next_id: AtomicU64::new(0),
+            connections: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    pub fn admit(self: &Arc<Self>) -> Option<Connection> {
+        let mut connections = self.connections.lock();
+        if connections.len() >= self.max_connections {
+            if self.overflow != ConnectionOverflow::EvictOldestIdle {
+                return None;
+            }
+
+            // Make room by closing the connection that has been idle the longest
+            let id = connections
+                .iter()
+                .filter_map(|(id, conn)| conn.idle_since.map(|idle_since| (*id, idle_since)))
+                .min_by_key(|(_, idle_since)| *idle_since)?
+                .0;
+            connections.remove(&id)?.evict.notify_one();
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let evict = Arc::new(Notify::new());
+        connections.insert(
+            id,
+            ConnectionState {
+                idle_since: None,
+                evict: evict.clone(),
+            },
+        );
+        Some(Connection {
+            id,
+            limiter: self.clone(),
+            evict,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.lock().is_empty()
+    }
+}
+
+impl Connection {
+    pub fn set_idle(&self, is_idle: bool) {
+        if let Some(conn) = self.limiter.connections.lock().get_mut(&self.id) {
+            conn.idle_since = if is_idle { Some(Instant::now()) } else { None };
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.limiter.connections.lock().remove(&self.id);
+    }
+}
+
+impl LogLimiter {
+    pub fn new(rate: Option<Rate>) -> Self {
+        LogLimiter {
+            rate,
+            events: DashMap::new(),
+        }
+    }
+
+    // Returns the number of events suppressed since the last one was logged,
+    // or None if this event should be suppressed as well.
+    pub fn is_allowed(&self, event: &'static str) -> Option<u64> {
+        let rate = if let Some(rate) = &self.rate {
+            rate
+        } else {
+            return Some(0);
+        };
+        let mut entry = self.events.entry(event).or_insert_with(|| {
+            (
+                RateLimiter::restore(
+                    rate.requests as f64,
+                    rate.period.as_secs_f64(),
+                    rate.requests as f64,
+                    Duration::ZERO,
+                ),
+                0,
+            )
+        });
+        let (limiter, suppressed) = entry.value_mut();
+        if limiter.is_allowed() {
+            Some(std::mem::take(suppressed))
+        } else {
+            *suppressed += 1;
+            None
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq)]
 pub struct ThrottleKey {
     hash: [u8; 32],
@@ -216,6 +402,23 @@ impl QueueQuota {
     }
 }
 
+impl Tarpit {
+    pub fn delay(&self, utilization: f64) -> Option<Duration> {
+        let threshold = self.threshold as f64 / 100.0;
+        if utilization > threshold {
+            let x = ((utilization - threshold) / (1.0 - threshold)).min(1.0);
+            let factor = match self.curve {
+                TarpitCurve::Linear => x,
+                TarpitCurve::Quadratic => x * x,
+                TarpitCurve::Exponential => (2f64.powf(10.0 * x) - 1.0) / 1023.0,
+            };
+            Some(self.max_delay.mul_f64(factor))
+        } else {
+            None
+        }
+    }
+}
+
 impl Throttle {
     pub fn new_key(&self, e: &impl Envelope) -> ThrottleKey {
         let mut hasher = blake3::Hasher::new();
@@ -273,6 +476,12 @@ impl Throttle {
                 }
             }
         }
+        if (self.keys & THROTTLE_COUNTRY) != 0 {
+            hasher.update(e.country().as_bytes());
+        }
+        if (self.keys & THROTTLE_ASN) != 0 {
+            hasher.update(&e.asn().to_ne_bytes()[..]);
+        }
         if let Some(rate_limit) = &self.rate {
             hasher.update(&rate_limit.period.as_secs().to_ne_bytes()[..]);
             hasher.update(&rate_limit.requests.to_ne_bytes()[..]);
@@ -288,6 +497,25 @@ impl Throttle {
 }
 
 impl<T: AsyncRead + AsyncWrite> Session<T> {
+    pub fn is_log_allowed(&self, event: &'static str) -> bool {
+        match self.core.session.log_limiter.is_allowed(event) {
+            Some(0) => true,
+            Some(suppressed) => {
+                tracing::info!(
+                    parent: &self.span,
+                    context = "log",
+                    event = "suppressed",
+                    kind = event,
+                    count = suppressed,
+                    "Suppressed {} similar events.",
+                    suppressed
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
     pub async fn is_allowed(&mut self) -> bool {
         let throttles = if !self.data.rcpt_to.is_empty() {
             &self.core.session.config.throttle.rcpt_to
@@ -296,6 +524,7 @@ impl<T: AsyncRead + AsyncWrite> Session<T> {
         } else {
             &self.core.session.config.throttle.connect
         };
+        let mut delay = Duration::ZERO;
 
         for t in throttles {
             if t.conditions.conditions.is_empty() || t.conditions.eval(self).await {
@@ -341,6 +570,12 @@ impl<T: AsyncRead + AsyncWrite> Session<T> {
                                     "Rate limit exceeded."
                                 );
                                 return false;
+                            } else if let Some(tarpit_delay) = t
+                                .tarpit
+                                .as_ref()
+                                .and_then(|tarpit| tarpit.delay(limiter.utilization()))
+                            {
+                                delay = std::cmp::max(delay, tarpit_delay);
                             }
                         }
                     }
@@ -367,6 +602,17 @@ impl<T: AsyncRead + AsyncWrite> Session<T> {
             }
         }
 
+        if !delay.is_zero() {
+            tracing::debug!(
+                parent: &self.span,
+                context = "throttle",
+                event = "tarpit",
+                delay_ms = delay.as_millis() as u64,
+                "Delaying response, sender is approaching its rate limit."
+            );
+            tokio::time::sleep(delay).await;
+        }
+
         true
     }
 
@@ -400,3 +646,169 @@ impl<T: AsyncRead + AsyncWrite> Session<T> {
         }
     }
 }
+
+const SNAPSHOT_SESSION: u8 = 0;
+const SNAPSHOT_QUEUE: u8 = 1;
+const SNAPSHOT_RECORD_LEN: usize = 1 + 32 + (4 * std::mem::size_of::<u64>());
+
+impl Core {
+    pub fn serialize_throttle(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(
+            std::mem::size_of::<u64>()
+                + (self.session.throttle.len() + self.queue.throttle.len()) * SNAPSHOT_RECORD_LEN,
+        );
+        buf.extend_from_slice(
+            &SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+                .to_le_bytes(),
+        );
+
+        for (scope, throttle) in [
+            (SNAPSHOT_SESSION, &self.session.throttle),
+            (SNAPSHOT_QUEUE, &self.queue.throttle),
+        ] {
+            for entry in throttle.iter() {
+                // Only rate limiters that are not fully replenished need to be stored
+                if let Some(rate) = &entry.value().rate {
+                    let available = rate.available();
+                    if available < rate.max_requests {
+                        buf.push(scope);
+                        buf.extend_from_slice(&entry.key().hash);
+                        buf.extend_from_slice(&rate.max_requests.to_le_bytes());
+                        buf.extend_from_slice(&rate.max_interval.to_le_bytes());
+                        buf.extend_from_slice(&available.to_le_bytes());
+                        buf.extend_from_slice(
+                            &entry
+                                .value()
+                                .concurrency
+                                .as_ref()
+                                .map_or(0, |c| c.max_concurrent)
+                                .to_le_bytes(),
+                        );
+                    }
+                }
+            }
+        }
+
+        buf
+    }
+
+    pub fn restore_throttle(&self, bytes: &[u8]) -> usize {
+        let (timestamp, records) = if bytes.len() >= std::mem::size_of::<u64>() {
+            bytes.split_at(std::mem::size_of::<u64>())
+        } else {
+            return 0;
+        };
+
+        // Time elapsed since the snapshot was taken also replenishes the limiters
+        let elapsed = Duration::from_secs(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+                .saturating_sub(u64::from_le_bytes(timestamp.try_into().unwrap())),
+        );
+
+        let mut restored = 0;
+        for record in records.chunks_exact(SNAPSHOT_RECORD_LEN) {
+            let throttle = match record[0] {
+                SNAPSHOT_SESSION => &self.session.throttle,
+                SNAPSHOT_QUEUE => &self.queue.throttle,
+                _ => continue,
+            };
+            let value = |pos: usize| {
+                let pos = 33 + (pos * std::mem::size_of::<u64>());
+                u64::from_le_bytes(
+                    record[pos..pos + std::mem::size_of::<u64>()]
+                        .try_into()
+                        .unwrap(),
+                )
+            };
+            let max_concurrent = value(3);
+
+            throttle.insert(
+                ThrottleKey {
+                    hash: record[1..33].try_into().unwrap(),
+                },
+                Limiter {
+                    rate: RateLimiter::restore(
+                        f64::from_bits(value(0)),
+                        f64::from_bits(value(1)),
+                        f64::from_bits(value(2)),
+                        elapsed,
+                    )
+                    .into(),
+                    concurrency: if max_concurrent > 0 {
+                        ConcurrencyLimiter::new(max_concurrent).into()
+                    } else {
+                        None
+                    },
+                },
+            );
+            restored += 1;
+        }
+
+        restored
+    }
+}
+
+impl ThrottleSnapshot {
+    pub fn restore(&self, core: &Core) {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => {
+                let restored = core.restore_throttle(&bytes);
+                tracing::debug!(
+                    context = "throttle",
+                    event = "restore",
+                    path = %self.path.display(),
+                    entries = restored,
+                    "Restored throttle state from snapshot."
+                );
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => {
+                tracing::warn!(
+                    context = "throttle",
+                    event = "error",
+                    "Failed to read throttle snapshot {}: {}",
+                    self.path.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    pub async fn save(&self, core: &Core) {
+        let mut tmp_path = self.path.clone();
+        tmp_path.set_extension("tmp");
+        let result = match tokio::fs::write(&tmp_path, core.serialize_throttle()).await {
+            Ok(_) => tokio::fs::rename(&tmp_path, &self.path).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            tracing::warn!(
+                context = "throttle",
+                event = "error",
+                "Failed to write throttle snapshot {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+
+    pub fn spawn(self, core: Arc<Core>, mut shutdown_rx: watch::Receiver<bool>) {
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.interval) => {
+                        self.save(&core).await;
+                    },
+                    _ = shutdown_rx.changed() => {
+                        self.save(&core).await;
+                        break;
+                    }
+                };
+            }
+        });
+    }
+}
@@ -21,7 +21,10 @@
  * for more details.
 */
 
-use std::sync::{atomic::Ordering, Arc};
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
 
 use tokio::sync::oneshot;
 
@@ -66,6 +69,16 @@ impl Core {
         self.queue.quota.retain(|_, v| {
             v.messages.load(Ordering::Relaxed) > 0 || v.size.load(Ordering::Relaxed) > 0
         });
+        let now = Instant::now();
+        self.session.auth_origins.retain(|_, v| v.expires > now);
+        self.session.abandoned.retain(|_, v| v.expires > now);
+        self.session.catch_all.retain(|_, v| v.expires > now);
+        self.queue.mx_stats.retain(|_, v| v.expires > now);
+        self.queue.no_pipelining.retain(|_, v| *v > now);
+        self.session
+            .auth_sessions
+            .retain(|_, v| v.concurrent.load(Ordering::Relaxed) > 0);
+        self.purge_rejected();
     }
 }
 
@@ -0,0 +1,277 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use ahash::AHashSet;
+use mail_send::SmtpClient;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::oneshot,
+};
+
+use crate::{
+    core::Session,
+    lookup::{Item, LookupResult},
+    outbound::session::{quit, read_greeting, say_helo, SessionParams},
+    queue::{DeliveryAttempt, Event, Message, QueueEnvelope, Status},
+};
+
+use super::IsTls;
+
+impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
+    pub async fn handle_atrn(&mut self, domains: Vec<String>) -> Result<(), ()> {
+        let lookup = match self.core.session.config.extensions.atrn.eval(self).await {
+            Some(lookup) if self.instance.is_smtp => lookup.clone(),
+            _ => {
+                return self.write(b"502 5.5.1 Command not implemented.\r\n").await;
+            }
+        };
+        if self.data.authenticated_as.is_empty() {
+            return self.write(b"530 5.7.0 Authentication required.\r\n").await;
+        } else if self.data.mail_from.is_some() {
+            return self
+                .write(b"503 5.5.1 Transaction already in progress.\r\n")
+                .await;
+        }
+
+        // Obtain the domains the authenticated user is allowed to dequeue
+        let authorized = match lookup
+            .lookup(Item::Expand(self.data.authenticated_as.clone()))
+            .await
+        {
+            Some(LookupResult::Values(values)) => values
+                .into_iter()
+                .map(|value| value.trim().to_lowercase())
+                .filter(|value| !value.is_empty())
+                .collect::<AHashSet<_>>(),
+            Some(_) => AHashSet::new(),
+            None => {
+                return self
+                    .write(b"451 4.4.3 Unable to verify authorization at this time.\r\n")
+                    .await;
+            }
+        };
+        let domains = if !domains.is_empty() {
+            let mut requested = Vec::with_capacity(domains.len());
+            for domain in domains {
+                let domain = domain.to_lowercase();
+                if authorized.contains(&domain) {
+                    if !requested.contains(&domain) {
+                        requested.push(domain);
+                    }
+                } else {
+                    tracing::info!(parent: &self.span,
+                        context = "atrn",
+                        event = "denied",
+                        domain = domain,
+                        "Authenticated user is not authorized to dequeue domain.");
+
+                    return self
+                        .write(
+                            format!("450 4.7.1 Access denied to domain {domain}.\r\n").as_bytes(),
+                        )
+                        .await;
+                }
+            }
+            requested
+        } else {
+            authorized.into_iter().collect()
+        };
+        if domains.is_empty() {
+            return self
+                .write(b"450 4.7.1 No domains available for dequeuing.\r\n")
+                .await;
+        }
+
+        // Take the queued messages for these domains from the queue manager
+        let (result_tx, result_rx) = oneshot::channel();
+        let messages = if self
+            .core
+            .queue
+            .tx
+            .send(Event::Release {
+                domains: domains.clone(),
+                result_tx,
+            })
+            .await
+            .is_ok()
+        {
+            result_rx.await.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        if messages.is_empty() {
+            return self.write(b"453 4.3.0 You have no mail.\r\n").await;
+        }
+
+        tracing::info!(parent: &self.span,
+            context = "atrn",
+            event = "reverse",
+            domains = ?domains,
+            messages = messages.len(),
+            "Reversing connection for on-demand relay.");
+
+        if self
+            .write(b"250 2.0.0 OK now reversing the connection.\r\n")
+            .await
+            .is_ok()
+        {
+            self.deliver_reversed(messages, &domains).await;
+        } else {
+            for message in messages {
+                DeliveryAttempt::from(message)
+                    .finish(&self.core, vec![])
+                    .await;
+            }
+        }
+
+        // The connection cannot be used once the roles have been reversed
+        Err(())
+    }
+
+    async fn deliver_reversed(&mut self, mut messages: Vec<Box<Message>>, domains: &[String]) {
+        let core = self.core.clone();
+        let queue_config = &core.queue.config;
+        let span = self.span.clone();
+        let hostname = if !self.data.helo_domain.is_empty() {
+            self.data.helo_domain.clone()
+        } else {
+            self.data.remote_ip.to_string()
+        };
+
+        // Obtain session parameters
+        let envelope = QueueEnvelope {
+            message: messages[0].as_ref(),
+            domain: &domains[0],
+            mx: &hostname,
+            remote_ip: self.data.remote_ip,
+            local_ip: self.data.local_ip,
+        };
+        let params = SessionParams {
+            span: &span,
+            hostname: &hostname,
+            credentials: None,
+            is_smtp: true,
+            local_hostname: queue_config.hostname.eval(&envelope).await,
+            timeout_ehlo: *queue_config.timeout.ehlo.eval(&envelope).await,
+            timeout_mail: *queue_config.timeout.mail.eval(&envelope).await,
+            timeout_rcpt: *queue_config.timeout.rcpt.eval(&envelope).await,
+            timeout_data: *queue_config.timeout.data.eval(&envelope).await,
+            pipelining: *queue_config.pipelining.eval(&envelope).await
+                && self.core.queue.has_pipelining(&hostname),
+            keep_alive: false,
+            retry_over_quota: !queue_config
+                .over_quota_retry
+                .eval(&envelope)
+                .await
+                .is_empty(),
+            spool: &queue_config.spool,
+            encryption: queue_config.encryption.as_ref(),
+            queue: &self.core.queue,
+        };
+        let mut smtp_client = SmtpClient {
+            stream: &mut self.stream,
+            timeout: *queue_config.timeout.greeting.eval(&envelope).await,
+        };
+
+        // Read greeting and say EHLO
+        let capabilities = match read_greeting(&mut smtp_client, &hostname).await {
+            Ok(_) => say_helo(&mut smtp_client, &params).await,
+            Err(status) => Err(status),
+        };
+
+        // Deliver messages over the reversed connection
+        match capabilities {
+            Ok(capabilities) => {
+                let mut num_transactions = 0;
+                'next_message: for message in messages.iter_mut() {
+                    for domain_idx in 0..message.domains.len() {
+                        let domain = &message.domains[domain_idx];
+                        if !matches!(
+                            domain.status,
+                            Status::Scheduled | Status::TemporaryFailure(_)
+                        ) || !domains.contains(&domain.domain.to_lowercase())
+                        {
+                            continue;
+                        }
+                        if num_transactions > 0
+                            && smtp_client
+                                .cmd(b"RSET\r\n")
+                                .await
+                                .and_then(|r| r.assert_positive_completion())
+                                .is_err()
+                        {
+                            break 'next_message;
+                        }
+                        num_transactions += 1;
+
+                        let mut recipients = std::mem::take(&mut message.recipients);
+                        let result = message
+                            .send_transaction(
+                                &mut smtp_client,
+                                &capabilities,
+                                recipients
+                                    .iter_mut()
+                                    .filter(|rcpt| rcpt.domain_idx == domain_idx),
+                                &params,
+                            )
+                            .await;
+                        message.recipients = recipients;
+
+                        let envelope = QueueEnvelope {
+                            message: message.as_ref(),
+                            domain: &message.domains[domain_idx].domain,
+                            mx: &hostname,
+                            remote_ip: self.data.remote_ip,
+                            local_ip: self.data.local_ip,
+                        };
+                        let schedule = queue_config.retry.eval(&envelope).await;
+                        let jitter = *queue_config.jitter.eval(&envelope).await;
+                        match result {
+                            Ok(status) => {
+                                message.domains[domain_idx].set_status(status, schedule, jitter);
+                            }
+                            Err(status) => {
+                                message.domains[domain_idx].set_status(status, schedule, jitter);
+                                break 'next_message;
+                            }
+                        }
+                    }
+                }
+            }
+            Err(status) => {
+                tracing::info!(parent: &span,
+                    context = "atrn",
+                    event = "failed",
+                    mx = &hostname,
+                    reason = %status,
+                    "Failed to start on-demand relay session.");
+            }
+        }
+        quit(smtp_client).await;
+
+        // Return messages to the queue
+        for message in messages {
+            DeliveryAttempt::from(message).finish(&core, vec![]).await;
+        }
+    }
+}
@@ -21,34 +21,62 @@
  * for more details.
 */
 
+use std::time::{Instant, SystemTime};
+
+use dashmap::mapref::entry::Entry;
+use mail_builder::encoders::base64::base64_encode;
 use mail_parser::decoders::base64::base64_decode;
 use mail_send::Credentials;
-use smtp_proto::{IntoString, AUTH_LOGIN, AUTH_OAUTHBEARER, AUTH_PLAIN, AUTH_XOAUTH2};
+use ring::constant_time::verify_slices_are_equal;
+use smtp_proto::{
+    IntoString, AUTH_CRAM_MD5, AUTH_EXTERNAL, AUTH_LOGIN, AUTH_OAUTHBEARER, AUTH_PLAIN,
+    AUTH_SCRAM_SHA_256, AUTH_XOAUTH2,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{
+    config::TravelAction,
+    core::{throttle::ConcurrencyLimiter, AuthOrigin, Session},
+    lookup::{hash::hmac_md5, Item, LookupResult},
+};
 
-use crate::{core::Session, lookup::Item};
+use super::scram::ScramState;
 
 pub struct SaslToken {
     mechanism: u64,
     credentials: Credentials<String>,
+    expired: bool,
+    pub scram: ScramState,
+    challenge: String,
 }
 
 impl SaslToken {
     pub fn from_mechanism(mechanism: u64) -> Option<SaslToken> {
         match mechanism {
-            AUTH_PLAIN | AUTH_LOGIN => SaslToken {
-                mechanism,
-                credentials: Credentials::Plain {
-                    username: String::new(),
-                    secret: String::new(),
-                },
+            AUTH_PLAIN | AUTH_LOGIN | AUTH_SCRAM_SHA_256 | AUTH_CRAM_MD5 | AUTH_EXTERNAL => {
+                SaslToken {
+                    mechanism,
+                    credentials: Credentials::Plain {
+                        username: String::new(),
+                        secret: String::new(),
+                    },
+
+                    expired: false,
+                    scram: ScramState::default(),
+                    challenge: String::new(),
+                }
+                .into()
             }
-            .into(),
             AUTH_OAUTHBEARER => SaslToken {
                 mechanism,
                 credentials: Credentials::OAuthBearer {
                     token: String::new(),
                 },
+
+                expired: false,
+                scram: ScramState::default(),
+                challenge: String::new(),
             }
             .into(),
             AUTH_XOAUTH2 => SaslToken {
@@ -57,6 +85,10 @@ impl SaslToken {
                     username: String::new(),
                     secret: String::new(),
                 },
+
+                expired: false,
+                scram: ScramState::default(),
+                challenge: String::new(),
             }
             .into(),
             _ => None,
@@ -70,7 +102,27 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
         token: &mut SaslToken,
         response: &[u8],
     ) -> Result<bool, ()> {
-        if response.is_empty() {
+        if token.expired {
+            // The client acknowledged the error challenge
+            return self
+                .auth_error(b"535 5.7.8 Authentication token expired, please reauthenticate.\r\n")
+                .await;
+        } else if token.mechanism == AUTH_SCRAM_SHA_256 {
+            return self.handle_scram(token, response).await;
+        } else if token.mechanism == AUTH_EXTERNAL {
+            // An empty authorization identity is sent as "="
+            if response.is_empty() && token.challenge.is_empty() {
+                token.challenge = "=".to_string();
+                self.write(b"334 \r\n").await?;
+                return Ok(true);
+            } else if response.is_empty() || response == b"=" {
+                return self.authenticate_external(String::new()).await;
+            } else if let Some(authzid) =
+                base64_decode(response).and_then(|authzid| String::from_utf8(authzid).ok())
+            {
+                return self.authenticate_external(authzid).await;
+            }
+        } else if response.is_empty() {
             match (token.mechanism, &token.credentials) {
                 (AUTH_PLAIN | AUTH_XOAUTH2 | AUTH_OAUTHBEARER, _) => {
                     self.write(b"334 Go ahead.\r\n").await?;
@@ -82,6 +134,28 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                         return Ok(true);
                     }
                 }
+                (AUTH_CRAM_MD5, _) if token.challenge.is_empty() => {
+                    token.challenge = format!(
+                        "<{}.{}@{}>",
+                        rand::random::<u32>(),
+                        SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .map_or(0, |d| d.as_secs()),
+                        self.instance.hostname
+                    );
+                    self.write(
+                        format!(
+                            "334 {}\r\n",
+                            String::from_utf8(
+                                base64_encode(token.challenge.as_bytes()).unwrap_or_default()
+                            )
+                            .unwrap_or_default()
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                    return Ok(true);
+                }
                 _ => (),
             }
         } else if let Some(response) = base64_decode(response) {
@@ -126,6 +200,14 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                 (AUTH_OAUTHBEARER, Credentials::OAuthBearer { token: token_ }) => {
                     let response = response.into_string();
                     if response.contains("auth=") {
+                        if self.is_bearer_expired(&response).await {
+                            token.expired = true;
+                            self.write(
+                                b"334 eyJzdGF0dXMiOiJpbnZhbGlkX3Rva2VuIiwic2NoZW1lcyI6ImJlYXJlciJ9\r\n",
+                            )
+                            .await?;
+                            return Ok(true);
+                        }
                         *token_ = response;
                         return self
                             .authenticate(std::mem::take(&mut token.credentials))
@@ -156,6 +238,14 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                     }
                     match (String::from_utf8(b_username), String::from_utf8(b_secret)) {
                         (Ok(s_username), Ok(s_secret)) if !s_username.is_empty() => {
+                            if self.is_bearer_expired(&s_secret).await {
+                                token.expired = true;
+                                self.write(
+                                    b"334 eyJzdGF0dXMiOiI0MDEiLCJzY2hlbWVzIjoiQmVhcmVyIn0=\r\n",
+                                )
+                                .await?;
+                                return Ok(true);
+                            }
                             *username = s_username;
                             *secret = s_secret;
                             return self
@@ -165,6 +255,21 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                         _ => (),
                     }
                 }
+                (AUTH_CRAM_MD5, _) if !token.challenge.is_empty() => {
+                    if let Some((username, digest)) = std::str::from_utf8(&response)
+                        .ok()
+                        .and_then(|response| response.rsplit_once(' '))
+                        .filter(|(username, _)| !username.is_empty())
+                    {
+                        return self
+                            .authenticate_cram_md5(
+                                username.to_string(),
+                                &token.challenge,
+                                digest.to_ascii_lowercase(),
+                            )
+                            .await;
+                    }
+                }
 
                 _ => (),
             }
@@ -180,6 +285,20 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                 | Credentials::XOauth2 { username, .. }
                 | Credentials::OAuthBearer { token: username } => username.to_string(),
             };
+            let burl_credentials = if self
+                .core
+                .session
+                .config
+                .extensions
+                .burl
+                .eval(self)
+                .await
+                .is_some()
+            {
+                Some(credentials.clone())
+            } else {
+                None
+            };
             if let Some(is_authenticated) = lookup
                 .lookup(Item::Authenticate(credentials))
                 .await
@@ -192,11 +311,109 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                     result = if is_authenticated {"success"} else {"failed"}
                 );
                 return if is_authenticated {
-                    self.data.authenticated_as = authenticated_as;
-                    self.eval_post_auth_params().await;
-                    self.write(b"235 2.7.0 Authentication succeeded.\r\n")
-                        .await?;
-                    Ok(false)
+                    self.auth_success(authenticated_as, burl_credentials).await
+                } else {
+                    self.auth_error(b"535 5.7.8 Authentication credentials invalid.\r\n")
+                        .await
+                };
+            }
+        } else {
+            tracing::warn!(
+                parent: &self.span,
+                context = "auth",
+                event = "error",
+                "No lookup list configured for authentication."
+            );
+        }
+        self.write(b"454 4.7.0 Temporary authentication failure\r\n")
+            .await?;
+
+        Ok(false)
+    }
+
+    async fn authenticate_cram_md5(
+        &mut self,
+        username: String,
+        challenge: &str,
+        digest: String,
+    ) -> Result<bool, ()> {
+        if let Some(lookup) = &self.params.auth_lookup {
+            if let Some(result) = lookup.lookup(Item::Secret(username.clone())).await {
+                let is_authenticated = match result {
+                    LookupResult::Values(secrets) => secrets.first().map_or(false, |secret| {
+                        verify_slices_are_equal(
+                            hmac_md5(secret.as_bytes(), challenge.as_bytes())
+                                .iter()
+                                .map(|b| format!("{b:02x}"))
+                                .collect::<String>()
+                                .as_bytes(),
+                            digest.as_bytes(),
+                        )
+                        .is_ok()
+                    }),
+                    _ => false,
+                };
+                tracing::debug!(
+                    parent: &self.span,
+                    context = "auth",
+                    event = "authenticate",
+                    result = if is_authenticated {"success"} else {"failed"}
+                );
+                return if is_authenticated {
+                    self.auth_success(username, None).await
+                } else {
+                    self.auth_error(b"535 5.7.8 Authentication credentials invalid.\r\n")
+                        .await
+                };
+            }
+        } else {
+            tracing::warn!(
+                parent: &self.span,
+                context = "auth",
+                event = "error",
+                "No lookup list configured for authentication."
+            );
+        }
+        self.write(b"454 4.7.0 Temporary authentication failure\r\n")
+            .await?;
+
+        Ok(false)
+    }
+
+    async fn authenticate_external(&mut self, authzid: String) -> Result<bool, ()> {
+        let subject = if let Some(subject) = self
+            .data
+            .peer_certificate
+            .as_ref()
+            .and_then(|cert| certificate_subject(&cert.0))
+        {
+            subject
+        } else {
+            return self
+                .auth_error(b"535 5.7.8 No client certificate was presented.\r\n")
+                .await;
+        };
+        if !authzid.is_empty() && authzid != subject {
+            return self
+                .auth_error(b"535 5.7.8 Authorization identity does not match certificate.\r\n")
+                .await;
+        }
+
+        if let Some(lookup) = &self.params.auth_lookup {
+            if let Some(is_authenticated) = lookup
+                .lookup(Item::AuthenticateExternal(subject.clone()))
+                .await
+                .map(bool::from)
+            {
+                tracing::debug!(
+                    parent: &self.span,
+                    context = "auth",
+                    event = "authenticate",
+                    subject = &subject,
+                    result = if is_authenticated {"success"} else {"failed"}
+                );
+                return if is_authenticated {
+                    self.auth_success(subject, None).await
                 } else {
                     self.auth_error(b"535 5.7.8 Authentication credentials invalid.\r\n")
                         .await
@@ -216,6 +433,146 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
         Ok(false)
     }
 
+    pub async fn auth_success(
+        &mut self,
+        authenticated_as: String,
+        credentials: Option<Credentials<String>>,
+    ) -> Result<bool, ()> {
+        if !self.verify_travel(&authenticated_as).await {
+            self.write(b"451 4.7.1 Authentication deferred for review.\r\n")
+                .await?;
+            return Ok(false);
+        }
+        self.data.authenticated_as = authenticated_as;
+        if !self.acquire_auth_session().await {
+            self.write(b"421 4.7.0 Too many concurrent sessions for this account.\r\n")
+                .await?;
+            return Err(());
+        }
+        self.data.auth_credentials = credentials;
+        self.eval_post_auth_params().await;
+        self.write(b"235 2.7.0 Authentication succeeded.\r\n")
+            .await?;
+        Ok(false)
+    }
+
+    async fn is_bearer_expired(&self, response: &str) -> bool {
+        let check_expiry = *self
+            .core
+            .session
+            .config
+            .auth
+            .oauth_check_expiry
+            .eval(self)
+            .await;
+        let max_age = *self.core.session.config.auth.oauth_max_age.eval(self).await;
+        if !check_expiry && max_age.is_none() {
+            return false;
+        }
+
+        // Tokens that are not JWTs are left to the authentication backend
+        let (expires, issued) = if let Some(claims) = bearer_token(response).and_then(jwt_claims) {
+            claims
+        } else {
+            return false;
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let reason = if check_expiry && expires.map_or(false, |expires| expires <= now) {
+            "expired"
+        } else if let (Some(max_age), Some(issued)) = (max_age, issued) {
+            if issued.saturating_add(max_age.as_secs()) <= now {
+                "too-old"
+            } else {
+                return false;
+            }
+        } else {
+            return false;
+        };
+
+        tracing::debug!(
+            parent: &self.span,
+            context = "auth",
+            event = "token-expired",
+            reason = reason,
+            "OAuth bearer token rejected."
+        );
+        true
+    }
+
+    async fn acquire_auth_session(&mut self) -> bool {
+        if let Some(max_sessions) = *self.core.session.config.auth.max_sessions.eval(self).await {
+            let in_flight = self
+                .core
+                .session
+                .auth_sessions
+                .entry(self.data.authenticated_as.clone())
+                .or_insert_with(|| ConcurrencyLimiter::new(max_sessions))
+                .is_allowed();
+            if let Some(in_flight) = in_flight {
+                self.in_flight.push(in_flight);
+            } else {
+                tracing::info!(
+                    parent: &self.span,
+                    context = "auth",
+                    event = "too-many-sessions",
+                    account = &self.data.authenticated_as,
+                    max_sessions = max_sessions,
+                    "Too many concurrent sessions for account."
+                );
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn verify_travel(&self, account: &str) -> bool {
+        let action = *self.core.session.config.auth.travel_action.eval(self).await;
+        let country = match (&self.data.geoip, action) {
+            (_, TravelAction::Disable) | (None, _) => return true,
+            (Some(geoip), _) if geoip.country.is_empty() => return true,
+            (Some(geoip), _) => &geoip.country,
+        };
+        let window = *self.core.session.config.auth.travel_window.eval(self).await;
+        let now = Instant::now();
+
+        match self.core.session.auth_origins.entry(account.to_string()) {
+            Entry::Occupied(mut entry) => {
+                let origin = entry.get();
+                if origin.expires > now && &origin.country != country {
+                    tracing::warn!(
+                        parent: &self.span,
+                        context = "auth",
+                        event = "impossible-travel",
+                        account = account,
+                        previous.ip = origin.remote_ip.to_string(),
+                        previous.country = &origin.country,
+                        country = country,
+                        "Account authenticated from distant locations within a short interval."
+                    );
+                    if action == TravelAction::Defer {
+                        return false;
+                    }
+                }
+                entry.insert(AuthOrigin {
+                    remote_ip: self.data.remote_ip,
+                    country: country.to_string(),
+                    expires: now + window,
+                });
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(AuthOrigin {
+                    remote_ip: self.data.remote_ip,
+                    country: country.to_string(),
+                    expires: now + window,
+                });
+            }
+        }
+
+        true
+    }
+
     pub async fn auth_error(&mut self, response: &[u8]) -> Result<bool, ()> {
         tokio::time::sleep(self.params.auth_errors_wait).await;
         self.data.auth_errors += 1;
@@ -235,3 +592,46 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
         }
     }
 }
+
+// Certificates are identified by their Common Name, or by their full subject if absent
+pub fn certificate_subject(der_certificate: &[u8]) -> Option<String> {
+    let (_, certificate) = X509Certificate::from_der(der_certificate).ok()?;
+    let subject = certificate.subject();
+    subject
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string())
+        .or_else(|| Some(subject.to_string()))
+        .filter(|subject| !subject.is_empty())
+}
+
+fn bearer_token(response: &str) -> Option<&str> {
+    let pos = response.to_ascii_lowercase().find("bearer ")?;
+    response[pos + 7..]
+        .split('\x01')
+        .next()
+        .map(|token| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+fn jwt_claims(token: &str) -> Option<(Option<u64>, Option<u64>)> {
+    let mut parts = token.split('.');
+    let payload = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(_), Some(payload), Some(_), None) => payload,
+        _ => return None,
+    };
+    let mut payload = payload.replace('-', "+").replace('_', "/");
+    while payload.len() % 4 != 0 {
+        payload.push('=');
+    }
+    let claims =
+        serde_json::from_slice::<serde_json::Value>(&base64_decode(payload.as_bytes())?).ok()?;
+    let claim = |name: &str| {
+        claims
+            .get(name)
+            .and_then(|value| value.as_u64().or_else(|| value.as_f64().map(|v| v as u64)))
+    };
+
+    Some((claim("exp"), claim("iat")))
+}
@@ -0,0 +1,125 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    core::Session,
+    lookup::imap::{Error, ImapUrl},
+};
+
+use super::IsTls;
+
+impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
+    pub async fn handle_burl(&mut self, uri: String, is_last: bool) -> Result<(), ()> {
+        let imap = self
+            .core
+            .session
+            .config
+            .extensions
+            .burl
+            .eval(self)
+            .await
+            .clone();
+        let (imap, credentials) = match (imap, self.data.auth_credentials.clone()) {
+            (Some(imap), Some(credentials)) => (imap, credentials),
+            _ => {
+                return self.write(b"503 5.5.1 BURL not allowed.\r\n").await;
+            }
+        };
+        if !self.can_send_data().await? {
+            return Ok(());
+        }
+        let url = if let Some(url) = ImapUrl::parse(&uri) {
+            url
+        } else {
+            return self.write(b"554 5.7.8 Invalid IMAP URL.\r\n").await;
+        };
+
+        match imap
+            .fetch(
+                &credentials,
+                &url,
+                self.params
+                    .max_message_size
+                    .saturating_sub(self.data.message.len()),
+            )
+            .await
+        {
+            Ok(bytes) => {
+                tracing::debug!(parent: &self.span,
+                    context = "burl",
+                    event = "fetch",
+                    url = &uri,
+                    size = bytes.len());
+
+                if bytes.len() + self.data.message.len() >= self.params.max_message_size {
+                    self.reset();
+                    return self
+                        .write(b"552 5.3.4 Message too big for system.\r\n")
+                        .await;
+                }
+                self.data.message.extend_from_slice(&bytes);
+
+                if is_last {
+                    let num_rcpts = self.data.rcpt_to.len();
+                    let message = self.queue_message().await;
+                    if self.instance.is_smtp {
+                        self.write(message.as_ref()).await?;
+                    } else {
+                        for _ in 0..num_rcpts {
+                            self.write(message.as_ref()).await?;
+                        }
+                    }
+                    self.reset();
+                    Ok(())
+                } else {
+                    self.write(b"250 2.5.0 Waiting for additional BURL or BDAT commands.\r\n")
+                        .await
+                }
+            }
+            Err(err) => {
+                tracing::info!(parent: &self.span,
+                    context = "burl",
+                    event = "fetch-failed",
+                    url = &uri,
+                    reason = %err);
+
+                self.reset();
+                self.write(match err {
+                    Error::AuthenticationFailed => {
+                        &b"554 5.7.0 IMAP server rejected the session credentials.\r\n"[..]
+                    }
+                    Error::InvalidResponse(_) | Error::InvalidChallenge(_) => {
+                        &b"554 5.6.6 IMAP URL resolution failed.\r\n"[..]
+                    }
+                    Error::TooLarge => &b"552 5.3.4 Message too big for system.\r\n"[..],
+                    Error::Io(_) | Error::Timeout | Error::TLSInvalidName | Error::Disconnected => {
+                        &b"454 4.4.1 IMAP server unavailable.\r\n"[..]
+                    }
+                })
+                .await
+            }
+        }
+    }
+}
@@ -0,0 +1,500 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_builder::encoders::base64::base64_encode;
+use mail_parser::{HeaderValue, Message, MimeHeaders, PartType};
+
+pub fn count_urls(raw_message: &[u8]) -> usize {
+    Message::parse(raw_message).map_or(0, |message| {
+        message
+            .parts
+            .iter()
+            .map(|part| match &part.body {
+                PartType::Text(text) | PartType::Html(text) => count_text_urls(text),
+                _ => 0,
+            })
+            .sum()
+    })
+}
+
+pub fn count_header_recipients(raw_message: &[u8]) -> usize {
+    Message::parse(raw_message).map_or(0, |message| {
+        [message.to(), message.cc()]
+            .into_iter()
+            .map(|value| match value {
+                HeaderValue::Address(_) => 1,
+                HeaderValue::AddressList(list) => list.len(),
+                HeaderValue::Group(group) => group.addresses.len(),
+                HeaderValue::GroupList(groups) => {
+                    groups.iter().map(|group| group.addresses.len()).sum()
+                }
+                _ => 0,
+            })
+            .sum()
+    })
+}
+
+pub fn find_display_name_spoofing(
+    raw_message: &[u8],
+    protected_names: &[String],
+) -> Option<(String, String)> {
+    let message = Message::parse(raw_message)?;
+    let addr = match message.from() {
+        HeaderValue::Address(addr) => addr,
+        HeaderValue::AddressList(list) => list.first()?,
+        _ => return None,
+    };
+    let name = normalize_display_name(addr.name.as_deref()?);
+    if !name.is_empty()
+        && protected_names
+            .iter()
+            .any(|protected| normalize_display_name(protected) == name)
+    {
+        Some((name, addr.address.as_deref()?.to_lowercase()))
+    } else {
+        None
+    }
+}
+
+fn normalize_display_name(name: &str) -> String {
+    name.split(|ch: char| ch.is_whitespace() || matches!(ch, '"' | '\'' | ','))
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+pub fn find_encoding_mismatch(raw_message: &[u8]) -> Option<String> {
+    Message::parse(raw_message).and_then(|message| message_encoding_mismatch(&message))
+}
+
+fn message_encoding_mismatch(message: &Message) -> Option<String> {
+    for part in &message.parts {
+        match &part.body {
+            PartType::Multipart(_) => continue,
+            PartType::Message(nested) => {
+                if let Some(encoding) = message_encoding_mismatch(nested) {
+                    return Some(encoding);
+                }
+                continue;
+            }
+            _ => (),
+        }
+
+        // Only 7bit-clean encodings can be verified, a missing header is not
+        // considered a declaration.
+        let encoding = if let Some(encoding) = part.content_transfer_encoding() {
+            encoding.trim().to_ascii_lowercase()
+        } else {
+            continue;
+        };
+        if matches!(encoding.as_str(), "7bit" | "quoted-printable" | "base64")
+            && message
+                .raw_message
+                .get(part.offset_body..part.offset_end)
+                .map_or(false, |body| body.iter().any(|&ch| ch >= 0x80 || ch == 0))
+        {
+            return Some(encoding);
+        }
+    }
+
+    None
+}
+
+fn count_text_urls(text: &str) -> usize {
+    let text = text.as_bytes();
+    let mut count = 0;
+    let mut pos = 0;
+
+    while let Some(offset) = text[pos..].iter().position(|&ch| ch == b':') {
+        let colon = pos + offset;
+        let is_url = text.get(colon + 1..colon + 3) == Some(b"//")
+            && [&b"http"[..], b"https"].iter().any(|scheme| {
+                colon >= scheme.len()
+                    && text[colon - scheme.len()..colon].eq_ignore_ascii_case(scheme)
+                    && (colon == scheme.len()
+                        || !text[colon - scheme.len() - 1].is_ascii_alphanumeric())
+            });
+        if is_url {
+            count += 1;
+        }
+        pos = colon + 1;
+    }
+
+    count
+}
+
+pub fn max_line_length(raw_message: &[u8]) -> usize {
+    raw_message
+        .split_inclusive(|&ch| ch == b'\n')
+        .map(|line| line.len())
+        .max()
+        .unwrap_or(0)
+}
+
+pub fn take_header(raw_message: &[u8], name: &str) -> Option<(String, Vec<u8>)> {
+    let mut offset = 0;
+    let mut found: Option<(usize, usize, Vec<u8>)> = None;
+
+    for line in raw_message.split_inclusive(|&ch| ch == b'\n') {
+        let start = offset;
+        offset += line.len();
+
+        if matches!(line, b"\r\n" | b"\n") {
+            break;
+        } else if matches!(line.first(), Some(b' ' | b'\t')) {
+            // Folded line
+            if let Some((_, end, value)) = &mut found {
+                *end = offset;
+                value.extend_from_slice(line);
+            }
+        } else if found.is_some() {
+            break;
+        } else if line.len() > name.len()
+            && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+            && line[name.len()] == b':'
+        {
+            found = Some((start, offset, line[name.len() + 1..].to_vec()));
+        }
+    }
+
+    let (start, end, value) = found?;
+    let value = String::from_utf8_lossy(&value)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut message = Vec::with_capacity(raw_message.len() - (end - start));
+    message.extend_from_slice(&raw_message[..start]);
+    message.extend_from_slice(&raw_message[end..]);
+
+    Some((value, message))
+}
+
+pub fn encode_binary_mime(raw_message: &[u8]) -> Option<Vec<u8>> {
+    let message = Message::parse(raw_message)?;
+    let mut result = Vec::with_capacity(raw_message.len() * 4 / 3);
+    let mut offset = 0;
+
+    for part in &message.parts {
+        if matches!(part.body, PartType::Multipart(_) | PartType::Message(_))
+            || part.offset_header < offset
+        {
+            continue;
+        }
+        let body = raw_message.get(part.offset_body..part.offset_end)?;
+        let needs_encoding = match part
+            .content_transfer_encoding()
+            .map(|encoding| encoding.trim().to_ascii_lowercase())
+            .as_deref()
+        {
+            None | Some("7bit") => !is_7bit_clean(body),
+            Some("8bit" | "binary") => true,
+            _ => false,
+        };
+        if !needs_encoding {
+            continue;
+        }
+
+        // The line break preceding a boundary delimiter is not part of the content
+        let body = if raw_message.get(part.offset_end) == Some(&b'-') {
+            body.strip_suffix(b"\r\n").unwrap_or(body)
+        } else {
+            body
+        };
+
+        // Replace the Content-Transfer-Encoding header
+        let mut headers = raw_message
+            .get(part.offset_header..part.offset_body)?
+            .to_vec();
+        while let Some((_, stripped)) = take_header(&headers, "Content-Transfer-Encoding") {
+            headers = stripped;
+        }
+        let headers_end = if headers.starts_with(b"\r\n") || headers.starts_with(b"\n") {
+            0
+        } else {
+            headers
+                .windows(2)
+                .position(|ch| matches!(ch, b"\n\r" | b"\n\n"))
+                .map_or(headers.len(), |pos| pos + 1)
+        };
+
+        result.extend_from_slice(&raw_message[offset..part.offset_header]);
+        result.extend_from_slice(&headers[..headers_end]);
+        result.extend_from_slice(b"Content-Transfer-Encoding: base64\r\n");
+        result.extend_from_slice(&headers[headers_end..]);
+        for line in base64_encode(body).ok()?.chunks(76) {
+            result.extend_from_slice(line);
+            result.extend_from_slice(b"\r\n");
+        }
+        offset = part.offset_end;
+    }
+
+    if offset > 0 {
+        result.extend_from_slice(&raw_message[offset..]);
+        Some(result)
+    } else {
+        None
+    }
+}
+
+fn is_7bit_clean(data: &[u8]) -> bool {
+    let mut line_len = 0;
+    let mut last_ch = 0;
+
+    for &ch in data {
+        match ch {
+            b'\n' if last_ch == b'\r' => line_len = 0,
+            b'\n' | 0 | 0x80..=0xff => return false,
+            _ if last_ch == b'\r' => return false,
+            b'\r' => (),
+            _ => line_len += 1,
+        }
+        if line_len > 998 {
+            return false;
+        }
+        last_ch = ch;
+    }
+
+    last_ch != b'\r'
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::{Message, MimeHeaders};
+
+    use super::{
+        count_header_recipients, count_urls, encode_binary_mime, find_display_name_spoofing,
+        find_encoding_mismatch, max_line_length, take_header,
+    };
+
+    #[test]
+    fn count_message_urls() {
+        let message = concat!(
+            "From: john@example.org\r\n",
+            "To: jane@example.org\r\n",
+            "Subject: Links\r\n",
+            "Content-Type: multipart/alternative; boundary=\"b\"\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "Visit https://example.org/a and HTTP://example.org/b.\r\n",
+            "Not a link: xhttp://example.org ftp://example.org mailto:john@example.org\r\n",
+            "--b\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<a href=\"https://example.org/c\">click</a>\r\n",
+            "--b--\r\n"
+        );
+
+        assert_eq!(count_urls(message.as_bytes()), 3);
+    }
+
+    #[test]
+    fn encoding_mismatch() {
+        for (message, expected) in [
+            (
+                concat!(
+                    "Subject: Plain\r\n",
+                    "Content-Transfer-Encoding: 7bit\r\n",
+                    "\r\n",
+                    "Plain ASCII text.\r\n"
+                ),
+                None,
+            ),
+            (
+                concat!(
+                    "Subject: 8bit\r\n",
+                    "Content-Transfer-Encoding: 8bit\r\n",
+                    "\r\n",
+                    "Caf\u{e9} con leche.\r\n"
+                ),
+                None,
+            ),
+            (
+                concat!(
+                    "Subject: Undeclared\r\n",
+                    "\r\n",
+                    "Caf\u{e9} con leche.\r\n"
+                ),
+                None,
+            ),
+            (
+                concat!(
+                    "Subject: Lying 7bit\r\n",
+                    "Content-Transfer-Encoding: 7bit\r\n",
+                    "\r\n",
+                    "Caf\u{e9} con leche.\r\n"
+                ),
+                Some("7bit"),
+            ),
+            (
+                concat!(
+                    "Subject: Multipart\r\n",
+                    "Content-Type: multipart/mixed; boundary=\"b\"\r\n",
+                    "\r\n",
+                    "--b\r\n",
+                    "Content-Type: text/plain\r\n",
+                    "Content-Transfer-Encoding: 8bit\r\n",
+                    "\r\n",
+                    "Caf\u{e9} con leche.\r\n",
+                    "--b\r\n",
+                    "Content-Type: application/octet-stream\r\n",
+                    "Content-Transfer-Encoding: Base64\r\n",
+                    "\r\n",
+                    "SGVsbG8\u{e9}\r\n",
+                    "--b--\r\n"
+                ),
+                Some("base64"),
+            ),
+        ] {
+            assert_eq!(
+                find_encoding_mismatch(message.as_bytes()).as_deref(),
+                expected,
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn header_recipients() {
+        for (message, expected) in [
+            ("Subject: test\r\n\r\nbody\r\n", 0),
+            ("To: jane@example.org\r\n\r\nbody\r\n", 1),
+            (
+                concat!(
+                    "To: Jane <jane@example.org>, bill@example.org\r\n",
+                    "Cc: Team: mike@example.org, lisa@example.org;\r\n",
+                    "\r\n",
+                    "body\r\n"
+                ),
+                4,
+            ),
+        ] {
+            assert_eq!(
+                count_header_recipients(message.as_bytes()),
+                expected,
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn display_name_spoofing() {
+        let protected = vec!["John Doe".to_string(), "Accounts Payable".to_string()];
+        for (message, expected) in [
+            (
+                "From: \"John  Doe\" <attacker@evil.com>\r\n\r\nbody\r\n",
+                Some(("john doe", "attacker@evil.com")),
+            ),
+            (
+                "From: 'ACCOUNTS payable' <Billing@Evil.com>\r\n\r\nbody\r\n",
+                Some(("accounts payable", "billing@evil.com")),
+            ),
+            ("From: John <john@example.org>\r\n\r\nbody\r\n", None),
+            ("From: john@example.org\r\n\r\nbody\r\n", None),
+            ("Subject: test\r\n\r\nbody\r\n", None),
+        ] {
+            assert_eq!(
+                find_display_name_spoofing(message.as_bytes(), &protected),
+                expected.map(|(name, addr)| (name.to_string(), addr.to_string())),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn line_length() {
+        let long_line = "a".repeat(998);
+        assert_eq!(max_line_length(b""), 0);
+        assert_eq!(max_line_length(b"Subject: test\r\n\r\nbody"), 15);
+        assert_eq!(
+            max_line_length(format!("Subject: test\r\n\r\n{long_line}\r\n").as_bytes()),
+            1000
+        );
+        assert_eq!(
+            max_line_length(format!("Subject: test\r\n\r\n{long_line}a\r\nbody\r\n").as_bytes()),
+            1001
+        );
+    }
+
+    #[test]
+    fn take_headers() {
+        let message = concat!(
+            "From: john@example.org\r\n",
+            "x-tracking-ID: abc\r\n",
+            "  def\r\n",
+            "Subject: test\r\n",
+            "\r\n",
+            "X-Tracking-Id: body\r\n"
+        );
+        let (value, message) = take_header(message.as_bytes(), "X-Tracking-Id").unwrap();
+        assert_eq!(value, "abc def");
+        assert_eq!(
+            std::str::from_utf8(&message).unwrap(),
+            concat!(
+                "From: john@example.org\r\n",
+                "Subject: test\r\n",
+                "\r\n",
+                "X-Tracking-Id: body\r\n"
+            )
+        );
+        assert!(take_header(&message, "X-Tracking-Id").is_none());
+        assert!(take_header(b"X-Tracking: abc\r\n\r\n", "X-Tracking-Id").is_none());
+    }
+
+    #[test]
+    fn binary_mime_to_base64() {
+        assert!(encode_binary_mime(
+            b"Subject: test\r\nContent-Transfer-Encoding: 7bit\r\n\r\nPlain text.\r\n"
+        )
+        .is_none());
+
+        let binary = b"\x00\x01bare\nlf\rand\xff\xfe high bytes\r\n".to_vec();
+        let mut message = concat!(
+            "Subject: Binary\r\n",
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n",
+            "\r\n",
+            "--b\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "Plain text.\r\n",
+            "--b\r\n",
+            "Content-Type: application/octet-stream\r\n",
+            "Content-Transfer-Encoding: binary\r\n",
+            "\r\n",
+        )
+        .as_bytes()
+        .to_vec();
+        message.extend_from_slice(&binary);
+        message.extend_from_slice(b"\r\n--b--\r\n");
+
+        let encoded = encode_binary_mime(&message).unwrap();
+        assert!(encoded.iter().all(|&ch| ch > 0 && ch < 0x80));
+        let parsed = Message::parse(&encoded).unwrap();
+        assert_eq!(parsed.body_text(0).unwrap().trim(), "Plain text.");
+        let attachment = parsed.attachment(0).unwrap();
+        assert_eq!(attachment.content_transfer_encoding().unwrap(), "base64");
+        assert_eq!(attachment.contents(), &binary[..]);
+    }
+}
@@ -31,7 +31,7 @@ use std::{
 
 use mail_auth::{
     common::headers::HeaderWriter, dmarc, AuthenticatedMessage, AuthenticationResults, DkimResult,
-    DmarcResult, ReceivedSpf,
+    DmarcResult, IprevOutput, IprevResult, ReceivedSpf, SpfResult,
 };
 use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
 use smtp_proto::{
@@ -43,18 +43,34 @@ use tokio::{
 };
 
 use crate::{
-    config::DNSBL_FROM,
+    config::{AuthMethod, ContentAction, DkimBodyLength, DNSBL_FROM},
     core::{scripts::ScriptResult, Session, SessionAddress},
     queue::{self, DomainPart, Message, SimpleEnvelope},
     reporting::analysis::AnalyzeReport,
 };
 
-use super::IsTls;
+use super::{
+    content::{
+        count_header_recipients, count_urls, find_display_name_spoofing, find_encoding_mismatch,
+        max_line_length, take_header,
+    },
+    IsTls,
+};
+
+const MAX_METADATA_LEN: usize = 1024;
 
 impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
     pub async fn queue_message(&mut self) -> Cow<'static, [u8]> {
-        // Authenticate message
         let raw_message = Arc::new(std::mem::take(&mut self.data.message));
+        let response = self.queue_message_(raw_message.clone()).await;
+        if response.starts_with(b"5") {
+            self.store_rejected(&raw_message, &response).await;
+        }
+        response
+    }
+
+    async fn queue_message_(&mut self, raw_message: Arc<Vec<u8>>) -> Cow<'static, [u8]> {
+        // Authenticate message
         let auth_message = if let Some(auth_message) = AuthenticatedMessage::parse(&raw_message) {
             auth_message
         } else {
@@ -92,11 +108,215 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                 .into();
         }
 
+        // Count URLs
+        let mut excessive_urls = None;
+        if let Some(max_urls) = *dc.max_urls.eval(self).await {
+            if raw_message.len() <= *dc.urls_max_scan_size.eval(self).await {
+                let message = raw_message.clone();
+                let num_urls = self
+                    .core
+                    .spawn_worker(move || count_urls(&message))
+                    .await
+                    .unwrap_or(0);
+                if num_urls > max_urls {
+                    tracing::info!(parent: &self.span,
+                        context = "data",
+                        event = "too-many-urls",
+                        return_path = self.data.mail_from.as_ref().unwrap().address,
+                        from = auth_message.from(),
+                        urls = num_urls,
+                        max_urls = max_urls);
+                    match *dc.urls_action.eval(self).await {
+                        ContentAction::Reject => {
+                            return (&b"550 5.7.1 Message contains too many URLs.\r\n"[..]).into();
+                        }
+                        ContentAction::Tag => {
+                            excessive_urls = num_urls.into();
+                        }
+                    }
+                }
+            }
+        }
+
+        // Verify Content-Transfer-Encoding declarations
+        let mut invalid_encoding = None;
+        if *dc.verify_encoding.eval(self).await {
+            let message = raw_message.clone();
+            if let Some(encoding) = self
+                .core
+                .spawn_worker(move || find_encoding_mismatch(&message))
+                .await
+                .flatten()
+            {
+                tracing::info!(parent: &self.span,
+                    context = "data",
+                    event = "invalid-encoding",
+                    return_path = self.data.mail_from.as_ref().unwrap().address,
+                    from = auth_message.from(),
+                    encoding = &encoding);
+                match *dc.encoding_action.eval(self).await {
+                    ContentAction::Reject => {
+                        return (&b"550 5.6.0 Message contains invalid transfer encoding.\r\n"[..])
+                            .into();
+                    }
+                    ContentAction::Tag => {
+                        invalid_encoding = encoding.into();
+                    }
+                }
+            }
+        }
+
+        // Enforce the maximum line length
+        let mut excessive_line_length = None;
+        if *dc.verify_line_length.eval(self).await {
+            let max_length = *dc.max_line_length.eval(self).await;
+            let line_length = max_line_length(&raw_message);
+            if line_length > max_length {
+                tracing::info!(parent: &self.span,
+                    context = "data",
+                    event = "line-too-long",
+                    return_path = self.data.mail_from.as_ref().unwrap().address,
+                    from = auth_message.from(),
+                    length = line_length,
+                    max_length = max_length);
+                match *dc.line_length_action.eval(self).await {
+                    ContentAction::Reject => {
+                        return (&b"550 5.6.0 Message contains lines exceeding the maximum length.\r\n"[..])
+                            .into();
+                    }
+                    ContentAction::Tag => {
+                        excessive_line_length = line_length.into();
+                    }
+                }
+            }
+        }
+
+        // Compare envelope and header recipients
+        let mut rcpt_mismatch = None;
+        if let Some(max_ratio) = *dc.max_rcpt_ratio.eval(self).await {
+            let message = raw_message.clone();
+            let envelope_rcpts = self.data.rcpt_to.len();
+            let header_rcpts = self
+                .core
+                .spawn_worker(move || count_header_recipients(&message))
+                .await
+                .unwrap_or(0);
+            if envelope_rcpts as f64 / header_rcpts.max(1) as f64 > max_ratio {
+                tracing::info!(parent: &self.span,
+                    context = "data",
+                    event = "recipient-mismatch",
+                    return_path = self.data.mail_from.as_ref().unwrap().address,
+                    from = auth_message.from(),
+                    envelope_rcpts = envelope_rcpts,
+                    header_rcpts = header_rcpts,
+                    max_ratio = max_ratio);
+                match *dc.rcpt_ratio_action.eval(self).await {
+                    ContentAction::Reject => {
+                        return (&b"550 5.7.1 Too many recipients not listed in the message headers.\r\n"[..])
+                            .into();
+                    }
+                    ContentAction::Tag => {
+                        rcpt_mismatch = (envelope_rcpts, header_rcpts).into();
+                    }
+                }
+            }
+        }
+
+        // Detect display name spoofing from external senders
+        let mut spoofed_name = None;
+        let protected_names = dc.protected_names.eval(self).await;
+        if !protected_names.is_empty() && self.data.authenticated_as.is_empty() {
+            let message = raw_message.clone();
+            let protected_names = protected_names.clone();
+            if let Some((name, address)) = self
+                .core
+                .spawn_worker(move || find_display_name_spoofing(&message, &protected_names))
+                .await
+                .flatten()
+            {
+                let is_local_domain = if let Some(domain_lookup) = &self.params.rcpt_lookup_domain {
+                    domain_lookup
+                        .contains(address.domain_part())
+                        .await
+                        .unwrap_or(false)
+                } else {
+                    false
+                };
+                if !is_local_domain {
+                    tracing::info!(parent: &self.span,
+                        context = "data",
+                        event = "display-name-spoofing",
+                        return_path = self.data.mail_from.as_ref().unwrap().address,
+                        from = address,
+                        name = name);
+                    match *dc.display_name_action.eval(self).await {
+                        ContentAction::Reject => {
+                            return (&b"550 5.7.1 Sender display name impersonates a protected name.\r\n"[..])
+                                .into();
+                        }
+                        ContentAction::Tag => {
+                            spoofed_name = (name, address).into();
+                        }
+                    }
+                }
+            }
+        }
+
         // Verify DKIM
         let dkim = *ac.dkim.verify.eval(self).await;
+        let dkim_required = ac.dkim.require.eval(self).await;
         let dmarc = *ac.dmarc.verify.eval(self).await;
-        let dkim_output = if dkim.verify() || dmarc.verify() {
-            let dkim_output = self.core.resolvers.dns.verify_dkim(&auth_message).await;
+        let softfail_no_dkim = match &self.data.spf_mail_from {
+            Some(spf_output) if spf_output.result() == SpfResult::SoftFail => {
+                *ac.spf.softfail_no_dkim.eval(self).await
+            }
+            _ => None,
+        };
+        let mut dkim_policy = Vec::new();
+        let dkim_output = if dkim.verify()
+            || dmarc.verify()
+            || dkim_required.is_some()
+            || softfail_no_dkim.is_some()
+        {
+            let mut dkim_output = self.core.resolvers.dns.verify_dkim(&auth_message).await;
+
+            // Apply body length limit policy
+            let body_length = *ac.dkim.body_length.eval(self).await;
+            if body_length != DkimBodyLength::Allow {
+                for signature in dkim_output
+                    .iter()
+                    .filter_map(|d| d.signature())
+                    .filter(|s| s.l > 0)
+                {
+                    tracing::info!(parent: &self.span,
+                        context = "dkim",
+                        event = "body-length",
+                        action = ?body_length,
+                        domain = signature.domain(),
+                        selector = signature.selector(),
+                        "DKIM signature with body length limit found.");
+
+                    if body_length == DkimBodyLength::Reject {
+                        return (&b"550 5.7.20 DKIM signatures with body length limits are not accepted.\r\n"[..]).into();
+                    }
+
+                    dkim_policy.push(format!(
+                        "dkim=policy ({}) header.d={} header.s={}",
+                        if body_length == DkimBodyLength::Ignore {
+                            "body length limit, signature ignored"
+                        } else {
+                            "body length limit"
+                        },
+                        signature.domain(),
+                        signature.selector()
+                    ));
+                }
+
+                if body_length == DkimBodyLength::Ignore {
+                    dkim_output.retain(|d| d.signature().map_or(true, |s| s.l == 0));
+                }
+            }
+
             let rejected = dkim.is_strict()
                 && !dkim_output
                     .iter()
@@ -138,11 +358,75 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                     from = auth_message.from(),
                     result = ?dkim_output.iter().map(|d| d.result().to_string()).collect::<Vec<_>>());
             }
+
+            // Require a valid signature from a specific domain
+            if let Some(required_domain) = dkim_required {
+                if !dkim_output.iter().any(|d| {
+                    matches!(d.result(), DkimResult::Pass)
+                        && d.signature()
+                            .map_or(false, |s| s.domain().eq_ignore_ascii_case(required_domain))
+                }) {
+                    tracing::info!(parent: &self.span,
+                        context = "dkim",
+                        event = "required",
+                        return_path = self.data.mail_from.as_ref().unwrap().address,
+                        from = auth_message.from(),
+                        domain = required_domain,
+                        result = ?dkim_output.iter().map(|d| d.result().to_string()).collect::<Vec<_>>(),
+                        "No passing DKIM signature found for required domain.");
+
+                    return if dkim_output.iter().any(|d| {
+                        matches!(d.result(), DkimResult::TempError(_))
+                            && d.signature()
+                                .map_or(false, |s| s.domain().eq_ignore_ascii_case(required_domain))
+                    }) {
+                        format!(
+                            "451 4.7.20 No passing DKIM signature found for {required_domain}.\r\n"
+                        )
+                        .into_bytes()
+                        .into()
+                    } else {
+                        format!(
+                            "550 5.7.20 No passing DKIM signature found for {required_domain}.\r\n"
+                        )
+                        .into_bytes()
+                        .into()
+                    };
+                }
+            }
+
             dkim_output
         } else {
             vec![]
         };
 
+        // Reject or tag SPF softfails without a valid DKIM signature
+        let mut spf_softfail_no_dkim = false;
+        if let Some(action) = softfail_no_dkim {
+            if !dkim_output
+                .iter()
+                .any(|d| matches!(d.result(), DkimResult::Pass))
+            {
+                tracing::info!(parent: &self.span,
+                    context = "spf",
+                    event = "softfail-no-dkim",
+                    action = ?action,
+                    return_path = self.data.mail_from.as_ref().unwrap().address,
+                    from = auth_message.from(),
+                    "SPF softfail and no passing DKIM signatures found.");
+
+                match action {
+                    ContentAction::Reject => {
+                        return (&b"550 5.7.23 SPF softfail and no passing DKIM signatures found.\r\n"[..])
+                            .into();
+                    }
+                    ContentAction::Tag => {
+                        spf_softfail_no_dkim = true;
+                    }
+                }
+            }
+        }
+
         // Verify ARC
         let arc = *ac.arc.verify.eval(self).await;
         let arc_sealer = ac.arc.seal.eval(self).await;
@@ -204,6 +488,7 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
         }
 
         // Verify DMARC
+        let mut dmarc_pass = false;
         match &self.data.spf_mail_from {
             Some(spf_output) if dmarc.verify() => {
                 let dmarc_output = self
@@ -230,6 +515,9 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                     && matches!(dmarc_output.spf_result(), DmarcResult::TempError(_))
                     || matches!(dmarc_output.dkim_result(), DmarcResult::TempError(_));
 
+                dmarc_pass = matches!(dmarc_output.spf_result(), DmarcResult::Pass)
+                    || matches!(dmarc_output.dkim_result(), DmarcResult::Pass);
+
                 // Add to DMARC output to the Authentication-Results header
                 auth_results = auth_results.with_dmarc_result(&dmarc_output);
 
@@ -275,6 +563,41 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
             _ => (),
         }
 
+        // Apply authentication policy
+        if let Some(policy) = ac.policy.eval(self).await {
+            let passed = |method: AuthMethod| match method {
+                AuthMethod::Spf => matches!(
+                    &self.data.spf_mail_from,
+                    Some(spf_output) if spf_output.result() == SpfResult::Pass
+                ),
+                AuthMethod::Dkim => dkim_output
+                    .iter()
+                    .any(|d| matches!(d.result(), DkimResult::Pass)),
+                AuthMethod::Dmarc => dmarc_pass,
+                AuthMethod::Arc => matches!(
+                    &arc_output,
+                    Some(arc_output) if matches!(arc_output.result(), DkimResult::Pass)
+                ),
+                AuthMethod::Iprev => matches!(
+                    &self.data.iprev,
+                    Some(IprevOutput {
+                        result: IprevResult::Pass,
+                        ..
+                    })
+                ),
+            };
+            if !policy.eval(&passed) {
+                tracing::info!(parent: &self.span,
+                    context = "auth-policy",
+                    event = "rejected",
+                    return_path = mail_from.address,
+                    from = auth_message.from(),
+                    "Message rejected by authentication policy.");
+
+                return (&b"550 5.7.1 Message rejected by authentication policy.\r\n"[..]).into();
+            }
+        }
+
         // Analyze reports
         if self.is_report() {
             self.core.analyze_report(raw_message.clone());
@@ -399,20 +722,54 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
             }
         }
 
+        // Extract message metadata supplied by authenticated clients
+        let mut metadata = None;
+        if let Some(header) = dc.metadata_header.eval(self).await {
+            if !self.data.authenticated_as.is_empty() {
+                if let Some((value, stripped)) =
+                    take_header(edited_message.as_ref().unwrap_or(&raw_message), header)
+                {
+                    if value.len() <= MAX_METADATA_LEN {
+                        metadata = Some(value).filter(|value| !value.is_empty());
+                    } else {
+                        tracing::debug!(parent: &self.span,
+                            context = "data",
+                            event = "metadata-too-large",
+                            size = value.len(),
+                            "Ignoring message metadata exceeding the maximum length.");
+                    }
+                    edited_message = Arc::new(stripped).into();
+                }
+            }
+        }
+
         // Build message
         let mail_from = self.data.mail_from.clone().unwrap();
-        let rcpt_to = std::mem::take(&mut self.data.rcpt_to);
+        let (rcpt_lists, rcpt_to): (Vec<_>, Vec<_>) = std::mem::take(&mut self.data.rcpt_to)
+            .into_iter()
+            .partition(|rcpt| self.data.rcpt_lists.contains(&rcpt.address_lcase));
         let mut message = self.build_message(mail_from, rcpt_to).await;
+        message.metadata = metadata;
 
         // Add Received header
         let mut headers = Vec::with_capacity(64);
         if *dc.add_received.eval(self).await {
-            self.write_received(&mut headers, message.id)
+            let include_cipher = *dc.add_received_cipher.eval(self).await;
+            self.write_received(&mut headers, message.id, include_cipher)
         }
 
         // Add authentication results header
         if *dc.add_auth_results.eval(self).await {
             auth_results.write_header(&mut headers);
+            if !dkim_policy.is_empty() {
+                headers.extend_from_slice(b"Authentication-Results: ");
+                headers.extend_from_slice(self.instance.hostname.as_bytes());
+                for result in &dkim_policy {
+                    headers.extend_from_slice(b";\r\n\t");
+                    headers.extend_from_slice(result.as_bytes());
+                }
+                headers.extend_from_slice(b"\r\n");
+            }
         }
 
         // Add Received-SPF header
@@ -429,6 +786,60 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
             }
         }
 
+        // Add GeoIP headers
+        if let Some(geoip) = &self.data.geoip {
+            if *dc.add_geoip.eval(self).await {
+                if !geoip.country.is_empty() {
+                    headers.extend_from_slice(b"X-Origin-Country: ");
+                    headers.extend_from_slice(geoip.country.as_bytes());
+                    headers.extend_from_slice(b"\r\n");
+                }
+                if geoip.asn != 0 {
+                    headers.extend_from_slice(b"X-Origin-ASN: ");
+                    headers.extend_from_slice(geoip.asn.to_string().as_bytes());
+                    headers.extend_from_slice(b"\r\n");
+                }
+            }
+        }
+
+        // Tag messages with too many URLs
+        if let Some(num_urls) = excessive_urls {
+            headers.extend_from_slice(b"X-Excessive-URLs: ");
+            headers.extend_from_slice(num_urls.to_string().as_bytes());
+            headers.extend_from_slice(b"\r\n");
+        }
+        if let Some(encoding) = invalid_encoding {
+            headers.extend_from_slice(b"X-Invalid-Transfer-Encoding: ");
+            headers.extend_from_slice(encoding.as_bytes());
+            headers.extend_from_slice(b"\r\n");
+        }
+        if let Some(line_length) = excessive_line_length {
+            headers.extend_from_slice(b"X-Excessive-Line-Length: ");
+            headers.extend_from_slice(line_length.to_string().as_bytes());
+            headers.extend_from_slice(b"\r\n");
+        }
+        if let Some((envelope_rcpts, header_rcpts)) = rcpt_mismatch {
+            headers.extend_from_slice(
+                format!(
+                    "X-Recipient-Mismatch: envelope={envelope_rcpts}; header={header_rcpts}\r\n"
+                )
+                .as_bytes(),
+            );
+        }
+
+        if spf_softfail_no_dkim {
+            headers.extend_from_slice(b"X-SPF-Softfail-No-DKIM: smtp.mailfrom=");
+            headers.extend_from_slice(message.return_path.as_bytes());
+            headers.extend_from_slice(b"\r\n");
+        }
+
+        if let Some((name, address)) = spoofed_name {
+            headers.extend_from_slice(
+                format!("X-Display-Name-Spoofing: name=\"{name}\"; address={address}\r\n")
+                    .as_bytes(),
+            );
+        }
+
         // ARC Seal
         if let (Some(arc_sealer), Some(arc_output)) = (arc_sealer, &arc_output) {
             if !dkim_output.is_empty() && arc_output.can_be_sealed() {
@@ -459,6 +870,22 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
             let _ = generate_message_id_header(&mut headers, &self.instance.hostname);
             headers.extend_from_slice(b"\r\n");
         }
+        if let Some(unsubscribe) = dc.add_list_unsubscribe.eval(self).await {
+            if !auth_message
+                .headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case(b"List-Unsubscribe"))
+            {
+                headers.extend_from_slice(b"List-Unsubscribe: <");
+                headers.extend_from_slice(unsubscribe.as_bytes());
+                headers.extend_from_slice(b">\r\n");
+                if unsubscribe.starts_with("https://") {
+                    headers.extend_from_slice(
+                        b"List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n",
+                    );
+                }
+            }
+        }
 
         // Add Return-Path
         if *dc.add_return_path.eval(self).await {
@@ -487,6 +914,14 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
         // Update size
         message.size = raw_message.len() + headers.len();
 
+        // Messages addressed only to mailing lists are queued after expansion
+        if message.recipients.is_empty() {
+            self.queue_list_message(rcpt_lists, headers, raw_message)
+                .await;
+            self.data.messages_sent += 1;
+            return (b"250 2.0.0 Message queued for delivery.\r\n"[..]).into();
+        }
+
         // Verify queue quota
         if self.core.queue.has_quota(&mut message).await {
             if self
@@ -495,6 +930,10 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                 .queue_message(message, Some(&headers), &raw_message, &self.span)
                 .await
             {
+                if !rcpt_lists.is_empty() {
+                    self.queue_list_message(rcpt_lists, headers, raw_message)
+                        .await;
+                }
                 self.data.messages_sent += 1;
                 (b"250 2.0.0 Message queued for delivery.\r\n"[..]).into()
             } else {
@@ -533,11 +972,12 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
             priority: self.data.priority,
             size: 0,
             env_id: mail_from.dsn_info,
+            metadata: None,
             queue_refs: Vec::with_capacity(0),
+            dedup_hash: None,
         });
 
         // Add recipients
-        let future_release = Duration::from_secs(self.data.future_release);
         rcpt_to.sort_unstable();
         for rcpt in rcpt_to {
             if message
@@ -546,16 +986,23 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                 .map_or(true, |d| d.domain != rcpt.domain)
             {
                 let envelope = SimpleEnvelope::new(message.as_ref(), &rcpt.domain);
+                let config = &self.core.queue.config;
+
+                // Hold the message until the next delivery window opens
+                let mut future_release = Duration::from_secs(self.data.future_release);
+                if let Some(window) = config.window.eval(&envelope).await {
+                    future_release =
+                        std::cmp::max(future_release, window.wait_time(message.created));
+                }
 
                 // Set next retry time
-                let retry = if self.data.future_release == 0 {
+                let retry = if future_release.is_zero() {
                     queue::Schedule::now()
                 } else {
                     queue::Schedule::later(future_release)
                 };
 
                 // Set expiration and notification times
-                let config = &self.core.queue.config;
                 let notify_intervals = config.notify.eval(&envelope).await;
                 let (notify, expires) = if self.data.delivery_by == 0 {
                     (
@@ -591,6 +1038,9 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                     (notify, Instant::now() + expire)
                 };
 
+                // Enforce the maximum message lifetime
+                let expires = message.max_expires(expires, config.max_lifetime);
+
                 message.domains.push(queue::Domain {
                     retry,
                     notify,
@@ -646,7 +1096,7 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
         }
     }
 
-    fn write_received(&self, headers: &mut Vec<u8>, id: u64) {
+    fn write_received(&self, headers: &mut Vec<u8>, id: u64, include_cipher: bool) {
         headers.extend_from_slice(b"Received: from ");
         headers.extend_from_slice(self.data.helo_domain.as_bytes());
         headers.extend_from_slice(b" (");
@@ -662,7 +1112,7 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
         headers.extend_from_slice(b" [");
         headers.extend_from_slice(self.data.remote_ip.to_string().as_bytes());
         headers.extend_from_slice(b"])\r\n\t");
-        self.stream.write_tls_header(headers);
+        self.stream.write_tls_header(headers, include_cipher);
         headers.extend_from_slice(b"by ");
         headers.extend_from_slice(self.instance.hostname.as_bytes());
         headers.extend_from_slice(b" (Stalwart SMTP) with ");
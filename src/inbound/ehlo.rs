@@ -24,7 +24,7 @@
 use std::{net::IpAddr, time::SystemTime};
 
 use crate::{
-    config::{DNSBL_EHLO, DNSBL_IP},
+    config::{EnvelopeKey, DNSBL_EHLO, DNSBL_IP},
     core::{scripts::ScriptResult, Session},
 };
 use mail_auth::spf::verify::HasLabels;
@@ -123,8 +123,7 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
         }
 
         let mut response = EhloResponse::new(self.instance.hostname.as_str());
-        response.capabilities =
-            EXT_ENHANCED_STATUS_CODES | EXT_8BIT_MIME | EXT_BINARY_MIME | EXT_SMTP_UTF8;
+        response.capabilities = EXT_ENHANCED_STATUS_CODES | EXT_8BIT_MIME | EXT_SMTP_UTF8;
         if !self.stream.is_tls() {
             response.capabilities |= EXT_START_TLS;
         }
@@ -134,13 +133,14 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
         let dc = &self.core.session.config.data;
 
         // Pipelining
-        if *ec.pipelining.eval(self).await {
+        self.data.pipelining = *ec.pipelining.eval(self).await;
+        if self.data.pipelining {
             response.capabilities |= EXT_PIPELINING;
         }
 
         // Chunking
         if *ec.chunking.eval(self).await {
-            response.capabilities |= EXT_CHUNKING;
+            response.capabilities |= EXT_CHUNKING | EXT_BINARY_MIME;
         }
 
         // Address Expansion
@@ -163,6 +163,11 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
             response.capabilities |= EXT_DSN;
         }
 
+        // Require Recipient Valid Since
+        if *ec.rrvs.eval(self).await && rc.lookup_created.eval(self).await.is_some() {
+            response.capabilities |= EXT_RRVS;
+        }
+
         // Authentication
         if self.data.authenticated_as.is_empty() {
             response.auth_mechanisms = *ac.mechanisms.eval(self).await;
@@ -170,12 +175,25 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                 if !self.stream.is_tls() {
                     response.auth_mechanisms &= !(AUTH_PLAIN | AUTH_LOGIN);
                 }
+                if self.data.peer_certificate.is_none() {
+                    response.auth_mechanisms &= !AUTH_EXTERNAL;
+                }
                 if response.auth_mechanisms != 0 {
                     response.capabilities |= EXT_AUTH;
                 }
             }
         }
 
+        // On-Demand Mail Relay
+        if !self.data.authenticated_as.is_empty() && ec.atrn.eval(self).await.is_some() {
+            response.capabilities |= EXT_ATRN;
+        }
+
+        // BURL
+        if self.data.auth_credentials.is_some() && ec.burl.eval(self).await.is_some() {
+            response.capabilities |= EXT_BURL;
+        }
+
         // Future release
         if let Some(value) = ec.future_release.eval(self).await {
             response.capabilities |= EXT_FUTURE_RELEASE;
@@ -199,8 +217,18 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
             response.mt_priority = *value;
         }
 
-        // Size
-        response.size = *dc.max_message_size.eval(self).await;
+        // Size, advertise the largest possible limit when it depends on the envelope.
+        // Limits that depend on authentication are re-evaluated on the next EHLO.
+        let envelope_keys = [
+            EnvelopeKey::Sender,
+            EnvelopeKey::SenderDomain,
+            EnvelopeKey::Priority,
+        ];
+        response.size = if dc.max_message_size.has_keys(&envelope_keys) {
+            *dc.max_message_size.max_value()
+        } else {
+            *dc.max_message_size.eval(self).await
+        };
         if response.size > 0 {
             response.capabilities |= EXT_SIZE;
         }
@@ -218,6 +246,26 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
         // Generate response
         let mut buf = Vec::with_capacity(64);
         response.write(&mut buf).ok();
+
+        // Custom keywords are only advertised, continue the last line and append them
+        let custom = ec.custom.eval(self).await;
+        if !custom.is_empty() {
+            let last_line = buf[..buf.len() - 2]
+                .iter()
+                .rposition(|&ch| ch == b'\n')
+                .map_or(0, |pos| pos + 1);
+            buf[last_line + 3] = b'-';
+            for (pos, keyword) in custom.iter().enumerate() {
+                buf.extend_from_slice(if pos == custom.len() - 1 {
+                    b"250 "
+                } else {
+                    b"250-"
+                });
+                buf.extend_from_slice(keyword.as_bytes());
+                buf.extend_from_slice(b"\r\n");
+            }
+        }
+
         self.write(&buf).await
     }
 
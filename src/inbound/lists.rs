@@ -0,0 +1,289 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::Arc, time::Instant};
+
+use ahash::AHashSet;
+use smtp_proto::Response;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    core::{Session, SessionAddress},
+    lookup::{Item, Lookup, LookupResult},
+    queue::{DeliveryAttempt, Error, ErrorDetails, HostResponse, Message, SimpleEnvelope, Status},
+};
+
+use super::IsTls;
+
+enum ListExpansion {
+    Members(Vec<String>),
+    TooLarge,
+    TemporaryFailure,
+}
+
+impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
+    pub async fn queue_list_message(
+        &self,
+        lists: Vec<SessionAddress>,
+        headers: Vec<u8>,
+        raw_message: Arc<Vec<u8>>,
+    ) {
+        let lookup = if let Some(lookup) = &self.params.rcpt_lookup_lists {
+            lookup.clone()
+        } else {
+            return;
+        };
+        let rc = &self.core.session.config.rcpt;
+        let max_members = *rc.lists_max_members.eval(self).await;
+        let max_depth = *rc.lists_max_depth.eval(self).await;
+
+        // Holds the lists pending expansion, their retry schedule and the sender to notify
+        let mut lists = self
+            .build_message(self.data.mail_from.clone().unwrap(), lists)
+            .await;
+        lists.size = raw_message.len() + headers.len();
+
+        let core = self.core.clone();
+        let span = self.span.clone();
+        let hostname = self.instance.hostname.clone();
+
+        tokio::spawn(async move {
+            let queue_config = &core.queue.config;
+            let mut attempt = DeliveryAttempt::from(lists);
+
+            loop {
+                let mut members = Vec::new();
+                let mut seen = AHashSet::new();
+                let mut expanded = Vec::new();
+                let mut failed = Vec::new();
+                for rcpt in &mut attempt.message.recipients {
+                    match expand_list(&lookup, &rcpt.address_lcase, max_members, max_depth, &span)
+                        .await
+                    {
+                        ListExpansion::Members(list_members) => {
+                            members.extend(
+                                list_members
+                                    .into_iter()
+                                    .filter(|member| seen.insert(member.clone())),
+                            );
+                            expanded.push(rcpt.address_lcase.clone());
+                        }
+                        ListExpansion::TooLarge => {
+                            rcpt.status = list_error(
+                                &hostname,
+                                &rcpt.address,
+                                [5, 5, 3],
+                                "Mailing list exceeds the maximum number of members.",
+                            );
+                        }
+                        ListExpansion::TemporaryFailure => {
+                            failed.push(rcpt.domain_idx);
+                        }
+                    }
+                }
+
+                if !members.is_empty() {
+                    let envelope = &attempt.message;
+                    let mut message = Message::new_boxed(
+                        envelope.return_path.as_str(),
+                        envelope.return_path_lcase.as_str(),
+                        envelope.return_path_domain.as_str(),
+                    );
+                    message.flags = envelope.flags;
+                    message.env_id = envelope.env_id.clone();
+                    message.priority = envelope.priority;
+                    message.size = envelope.size;
+                    for member in members {
+                        message.add_recipient(member, queue_config).await;
+                    }
+
+                    let error = if core.queue.has_quota(&mut message).await {
+                        if core
+                            .queue
+                            .queue_message(message, Some(&headers), &raw_message, &span)
+                            .await
+                        {
+                            None
+                        } else {
+                            Some(([5, 3, 0], "Failed to queue expanded mailing list message."))
+                        }
+                    } else {
+                        tracing::warn!(
+                            parent: &span,
+                            context = "queue",
+                            event = "quota-exceeded",
+                            from = attempt.message.return_path,
+                            "Queue quota exceeded, discarding expanded list message."
+                        );
+                        Some(([5, 3, 1], "Mail system full."))
+                    };
+
+                    if let Some((esc, reason)) = error {
+                        for rcpt in &mut attempt.message.recipients {
+                            if expanded.contains(&rcpt.address_lcase) {
+                                rcpt.status = list_error(&hostname, &rcpt.address, esc, reason);
+                            }
+                        }
+                        expanded.clear();
+                    }
+                } else if expanded.len() == attempt.message.recipients.len() {
+                    tracing::debug!(parent: &span,
+                        context = "list",
+                        event = "empty",
+                        "Mailing list expansion produced no recipients.");
+                }
+                attempt
+                    .message
+                    .recipients
+                    .retain(|rcpt| !expanded.contains(&rcpt.address_lcase));
+
+                // Lists that could not be expanded are retried until they expire
+                let mut domains = std::mem::take(&mut attempt.message.domains);
+                for (domain_idx, domain) in domains.iter_mut().enumerate() {
+                    if failed.contains(&domain_idx) {
+                        let envelope = SimpleEnvelope::new(&attempt.message, &domain.domain);
+                        domain.set_status(
+                            Status::TemporaryFailure(Error::Io(
+                                "Failed to expand mailing list.".to_string(),
+                            )),
+                            queue_config.retry.eval(&envelope).await,
+                            *queue_config.jitter.eval(&envelope).await,
+                        );
+                    } else {
+                        domain.status = Status::Completed(());
+                    }
+                }
+                attempt.message.domains = domains;
+
+                let has_pending = attempt.has_pending_delivery();
+                core.queue.send_dsn(&mut attempt).await;
+                if !has_pending {
+                    break;
+                }
+
+                let message = attempt.message.as_mut();
+                let domains = &message.domains;
+                message.recipients.retain(|rcpt| {
+                    matches!(&rcpt.status, Status::Scheduled)
+                        && matches!(
+                            &domains[rcpt.domain_idx].status,
+                            Status::TemporaryFailure(_)
+                        )
+                });
+                if let Some(next_retry) = domains
+                    .iter()
+                    .filter(|domain| matches!(&domain.status, Status::TemporaryFailure(_)))
+                    .map(|domain| std::cmp::min(domain.retry.due, domain.expires))
+                    .min()
+                {
+                    tokio::time::sleep(next_retry.saturating_duration_since(Instant::now())).await;
+                }
+            }
+        });
+    }
+}
+
+fn list_error(
+    hostname: &str,
+    list: &str,
+    esc: [u8; 3],
+    reason: &str,
+) -> Status<HostResponse<String>, HostResponse<ErrorDetails>> {
+    Status::PermanentFailure(HostResponse {
+        hostname: ErrorDetails {
+            entity: hostname.to_string(),
+            details: format!("RCPT TO:<{list}>"),
+        },
+        response: Response {
+            code: 550,
+            esc,
+            message: reason.to_string(),
+        },
+    })
+}
+
+// Expands a list and its nested lists into their members
+async fn expand_list(
+    lookup: &Lookup,
+    list: &str,
+    max_members: usize,
+    max_depth: usize,
+    span: &tracing::Span,
+) -> ListExpansion {
+    let mut members = Vec::new();
+    let mut seen = AHashSet::from_iter([list.to_string()]);
+    let mut pending = vec![(list.to_string(), 0)];
+
+    while let Some((list, depth)) = pending.pop() {
+        match lookup.lookup(Item::Expand(list.clone())).await {
+            Some(LookupResult::Values(values)) => {
+                for value in values {
+                    // Skip duplicates and lists that were already expanded
+                    let member = value.trim().to_lowercase();
+                    if member.is_empty() || !seen.insert(member.clone()) {
+                        continue;
+                    }
+
+                    if lookup.contains(&member).await.unwrap_or(false) {
+                        if depth + 1 < max_depth {
+                            pending.push((member, depth + 1));
+                        } else {
+                            tracing::info!(parent: span,
+                                context = "list",
+                                event = "too-deep",
+                                list = &member,
+                                max_depth = max_depth,
+                                "Nested mailing list exceeds the maximum expansion depth.");
+                        }
+                    } else if members.len() < max_members {
+                        members.push(member);
+                    } else {
+                        tracing::info!(parent: span,
+                            context = "list",
+                            event = "too-many-members",
+                            list = &list,
+                            max_members = max_members,
+                            "Mailing list expansion exceeds the maximum number of members.");
+                        return ListExpansion::TooLarge;
+                    }
+                }
+            }
+            Some(_) => {
+                tracing::debug!(parent: span,
+                    context = "list",
+                    event = "not-found",
+                    list = &list);
+            }
+            None => {
+                tracing::warn!(parent: span,
+                    context = "list",
+                    event = "temp-fail",
+                    list = &list,
+                    "Failed to expand mailing list, will retry later.");
+                return ListExpansion::TemporaryFailure;
+            }
+        }
+    }
+
+    ListExpansion::Members(members)
+}
@@ -21,14 +21,17 @@
  * for more details.
 */
 
-use std::time::SystemTime;
+use std::{
+    sync::atomic::Ordering,
+    time::{Instant, SystemTime},
+};
 
 use mail_auth::{IprevOutput, IprevResult, SpfOutput, SpfResult};
-use smtp_proto::{MailFrom, MAIL_BY_NOTIFY, MAIL_BY_RETURN, MAIL_REQUIRETLS};
+use smtp_proto::{MailFrom, MAIL_BODY_BINARYMIME, MAIL_BY_NOTIFY, MAIL_BY_RETURN, MAIL_REQUIRETLS};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::{
-    config::{DNSBL_IPREV, DNSBL_RETURN_PATH},
+    config::{AddressStrictness, VerifyDomain, DNSBL_IPREV, DNSBL_RETURN_PATH},
     core::{scripts::ScriptResult, Session, SessionAddress},
     queue::DomainPart,
 };
@@ -36,7 +39,26 @@ use crate::{
 use super::IsTls;
 
 impl<T: AsyncWrite + AsyncRead + Unpin + IsTls> Session<T> {
-    pub async fn handle_mail_from(&mut self, from: MailFrom<String>) -> Result<(), ()> {
+    async fn sender_domain_resolves(&self, domain: &str) -> mail_auth::Result<bool> {
+        let dns = &self.core.resolvers.dns;
+        match dns.mx_lookup(domain).await {
+            Ok(mx) if !mx.is_empty() => return Ok(true),
+            Ok(_) | Err(mail_auth::Error::DnsRecordNotFound(_)) => (),
+            Err(err) => return Err(err),
+        }
+        match dns.ipv4_lookup(domain).await {
+            Ok(ips) if !ips.is_empty() => return Ok(true),
+            Ok(_) | Err(mail_auth::Error::DnsRecordNotFound(_)) => (),
+            Err(err) => return Err(err),
+        }
+        match dns.ipv6_lookup(domain).await {
+            Ok(ips) => Ok(!ips.is_empty()),
+            Err(mail_auth::Error::DnsRecordNotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn handle_mail_from(&mut self, mut from: MailFrom<String>) -> Result<(), ()> {
         if self.data.helo_domain.is_empty()
             && (self.params.ehlo_require
                 || self.params.spf_ehlo.verify()
@@ -49,10 +71,28 @@ impl<T: AsyncWrite + AsyncRead + Unpin + IsTls> Session<T> {
             return self
                 .write(b"503 5.5.1 Multiple MAIL commands not allowed.\r\n")
                 .await;
+        } else if self.core.draining.load(Ordering::Relaxed) {
+            tracing::info!(parent: &self.span,
+                context = "mail-from",
+                event = "reject",
+                reason = "draining",
+                "Rejected transaction while draining.");
+            self.write(b"421 4.3.2 Service not accepting new messages, try again later.\r\n")
+                .await?;
+            return Err(());
         } else if self.params.auth_require && self.data.authenticated_as.is_empty() {
             return self
                 .write(b"503 5.5.1 You must authenticate first.\r\n")
                 .await;
+        } else if !self.stream.is_tls()
+            && *self.core.session.config.mail.require_tls.eval(self).await
+        {
+            tracing::info!(parent: &self.span,
+                context = "mail-from",
+                event = "reject",
+                reason = "tls-required",
+                "Rejected transaction on a plain text connection.");
+            return self.write(b"530 5.7.10 Encryption required.\r\n").await;
         } else if self.has_dnsbl_error() {
             // There was a previous DNSBL error
             return self.write_dnsbl_error().await;
@@ -108,6 +148,16 @@ impl<T: AsyncWrite + AsyncRead + Unpin + IsTls> Session<T> {
             return self.write(message).await;
         }
 
+        // Validate and normalize the address syntax
+        from.address = match normalize_address(from.address, self.params.address_strictness) {
+            Some(address) => address,
+            None => {
+                return self
+                    .write(b"501 5.1.7 Bad sender mailbox address syntax.\r\n")
+                    .await;
+            }
+        };
+
         let (address, address_lcase, domain) = if !from.address.is_empty() {
             let address_lcase = from.address.to_lowercase();
             let domain = address_lcase.domain_part().to_string();
@@ -127,6 +177,48 @@ impl<T: AsyncWrite + AsyncRead + Unpin + IsTls> Session<T> {
             return Ok(());
         }
 
+        // Verify that the sender domain resolves
+        if !domain.is_empty() && self.data.authenticated_as.is_empty() {
+            let verify_domain = *self.core.session.config.mail.verify_domain.eval(self).await;
+            if verify_domain != VerifyDomain::Disable {
+                let timeout = *self
+                    .core
+                    .session
+                    .config
+                    .mail
+                    .verify_domain_timeout
+                    .eval(self)
+                    .await;
+                let result = tokio::time::timeout(timeout, self.sender_domain_resolves(&domain))
+                    .await
+                    .unwrap_or_else(|_| Err(mail_auth::Error::DnsError("timeout".to_string())));
+
+                tracing::debug!(parent: &self.span,
+                    context = "mail-from",
+                    event = "verify-domain",
+                    domain = &domain,
+                    result = ?result);
+
+                match result {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        return self
+                            .write(if verify_domain == VerifyDomain::Permanent {
+                                &b"550 5.1.8 Sender domain does not resolve.\r\n"[..]
+                            } else {
+                                &b"450 4.1.8 Sender domain does not resolve.\r\n"[..]
+                            })
+                            .await;
+                    }
+                    Err(_) => {
+                        return self
+                            .write(b"451 4.4.3 Temporary error resolving sender domain.\r\n")
+                            .await;
+                    }
+                }
+            }
+        }
+
         let has_dsn = from.env_id.is_some();
         self.data.mail_from = SessionAddress {
             address,
@@ -159,6 +251,12 @@ impl<T: AsyncWrite + AsyncRead + Unpin + IsTls> Session<T> {
                 .write(b"501 5.5.4 REQUIRETLS has been disabled.\r\n")
                 .await;
         }
+        if (from.flags & MAIL_BODY_BINARYMIME) != 0 && !*config.chunking.eval(self).await {
+            self.data.mail_from = None;
+            return self
+                .write(b"501 5.5.4 BINARYMIME requires CHUNKING.\r\n")
+                .await;
+        }
         if (from.flags & (MAIL_BY_NOTIFY | MAIL_BY_RETURN)) != 0 {
             if let Some(duration) = config.deliver_by.eval(self).await {
                 if from.by.checked_abs().unwrap_or(0) as u64 <= duration.as_secs()
@@ -303,6 +401,7 @@ impl<T: AsyncWrite + AsyncRead + Unpin + IsTls> Session<T> {
                 address = &self.data.mail_from.as_ref().unwrap().address);
 
             self.eval_rcpt_params().await;
+            self.data.mail_from_at = Instant::now().into();
             self.write(b"250 2.1.0 OK\r\n").await
         } else {
             self.data.mail_from = None;
@@ -345,3 +444,73 @@ impl<T: AsyncWrite + AsyncRead + Unpin + IsTls> Session<T> {
         Ok(result)
     }
 }
+
+pub fn normalize_address(address: String, strictness: AddressStrictness) -> Option<String> {
+    if address.is_empty() {
+        return Some(address);
+    }
+
+    // Source routes (RFC 5321, Appendix C) are ignored in lenient mode
+    let address = if address.starts_with('@') {
+        if strictness == AddressStrictness::Strict {
+            return None;
+        }
+        address.split_once(':')?.1.to_string()
+    } else {
+        address
+    };
+
+    match strictness {
+        AddressStrictness::Lenient => {
+            // Remove unnecessary quotes from the local part
+            if let Some((local, domain)) = address.rsplit_once('@') {
+                if let Some(local) = local
+                    .strip_prefix('"')
+                    .and_then(|local| local.strip_suffix('"'))
+                    .filter(|local| is_dot_atom(local))
+                {
+                    return Some(format!("{local}@{domain}"));
+                }
+            }
+            Some(address)
+        }
+        AddressStrictness::Strict => {
+            let (local, domain) = address.rsplit_once('@')?;
+            if is_dot_atom(local) && is_domain(domain) {
+                Some(address)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+fn is_dot_atom(value: &str) -> bool {
+    !value.is_empty()
+        && value.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom.chars().all(|ch| {
+                    ch.is_ascii_alphanumeric()
+                        || !ch.is_ascii()
+                        || "!#$%&'*+-/=?^_`{|}~".contains(ch)
+                })
+        })
+}
+
+fn is_domain(value: &str) -> bool {
+    if let Some(literal) = value.strip_prefix('[') {
+        literal.strip_suffix(']').map_or(false, |literal| {
+            !literal.is_empty() && !literal.contains(['[', ']', '\\'])
+        })
+    } else {
+        !value.is_empty()
+            && value.split('.').all(|label| {
+                !label.is_empty()
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label
+                        .chars()
+                        .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || !ch.is_ascii())
+            })
+    }
+}
@@ -29,25 +29,30 @@ use tokio_rustls::server::TlsStream;
 
 use crate::config::{ArcSealer, DkimSigner};
 
+pub mod atrn;
 pub mod auth;
+pub mod burl;
+pub mod content;
 pub mod data;
 pub mod ehlo;
+pub mod lists;
 pub mod mail;
 pub mod rcpt;
+pub mod scram;
 pub mod session;
 pub mod spawn;
 pub mod vrfy;
 
 pub trait IsTls {
     fn is_tls(&self) -> bool;
-    fn write_tls_header(&self, headers: &mut Vec<u8>);
+    fn write_tls_header(&self, headers: &mut Vec<u8>, include_cipher: bool);
 }
 
 impl IsTls for TcpStream {
     fn is_tls(&self) -> bool {
         false
     }
-    fn write_tls_header(&self, _headers: &mut Vec<u8>) {}
+    fn write_tls_header(&self, _headers: &mut Vec<u8>, _include_cipher: bool) {}
 }
 
 impl IsTls for TlsStream<TcpStream> {
@@ -55,7 +60,7 @@ impl IsTls for TlsStream<TcpStream> {
         true
     }
 
-    fn write_tls_header(&self, headers: &mut Vec<u8>) {
+    fn write_tls_header(&self, headers: &mut Vec<u8>, include_cipher: bool) {
         let (_, conn) = self.get_ref();
         headers.extend_from_slice(b"(using ");
         headers.extend_from_slice(
@@ -76,19 +81,21 @@ impl IsTls for TlsStream<TcpStream> {
             }
             .as_bytes(),
         );
-        headers.extend_from_slice(b" with cipher ");
-        headers.extend_from_slice(
-            match conn.negotiated_cipher_suite() {
-                Some(rustls::SupportedCipherSuite::Tls13(cs)) => {
-                    cs.common.suite.as_str().unwrap_or("unknown")
-                }
-                Some(rustls::SupportedCipherSuite::Tls12(cs)) => {
-                    cs.common.suite.as_str().unwrap_or("unknown")
+        if include_cipher {
+            headers.extend_from_slice(b" with cipher ");
+            headers.extend_from_slice(
+                match conn.negotiated_cipher_suite() {
+                    Some(rustls::SupportedCipherSuite::Tls13(cs)) => {
+                        cs.common.suite.as_str().unwrap_or("unknown")
+                    }
+                    Some(rustls::SupportedCipherSuite::Tls12(cs)) => {
+                        cs.common.suite.as_str().unwrap_or("unknown")
+                    }
+                    None => "unknown",
                 }
-                None => "unknown",
-            }
-            .as_bytes(),
-        );
+                .as_bytes(),
+            );
+        }
         headers.extend_from_slice(b")\r\n\t");
     }
 }
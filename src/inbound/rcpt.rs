@@ -21,14 +21,21 @@
  * for more details.
 */
 
+use std::{net::IpAddr, time::Instant};
+
+use mail_parser::DateTime;
+use rand::{distributions::Alphanumeric, Rng};
 use smtp_proto::{
     RcptTo, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS,
+    RCPT_RRVS_CONTINUE,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::{
-    core::{scripts::ScriptResult, Session, SessionAddress},
-    queue::DomainPart,
+    config::{CatchAllAction, LookupFailAction},
+    core::{scripts::ScriptResult, CatchAllDomain, Envelope, Session, SessionAddress},
+    lookup::Lookup,
+    queue::{DomainPart, RCPT_VERIFY_PENDING},
 };
 
 impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
@@ -39,6 +46,8 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                 return self.write(b"503 5.5.1 Invalid recipient.\r\n").await;
             } else if to.address.contains("delay@") {
                 return self.write(b"451 4.5.3 Try again later.\r\n").await;
+            } else if to.address.contains("full@") {
+                return self.write(b"552 5.2.2 Mailbox full.\r\n").await;
             }
         }
 
@@ -48,6 +57,26 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
             return self.write(b"451 4.5.3 Too many recipients.\r\n").await;
         }
 
+        // Tarpit clients that did not wait for the MAIL FROM response
+        if self.data.rcpt_to.is_empty() && !self.data.pipelining {
+            let rc = &self.core.session.config.rcpt;
+            if let (Some(min_delay), Some(mail_from_at)) =
+                (*rc.min_delay.eval(self).await, self.data.mail_from_at)
+            {
+                let elapsed = mail_from_at.elapsed();
+                if elapsed < min_delay {
+                    let wait = *rc.min_delay_wait.eval(self).await;
+                    tracing::debug!(parent: &self.span,
+                        context = "rcpt",
+                        event = "too-fast",
+                        elapsed = elapsed.as_micros() as u64,
+                        wait = wait.as_millis() as u64,
+                        "Client did not wait for the MAIL FROM response.");
+                    tokio::time::sleep(wait).await;
+                }
+            }
+        }
+
         // Verify parameters
         if ((to.flags
             & (RCPT_NOTIFY_DELAY | RCPT_NOTIFY_NEVER | RCPT_NOTIFY_SUCCESS | RCPT_NOTIFY_FAILURE)
@@ -58,20 +87,74 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
             return self
                 .write(b"501 5.5.4 DSN extension has been disabled.\r\n")
                 .await;
+        } else if to.rrvs != 0 && !self.params.rcpt_rrvs {
+            return self
+                .write(b"501 5.5.4 RRVS extension has been disabled.\r\n")
+                .await;
+        }
+
+        // Validate and normalize the address syntax
+        let mut address = if to.address.eq_ignore_ascii_case("postmaster") {
+            to.address
+        } else if let Some(address) = normalize_address(to.address, self.params.address_strictness)
+        {
+            address
+        } else {
+            return self
+                .write(b"501 5.1.3 Bad destination mailbox address syntax.\r\n")
+                .await;
+        };
+
+        // Rewrite aliased domains
+        let mut address_lcase = address.to_lowercase();
+        if let Some(domain) = self
+            .core
+            .session
+            .config
+            .rcpt
+            .domain_aliases
+            .get(address_lcase.domain_part())
+        {
+            if let Some((local_part, _)) = address.rsplit_once('@') {
+                let new_address = format!("{local_part}@{domain}");
+                tracing::debug!(parent: &self.span,
+                    context = "rcpt",
+                    event = "rewrite",
+                    address = &address,
+                    new_address = &new_address,
+                    "Recipient domain rewritten.");
+                address_lcase = new_address.to_lowercase();
+                address = new_address;
+            }
         }
 
         // Build RCPT
-        let address_lcase = to.address.to_lowercase();
-        let rcpt = SessionAddress {
+        let mut rcpt = SessionAddress {
             domain: address_lcase.domain_part().to_string(),
             address_lcase,
-            address: to.address,
+            address,
             flags: to.flags,
-            dsn_info: to.orcpt,
+            dsn_info: to.orcpt.filter(|orcpt| !orcpt.is_empty()),
+        };
+
+        // Mailing lists are accepted right away and expanded once the message is queued
+        let is_list = if let Some(list_lookup) = &self.params.rcpt_lookup_lists {
+            list_lookup
+                .contains(&rcpt.address_lcase)
+                .await
+                .unwrap_or(false)
+        } else {
+            false
         };
 
         // Verify address
-        if let (Some(domain_lookup), Some(address_lookup)) = (
+        if is_list {
+            tracing::debug!(parent: &self.span,
+                context = "rcpt",
+                event = "list",
+                address = &rcpt.address_lcase,
+                "Mailing list expansion deferred.");
+        } else if let (Some(domain_lookup), Some(address_lookup)) = (
             &self.params.rcpt_lookup_domain,
             &self.params.rcpt_lookup_addresses,
         ) {
@@ -81,15 +164,40 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                         address_lookup.contains(&rcpt.address_lcase).await
                     {
                         if !is_local_address {
-                            tracing::debug!(parent: &self.span,
-                                            context = "rcpt", 
-                                            event = "error",
-                                            address = &rcpt.address_lcase,
-                                            "Mailbox does not exist.");
+                            if self.is_log_allowed("rcpt-unknown") {
+                                tracing::debug!(parent: &self.span,
+                                                context = "rcpt",
+                                                event = "error",
+                                                address = &rcpt.address_lcase,
+                                                "Mailbox does not exist.");
+                            }
                             return self
                                 .rcpt_error(b"550 5.1.2 Mailbox does not exist.\r\n")
                                 .await;
+                        } else if let Some(action) = self.params.rcpt_catch_all {
+                            if self.is_catch_all(&rcpt.domain, address_lookup).await {
+                                tracing::debug!(parent: &self.span,
+                                    context = "rcpt",
+                                    event = "catch-all",
+                                    address = &rcpt.address_lcase,
+                                    action = ?action,
+                                    "Recipient domain accepts all addresses.");
+                                if action == CatchAllAction::NoBounce {
+                                    rcpt.flags = (rcpt.flags
+                                        & !(RCPT_NOTIFY_DELAY
+                                            | RCPT_NOTIFY_SUCCESS
+                                            | RCPT_NOTIFY_FAILURE))
+                                        | RCPT_NOTIFY_NEVER;
+                                }
+                            }
                         }
+                    } else if self.can_defer_verification(&rcpt).await {
+                        tracing::info!(parent: &self.span,
+                            context = "rcpt",
+                            event = "verify-deferred",
+                            address = &rcpt.address_lcase,
+                            "Temporary address verification failure, deferring verification until delivery.");
+                        rcpt.flags |= RCPT_VERIFY_PENDING;
                     } else {
                         tracing::debug!(parent: &self.span,
                             context = "rcpt", 
@@ -101,13 +209,22 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                             .await;
                     }
                 } else if !self.params.rcpt_relay {
-                    tracing::debug!(parent: &self.span,
-                        context = "rcpt", 
-                        event = "error",
-                        address = &rcpt.address_lcase,
-                        "Relay not allowed.");
+                    if self.is_log_allowed("rcpt-relay") {
+                        tracing::debug!(parent: &self.span,
+                            context = "rcpt",
+                            event = "error",
+                            address = &rcpt.address_lcase,
+                            "Relay not allowed.");
+                    }
                     return self.rcpt_error(b"550 5.1.2 Relay not allowed.\r\n").await;
                 }
+            } else if self.can_defer_verification(&rcpt).await {
+                tracing::info!(parent: &self.span,
+                    context = "rcpt",
+                    event = "verify-deferred",
+                    address = &rcpt.address_lcase,
+                    "Temporary domain verification failure, deferring verification until delivery.");
+                rcpt.flags |= RCPT_VERIFY_PENDING;
             } else {
                 tracing::debug!(parent: &self.span,
                     context = "rcpt", 
@@ -120,14 +237,65 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                     .await;
             }
         } else if !self.params.rcpt_relay {
-            tracing::debug!(parent: &self.span,
-                context = "rcpt", 
-                event = "error",
-                address = &rcpt.address_lcase,
-                "Relay not allowed.");
+            if self.is_log_allowed("rcpt-relay") {
+                tracing::debug!(parent: &self.span,
+                    context = "rcpt",
+                    event = "error",
+                    address = &rcpt.address_lcase,
+                    "Relay not allowed.");
+            }
             return self.rcpt_error(b"550 5.1.2 Relay not allowed.\r\n").await;
         }
 
+        // Require Recipient Valid Since
+        if to.rrvs != 0 && !is_list {
+            if let Some(lookup) = &self.params.rcpt_lookup_created {
+                let created = match lookup.fetch_one(&rcpt.address_lcase).await {
+                    Some(created) => created.and_then(|created| {
+                        created.parse::<i64>().ok().or_else(|| {
+                            DateTime::parse_rfc3339(&created).map(|dt| dt.to_timestamp())
+                        })
+                    }),
+                    None => {
+                        tracing::debug!(parent: &self.span,
+                            context = "rcpt",
+                            event = "error",
+                            address = &rcpt.address_lcase,
+                            "Temporary mailbox creation date lookup failure.");
+                        return self
+                            .write(b"451 4.4.3 Unable to verify address at this time.\r\n")
+                            .await;
+                    }
+                };
+
+                match created {
+                    Some(created) if created > to.rrvs => {
+                        tracing::debug!(parent: &self.span,
+                            context = "rcpt",
+                            event = "rrvs",
+                            address = &rcpt.address_lcase,
+                            created = created,
+                            rrvs = to.rrvs,
+                            "Mailbox owner has changed.");
+                        return self
+                            .rcpt_error(b"550 5.7.17 Mailbox owner has changed.\r\n")
+                            .await;
+                    }
+                    None if (to.flags & RCPT_RRVS_CONTINUE) == 0 => {
+                        tracing::debug!(parent: &self.span,
+                            context = "rcpt",
+                            event = "rrvs",
+                            address = &rcpt.address_lcase,
+                            "Mailbox creation date is not available.");
+                        return self
+                            .rcpt_error(b"550 5.7.19 RRVS test cannot be completed.\r\n")
+                            .await;
+                    }
+                    _ => (),
+                }
+            }
+        }
+
         if !self.data.rcpt_to.contains(&rcpt) {
             self.data.rcpt_to.push(rcpt);
 
@@ -149,6 +317,11 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
                     context = "rcpt",
                     event = "success",
                     address = &self.data.rcpt_to.last().unwrap().address);
+                if is_list {
+                    self.data
+                        .rcpt_lists
+                        .push(self.data.rcpt_to.last().unwrap().address_lcase.clone());
+                }
             } else {
                 self.data.rcpt_to.pop();
                 return self
@@ -160,6 +333,58 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
         self.write(b"250 2.1.5 OK\r\n").await
     }
 
+    // Recipients are only accepted unverified when they will be verified before delivery
+    async fn can_defer_verification(&self, rcpt: &SessionAddress) -> bool {
+        self.params.rcpt_lookup_fail_action == LookupFailAction::Accept
+            && self
+                .core
+                .queue
+                .config
+                .verify_addresses
+                .eval(&RcptEnvelope {
+                    session: self,
+                    rcpt,
+                })
+                .await
+                .is_some()
+    }
+
+    async fn is_catch_all(&self, domain: &str, address_lookup: &Lookup) -> bool {
+        if let Some(entry) = self.core.session.catch_all.get(domain) {
+            if entry.expires > Instant::now() {
+                return entry.is_catch_all;
+            }
+        }
+
+        // Probe the domain with an address that should not exist
+        let probe = format!(
+            "{}@{}",
+            rand::thread_rng()
+                .sample_iter(Alphanumeric)
+                .take(24)
+                .map(|ch| char::from(ch.to_ascii_lowercase()))
+                .collect::<String>(),
+            domain
+        );
+        if let Some(is_catch_all) = address_lookup.contains(&probe).await {
+            tracing::debug!(parent: &self.span,
+                context = "rcpt",
+                event = "catch-all-probe",
+                domain = domain,
+                result = is_catch_all);
+            self.core.session.catch_all.insert(
+                domain.to_string(),
+                CatchAllDomain {
+                    is_catch_all,
+                    expires: Instant::now() + self.core.session.config.rcpt.catch_all_ttl,
+                },
+            );
+            is_catch_all
+        } else {
+            false
+        }
+    }
+
     async fn rcpt_error(&mut self, response: &[u8]) -> Result<(), ()> {
         tokio::time::sleep(self.params.rcpt_errors_wait).await;
         self.data.rcpt_errors += 1;
@@ -180,3 +405,62 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
         }
     }
 }
+
+struct RcptEnvelope<'x, T: AsyncRead + AsyncWrite> {
+    session: &'x Session<T>,
+    rcpt: &'x SessionAddress,
+}
+
+impl<'x, T: AsyncRead + AsyncWrite> Envelope for RcptEnvelope<'x, T> {
+    fn local_ip(&self) -> IpAddr {
+        self.session.local_ip()
+    }
+
+    fn remote_ip(&self) -> IpAddr {
+        self.session.remote_ip()
+    }
+
+    fn sender_domain(&self) -> &str {
+        self.session.sender_domain()
+    }
+
+    fn sender(&self) -> &str {
+        self.session.sender()
+    }
+
+    fn rcpt_domain(&self) -> &str {
+        self.rcpt.domain.as_str()
+    }
+
+    fn rcpt(&self) -> &str {
+        self.rcpt.address_lcase.as_str()
+    }
+
+    fn helo_domain(&self) -> &str {
+        self.session.helo_domain()
+    }
+
+    fn authenticated_as(&self) -> &str {
+        self.session.authenticated_as()
+    }
+
+    fn mx(&self) -> &str {
+        ""
+    }
+
+    fn listener_id(&self) -> u16 {
+        self.session.listener_id()
+    }
+
+    fn priority(&self) -> i16 {
+        self.session.priority()
+    }
+
+    fn country(&self) -> &str {
+        self.session.country()
+    }
+
+    fn asn(&self) -> u32 {
+        self.session.asn()
+    }
+}
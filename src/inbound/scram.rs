@@ -0,0 +1,402 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::num::NonZeroU32;
+
+use mail_builder::encoders::base64::base64_encode;
+use mail_parser::decoders::base64::base64_decode;
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use ring::{constant_time::verify_slices_are_equal, pbkdf2};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    core::Session,
+    lookup::{hash::hmac_sha256, Item, LookupResult},
+};
+
+use super::auth::SaslToken;
+
+pub const SCRAM_ITERATIONS: u32 = 4096;
+const SCRAM_PREFIX: &str = "SCRAM-SHA-256$";
+
+#[derive(Debug, Default)]
+pub enum ScramState {
+    #[default]
+    ClientFirst,
+    ServerFirst(Box<ScramExchange>),
+    ServerFinal(String),
+}
+
+#[derive(Debug)]
+pub struct ScramExchange {
+    username: String,
+    gs2_header: String,
+    nonce: String,
+    auth_message: String,
+    stored_key: [u8; 32],
+    server_key: [u8; 32],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScramSecret {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: [u8; 32],
+    pub server_key: [u8; 32],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ClientFirst {
+    pub gs2_header: String,
+    pub username: String,
+    pub nonce: String,
+    pub message_bare: String,
+}
+
+impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
+    pub async fn handle_scram(
+        &mut self,
+        token: &mut SaslToken,
+        response: &[u8],
+    ) -> Result<bool, ()> {
+        let response = if !response.is_empty() {
+            match base64_decode(response).and_then(|r| String::from_utf8(r).ok()) {
+                Some(response) => response,
+                None => return self.auth_error(b"500 5.5.6 Invalid challenge.\r\n").await,
+            }
+        } else {
+            String::new()
+        };
+
+        match (std::mem::take(&mut token.scram), response.is_empty()) {
+            (ScramState::ClientFirst, true) => {
+                self.write(b"334 \r\n").await?;
+                Ok(true)
+            }
+            (ScramState::ClientFirst, false) => {
+                let client_first = match ClientFirst::parse(&response) {
+                    Some(client_first) => client_first,
+                    None => {
+                        return self
+                            .auth_error(b"504 5.5.4 Unsupported SCRAM parameters.\r\n")
+                            .await
+                    }
+                };
+
+                // Obtain the salted keys for this account
+                let salt = fake_salt(
+                    &self.core.session.config.auth.scram_secret,
+                    &client_first.username,
+                );
+                let secret = if let Some(lookup) = &self.params.auth_lookup {
+                    match lookup
+                        .lookup(Item::ScramSecret(client_first.username.clone()))
+                        .await
+                    {
+                        Some(LookupResult::Values(values)) => values
+                            .first()
+                            .and_then(|secret| ScramSecret::parse(secret, salt.clone())),
+                        Some(_) => None,
+                        None => {
+                            self.write(b"454 4.7.0 Temporary authentication failure\r\n")
+                                .await?;
+                            return Ok(false);
+                        }
+                    }
+                } else {
+                    tracing::warn!(
+                        parent: &self.span,
+                        context = "auth",
+                        event = "error",
+                        "No lookup list configured for authentication."
+                    );
+                    self.write(b"454 4.7.0 Temporary authentication failure\r\n")
+                        .await?;
+                    return Ok(false);
+                };
+
+                // Unknown accounts are challenged with random keys so they fail at the last step,
+                // their salt is derived from the username so it does not change between attempts
+                let secret = secret.unwrap_or_else(|| ScramSecret::random(salt));
+                let nonce = format!(
+                    "{}{}",
+                    client_first.nonce,
+                    rand::thread_rng()
+                        .sample_iter(Alphanumeric)
+                        .take(24)
+                        .map(char::from)
+                        .collect::<String>()
+                );
+                let server_first = format!(
+                    "r={},s={},i={}",
+                    nonce,
+                    encode(&secret.salt),
+                    secret.iterations
+                );
+                self.write(format!("334 {}\r\n", encode(server_first.as_bytes())).as_bytes())
+                    .await?;
+                token.scram = ScramState::ServerFirst(Box::new(ScramExchange {
+                    username: client_first.username,
+                    gs2_header: client_first.gs2_header,
+                    nonce,
+                    auth_message: format!("{},{}", client_first.message_bare, server_first),
+                    stored_key: secret.stored_key,
+                    server_key: secret.server_key,
+                }));
+                Ok(true)
+            }
+            (ScramState::ServerFirst(exchange), false) => {
+                if let Some(server_signature) = exchange.verify(&response) {
+                    tracing::debug!(
+                        parent: &self.span,
+                        context = "auth",
+                        event = "authenticate",
+                        result = "success"
+                    );
+                    self.write(
+                        format!(
+                            "334 {}\r\n",
+                            encode(format!("v={}", encode(&server_signature)).as_bytes())
+                        )
+                        .as_bytes(),
+                    )
+                    .await?;
+                    token.scram = ScramState::ServerFinal(exchange.username);
+                    Ok(true)
+                } else {
+                    tracing::debug!(
+                        parent: &self.span,
+                        context = "auth",
+                        event = "authenticate",
+                        result = "failed"
+                    );
+                    self.auth_error(b"535 5.7.8 Authentication credentials invalid.\r\n")
+                        .await
+                }
+            }
+            (ScramState::ServerFinal(username), true) => self.auth_success(username, None).await,
+            _ => self.auth_error(b"500 5.5.6 Invalid challenge.\r\n").await,
+        }
+    }
+}
+
+impl ClientFirst {
+    pub fn parse(message: &str) -> Option<ClientFirst> {
+        // Channel binding is not supported
+        let (cbind_flag, rest) = message.split_once(',')?;
+        if !matches!(cbind_flag, "n" | "y") {
+            return None;
+        }
+        let (authzid, message_bare) = rest.split_once(',')?;
+        let gs2_header = message[..message.len() - message_bare.len()].to_string();
+
+        let mut username = None;
+        let mut nonce = None;
+        for (pos, attribute) in message_bare.split(',').enumerate() {
+            match (pos, attribute.split_once('=')?) {
+                (0, ("n", value)) => {
+                    username = unescape(value);
+                }
+                (1, ("r", value)) if !value.is_empty() => {
+                    nonce = value.to_string().into();
+                }
+                (0 | 1, _) => return None,
+                _ => (),
+            }
+        }
+        let username = username.filter(|username| !username.is_empty())?;
+
+        // Authorizing as a different identity is not allowed
+        if !authzid.is_empty() && authzid.strip_prefix("a=").and_then(unescape)? != username {
+            return None;
+        }
+
+        Some(ClientFirst {
+            gs2_header,
+            username,
+            nonce: nonce?,
+            message_bare: message_bare.to_string(),
+        })
+    }
+}
+
+impl ScramExchange {
+    pub fn verify(&self, client_final: &str) -> Option<[u8; 32]> {
+        let (without_proof, proof) = client_final.rsplit_once(",p=")?;
+        let mut channel_binding = None;
+        let mut nonce = None;
+        for attribute in without_proof.split(',') {
+            match attribute.split_once('=')? {
+                ("c", value) => channel_binding = value.into(),
+                ("r", value) => nonce = value.into(),
+                _ => (),
+            }
+        }
+        if channel_binding? != encode(self.gs2_header.as_bytes()) || nonce? != self.nonce {
+            return None;
+        }
+
+        let auth_message = format!("{},{}", self.auth_message, without_proof);
+        let client_signature = hmac_sha256(&self.stored_key, auth_message.as_bytes());
+        let mut client_key: [u8; 32] = base64_decode(proof.as_bytes())?.try_into().ok()?;
+        client_key
+            .iter_mut()
+            .zip(client_signature.iter())
+            .for_each(|(k, s)| *k ^= s);
+        verify_slices_are_equal(&Sha256::digest(client_key), &self.stored_key).ok()?;
+        Some(hmac_sha256(&self.server_key, auth_message.as_bytes()))
+    }
+}
+
+impl ScramSecret {
+    // Secrets are either in RFC 5803 format or stored in plain text
+    pub fn parse(secret: &str, salt: Vec<u8>) -> Option<ScramSecret> {
+        if let Some(secret) = secret.strip_prefix(SCRAM_PREFIX) {
+            let (params, keys) = secret.split_once('$')?;
+            let (iterations, salt) = params.split_once(':')?;
+            let (stored_key, server_key) = keys.split_once(':')?;
+            Some(ScramSecret {
+                salt: base64_decode(salt.as_bytes())?,
+                iterations: iterations.parse().ok().filter(|i| *i > 0)?,
+                stored_key: base64_decode(stored_key.as_bytes())?.try_into().ok()?,
+                server_key: base64_decode(server_key.as_bytes())?.try_into().ok()?,
+            })
+        } else {
+            Some(ScramSecret::derive(
+                secret.as_bytes(),
+                salt,
+                SCRAM_ITERATIONS,
+            ))
+        }
+    }
+
+    pub fn derive(password: &[u8], salt: Vec<u8>, iterations: u32) -> ScramSecret {
+        let salted_password = salted_password(password, &salt, iterations);
+        ScramSecret {
+            stored_key: Sha256::digest(hmac_sha256(&salted_password, b"Client Key")).into(),
+            server_key: hmac_sha256(&salted_password, b"Server Key"),
+            salt,
+            iterations,
+        }
+    }
+
+    fn random(salt: Vec<u8>) -> ScramSecret {
+        let mut rng = rand::thread_rng();
+        let mut secret = ScramSecret {
+            salt,
+            iterations: SCRAM_ITERATIONS,
+            stored_key: [0u8; 32],
+            server_key: [0u8; 32],
+        };
+        rng.fill_bytes(&mut secret.stored_key);
+        rng.fill_bytes(&mut secret.server_key);
+        secret
+    }
+}
+
+pub fn salted_password(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut result = [0u8; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(iterations).unwrap_or(NonZeroU32::MIN),
+        salt,
+        password,
+        &mut result,
+    );
+    result
+}
+
+// Salt for accounts without one, stable across attempts so that unknown users
+// cannot be told apart from existing ones (RFC 5802, section 9)
+fn fake_salt(server_secret: &[u8], username: &str) -> Vec<u8> {
+    hmac_sha256(server_secret, username.as_bytes())[..16].to_vec()
+}
+
+fn unescape(value: &str) -> Option<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '=' => match (chars.next(), chars.next()) {
+                (Some('2'), Some('C')) => result.push(','),
+                (Some('3'), Some('D')) => result.push('='),
+                _ => return None,
+            },
+            ',' => return None,
+            _ => result.push(ch),
+        }
+    }
+    Some(result)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    String::from_utf8(base64_encode(bytes).unwrap_or_default()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientFirst, ScramSecret};
+
+    #[test]
+    fn scram_rfc7677() {
+        // Test vector from RFC 7677, section 3
+        let client_first = ClientFirst::parse("n,,n=user,r=rOprNGfwEbeRWgbNEkqO").unwrap();
+        assert_eq!(client_first.gs2_header, "n,,");
+        assert_eq!(client_first.username, "user");
+        assert_eq!(client_first.message_bare, "n=user,r=rOprNGfwEbeRWgbNEkqO");
+
+        let secret = ScramSecret::parse(
+            "SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==$WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU=",
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(
+            ScramSecret::derive(b"pencil", secret.salt.clone(), 4096),
+            secret
+        );
+
+        let exchange = super::ScramExchange {
+            username: client_first.username,
+            gs2_header: client_first.gs2_header,
+            nonce: "rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_string(),
+            auth_message: format!(
+                "{},r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                client_first.message_bare
+            ),
+            stored_key: secret.stored_key,
+            server_key: secret.server_key,
+        };
+        assert_eq!(
+            super::encode(
+                &exchange
+                    .verify("c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=")
+                    .unwrap()
+            ),
+            "6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+        assert!(exchange
+            .verify("c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=AHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=")
+            .is_none());
+    }
+}
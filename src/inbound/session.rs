@@ -44,7 +44,23 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
         'outer: loop {
             match &mut state {
                 State::Request(receiver) => loop {
-                    match receiver.ingest(&mut iter, bytes) {
+                    let result = receiver.ingest(&mut iter, bytes);
+                    if result.is_ok() {
+                        self.data.commands += 1;
+                        if self.params.command_limit > 0
+                            && self.data.commands > self.params.command_limit
+                        {
+                            tracing::info!(parent: &self.span,
+                                context = "session",
+                                event = "disconnect",
+                                reason = "command-limit",
+                                "Too many commands issued during this session.");
+                            self.write(b"421 4.3.2 Too many commands, closing connection.\r\n")
+                                .await?;
+                            return Err(());
+                        }
+                    }
+                    match result {
                         Ok(request) => match request {
                             Request::Rcpt { to } => {
                                 self.handle_rcpt_to(to).await?;
@@ -60,7 +76,25 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                                 }
                             }
                             Request::Data => {
-                                if self.can_send_data().await? {
+                                if *self
+                                    .core
+                                    .session
+                                    .config
+                                    .extensions
+                                    .require_chunking
+                                    .eval(self)
+                                    .await
+                                {
+                                    self.write(b"503 5.5.1 Use BDAT.\r\n").await?;
+                                } else if self
+                                    .data
+                                    .mail_from
+                                    .as_ref()
+                                    .map_or(false, |from| (from.flags & MAIL_BODY_BINARYMIME) != 0)
+                                {
+                                    self.write(b"503 5.5.1 BINARYMIME requires BDAT.\r\n")
+                                        .await?;
+                                } else if self.can_send_data().await? {
                                     self.write(b"354 Start mail input; end with <CRLF>.<CRLF>\r\n")
                                         .await?;
                                     self.data.message = Vec::with_capacity(1024);
@@ -72,7 +106,18 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                                 chunk_size,
                                 is_last,
                             } => {
-                                state = if chunk_size + self.data.message.len()
+                                state = if !*self
+                                    .core
+                                    .session
+                                    .config
+                                    .extensions
+                                    .chunking
+                                    .eval(self)
+                                    .await
+                                {
+                                    // Chunking is disabled, discard chunk.
+                                    State::BdatRejected(DummyDataReceiver::new_bdat(chunk_size))
+                                } else if chunk_size + self.data.message.len()
                                     < self.params.max_message_size
                                 {
                                     if self.data.message.is_empty() {
@@ -82,8 +127,19 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                                     }
                                     State::Bdat(BdatReceiver::new(chunk_size, is_last))
                                 } else {
-                                    // Chunk is too large, ignore.
-                                    State::DataTooLarge(DummyDataReceiver::new_bdat(chunk_size))
+                                    // Chunk is too large, reply now and discard it.
+                                    tracing::debug!(
+                                        parent: &self.span,
+                                        context = "data",
+                                        event = "too-large",
+                                        chunk_size = chunk_size,
+                                        "Message is too large."
+                                    );
+
+                                    self.data.message = Vec::with_capacity(0);
+                                    self.write(b"552 5.3.4 Message too big for system.\r\n")
+                                        .await?;
+                                    State::BdatTooLarge(DummyDataReceiver::new_bdat(chunk_size))
                                 };
                                 continue 'outer;
                             }
@@ -133,6 +189,16 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                             Request::StartTls => {
                                 if !self.stream.is_tls() {
                                     self.write(b"220 2.0.0 Ready to start TLS.\r\n").await?;
+
+                                    // Plaintext pipelined after STARTTLS must not be processed
+                                    if !iter.as_slice().is_empty() {
+                                        tracing::info!(parent: &self.span,
+                                            context = "tls",
+                                            event = "starttls-injection",
+                                            bytes = iter.len(),
+                                            "Client sent data after STARTTLS, closing connection.");
+                                        return Err(());
+                                    }
                                     self.state = State::default();
                                     return Ok(false);
                                 } else {
@@ -172,7 +238,13 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                                     self.write(b"502 5.5.1 Invalid command.\r\n").await?;
                                 }
                             }
-                            Request::Etrn { .. } | Request::Atrn { .. } | Request::Burl { .. } => {
+                            Request::Burl { uri, is_last } => {
+                                self.handle_burl(uri, is_last).await?;
+                            }
+                            Request::Atrn { domains } => {
+                                self.handle_atrn(domains).await?;
+                            }
+                            Request::Etrn { .. } => {
                                 self.write(b"502 5.5.1 Command not implemented.\r\n")
                                     .await?;
                             }
@@ -180,6 +252,12 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                         Err(err) => match err {
                             Error::NeedsMoreData { .. } => break 'outer,
                             Error::UnknownCommand | Error::InvalidResponse { .. } => {
+                                if self.is_log_allowed("invalid-command") {
+                                    tracing::debug!(parent: &self.span,
+                                        context = "session",
+                                        event = "invalid-command",
+                                        "Invalid command received.");
+                                }
                                 self.write(b"500 5.5.1 Invalid command.\r\n").await?;
                             }
                             Error::InvalidSenderAddress => {
@@ -304,6 +382,23 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
                         break 'outer;
                     }
                 }
+                State::BdatTooLarge(receiver) => {
+                    if receiver.ingest(&mut iter) {
+                        state = State::default();
+                    } else {
+                        break 'outer;
+                    }
+                }
+                State::BdatRejected(receiver) => {
+                    if receiver.ingest(&mut iter) {
+                        self.data.message = Vec::with_capacity(0);
+                        self.write(b"503 5.5.1 CHUNKING is not enabled.\r\n")
+                            .await?;
+                        state = State::default();
+                    } else {
+                        break 'outer;
+                    }
+                }
                 State::RequestTooLarge(receiver) => {
                     if receiver.ingest(&mut iter) {
                         self.write(b"554 5.3.4 Line is too long.\r\n").await?;
@@ -324,8 +419,10 @@ impl<T: AsyncWrite + AsyncRead + IsTls + Unpin> Session<T> {
 impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
     pub fn reset(&mut self) {
         self.data.mail_from = None;
+        self.data.mail_from_at = None;
         self.data.spf_mail_from = None;
         self.data.rcpt_to.clear();
+        self.data.rcpt_lists.clear();
         self.data.message = Vec::with_capacity(0);
         self.data.priority = 0;
         self.data.delivery_by = 0;
@@ -334,6 +431,8 @@ impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
 
     #[inline(always)]
     pub async fn write(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        let folded = fold_response(bytes);
+        let bytes = folded.as_deref().unwrap_or(bytes);
         let err = match self.stream.write_all(bytes).await {
             Ok(_) => match self.stream.flush().await {
                 Ok(_) => {
@@ -450,4 +549,92 @@ impl<T: AsyncRead + AsyncWrite> Envelope for Session<T> {
     fn priority(&self) -> i16 {
         self.data.priority
     }
+
+    fn country(&self) -> &str {
+        self.data
+            .geoip
+            .as_ref()
+            .map_or("", |geoip| geoip.country.as_str())
+    }
+
+    fn asn(&self) -> u32 {
+        self.data.geoip.as_ref().map_or(0, |geoip| geoip.asn)
+    }
+}
+
+// RFC 5321 limits reply lines to 512 octets including the trailing CRLF
+const MAX_REPLY_LINE: usize = 510;
+
+pub fn fold_response(bytes: &[u8]) -> Option<Vec<u8>> {
+    if !bytes
+        .split(|&ch| ch == b'\n')
+        .any(|line| line.strip_suffix(b"\r").unwrap_or(line).len() > MAX_REPLY_LINE)
+    {
+        return None;
+    }
+
+    let mut folded = Vec::with_capacity(bytes.len() + 64);
+    for line in bytes.split_inclusive(|&ch| ch == b'\n') {
+        let text = line
+            .strip_suffix(b"\n")
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .unwrap_or(line);
+        if text.len() <= MAX_REPLY_LINE
+            || !text[..3].iter().all(|ch| ch.is_ascii_digit())
+            || !matches!(text[3], b' ' | b'-')
+        {
+            folded.extend_from_slice(line);
+            continue;
+        }
+
+        // Repeat the reply code and enhanced status code on every continuation line
+        let (code, separator) = (&text[..3], text[3]);
+        let esc_len = text[4..]
+            .iter()
+            .position(|&ch| ch == b' ')
+            .filter(|&pos| {
+                let esc = &text[4..4 + pos];
+                esc.split(|&ch| ch == b'.').count() == 3
+                    && esc
+                        .split(|&ch| ch == b'.')
+                        .all(|part| !part.is_empty() && part.iter().all(|ch| ch.is_ascii_digit()))
+            })
+            .map_or(0, |pos| pos + 1);
+        let esc = &text[4..4 + esc_len];
+        let width = MAX_REPLY_LINE - 4 - esc_len;
+
+        let mut remaining = &text[4 + esc_len..];
+        loop {
+            let (chunk, rest, is_last) = if remaining.len() <= width {
+                (remaining, &b""[..], true)
+            } else if let Some(pos) = remaining[..=width]
+                .iter()
+                .rposition(|&ch| ch == b' ')
+                .filter(|&pos| pos > 0)
+            {
+                (&remaining[..pos], &remaining[pos + 1..], false)
+            } else {
+                // Avoid splitting multi-byte UTF-8 sequences
+                let mut pos = width;
+                while pos > 0 && (remaining[pos] & 0xC0) == 0x80 {
+                    pos -= 1;
+                }
+                if pos == 0 {
+                    pos = width;
+                }
+                (&remaining[..pos], &remaining[pos..], false)
+            };
+            folded.extend_from_slice(code);
+            folded.push(if is_last { separator } else { b'-' });
+            folded.extend_from_slice(esc);
+            folded.extend_from_slice(chunk);
+            folded.extend_from_slice(b"\r\n");
+            if is_last {
+                break;
+            }
+            remaining = rest;
+        }
+    }
+
+    Some(folded)
 }
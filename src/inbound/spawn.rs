@@ -21,7 +21,10 @@
  * for more details.
 */
 
-use std::{sync::Arc, time::Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -33,7 +36,8 @@ use tokio_rustls::{server::TlsStream, TlsAcceptor};
 use crate::{
     config::{Server, ServerProtocol},
     core::{
-        scripts::ScriptResult, Core, ServerInstance, Session, SessionData, SessionParameters, State,
+        scripts::ScriptResult, throttle::ConnectionLimiter, AbandonedSessions, Core,
+        ServerInstance, Session, SessionData, SessionEnd, SessionParameters, State,
     },
 };
 
@@ -44,6 +48,7 @@ impl Server {
         // Build TLS acceptor
         let tls_acceptor = self.tls.map(|config| TlsAcceptor::from(Arc::new(config)));
         let tls_implicit = self.tls_implicit;
+        let tls_detect = self.tls_detect;
 
         // Prepare instance
         let instance = Arc::new(ServerInstance {
@@ -52,6 +57,9 @@ impl Server {
             listener_id: self.internal_id,
             is_smtp: self.protocol == ServerProtocol::Smtp,
             hostname: self.hostname,
+            connections: self
+                .max_connections
+                .map(|max| Arc::new(ConnectionLimiter::new(max, self.overflow))),
         });
 
         // Spawn listeners
@@ -110,6 +118,24 @@ impl Server {
                                         continue;
                                     }
 
+                                    // Enforce the listener's connection limit
+                                    let connection = if let Some(connections) = &instance.connections {
+                                        if let Some(connection) = connections.admit() {
+                                            Some(connection)
+                                        } else {
+                                            tracing::info!(
+                                                parent: &span,
+                                                context = "throttle",
+                                                event = "too-many-connections",
+                                                max_connections = connections.max_connections,
+                                                "Too many connections to listener."
+                                            );
+                                            continue;
+                                        }
+                                    } else {
+                                        None
+                                    };
+
                                     // Create session
                                     let mut session = Session {
                                         core: core.clone(),
@@ -118,10 +144,17 @@ impl Server {
                                         span,
                                         stream,
                                         in_flight,
+                                        connection,
                                         data: SessionData::new(local_ip, remote_addr.ip()),
                                         params: SessionParameters::default(),
                                     };
 
+                                    // Lookup origin country and ASN
+                                    if let Some(geoip) = &core.session.config.geoip {
+                                        session.data.geoip =
+                                            geoip.lookup(&session.data.remote_ip);
+                                    }
+
                                     // Enforce throttle
                                     if !session.is_allowed().await {
                                         continue;
@@ -133,6 +166,10 @@ impl Server {
                                     let instance = instance.clone();
 
                                     tokio::spawn(async move {
+                                        let tls_implicit = match tls_detect {
+                                            Some(timeout) => session.is_tls_handshake(timeout).await,
+                                            None => tls_implicit,
+                                        };
                                         if tls_implicit {
                                             if let Ok(mut session) = session.into_tls(tls_acceptor.unwrap()).await {
                                                 if session.init_conn(&instance.greeting).await {
@@ -173,19 +210,44 @@ impl Session<TcpStream> {
         acceptor: TlsAcceptor,
     ) -> Result<Session<TlsStream<TcpStream>>, ()> {
         let span = self.span;
-        Ok(Session {
-            stream: match acceptor.accept(self.stream).await {
-                Ok(stream) => {
+
+        // Limit concurrent TLS handshakes
+        let _handshake = match self.core.tls_handshakes.acquire().await {
+            Some(permit) => permit,
+            None => {
+                tracing::info!(
+                    parent: &span,
+                    context = "throttle",
+                    event = "too-many-handshakes",
+                    max_concurrent = self.core.tls_handshakes.max_concurrent,
+                    "Too many concurrent TLS handshakes."
+                );
+                return Err(());
+            }
+        };
+
+        let stream = match acceptor.accept(self.stream).await {
+            Ok(stream) => {
+                tracing::info!(
+                    parent: &span,
+                    context = "tls",
+                    event = "handshake",
+                    version = ?stream.get_ref().1.protocol_version().unwrap_or(rustls::ProtocolVersion::TLSv1_3),
+                    cipher = ?stream.get_ref().1.negotiated_cipher_suite().unwrap_or(rustls::cipher_suite::TLS13_AES_128_GCM_SHA256),
+                    client_cert = stream.get_ref().1.peer_certificates().is_some(),
+                );
+                stream
+            }
+            Err(err) => {
+                if is_version_rejected(&err) {
                     tracing::info!(
                         parent: &span,
                         context = "tls",
-                        event = "handshake",
-                        version = ?stream.get_ref().1.protocol_version().unwrap_or(rustls::ProtocolVersion::TLSv1_3),
-                        cipher = ?stream.get_ref().1.negotiated_cipher_suite().unwrap_or(rustls::cipher_suite::TLS13_AES_128_GCM_SHA256),
+                        event = "version-rejected",
+                        "Rejected TLS connection below the minimum protocol version: {}",
+                        err
                     );
-                    stream
-                }
-                Err(err) => {
+                } else {
                     tracing::debug!(
                         parent: &span,
                         context = "tls",
@@ -193,25 +255,56 @@ impl Session<TcpStream> {
                         "Failed to accept TLS connection: {}",
                         err
                     );
-                    return Err(());
                 }
-            },
+                return Err(());
+            }
+        };
+
+        // Keep the client certificate for SASL EXTERNAL
+        let mut data = self.data;
+        data.peer_certificate = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .cloned();
+
+        Ok(Session {
+            stream,
             state: self.state,
-            data: self.data,
+            data,
             instance: self.instance,
             core: self.core,
             in_flight: self.in_flight,
+            connection: self.connection,
             params: self.params,
             span,
         })
     }
 
+    pub async fn is_tls_handshake(&self, timeout: Duration) -> bool {
+        // Implicit TLS clients send a ClientHello right away while
+        // SMTP clients wait for the greeting.
+        let mut buf = [0u8; 1];
+        let is_tls = matches!(
+            tokio::time::timeout(timeout, self.stream.peek(&mut buf)).await,
+            Ok(Ok(1)) if buf[0] == 0x16
+        );
+        tracing::debug!(
+            parent: &self.span,
+            context = "tls",
+            event = "detect",
+            implicit = is_tls,
+        );
+        is_tls
+    }
+
     pub async fn handle_conn(
         mut self,
         tls_acceptor: Option<TlsAcceptor>,
         shutdown_rx: watch::Receiver<bool>,
     ) {
-        if let Some(shutdown_rx) = self.handle_conn_(shutdown_rx).await {
+        if let SessionEnd::StartTls(shutdown_rx) = self.handle_conn_(shutdown_rx).await {
             if let Some(tls_acceptor) = tls_acceptor {
                 if let Ok(session) = self.into_tls(tls_acceptor).await {
                     session.handle_conn(shutdown_rx).await;
@@ -232,6 +325,14 @@ impl<T: AsyncRead + AsyncWrite + IsTls + Unpin> Session<T> {
         self.eval_session_params().await;
         self.verify_ip_dnsbl().await;
 
+        // Penalize clients that repeatedly abandon their sessions
+        if !self.is_abandon_allowed().await {
+            let _ = self
+                .write(b"421 4.7.0 Too many abandoned sessions, try again later.\r\n")
+                .await;
+            return false;
+        }
+
         // Sieve filtering
         if let Some(script) = self.core.session.config.connect.script.eval(self).await {
             if let ScriptResult::Reject(message) = self.run_script(script.clone(), None).await {
@@ -252,13 +353,18 @@ impl<T: AsyncRead + AsyncWrite + IsTls + Unpin> Session<T> {
         true
     }
 
-    pub async fn handle_conn_(
-        &mut self,
-        mut shutdown_rx: watch::Receiver<bool>,
-    ) -> Option<watch::Receiver<bool>> {
+    pub async fn handle_conn_(&mut self, mut shutdown_rx: watch::Receiver<bool>) -> SessionEnd {
         let mut buf = vec![0; 8192];
+        let evict = self.connection.as_ref().map(|c| c.evict.clone());
+
+        let end = loop {
+            // Sessions waiting between transactions may be evicted to admit new clients
+            if let Some(connection) = &self.connection {
+                connection.set_idle(
+                    self.data.mail_from.is_none() && matches!(self.state, State::Request(_)),
+                );
+            }
 
-        loop {
             tokio::select! {
                 result = tokio::time::timeout(
                     self.params.timeout,
@@ -271,10 +377,10 @@ impl<T: AsyncRead + AsyncWrite + IsTls + Unpin> Session<T> {
                                         match self.ingest(&buf[..bytes_read]).await {
                                             Ok(true) => (),
                                             Ok(false) => {
-                                                return (shutdown_rx).into();
+                                                return SessionEnd::StartTls(shutdown_rx);
                                             }
                                             Err(_) => {
-                                                break;
+                                                break SessionEnd::ClosedByServer;
                                             }
                                         }
                                     } else if bytes_read > self.data.bytes_left {
@@ -288,7 +394,7 @@ impl<T: AsyncRead + AsyncWrite + IsTls + Unpin> Session<T> {
                                             reason = "transfer-limit",
                                             "Client exceeded incoming transfer limit."
                                         );
-                                        break;
+                                        break SessionEnd::ClosedByServer;
                                     } else {
                                         self
                                             .write(format!("453 4.3.2 {} Session open for too long.\r\n", self.instance.hostname).as_bytes())
@@ -300,7 +406,7 @@ impl<T: AsyncRead + AsyncWrite + IsTls + Unpin> Session<T> {
                                             reason = "loiter",
                                             "Session open for too long."
                                         );
-                                        break;
+                                        break SessionEnd::ClosedByServer;
                                     }
                                 } else {
                                     tracing::debug!(
@@ -309,11 +415,11 @@ impl<T: AsyncRead + AsyncWrite + IsTls + Unpin> Session<T> {
                                         reason = "peer",
                                         "Connection closed by peer."
                                     );
-                                    break;
+                                    break SessionEnd::ClosedByPeer;
                                 }
                             }
                             Ok(Err(_)) => {
-                                break;
+                                break SessionEnd::ClosedByPeer;
                             }
                             Err(_) => {
                                 tracing::debug!(
@@ -326,10 +432,28 @@ impl<T: AsyncRead + AsyncWrite + IsTls + Unpin> Session<T> {
                                     .write(format!("221 2.0.0 {} Disconnecting inactive client.\r\n", self.instance.hostname).as_bytes())
                                     .await
                                     .ok();
-                                break;
+                                break SessionEnd::ClosedByServer;
                             }
                         }
                 },
+                _ = async {
+                    match &evict {
+                        Some(evict) => evict.notified().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    tracing::debug!(
+                        parent: &self.span,
+                        event = "disconnect",
+                        reason = "evicted",
+                        "Idle connection evicted to admit a new client."
+                    );
+                    self
+                        .write(format!("421 4.3.2 {} Closing idle connection.\r\n", self.instance.hostname).as_bytes())
+                        .await
+                        .ok();
+                    break SessionEnd::ClosedByServer;
+                },
                 _ = shutdown_rx.changed() => {
                     tracing::debug!(
                         parent: &self.span,
@@ -338,11 +462,89 @@ impl<T: AsyncRead + AsyncWrite + IsTls + Unpin> Session<T> {
                         "Server shutting down."
                     );
                     self.write(b"421 4.3.0 Server shutting down.\r\n").await.ok();
-                    break;
+                    break SessionEnd::ClosedByServer;
                 }
             };
+        };
+
+        // Only sessions dropped by the client count as abandoned
+        if matches!(end, SessionEnd::ClosedByPeer) && self.data.messages_sent == 0 {
+            self.track_abandoned().await;
         }
 
-        None
+        end
     }
+
+    pub async fn is_abandon_allowed(&self) -> bool {
+        let config = &self.core.session.config.connect.abandoned;
+        let threshold = if let Some(threshold) = *config.threshold.eval(self).await {
+            threshold
+        } else {
+            return true;
+        };
+        let count = match self.core.session.abandoned.get(&self.data.remote_ip) {
+            Some(entry) if entry.expires > Instant::now() => entry.count,
+            _ => return true,
+        };
+        if count < threshold {
+            return true;
+        }
+
+        if matches!(*config.block.eval(self).await, Some(block) if count >= block) {
+            tracing::info!(
+                parent: &self.span,
+                context = "throttle",
+                event = "abandoned-block",
+                abandoned = count,
+                "Rejecting client that repeatedly abandoned its sessions."
+            );
+            return false;
+        }
+
+        // Escalate the delay with each additional abandoned session
+        let delay = *config.delay.eval(self).await * (count - threshold + 1) as u32;
+        tracing::debug!(
+            parent: &self.span,
+            context = "throttle",
+            event = "abandoned-delay",
+            abandoned = count,
+            delay = delay.as_millis() as u64,
+            "Delaying client that repeatedly abandoned its sessions."
+        );
+        tokio::time::sleep(delay).await;
+
+        true
+    }
+
+    pub async fn track_abandoned(&self) {
+        let config = &self.core.session.config.connect.abandoned;
+        if config.threshold.eval(self).await.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let expires = now + *config.window.eval(self).await;
+        let mut entry = self
+            .core
+            .session
+            .abandoned
+            .entry(self.data.remote_ip)
+            .or_insert(AbandonedSessions { count: 0, expires });
+        if entry.expires <= now {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.expires = expires;
+    }
+}
+
+pub fn is_version_rejected(err: &std::io::Error) -> bool {
+    matches!(
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::PeerIncompatible(
+            rustls::PeerIncompatible::Tls12NotOffered
+                | rustls::PeerIncompatible::Tls12NotOfferedOrEnabled
+                | rustls::PeerIncompatible::SupportedVersionsExtensionRequired
+        ))
+    )
 }
@@ -34,6 +34,8 @@ pub struct LookupCache<T: Hash + Eq> {
     cache_neg: lru_cache::LruCache<T, Instant, ahash::RandomState>,
     ttl_pos: Duration,
     ttl_neg: Duration,
+    hits: u64,
+    misses: u64,
 }
 
 impl<T: Hash + Eq> LookupCache<T> {
@@ -43,10 +45,26 @@ impl<T: Hash + Eq> LookupCache<T> {
             cache_neg: lru_cache::LruCache::with_hasher(capacity, ahash::RandomState::new()),
             ttl_pos,
             ttl_neg,
+            hits: 0,
+            misses: 0,
         }
     }
 
     pub fn get<Q: ?Sized>(&mut self, name: &Q) -> Option<bool>
+    where
+        T: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let result = self.get_(name);
+        if result.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        result
+    }
+
+    fn get_<Q: ?Sized>(&mut self, name: &Q) -> Option<bool>
     where
         T: Borrow<Q>,
         Q: Hash + Eq,
@@ -65,7 +83,7 @@ impl<T: Hash + Eq> LookupCache<T> {
         if *valid_until >= Instant::now() {
             Some(false)
         } else {
-            self.cache_pos.remove(name);
+            self.cache_neg.remove(name);
             None
         }
     }
@@ -78,6 +96,14 @@ impl<T: Hash + Eq> LookupCache<T> {
         self.cache_neg.insert(item, Instant::now() + self.ttl_neg);
     }
 
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
     pub fn clear(&mut self) {
         self.cache_pos.clear();
         self.cache_neg.clear();
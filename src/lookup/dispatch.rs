@@ -23,7 +23,7 @@
 
 use mail_send::Credentials;
 
-use super::{Item, Lookup, LookupResult};
+use super::{hash::verify_secret, Item, Lookup, LookupResult};
 
 impl Lookup {
     pub async fn contains(&self, entry: &str) -> Option<bool> {
@@ -37,6 +37,18 @@ impl Lookup {
         }
     }
 
+    pub async fn fetch_one(&self, entry: &str) -> Option<Option<String>> {
+        match self {
+            Lookup::Sql(sql) => sql.fetch_one(entry).await,
+            Lookup::Local(entries) => Some(entries.iter().find_map(|item| {
+                item.strip_prefix(entry)
+                    .and_then(|value| value.strip_prefix(':'))
+                    .map(|value| value.to_string())
+            })),
+            Lookup::Remote(_) => None,
+        }
+    }
+
     pub async fn lookup(&self, item: Item) -> Option<LookupResult> {
         match self {
             Lookup::Remote(tx) => tx.lookup(item).await,
@@ -45,14 +57,23 @@ impl Lookup {
                 Item::IsAccount(account) => sql.exists(&account).await.map(LookupResult::from),
                 Item::Authenticate(credentials) => match credentials {
                     Credentials::Plain { username, secret }
-                    | Credentials::XOauth2 { username, secret } => sql
-                        .fetch_one(&username)
-                        .await
-                        .map(|pwd| LookupResult::from(pwd.map_or(false, |pwd| pwd == secret))),
+                    | Credentials::XOauth2 { username, secret } => {
+                        match sql.fetch_one(&username).await? {
+                            Some(pwd) => verify_secret_blocking(pwd, secret).await,
+                            None => Some(LookupResult::False),
+                        }
+                    }
                     Credentials::OAuthBearer { token } => {
                         sql.exists(&token).await.map(LookupResult::from)
                     }
                 },
+                Item::AuthenticateExternal(subject) => {
+                    sql.exists(&subject).await.map(LookupResult::from)
+                }
+                Item::ScramSecret(username) | Item::Secret(username) => sql
+                    .fetch_one(&username)
+                    .await
+                    .map(|secret| LookupResult::from(secret.into_iter().collect::<Vec<_>>())),
                 Item::Verify(account) => sql.fetch_many(&account).await.map(LookupResult::from),
                 Item::Expand(list) => sql.fetch_many(&list).await.map(LookupResult::from),
             },
@@ -87,7 +108,47 @@ impl Lookup {
                         None
                     }
                 }
+                Item::AuthenticateExternal(subject) => {
+                    if !list.is_empty() {
+                        Some(list.contains(&subject).into())
+                    } else {
+                        None
+                    }
+                }
+                Item::ScramSecret(username) | Item::Secret(username) => {
+                    if !list.is_empty() {
+                        Some(LookupResult::from(
+                            list.iter()
+                                .filter_map(|entry| {
+                                    entry
+                                        .strip_prefix(&username)
+                                        .and_then(|secret| secret.strip_prefix(':'))
+                                        .map(|secret| secret.to_string())
+                                })
+                                .take(1)
+                                .collect::<Vec<_>>(),
+                        ))
+                    } else {
+                        None
+                    }
+                }
             },
         }
     }
 }
+
+// bcrypt and crypt(3) hashes are expensive to verify, keep them off the async runtime
+async fn verify_secret_blocking(stored: String, secret: String) -> Option<LookupResult> {
+    match tokio::task::spawn_blocking(move || verify_secret(&stored, &secret)).await {
+        Ok(result) => Some(result.into()),
+        Err(err) => {
+            tracing::warn!(
+                context = "lookup",
+                event = "error",
+                reason = %err,
+                "Failed to verify secret."
+            );
+            None
+        }
+    }
+}
@@ -0,0 +1,121 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::net::IpAddr;
+
+use maxminddb::{geoip2, Reader};
+
+#[derive(Default)]
+pub struct GeoIpDatabase {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoIp {
+    pub country: String,
+    pub asn: u32,
+}
+
+impl GeoIpDatabase {
+    pub fn open(country: Option<&str>, asn: Option<&str>) -> crate::config::Result<Self> {
+        let open = |path: Option<&str>| {
+            path.map(|path| {
+                Reader::open_readfile(path)
+                    .map_err(|err| format!("Failed to open GeoIP database {path:?}: {err}"))
+            })
+            .transpose()
+        };
+
+        Ok(GeoIpDatabase {
+            country: open(country)?,
+            asn: open(asn)?,
+        })
+    }
+
+    pub fn lookup(&self, ip: &IpAddr) -> Option<GeoIp> {
+        let country = self
+            .country
+            .as_ref()
+            .and_then(|db| db.lookup::<geoip2::Country>(*ip).ok())
+            .and_then(|result| result.country)
+            .and_then(|country| country.iso_code)
+            .map(|iso_code| iso_code.to_ascii_uppercase());
+        let asn = self
+            .asn
+            .as_ref()
+            .and_then(|db| db.lookup::<geoip2::Asn>(*ip).ok())
+            .and_then(|result| result.autonomous_system_number);
+
+        if country.is_some() || asn.is_some() {
+            Some(GeoIp {
+                country: country.unwrap_or_default(),
+                asn: asn.unwrap_or_default(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, path::PathBuf};
+
+    use super::{GeoIp, GeoIpDatabase};
+
+    #[test]
+    fn geoip_lookup() {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources");
+        path.push("tests");
+        path.push("geoip");
+        let db = GeoIpDatabase::open(
+            path.join("country.mmdb").to_str(),
+            path.join("asn.mmdb").to_str(),
+        )
+        .unwrap();
+
+        for (ip, expected) in [
+            // Nested networks resolve to the most specific entry
+            ("81.2.69.160", Some(("IE", 64512))),
+            ("81.2.69.1", Some(("IE", 20712))),
+            ("81.2.70.1", Some(("GB", 20712))),
+            ("10.20.30.40", Some(("US", 0))),
+            ("11.0.0.1", None),
+            ("2a02:c7f:1234::1", Some(("DE", 3320))),
+            ("2a03::1", None),
+        ] {
+            assert_eq!(
+                db.lookup(&ip.parse::<IpAddr>().unwrap()),
+                expected.map(|(country, asn)| GeoIp {
+                    country: country.to_string(),
+                    asn,
+                }),
+                "failed for {ip}"
+            );
+        }
+
+        assert!(GeoIpDatabase::open(path.join("missing.mmdb").to_str(), None).is_err());
+    }
+}
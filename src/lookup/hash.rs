@@ -0,0 +1,130 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_parser::decoders::base64::base64_decode;
+use ring::{constant_time::verify_slices_are_equal, hmac};
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha512};
+
+// Verifies a secret against a stored value, which is either a plain text
+// password, a crypt(3) hash (bcrypt, SHA-256 or SHA-512 crypt) or a hash
+// using the RFC 2307 "{SCHEME}base64" syntax.
+pub fn verify_secret(stored: &str, secret: &str) -> bool {
+    let (scheme, hashed) = match stored
+        .strip_prefix('{')
+        .and_then(|stored| stored.split_once('}'))
+    {
+        Some((scheme, hashed)) => (scheme.to_ascii_uppercase(), hashed),
+        None if stored.starts_with('$') => return verify_crypt(stored, secret),
+        None => return is_equal(stored.as_bytes(), secret.as_bytes()),
+    };
+
+    let (digest_len, is_salted) = match scheme.as_str() {
+        "PLAIN" | "CLEAR" | "CLEARTEXT" => return is_equal(hashed.as_bytes(), secret.as_bytes()),
+        "CRYPT" if hashed.starts_with('$') => return verify_crypt(hashed, secret),
+        "MD5" => (16, false),
+        "SMD5" => (16, true),
+        "SHA" => (20, false),
+        "SSHA" => (20, true),
+        "SHA256" => (32, false),
+        "SSHA256" => (32, true),
+        "SHA512" => (64, false),
+        "SSHA512" => (64, true),
+        _ => {
+            tracing::debug!(
+                context = "lookup",
+                event = "error",
+                scheme = scheme,
+                "Unsupported password hash scheme."
+            );
+            return false;
+        }
+    };
+    let hashed = match base64_decode(hashed.as_bytes()) {
+        Some(hashed)
+            if (is_salted && hashed.len() > digest_len)
+                || (!is_salted && hashed.len() == digest_len) =>
+        {
+            hashed
+        }
+        _ => return false,
+    };
+    let (digest, salt) = hashed.split_at(digest_len);
+
+    let mut value = Vec::with_capacity(secret.len() + salt.len());
+    value.extend_from_slice(secret.as_bytes());
+    value.extend_from_slice(salt);
+    match digest_len {
+        16 => is_equal(&md5::compute(&value).0, digest),
+        20 => is_equal(&Sha1::digest(&value), digest),
+        32 => is_equal(&Sha256::digest(&value), digest),
+        _ => is_equal(&Sha512::digest(&value), digest),
+    }
+}
+
+fn verify_crypt(hashed: &str, secret: &str) -> bool {
+    if hashed.starts_with("$2a$") || hashed.starts_with("$2b$") || hashed.starts_with("$2y$") {
+        bcrypt::verify(secret, hashed).unwrap_or(false)
+    } else if hashed.starts_with("$6$") {
+        sha_crypt::sha512_check(secret, hashed).is_ok()
+    } else if hashed.starts_with("$5$") {
+        sha_crypt::sha256_check(secret, hashed).is_ok()
+    } else {
+        tracing::debug!(
+            context = "lookup",
+            event = "error",
+            "Unsupported password hash scheme."
+        );
+        false
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data)
+        .as_ref()
+        .try_into()
+        .unwrap()
+}
+
+// HMAC-MD5 is not provided by ring
+pub fn hmac_md5(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..16].copy_from_slice(&md5::compute(key).0);
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = md5::Context::new();
+    inner.consume(block.map(|b| b ^ 0x36));
+    inner.consume(data);
+    let mut outer = md5::Context::new();
+    outer.consume(block.map(|b| b ^ 0x5c));
+    outer.consume(inner.compute().0);
+    outer.compute().0
+}
+
+#[inline(always)]
+fn is_equal(a: &[u8], b: &[u8]) -> bool {
+    verify_slices_are_equal(a, b).is_ok()
+}
@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use std::{fmt::Display, sync::Arc, time::Duration};
+use std::{fmt::Display, ops::Range, sync::Arc, time::Duration};
 
 use mail_send::Credentials;
 use rustls::ServerName;
@@ -109,6 +109,14 @@ impl ImapAuthClientBuilder {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImapUrl {
+    pub mailbox: String,
+    pub uid_validity: Option<u32>,
+    pub uid: u32,
+    pub section: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
@@ -118,6 +126,7 @@ pub enum Error {
     AuthenticationFailed,
     TLSInvalidName,
     Disconnected,
+    TooLarge,
 }
 
 impl RemoteLookup for Arc<ImapAuthClientBuilder> {
@@ -206,6 +215,28 @@ impl ImapAuthClientBuilder {
     }
 }
 
+impl ImapAuthClientBuilder {
+    pub async fn fetch(
+        &self,
+        credentials: &Credentials<String>,
+        url: &ImapUrl,
+        max_size: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut client = self.connect().await?;
+        let mechanism = match credentials {
+            Credentials::Plain { .. } => AUTH_PLAIN,
+            Credentials::OAuthBearer { .. } => AUTH_OAUTHBEARER,
+            Credentials::XOauth2 { .. } => AUTH_XOAUTH2,
+        };
+        tokio::time::timeout(self.timeout, client.authenticate(mechanism, credentials))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        let result = client.fetch(url, max_size).await;
+        client.logout().await.ok();
+        result
+    }
+}
+
 impl ImapAuthClient<TcpStream> {
     async fn start_tls(
         mut self,
@@ -369,6 +400,93 @@ impl<T: AsyncRead + AsyncWrite + Unpin> ImapAuthClient<T> {
         .map_err(|_| Error::Timeout)?
     }
 
+    pub async fn fetch(&mut self, url: &ImapUrl, max_size: usize) -> Result<Vec<u8>, Error> {
+        tokio::time::timeout(self.timeout, async {
+            self.write(format!("C4 EXAMINE {}\r\n", quote_string(&url.mailbox)).as_bytes())
+                .await?;
+            let (response, _) = self.read_response(b"C4 ", max_size).await?;
+            if let Some(uid_validity) = url.uid_validity {
+                let expected = format!("[UIDVALIDITY {uid_validity}]");
+                if !response
+                    .windows(expected.len())
+                    .any(|w| w == expected.as_bytes())
+                {
+                    return Err(Error::InvalidResponse(format!(
+                        "UIDVALIDITY of mailbox {:?} does not match {}",
+                        url.mailbox, uid_validity
+                    )));
+                }
+            }
+
+            self.write(
+                format!(
+                    "C5 UID FETCH {} BODY.PEEK[{}]\r\n",
+                    url.uid,
+                    url.section.as_deref().unwrap_or_default()
+                )
+                .as_bytes(),
+            )
+            .await?;
+            match self.read_response(b"C5 ", max_size).await? {
+                (mut response, Some(literal)) => {
+                    response.truncate(literal.end);
+                    response.drain(..literal.start);
+                    Ok(response)
+                }
+                _ => Err(Error::InvalidResponse(format!(
+                    "Message with UID {} not found",
+                    url.uid
+                ))),
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout)?
+    }
+
+    async fn read_response(
+        &mut self,
+        tag: &[u8],
+        max_size: usize,
+    ) -> Result<(Vec<u8>, Option<Range<usize>>), Error> {
+        let mut buf = vec![0u8; 4096];
+        let mut response = Vec::with_capacity(4096);
+        let mut literal = None;
+        let mut pos = 0;
+
+        loop {
+            let br = self.stream.read(&mut buf).await?;
+            if br == 0 {
+                return Err(Error::Disconnected);
+            }
+            response.extend_from_slice(&buf[..br]);
+
+            while let Some(line_end) = response
+                .get(pos..)
+                .and_then(|bytes| bytes.iter().position(|&ch| ch == b'\n'))
+                .map(|offset| pos + offset + 1)
+            {
+                let line = &response[pos..line_end];
+                if let Some(status) = line.strip_prefix(tag) {
+                    return if status.starts_with(b"OK") {
+                        Ok((response, literal))
+                    } else {
+                        Err(Error::InvalidResponse(line.to_vec().into_string()))
+                    };
+                } else if let Some(size) = literal_size(line) {
+                    if size > max_size {
+                        return Err(Error::TooLarge);
+                    }
+                    if literal.is_none() {
+                        literal = Some(line_end..line_end + size);
+                    }
+                    pos = line_end + size;
+                } else {
+                    pos = line_end;
+                }
+            }
+        }
+    }
+
     pub async fn noop(&mut self) -> Result<(), Error> {
         tokio::time::timeout(self.timeout, async {
             self.write(b"C8 NOOP\r\n").await?;
@@ -435,6 +553,154 @@ impl<T: AsyncRead + AsyncWrite + Unpin> ImapAuthClient<T> {
     }
 }
 
+impl ImapUrl {
+    pub fn parse(url: &str) -> Option<Self> {
+        let url = url
+            .get(..7)
+            .filter(|scheme| scheme.eq_ignore_ascii_case("imap://"))
+            .and_then(|_| url.get(7..))?;
+        let (_, path) = url.split_once('/')?;
+        let uid_pos = path.to_ascii_lowercase().find("/;uid=")?;
+        let (mailbox, uid_validity) = match path[..uid_pos].split_once(';') {
+            Some((mailbox, params)) => (
+                mailbox,
+                Some(
+                    params
+                        .get(..12)
+                        .filter(|param| param.eq_ignore_ascii_case("uidvalidity="))
+                        .and_then(|_| params[12..].parse().ok())?,
+                ),
+            ),
+            None => (&path[..uid_pos], None),
+        };
+
+        let mut uid = None;
+        let mut section = None;
+        for param in path[uid_pos + 2..].split([';', '/']) {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.eq_ignore_ascii_case("uid") {
+                    uid = value.parse().ok().filter(|&uid| uid != 0);
+                } else if name.eq_ignore_ascii_case("section") {
+                    section = Some(value)
+                        .filter(|value| value.bytes().all(is_bchar))
+                        .and_then(url_decode)
+                        .filter(|section| is_valid_section(section))?
+                        .into();
+                }
+            }
+        }
+
+        if !mailbox.bytes().all(is_bchar) {
+            return None;
+        }
+
+        Some(ImapUrl {
+            mailbox: url_decode(mailbox).filter(|mailbox| {
+                !mailbox.is_empty() && !mailbox.chars().any(|ch| ch.is_control())
+            })?,
+            uid_validity,
+            uid: uid?,
+            section,
+        })
+    }
+}
+
+// RFC 5092 bchar, '%' is accepted here and pct-encoding checked by url_decode
+fn is_bchar(ch: u8) -> bool {
+    ch.is_ascii_alphanumeric()
+        || matches!(
+            ch,
+            b'-' | b'.'
+                | b'_'
+                | b'~'
+                | b'%'
+                | b'!'
+                | b'$'
+                | b'\''
+                | b'('
+                | b')'
+                | b'*'
+                | b'+'
+                | b','
+                | b'&'
+                | b'='
+                | b':'
+                | b'@'
+                | b'/'
+        )
+}
+
+// RFC 3501 section-spec: [part ["." section-text]] / section-msgtext
+fn is_valid_section(section: &str) -> bool {
+    let mut parts = section.split('.').peekable();
+    let mut has_part = false;
+    while parts
+        .next_if(|part| {
+            !part.is_empty() && !part.starts_with('0') && part.bytes().all(|ch| ch.is_ascii_digit())
+        })
+        .is_some()
+    {
+        has_part = true;
+    }
+    let text_upper = parts.collect::<Vec<_>>().join(".").to_ascii_uppercase();
+    match text_upper.as_str() {
+        "" | "HEADER" | "TEXT" => return true,
+        "MIME" => return has_part,
+        _ => (),
+    }
+    let fields = if let Some(fields) = text_upper.strip_prefix("HEADER.FIELDS.NOT ") {
+        fields
+    } else if let Some(fields) = text_upper.strip_prefix("HEADER.FIELDS ") {
+        fields
+    } else {
+        return false;
+    };
+    fields
+        .strip_prefix('(')
+        .and_then(|fields| fields.strip_suffix(')'))
+        .map_or(false, |fields| {
+            fields.split(' ').all(|field| {
+                !field.is_empty()
+                    && field
+                        .bytes()
+                        .all(|ch| ch.is_ascii_graphic() && !matches!(ch, b':' | b'(' | b')'))
+            })
+        })
+}
+
+fn url_decode(value: &str) -> Option<String> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut bytes = value.as_bytes().iter();
+    while let Some(&ch) = bytes.next() {
+        if ch == b'%' {
+            let hex = [*bytes.next()?, *bytes.next()?];
+            result.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            result.push(ch);
+        }
+    }
+    String::from_utf8(result).ok()
+}
+
+fn quote_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for ch in value.chars() {
+        if matches!(ch, '"' | '\\') {
+            result.push('\\');
+        }
+        result.push(ch);
+    }
+    result.push('"');
+    result
+}
+
+fn literal_size(line: &[u8]) -> Option<usize> {
+    let line = line.strip_suffix(b"}\r\n")?;
+    let start = line.iter().rposition(|&ch| ch == b'{')?;
+    std::str::from_utf8(&line[start + 1..]).ok()?.parse().ok()
+}
+
 impl From<std::io::Error> for Error {
     fn from(error: std::io::Error) -> Self {
         Error::Io(error)
@@ -451,13 +717,14 @@ impl Display for Error {
             Error::TLSInvalidName => f.write_str("Invalid TLS name"),
             Error::Disconnected => f.write_str("Connection disconnected by peer"),
             Error::AuthenticationFailed => f.write_str("Authentication failed"),
+            Error::TooLarge => f.write_str("Response literal exceeds the maximum size"),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::lookup::imap::ImapAuthClient;
+    use crate::lookup::imap::{ImapAuthClient, ImapUrl};
     use mail_send::smtp::tls::build_tls_connector;
     use smtp_proto::{AUTH_OAUTHBEARER, AUTH_PLAIN, AUTH_XOAUTH, AUTH_XOAUTH2};
     use std::time::Duration;
@@ -482,4 +749,61 @@ mod test {
         );
         client.logout().await.unwrap();
     }
+
+    #[test]
+    fn parse_imap_url() {
+        assert_eq!(
+            ImapUrl::parse(
+                "imap://joe@example.com/INBOX;UIDVALIDITY=785799047/;UID=113330/;section=1.5.9;urlauth=submit+joe:internal:91354a473744909de610943775f92038"
+            ),
+            Some(ImapUrl {
+                mailbox: "INBOX".to_string(),
+                uid_validity: Some(785799047),
+                uid: 113330,
+                section: Some("1.5.9".to_string()),
+            })
+        );
+        assert_eq!(
+            ImapUrl::parse("imap://example.com/Sent%20Items/2023/;uid=20"),
+            Some(ImapUrl {
+                mailbox: "Sent Items/2023".to_string(),
+                uid_validity: None,
+                uid: 20,
+                section: None,
+            })
+        );
+        for (url, section) in [
+            (
+                "imap://example.com/INBOX/;uid=20/;section=1.2.MIME",
+                "1.2.MIME",
+            ),
+            ("imap://example.com/INBOX/;uid=20/;section=text", "text"),
+            (
+                "imap://example.com/INBOX/;uid=20/;section=HEADER.FIELDS%20(From%20To)",
+                "HEADER.FIELDS (From To)",
+            ),
+        ] {
+            assert_eq!(
+                ImapUrl::parse(url).and_then(|url| url.section).as_deref(),
+                Some(section),
+                "{url}"
+            );
+        }
+        for invalid in [
+            "http://example.com/INBOX/;uid=20",
+            "imap://example.com/INBOX",
+            "imap://example.com//;uid=20",
+            "imap://example.com/INBOX/;uid=abc",
+            "imap://example.com/INBOX/;uid=0",
+            "imap://example.com/IN%0D%0ABOX/;uid=20",
+            "imap://example.com/IN BOX/;uid=20",
+            "imap://example.com/INBOX\"/;uid=20",
+            "imap://example.com/INBOX/;uid=20/;section=1%0D%0A",
+            "imap://example.com/INBOX/;uid=20/;section=1]%20BODY[",
+            "imap://example.com/INBOX/;uid=20/;section=0.1",
+            "imap://example.com/INBOX/;uid=20/;section=HEADER.FIELDS%20FROM",
+        ] {
+            assert_eq!(ImapUrl::parse(invalid), None, "{invalid}");
+        }
+    }
 }
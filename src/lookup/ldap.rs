@@ -0,0 +1,751 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fmt::Display, sync::Arc, time::Duration};
+
+use mail_send::Credentials;
+use rustls::ServerName;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{mpsc, oneshot},
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::config::LdapSettings;
+
+use super::{spawn::LoggedUnwrap, Event, Item, LookupItem, LookupResult, RemoteLookup};
+
+const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+const OID_STARTTLS: &[u8] = b"1.3.6.1.4.1.1466.20037";
+
+const LDAP_SUCCESS: u32 = 0;
+const LDAP_SIZE_LIMIT_EXCEEDED: u32 = 4;
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+
+pub struct LdapClient<T: AsyncRead + AsyncWrite> {
+    stream: T,
+    timeout: Duration,
+    message_id: i64,
+    buf: Vec<u8>,
+}
+
+pub struct LdapClientBuilder {
+    pub addr: String,
+    pub timeout: Duration,
+    pub tls_connector: TlsConnector,
+    pub tls_hostname: String,
+    pub tls_implicit: bool,
+    pub bind_dn: Option<String>,
+    pub bind_secret: Option<String>,
+    pub settings: LdapSettings,
+    pub max_requests: usize,
+    pub max_auth_errors: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub dn: String,
+    pub attributes: Vec<(String, Vec<String>)>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Timeout,
+    InvalidResponse(String),
+    InvalidFilter(String),
+    OperationFailed { code: u32, message: String },
+    TLSInvalidName,
+    Disconnected,
+}
+
+impl RemoteLookup for Arc<LdapClientBuilder> {
+    fn spawn_lookup(&self, lookup: LookupItem, tx: mpsc::Sender<Event>) {
+        let builder = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = builder.lookup(lookup, &tx).await {
+                tracing::warn!(
+                    context = "remote",
+                    event = "lookup-failed",
+                    remote.addr = &builder.addr,
+                    remote.protocol = "ldap",
+                    "Remote lookup failed: {}",
+                    err
+                );
+                tx.send(Event::WorkerFailed).await.logged_unwrap();
+            }
+        });
+    }
+}
+
+impl LdapClientBuilder {
+    pub async fn connect(&self) -> Result<LdapClient<TlsStream<TcpStream>>, Error> {
+        LdapClient::connect(
+            &self.addr,
+            self.timeout,
+            &self.tls_connector,
+            &self.tls_hostname,
+            self.tls_implicit,
+        )
+        .await
+    }
+
+    pub async fn lookup(
+        &self,
+        mut lookup: LookupItem,
+        tx: &mpsc::Sender<Event>,
+    ) -> Result<(), Error> {
+        let mut client = self.connect().await?;
+        let mut is_bound = false;
+        let mut num_requests = 0;
+        let mut num_auth_failures = 0;
+
+        loop {
+            let result = match &lookup.item {
+                Item::IsAccount(address) => {
+                    self.bind(&mut client, &mut is_bound).await?;
+                    let filter = build_filter(&self.settings.filter_account, address)?;
+                    (!client
+                        .search(&self.settings.base_dn, &filter, &["1.1"], 1)
+                        .await?
+                        .is_empty())
+                    .into()
+                }
+                Item::Verify(address) => {
+                    self.bind(&mut client, &mut is_bound).await?;
+                    let filter = build_filter(&self.settings.filter_account, address)?;
+                    let attribute = self.settings.attribute_mail.as_str();
+                    client
+                        .search(&self.settings.base_dn, &filter, &[attribute], 10)
+                        .await?
+                        .into_iter()
+                        .flat_map(|entry| entry.into_values(attribute))
+                        .collect::<Vec<_>>()
+                        .into()
+                }
+                Item::Authenticate(Credentials::Plain { username, secret })
+                    if !secret.is_empty() =>
+                {
+                    // Find the user's DN and then bind as that user
+                    self.bind(&mut client, &mut is_bound).await?;
+                    let filter = build_filter(&self.settings.filter_auth, username)?;
+                    let entries = client
+                        .search(&self.settings.base_dn, &filter, &["1.1"], 2)
+                        .await?;
+                    let result = if let [entry] = entries.as_slice() {
+                        is_bound = false;
+                        client.bind(&entry.dn, secret).await?
+                    } else {
+                        false
+                    };
+                    if !result {
+                        num_auth_failures += 1;
+                    }
+                    result.into()
+                }
+                Item::Authenticate(_)
+                | Item::ScramSecret(_)
+                | Item::Secret(_)
+                | Item::AuthenticateExternal(_)
+                | Item::Expand(_) => {
+                    // Secrets are not disclosed and empty passwords would
+                    // result in an unauthenticated bind
+                    LookupResult::False
+                }
+            };
+            num_requests += 1;
+
+            // Try to reuse the connection with any queued requests
+            let cached_result = match &result {
+                LookupResult::True => Some(true),
+                LookupResult::False => Some(false),
+                LookupResult::Values(_) => None,
+            };
+            lookup.result.send(result).logged_unwrap();
+            if num_requests < self.max_requests && num_auth_failures < self.max_auth_errors {
+                let (next_lookup_tx, next_lookup_rx) = oneshot::channel::<Option<LookupItem>>();
+                if tx
+                    .send(Event::WorkerReady {
+                        item: lookup.item,
+                        result: cached_result,
+                        next_lookup: next_lookup_tx.into(),
+                    })
+                    .await
+                    .logged_unwrap()
+                {
+                    if let Ok(Some(next_lookup)) = next_lookup_rx.await {
+                        lookup = next_lookup;
+                        continue;
+                    }
+                }
+            } else {
+                tx.send(Event::WorkerReady {
+                    item: lookup.item,
+                    result: cached_result,
+                    next_lookup: None,
+                })
+                .await
+                .logged_unwrap();
+            }
+            break;
+        }
+
+        client.unbind().await.ok();
+        Ok(())
+    }
+
+    async fn bind<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: &mut LdapClient<T>,
+        is_bound: &mut bool,
+    ) -> Result<(), Error> {
+        if !*is_bound {
+            if client
+                .bind(
+                    self.bind_dn.as_deref().unwrap_or_default(),
+                    self.bind_secret.as_deref().unwrap_or_default(),
+                )
+                .await?
+            {
+                *is_bound = true;
+            } else {
+                return Err(Error::OperationFailed {
+                    code: LDAP_INVALID_CREDENTIALS,
+                    message: "Invalid bind credentials".to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LdapClient<TcpStream> {
+    async fn start_tls(
+        mut self,
+        tls_connector: &TlsConnector,
+        tls_hostname: &str,
+    ) -> Result<LdapClient<TlsStream<TcpStream>>, Error> {
+        tokio::time::timeout(self.timeout, async {
+            let mut request = Vec::new();
+            ber(&mut request, 0x80, OID_STARTTLS);
+            let message_id = self.send(0x77, &request).await?;
+            let (tag, response) = self.read_response(message_id).await?;
+            if tag != 0x78 {
+                return Err(Error::InvalidResponse(format!(
+                    "Unexpected operation {tag:#x}"
+                )));
+            }
+            match parse_result(&response)? {
+                (LDAP_SUCCESS, _) => Ok(()),
+                (code, message) => Err(Error::OperationFailed { code, message }),
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
+
+        self.into_tls(tls_connector, tls_hostname).await
+    }
+
+    async fn into_tls(
+        self,
+        tls_connector: &TlsConnector,
+        tls_hostname: &str,
+    ) -> Result<LdapClient<TlsStream<TcpStream>>, Error> {
+        tokio::time::timeout(self.timeout, async {
+            Ok(LdapClient {
+                stream: tls_connector
+                    .connect(
+                        ServerName::try_from(tls_hostname).map_err(|_| Error::TLSInvalidName)?,
+                        self.stream,
+                    )
+                    .await?,
+                timeout: self.timeout,
+                message_id: self.message_id,
+                buf: self.buf,
+            })
+        })
+        .await
+        .map_err(|_| Error::Timeout)?
+    }
+}
+
+impl LdapClient<TlsStream<TcpStream>> {
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        timeout: Duration,
+        tls_connector: &TlsConnector,
+        tls_hostname: &str,
+        tls_implicit: bool,
+    ) -> Result<Self, Error> {
+        let client: LdapClient<TcpStream> = tokio::time::timeout(timeout, async {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => Ok(LdapClient {
+                    stream,
+                    timeout,
+                    message_id: 0,
+                    buf: Vec::new(),
+                }),
+                Err(err) => Err(Error::Io(err)),
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout)??;
+
+        if tls_implicit {
+            client.into_tls(tls_connector, tls_hostname).await
+        } else {
+            client.start_tls(tls_connector, tls_hostname).await
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> LdapClient<T> {
+    pub async fn bind(&mut self, dn: &str, secret: &str) -> Result<bool, Error> {
+        tokio::time::timeout(self.timeout, async {
+            let mut request = Vec::with_capacity(dn.len() + secret.len() + 16);
+            ber_int(&mut request, 0x02, 3);
+            ber(&mut request, 0x04, dn.as_bytes());
+            ber(&mut request, 0x80, secret.as_bytes());
+            let message_id = self.send(0x60, &request).await?;
+
+            match self.read_response(message_id).await? {
+                (0x61, response) => match parse_result(&response)? {
+                    (LDAP_SUCCESS, _) => Ok(true),
+                    (LDAP_INVALID_CREDENTIALS, _) => Ok(false),
+                    (code, message) => Err(Error::OperationFailed { code, message }),
+                },
+                (tag, _) => Err(Error::InvalidResponse(format!(
+                    "Unexpected operation {tag:#x}"
+                ))),
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout)?
+    }
+
+    pub async fn search(
+        &mut self,
+        base_dn: &str,
+        filter: &[u8],
+        attributes: &[&str],
+        size_limit: i64,
+    ) -> Result<Vec<Entry>, Error> {
+        tokio::time::timeout(self.timeout, async {
+            let mut request = Vec::with_capacity(base_dn.len() + filter.len() + 64);
+            ber(&mut request, 0x04, base_dn.as_bytes());
+            ber_int(&mut request, 0x0a, 2); // wholeSubtree
+            ber_int(&mut request, 0x0a, 0); // neverDerefAliases
+            ber_int(&mut request, 0x02, size_limit);
+            ber_int(&mut request, 0x02, self.timeout.as_secs() as i64);
+            ber(&mut request, 0x01, &[0]);
+            request.extend_from_slice(filter);
+            let mut attribute_list = Vec::new();
+            for attribute in attributes {
+                ber(&mut attribute_list, 0x04, attribute.as_bytes());
+            }
+            ber(&mut request, 0x30, &attribute_list);
+            let message_id = self.send(0x63, &request).await?;
+
+            let mut entries = Vec::new();
+            loop {
+                match self.read_response(message_id).await? {
+                    (0x64, response) => {
+                        entries.push(Entry::parse(&response).ok_or_else(|| {
+                            Error::InvalidResponse("Invalid search result entry".to_string())
+                        })?);
+                    }
+                    (0x73, _) => {
+                        // Referrals are not followed
+                    }
+                    (0x65, response) => {
+                        return match parse_result(&response)? {
+                            (LDAP_SUCCESS, _) => Ok(entries),
+                            (LDAP_SIZE_LIMIT_EXCEEDED, _) if !entries.is_empty() => Ok(entries),
+                            (code, message) => Err(Error::OperationFailed { code, message }),
+                        };
+                    }
+                    (tag, _) => {
+                        return Err(Error::InvalidResponse(format!(
+                            "Unexpected operation {tag:#x}"
+                        )));
+                    }
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::Timeout)?
+    }
+
+    pub async fn unbind(&mut self) -> Result<(), Error> {
+        tokio::time::timeout(self.timeout, async {
+            self.send(0x42, &[]).await?;
+            Ok(())
+        })
+        .await
+        .map_err(|_| Error::Timeout)?
+    }
+
+    async fn send(&mut self, tag: u8, request: &[u8]) -> Result<i64, Error> {
+        self.message_id += 1;
+        let mut message = Vec::with_capacity(request.len() + 16);
+        ber_int(&mut message, 0x02, self.message_id);
+        ber(&mut message, tag, request);
+        let mut packet = Vec::with_capacity(message.len() + 6);
+        ber(&mut packet, 0x30, &message);
+        self.stream.write_all(&packet).await?;
+        self.stream.flush().await?;
+        Ok(self.message_id)
+    }
+
+    async fn read_response(&mut self, message_id: i64) -> Result<(u8, Vec<u8>), Error> {
+        loop {
+            if let Some(size) = ber_size(&self.buf) {
+                if size > MAX_MESSAGE_SIZE {
+                    return Err(Error::InvalidResponse(format!(
+                        "Message size {size} exceeds maximum"
+                    )));
+                } else if size <= self.buf.len() {
+                    let message = self.buf.drain(..size).collect::<Vec<_>>();
+                    let (id, tag, response) = parse_message(&message).ok_or_else(|| {
+                        Error::InvalidResponse("Invalid LDAP message".to_string())
+                    })?;
+                    if id == message_id {
+                        return Ok((tag, response.to_vec()));
+                    } else if id == 0 {
+                        // Notice of disconnection
+                        return Err(Error::Disconnected);
+                    }
+                    continue;
+                }
+            } else if self.buf.len() > MAX_MESSAGE_SIZE {
+                return Err(Error::InvalidResponse("Invalid LDAP message".to_string()));
+            }
+
+            let mut buf = [0u8; 4096];
+            let br = self.stream.read(&mut buf).await?;
+            if br > 0 {
+                self.buf.extend_from_slice(&buf[..br]);
+            } else {
+                return Err(Error::Disconnected);
+            }
+        }
+    }
+}
+
+impl Entry {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut reader = BerReader::new(bytes);
+        let dn = String::from_utf8(reader.expect(0x04)?.to_vec()).ok()?;
+        let mut attributes = Vec::new();
+        let mut attribute_list = BerReader::new(reader.expect(0x30)?);
+        while let Some(attribute) = attribute_list.expect(0x30) {
+            let mut attribute = BerReader::new(attribute);
+            let name = String::from_utf8(attribute.expect(0x04)?.to_vec()).ok()?;
+            let mut value_list = BerReader::new(attribute.expect(0x31)?);
+            let mut values = Vec::new();
+            while let Some(value) = value_list.expect(0x04) {
+                values.push(String::from_utf8_lossy(value).into_owned());
+            }
+            attributes.push((name, values));
+        }
+
+        Some(Entry { dn, attributes })
+    }
+
+    pub fn into_values(self, attribute: &str) -> Vec<String> {
+        self.attributes
+            .into_iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(attribute))
+            .flat_map(|(_, values)| values)
+            .collect()
+    }
+}
+
+pub struct BerReader<'x> {
+    bytes: &'x [u8],
+    pos: usize,
+}
+
+impl<'x> BerReader<'x> {
+    pub fn new(bytes: &'x [u8]) -> Self {
+        BerReader { bytes, pos: 0 }
+    }
+
+    pub fn expect(&mut self, tag: u8) -> Option<&'x [u8]> {
+        self.next()
+            .and_then(|(tag_, value)| if tag_ == tag { Some(value) } else { None })
+    }
+}
+
+impl<'x> Iterator for BerReader<'x> {
+    type Item = (u8, &'x [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bytes = self.bytes.get(self.pos..)?;
+        let size = ber_size(bytes).filter(|size| *size <= bytes.len())?;
+        let header_len = size - ber_content_len(bytes)?;
+        self.pos += size;
+        Some((bytes[0], &bytes[header_len..size]))
+    }
+}
+
+pub fn parse_message(bytes: &[u8]) -> Option<(i64, u8, &[u8])> {
+    let mut message = BerReader::new(BerReader::new(bytes).expect(0x30)?);
+    let id = parse_int(message.expect(0x02)?)?;
+    let (tag, operation) = message.next()?;
+    Some((id, tag, operation))
+}
+
+fn parse_result(bytes: &[u8]) -> Result<(u32, String), Error> {
+    let mut reader = BerReader::new(bytes);
+    reader
+        .expect(0x0a)
+        .and_then(parse_int)
+        .and_then(|code| {
+            let _matched_dn = reader.expect(0x04)?;
+            let message = reader.expect(0x04)?;
+            Some((code as u32, String::from_utf8_lossy(message).into_owned()))
+        })
+        .ok_or_else(|| Error::InvalidResponse("Invalid LDAP result".to_string()))
+}
+
+pub fn parse_int(bytes: &[u8]) -> Option<i64> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return None;
+    }
+    let mut value = if bytes[0] & 0x80 != 0 { -1i64 } else { 0 };
+    for &byte in bytes {
+        value = (value << 8) | byte as i64;
+    }
+    Some(value)
+}
+
+// Returns the total size of the BER element at the start of the buffer
+fn ber_size(bytes: &[u8]) -> Option<usize> {
+    let content_len = ber_content_len(bytes)?;
+    let header_len = match *bytes.get(1)? {
+        len if len < 0x80 => 2,
+        len => 2 + (len & 0x7f) as usize,
+    };
+    header_len.checked_add(content_len)
+}
+
+fn ber_content_len(bytes: &[u8]) -> Option<usize> {
+    match *bytes.get(1)? {
+        len if len < 0x80 => Some(len as usize),
+        0x80 => None,
+        len => {
+            let num_bytes = (len & 0x7f) as usize;
+            if num_bytes > std::mem::size_of::<usize>() {
+                return None;
+            }
+            let mut content_len = 0usize;
+            for &byte in bytes.get(2..2 + num_bytes)? {
+                content_len = (content_len << 8) | byte as usize;
+            }
+            Some(content_len)
+        }
+    }
+}
+
+pub fn ber(buf: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buf.push(tag);
+    if value.len() < 0x80 {
+        buf.push(value.len() as u8);
+    } else {
+        let len = value.len().to_be_bytes();
+        let skip = len.iter().take_while(|&&byte| byte == 0).count();
+        buf.push(0x80 | (len.len() - skip) as u8);
+        buf.extend_from_slice(&len[skip..]);
+    }
+    buf.extend_from_slice(value);
+}
+
+pub fn ber_int(buf: &mut Vec<u8>, tag: u8, value: i64) {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    ber(buf, tag, &bytes[start..]);
+}
+
+pub fn build_filter(template: &str, value: &str) -> Result<Vec<u8>, Error> {
+    let filter = template.replace("%s", &escape_filter_value(value));
+    encode_filter(&filter).ok_or(Error::InvalidFilter(filter))
+}
+
+pub fn escape_filter_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for ch in value.chars() {
+        match ch {
+            '*' | '(' | ')' | '\\' | '\0' => {
+                result.push_str(&format!("\\{:02x}", ch as u8));
+            }
+            _ => result.push(ch),
+        }
+    }
+    result
+}
+
+// Encodes an RFC 4515 search filter
+pub fn encode_filter(filter: &str) -> Option<Vec<u8>> {
+    let filter = filter.trim().as_bytes();
+    let mut buf = Vec::with_capacity(filter.len() + 16);
+    if filter.first() == Some(&b'(') {
+        let mut pos = 0;
+        encode_filter_(filter, &mut pos, &mut buf)?;
+        if pos != filter.len() {
+            return None;
+        }
+    } else {
+        encode_filter_item(filter, &mut buf)?;
+    }
+    Some(buf)
+}
+
+fn encode_filter_(filter: &[u8], pos: &mut usize, buf: &mut Vec<u8>) -> Option<()> {
+    if filter.get(*pos)? != &b'(' {
+        return None;
+    }
+    *pos += 1;
+
+    match filter.get(*pos)? {
+        ch @ (b'&' | b'|') => {
+            let tag = if *ch == b'&' { 0xa0 } else { 0xa1 };
+            *pos += 1;
+            let mut items = Vec::new();
+            while filter.get(*pos)? == &b'(' {
+                encode_filter_(filter, pos, &mut items)?;
+            }
+            ber(buf, tag, &items);
+        }
+        b'!' => {
+            *pos += 1;
+            let mut item = Vec::new();
+            encode_filter_(filter, pos, &mut item)?;
+            ber(buf, 0xa2, &item);
+        }
+        _ => {
+            let end = *pos + filter.get(*pos..)?.iter().position(|&ch| ch == b')')?;
+            encode_filter_item(&filter[*pos..end], buf)?;
+            *pos = end;
+        }
+    }
+
+    if filter.get(*pos)? != &b')' {
+        return None;
+    }
+    *pos += 1;
+    Some(())
+}
+
+fn encode_filter_item(item: &[u8], buf: &mut Vec<u8>) -> Option<()> {
+    let eq_pos = item.iter().position(|&ch| ch == b'=')?;
+    let (tag, attribute) = match item[..eq_pos].last()? {
+        b'~' => (0xa8, &item[..eq_pos - 1]),
+        b'>' => (0xa5, &item[..eq_pos - 1]),
+        b'<' => (0xa6, &item[..eq_pos - 1]),
+        _ => (0xa3, &item[..eq_pos]),
+    };
+    let value = &item[eq_pos + 1..];
+    if attribute.is_empty()
+        || !attribute
+            .iter()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, b'-' | b'.' | b';'))
+    {
+        return None;
+    }
+
+    let mut assertion = Vec::with_capacity(attribute.len() + value.len() + 4);
+    ber(&mut assertion, 0x04, attribute);
+    if tag == 0xa3 && value == b"*" {
+        ber(buf, 0x87, attribute);
+    } else if tag == 0xa3 && value.contains(&b'*') {
+        let parts = value.split(|&ch| ch == b'*').collect::<Vec<_>>();
+        let mut substrings = Vec::new();
+        for (pos, part) in parts.iter().enumerate() {
+            if !part.is_empty() {
+                let tag = if pos == 0 {
+                    0x80
+                } else if pos == parts.len() - 1 {
+                    0x82
+                } else {
+                    0x81
+                };
+                ber(&mut substrings, tag, &decode_filter_value(part)?);
+            }
+        }
+        ber(&mut assertion, 0x30, &substrings);
+        ber(buf, 0xa4, &assertion);
+    } else if !value.contains(&b'*') {
+        ber(&mut assertion, 0x04, &decode_filter_value(value)?);
+        ber(buf, tag, &assertion);
+    } else {
+        return None;
+    }
+
+    Some(())
+}
+
+fn decode_filter_value(value: &[u8]) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut iter = value.iter();
+    while let Some(&ch) = iter.next() {
+        match ch {
+            b'\\' => {
+                let hex = [*iter.next()?, *iter.next()?];
+                result.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b'(' | b')' | 0 => return None,
+            _ => result.push(ch),
+        }
+    }
+    Some(result)
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(io) => write!(f, "I/O error: {io}"),
+            Error::Timeout => f.write_str("Connection time-out"),
+            Error::InvalidResponse(response) => write!(f, "Unexpected response: {response}"),
+            Error::InvalidFilter(filter) => write!(f, "Invalid search filter: {filter:?}"),
+            Error::OperationFailed { code, message } => {
+                write!(f, "Operation failed with code {code}: {message:?}")
+            }
+            Error::TLSInvalidName => f.write_str("Invalid TLS name"),
+            Error::Disconnected => f.write_str("Connection disconnected by peer"),
+        }
+    }
+}
@@ -30,7 +30,10 @@ use self::cache::LookupCache;
 
 pub mod cache;
 pub mod dispatch;
+pub mod geoip;
+pub mod hash;
 pub mod imap;
+pub mod ldap;
 pub mod smtp;
 pub mod spawn;
 pub mod sql;
@@ -80,6 +83,9 @@ pub enum Event {
 pub enum Item {
     IsAccount(String),
     Authenticate(Credentials<String>),
+    AuthenticateExternal(String),
+    ScramSecret(String),
+    Secret(String),
     Verify(String),
     Expand(String),
 }
@@ -92,6 +92,10 @@ impl SmtpClientBuilder {
                         !result && num_auth_failures < self.max_auth_errors,
                     )
                 }
+                Item::ScramSecret(_) | Item::Secret(_) | Item::AuthenticateExternal(_) => {
+                    // Remote SMTP servers cannot disclose secrets or vouch for certificates
+                    (LookupResult::False, true)
+                }
                 Item::Verify(address) | Item::Expand(address) => {
                     let reply = client
                         .cmd(
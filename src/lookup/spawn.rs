@@ -28,8 +28,9 @@ use mail_send::smtp::tls::build_tls_connector;
 use tokio::sync::{mpsc, oneshot};
 
 use super::{
-    cache::LookupCache, imap::ImapAuthClientBuilder, smtp::SmtpClientBuilder, Event, Item,
-    LookupChannel, LookupItem, LookupResult, RemoteHost, RemoteLookup,
+    cache::LookupCache, imap::ImapAuthClientBuilder, ldap::LdapClientBuilder,
+    smtp::SmtpClientBuilder, Event, Item, LookupChannel, LookupItem, LookupResult, RemoteHost,
+    RemoteLookup,
 };
 
 impl Host {
@@ -95,6 +96,31 @@ impl Host {
                     )
                     .await;
                 }
+                ServerProtocol::Ldap => {
+                    RemoteHost {
+                        tx: self.channel_tx,
+                        host: Arc::new(LdapClientBuilder {
+                            addr: format!("{}:{}", self.address, self.port),
+                            timeout: self.timeout,
+                            tls_connector: build_tls_connector(self.tls_allow_invalid_certs),
+                            tls_hostname: self.address,
+                            tls_implicit: self.tls_implicit,
+                            bind_dn: self.username,
+                            bind_secret: self.secret,
+                            settings: self.ldap.unwrap_or_default(),
+                            max_requests: self.max_requests,
+                            max_auth_errors: self.max_errors,
+                        }),
+                    }
+                    .run(
+                        self.channel_rx,
+                        self.cache_entries,
+                        self.cache_ttl_positive,
+                        self.cache_ttl_negative,
+                        self.concurrency,
+                    )
+                    .await;
+                }
                 ServerProtocol::Http => {
                     eprintln!("HTTP lookups are not supported.");
                     std::process::exit(0);
@@ -124,6 +150,12 @@ impl<T: RemoteLookup> RemoteHost<T> {
             match event {
                 Event::Lookup(lookup) => {
                     if let Some(result) = cache.get(&lookup.item) {
+                        tracing::trace!(
+                            context = "remote",
+                            event = "cache-hit",
+                            hits = cache.hits(),
+                            misses = cache.misses()
+                        );
                         lookup.result.send(result.into()).logged_unwrap();
                     } else if active_lookups < max_concurrent {
                         active_lookups += 1;
@@ -175,6 +207,13 @@ impl<T: RemoteLookup> RemoteHost<T> {
                     break;
                 }
                 Event::Reload => {
+                    tracing::debug!(
+                        context = "remote",
+                        event = "cache-clear",
+                        hits = cache.hits(),
+                        misses = cache.misses(),
+                        "Clearing lookup cache."
+                    );
                     cache.clear();
                 }
             }
@@ -251,6 +290,9 @@ impl Debug for Item {
         match self {
             Self::IsAccount(arg0) => f.debug_tuple("Rcpt").field(arg0).finish(),
             Self::Authenticate(_) => f.debug_tuple("Auth").finish(),
+            Self::AuthenticateExternal(arg0) => f.debug_tuple("AuthExt").field(arg0).finish(),
+            Self::ScramSecret(arg0) => f.debug_tuple("Scram").field(arg0).finish(),
+            Self::Secret(arg0) => f.debug_tuple("Secret").field(arg0).finish(),
             Self::Expand(arg0) => f.debug_tuple("Expn").field(arg0).finish(),
             Self::Verify(arg0) => f.debug_tuple("Vrfy").field(arg0).finish(),
         }
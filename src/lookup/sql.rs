@@ -25,12 +25,17 @@ use super::{SqlDatabase, SqlQuery};
 
 impl SqlQuery {
     pub async fn exists(&self, param: &str) -> Option<bool> {
-        if let Some(result) = self
-            .cache
-            .as_ref()
-            .and_then(|cache| cache.lock().get(param))
-        {
-            return Some(result);
+        if let Some(cache) = &self.cache {
+            let mut cache = cache.lock();
+            if let Some(result) = cache.get(param) {
+                tracing::trace!(
+                    context = "sql",
+                    event = "cache-hit",
+                    hits = cache.hits(),
+                    misses = cache.misses()
+                );
+                return Some(result);
+            }
         }
         let result = match &self.db {
             super::SqlDatabase::Postgres(pool) => {
@@ -27,7 +27,7 @@ use dashmap::DashMap;
 use mail_send::smtp::tls::build_tls_connector;
 use opentelemetry::{
     sdk::{
-        trace::{self, Sampler},
+        trace::{self, Sampler, Tracer},
         Resource,
     },
     KeyValue,
@@ -37,7 +37,7 @@ use opentelemetry_semantic_conventions::resource::{SERVICE_NAME, SERVICE_VERSION
 use stalwart_smtp::{
     config::{Config, ConfigContext, ServerProtocol},
     core::{
-        throttle::{ConcurrencyLimiter, ThrottleKeyHasherBuilder},
+        throttle::{ConcurrencyLimiter, HandshakeLimiter, LogLimiter, ThrottleKeyHasherBuilder},
         Core, QueueCore, ReportCore, SessionCore, TlsConnectors,
     },
     failed,
@@ -84,6 +84,10 @@ async fn main() -> std::io::Result<()> {
     let report_config = config
         .parse_reports(&config_context)
         .failed("Configuration error");
+    let throttle_snapshot = config
+        .parse_throttle_snapshot()
+        .failed("Configuration error");
+    let load_throttle = config.parse_load_throttle().failed("Configuration error");
 
     // Build core
     let (queue_tx, queue_rx) = mpsc::channel(1024);
@@ -99,6 +103,16 @@ async fn main() -> std::io::Result<()> {
             )
             .build()
             .unwrap(),
+        tls_handshakes: HandshakeLimiter::new(
+            config
+                .property("global.tls.max-handshakes")
+                .failed("Failed to parse maximum concurrent TLS handshakes")
+                .unwrap_or(256),
+            config
+                .property("global.tls.handshake-wait")
+                .failed("Failed to parse TLS handshake wait time")
+                .unwrap_or_else(|| Duration::from_secs(1)),
+        ),
         resolvers: config.build_resolvers().failed("Failed to build resolvers"),
         session: SessionCore {
             config: session_config,
@@ -120,9 +134,24 @@ async fn main() -> std::io::Result<()> {
                     .unwrap_or(32)
                     .next_power_of_two() as usize,
             ),
+            auth_origins: DashMap::new(),
+            auth_sessions: DashMap::new(),
+            abandoned: DashMap::new(),
+            catch_all: DashMap::new(),
+            log_limiter: LogLimiter::new(
+                config
+                    .property("global.tracing.rate-limit")
+                    .failed("Failed to parse log rate limit"),
+            ),
         },
         queue: QueueCore {
             config: queue_config,
+            concurrency: ConcurrencyLimiter::new(
+                config
+                    .property("queue.outbound.concurrency")
+                    .failed("Failed to parse outbound concurrency")
+                    .unwrap_or(8192),
+            ),
             throttle: DashMap::with_capacity_and_hasher_and_shard_amount(
                 config
                     .property("global.shared-map.capacity")
@@ -148,6 +177,10 @@ async fn main() -> std::io::Result<()> {
                     .unwrap_or(32)
                     .next_power_of_two() as usize,
             ),
+            mx_stats: DashMap::new(),
+            no_pipelining: DashMap::new(),
+            source_ips: DashMap::new(),
+            connections: DashMap::new(),
             tx: queue_tx,
             connectors: TlsConnectors {
                 pki_verify: build_tls_connector(false),
@@ -160,8 +193,14 @@ async fn main() -> std::io::Result<()> {
         },
         mail_auth: mail_auth_config,
         sieve: sieve_config,
+        draining: false.into(),
     });
 
+    // Restore throttle state
+    if let Some(throttle_snapshot) = &throttle_snapshot {
+        throttle_snapshot.restore(&core);
+    }
+
     // Bind ports before dropping privileges
     for server in &config_context.servers {
         for listener in &server.listeners {
@@ -204,8 +243,18 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
-    // Spawn listeners
+    // Spawn throttle snapshots
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    if let Some(throttle_snapshot) = throttle_snapshot {
+        throttle_snapshot.spawn(core.clone(), shutdown_rx.clone());
+    }
+
+    // Spawn load monitor
+    if let Some(load_throttle) = load_throttle {
+        load_throttle.spawn(core.clone(), shutdown_rx.clone());
+    }
+
+    // Spawn listeners
     for server in config_context.servers {
         match server.protocol {
             ServerProtocol::Smtp | ServerProtocol::Lmtp => server
@@ -218,21 +267,45 @@ async fn main() -> std::io::Result<()> {
                 eprintln!("Invalid protocol 'imap' for listener '{}'.", server.id);
                 std::process::exit(0);
             }
+            ServerProtocol::Ldap => {
+                eprintln!("Invalid protocol 'ldap' for listener '{}'.", server.id);
+                std::process::exit(0);
+            }
         }
     }
 
     // Wait for shutdown signal
     #[cfg(not(target_env = "msvc"))]
     {
+        use std::sync::atomic::Ordering;
         use tokio::signal::unix::{signal, SignalKind};
 
         let mut h_term = signal(SignalKind::terminate()).failed("start signal handler");
         let mut h_int = signal(SignalKind::interrupt()).failed("start signal handler");
+        let mut h_usr1 = signal(SignalKind::user_defined1()).failed("start signal handler");
 
-        tokio::select! {
-            _ = h_term.recv() => tracing::debug!("Received SIGTERM."),
-            _ = h_int.recv() => tracing::debug!("Received SIGINT."),
-        };
+        loop {
+            tokio::select! {
+                _ = h_term.recv() => {
+                    tracing::debug!("Received SIGTERM.");
+                    break;
+                },
+                _ = h_int.recv() => {
+                    tracing::debug!("Received SIGINT.");
+                    break;
+                },
+                _ = h_usr1.recv() => {
+                    // Toggle draining, new transactions are refused while it is enabled
+                    let draining = !core.draining.fetch_xor(true, Ordering::Relaxed);
+                    tracing::info!(
+                        context = "server",
+                        event = if draining { "drain-start" } else { "drain-stop" },
+                        "Received SIGUSR1, {} new transactions.",
+                        if draining { "refusing" } else { "accepting" }
+                    );
+                },
+            };
+        }
     }
 
     #[cfg(target_env = "msvc")]
@@ -267,7 +340,17 @@ fn enable_tracing(config: &Config) -> stalwart_smtp::config::Result<Option<Worke
     let env_filter = EnvFilter::builder()
         .parse(format!("stalwart_smtp={}", level))
         .failed("Failed to log level");
-    match config.value("global.tracing.method").unwrap_or_default() {
+    let syslog = config.build_syslog_layer()?;
+    let method = config.value("global.tracing.method").unwrap_or_default();
+    let otel = if !matches!(method, "otel" | "open-telemetry")
+        && (config.value("global.tracing.otel.transport").is_some()
+            || config.value("global.tracing.otel.endpoint").is_some())
+    {
+        Some(build_otel_tracer(config, "global.tracing.otel")?)
+    } else {
+        None
+    };
+    match method {
         "log" => {
             let path = config.value_require("global.tracing.path")?;
             let prefix = config.value_require("global.tracing.prefix")?;
@@ -286,7 +369,9 @@ fn enable_tracing(config: &Config) -> stalwart_smtp::config::Result<Option<Worke
                 tracing_subscriber::FmtSubscriber::builder()
                     .with_env_filter(env_filter)
                     .with_writer(non_blocking)
-                    .finish(),
+                    .finish()
+                    .with(syslog)
+                    .with(otel.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer))),
             )
             .failed("Failed to set subscriber");
             Ok(guard.into())
@@ -295,72 +380,106 @@ fn enable_tracing(config: &Config) -> stalwart_smtp::config::Result<Option<Worke
             tracing::subscriber::set_global_default(
                 tracing_subscriber::FmtSubscriber::builder()
                     .with_env_filter(env_filter)
-                    .finish(),
+                    .finish()
+                    .with(syslog)
+                    .with(otel.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer))),
             )
             .failed("Failed to set subscriber");
 
             Ok(None)
         }
         "otel" | "open-telemetry" => {
-            let tracer = match config.value_require("global.tracing.transport")? {
-                "grpc" => {
-                    let mut exporter = opentelemetry_otlp::new_exporter().tonic();
-                    if let Some(endpoint) = config.value("global.tracing.endpoint") {
-                        exporter = exporter.with_endpoint(endpoint);
-                    }
-                    opentelemetry_otlp::new_pipeline()
-                        .tracing()
-                        .with_exporter(exporter)
-                }
-                "http" => {
-                    let mut headers = HashMap::new();
-                    for (_, value) in config.values("global.tracing.headers") {
-                        if let Some((key, value)) = value.split_once(':') {
-                            headers.insert(key.trim().to_string(), value.trim().to_string());
-                        } else {
-                            return Err(format!("Invalid open-telemetry header {value:?}"));
-                        }
-                    }
-                    let mut exporter = opentelemetry_otlp::new_exporter()
-                        .http()
-                        .with_endpoint(config.value_require("global.tracing.endpoint")?);
-                    if !headers.is_empty() {
-                        exporter = exporter.with_headers(headers);
-                    }
-                    opentelemetry_otlp::new_pipeline()
-                        .tracing()
-                        .with_exporter(exporter)
-                }
-                transport => {
-                    return Err(format!(
-                        "Unsupported open-telemetry transport {transport:?}"
-                    ));
-                }
-            }
-            .with_trace_config(
-                trace::config()
-                    .with_resource(Resource::new(vec![
-                        KeyValue::new(SERVICE_NAME, "stalwart-smtp".to_string()),
-                        KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION").to_string()),
-                    ]))
-                    .with_sampler(Sampler::AlwaysOn),
-            )
-            .install_batch(opentelemetry::runtime::Tokio)
-            .failed("Failed to create tracer");
+            let tracer = build_otel_tracer(config, "global.tracing")?;
 
             tracing::subscriber::set_global_default(
                 tracing_subscriber::Registry::default()
                     .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                    .with(env_filter)
+                    .with(syslog),
+            )
+            .failed("Failed to set subscriber");
+
+            Ok(None)
+        }
+        "syslog" | "" if syslog.is_some() || otel.is_some() => {
+            tracing::subscriber::set_global_default(
+                tracing_subscriber::Registry::default()
+                    .with(otel.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+                    .with(syslog)
                     .with(env_filter),
             )
             .failed("Failed to set subscriber");
 
             Ok(None)
         }
+        "syslog" => Err("Missing property \"global.tracing.syslog.endpoint\".".to_string()),
         _ => Ok(None),
     }
 }
 
+fn build_otel_tracer(config: &Config, prefix: &str) -> stalwart_smtp::config::Result<Tracer> {
+    let sampling_rate = config
+        .property::<f64>(format!("{prefix}.sampling-rate"))?
+        .unwrap_or(1.0);
+    if !(0.0..=1.0).contains(&sampling_rate) {
+        return Err(format!(
+            "Invalid open-telemetry sampling rate {sampling_rate}, expected a value between 0 and 1."
+        ));
+    }
+
+    let tracer = match config.value_require(format!("{prefix}.transport"))? {
+        "grpc" => {
+            let mut exporter = opentelemetry_otlp::new_exporter().tonic();
+            if let Some(endpoint) = config.value(format!("{prefix}.endpoint")) {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+        }
+        "http" => {
+            let mut headers = HashMap::new();
+            for (_, value) in config.values(format!("{prefix}.headers")) {
+                if let Some((key, value)) = value.split_once(':') {
+                    headers.insert(key.trim().to_string(), value.trim().to_string());
+                } else {
+                    return Err(format!("Invalid open-telemetry header {value:?}"));
+                }
+            }
+            let mut exporter = opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(config.value_require(format!("{prefix}.endpoint"))?);
+            if !headers.is_empty() {
+                exporter = exporter.with_headers(headers);
+            }
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+        }
+        transport => {
+            return Err(format!(
+                "Unsupported open-telemetry transport {transport:?}"
+            ));
+        }
+    }
+    .with_trace_config(
+        trace::config()
+            .with_resource(Resource::new(vec![
+                KeyValue::new(SERVICE_NAME, "stalwart-smtp".to_string()),
+                KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION").to_string()),
+            ]))
+            .with_sampler(if sampling_rate < 1.0 {
+                Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sampling_rate)))
+            } else {
+                Sampler::AlwaysOn
+            }),
+    )
+    .install_batch(opentelemetry::runtime::Tokio)
+    .failed("Failed to create tracer");
+
+    Ok(tracer)
+}
+
 fn parse_config() -> Config {
     let mut config_path = None;
     let mut found_param = false;
@@ -21,7 +21,12 @@
  * for more details.
 */
 
-use rustls::Certificate;
+use std::time::SystemTime;
+
+use rustls::{
+    client::{ServerCertVerifier, WebPkiVerifier},
+    Certificate, OwnedTrustAnchor, RootCertStore, ServerName,
+};
 use sha1::Digest;
 use sha2::{Sha256, Sha512};
 use x509_parser::prelude::{FromDer, X509Certificate};
@@ -36,6 +41,16 @@ impl Tlsa {
         span: &tracing::Span,
         hostname: &str,
         certificates: Option<&[Certificate]>,
+    ) -> Result<(), Status<(), Error>> {
+        self.verify_at(span, hostname, certificates, SystemTime::now())
+    }
+
+    fn verify_at(
+        &self,
+        span: &tracing::Span,
+        hostname: &str,
+        certificates: Option<&[Certificate]>,
+        now: SystemTime,
     ) -> Result<(), Status<(), Error>> {
         let certificates = if let Some(certificates) = certificates {
             certificates
@@ -53,8 +68,7 @@ impl Tlsa {
             })));
         };
 
-        let mut matched_end_entity = false;
-        let mut matched_intermediate = false;
+        let mut matched = false;
         'outer: for (pos, der_certificate) in certificates.iter().enumerate() {
             // Parse certificate
             let certificate = match X509Certificate::from_der(der_certificate.as_ref()) {
@@ -118,23 +132,31 @@ impl Tlsa {
                             hash
                         );
 
-                        if is_end_entity {
-                            matched_end_entity = true;
-                            if !self.has_intermediates {
-                                break 'outer;
+                        // DANE-EE(3) matches bypass name and expiry checks (RFC 7671),
+                        // DANE-TA(2) matches require a valid chain to the trust anchor.
+                        if !is_end_entity {
+                            if let Err(err) = verify_pkix(certificates, pos, hostname, now) {
+                                tracing::debug!(
+                                    parent: span,
+                                    context = "dane",
+                                    event = "pkix-failed",
+                                    mx = hostname,
+                                    "Failed to validate chain to TLSA trust anchor: {}",
+                                    err
+                                );
+                                continue;
                             }
-                        } else {
-                            matched_intermediate = true;
-                            break 'outer;
                         }
+
+                        // RFC 7671: a match against any published record is sufficient
+                        matched = true;
+                        break 'outer;
                     }
                 }
             }
         }
 
-        if (self.has_end_entities == matched_end_entity)
-            && (self.has_intermediates == matched_intermediate)
-        {
+        if matched {
             tracing::info!(
                 parent: span,
                 context = "dane",
@@ -159,6 +181,90 @@ impl Tlsa {
     }
 }
 
+// Opportunistic DANE fallback still requires a certificate trusted by the web PKI
+pub fn verify_fallback(
+    span: &tracing::Span,
+    hostname: &str,
+    certificates: Option<&[Certificate]>,
+) -> Result<(), Status<(), Error>> {
+    verify_fallback_at(span, hostname, certificates, SystemTime::now())
+}
+
+fn verify_fallback_at(
+    span: &tracing::Span,
+    hostname: &str,
+    certificates: Option<&[Certificate]>,
+    now: SystemTime,
+) -> Result<(), Status<(), Error>> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    let result = match (
+        certificates.filter(|certificates| !certificates.is_empty()),
+        ServerName::try_from(hostname.trim_end_matches('.')),
+    ) {
+        (Some(certificates), Ok(server_name)) => WebPkiVerifier::new(roots, None)
+            .verify_server_cert(
+                &certificates[0],
+                &certificates[1..],
+                &server_name,
+                &mut std::iter::empty(),
+                &[],
+                now,
+            )
+            .map(|_| ())
+            .map_err(|err| err.to_string()),
+        (None, _) => Err("No certificates were provided by host".to_string()),
+        (_, Err(err)) => Err(err.to_string()),
+    };
+
+    result.map_err(|err| {
+        tracing::info!(
+            parent: span,
+            context = "dane",
+            event = "fallback-pkix-failed",
+            mx = hostname,
+            "Failed to validate certificate after DANE fallback: {}",
+            err
+        );
+        Status::PermanentFailure(Error::TlsError(ErrorDetails {
+            entity: hostname.to_string(),
+            details: format!("Invalid certificate: {err}"),
+        }))
+    })
+}
+
+fn verify_pkix(
+    certificates: &[Certificate],
+    trust_anchor: usize,
+    hostname: &str,
+    now: SystemTime,
+) -> Result<(), rustls::Error> {
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&certificates[trust_anchor])
+        .map_err(|err| rustls::Error::General(format!("{err:?}")))?;
+    let server_name = ServerName::try_from(hostname.trim_end_matches('.'))
+        .map_err(|err| rustls::Error::General(err.to_string()))?;
+
+    WebPkiVerifier::new(roots, None)
+        .verify_server_cert(
+            &certificates[0],
+            &certificates[1..trust_anchor],
+            &server_name,
+            &mut std::iter::empty(),
+            &[],
+            now,
+        )
+        .map(|_| ())
+}
+
 #[cfg(test)]
 mod test {
     use std::{
@@ -167,7 +273,7 @@ mod test {
         io::{BufRead, BufReader},
         num::ParseIntError,
         path::PathBuf,
-        time::{Duration, Instant},
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     };
 
     use mail_auth::{
@@ -179,6 +285,8 @@ mod test {
         Resolver,
     };
     use rustls::Certificate;
+    use sha2::{Digest, Sha256};
+    use x509_parser::prelude::{FromDer, X509Certificate};
 
     use crate::{
         core::Resolvers,
@@ -186,6 +294,8 @@ mod test {
         queue::{Error, ErrorDetails, Status},
     };
 
+    use super::verify_fallback_at;
+
     #[tokio::test]
     async fn dane_test() {
         let conf = ResolverConfig::cloudflare_tls();
@@ -196,11 +306,14 @@ mod test {
         let r = Resolvers {
             dns: Resolver::new_cloudflare().unwrap(),
             dnssec: DnssecResolver {
-                resolver: AsyncResolver::tokio(conf, opts).unwrap(),
+                resolver: AsyncResolver::tokio(conf.clone(), opts).unwrap(),
             },
+            uncached: AsyncResolver::tokio(conf, ResolverOpts::default()).unwrap(),
             cache: crate::core::DnsCache {
                 tlsa: LruCache::with_capacity(10),
                 mta_sts: LruCache::with_capacity(10),
+                mx_negative: LruCache::with_capacity(10),
+                mx_negative_ttl: Duration::from_secs(300),
             },
         };
 
@@ -298,6 +411,184 @@ mod test {
         }
     }
 
+    #[test]
+    fn dane_intermediate_only() {
+        let certs = load_certs("mail.ietf.org", 4);
+        let valid_at = valid_at(&certs[0]);
+        let span = tracing::info_span!("test_span");
+
+        // Only a PKIX-TA intermediate record is published
+        let tlsa = Tlsa {
+            entries: vec![TlsaEntry {
+                is_end_entity: false,
+                is_sha256: true,
+                is_spki: true,
+                data: spki_hash(&certs[1]),
+            }],
+            has_end_entities: false,
+            has_intermediates: true,
+        };
+        assert_eq!(
+            tlsa.verify_at(&span, "mail.ietf.org", Some(&certs), valid_at),
+            Ok(())
+        );
+
+        // A matching intermediate is enough even if the end-entity record does not match
+        let tlsa = Tlsa {
+            entries: vec![
+                TlsaEntry {
+                    is_end_entity: true,
+                    is_sha256: true,
+                    is_spki: true,
+                    data: vec![1, 2, 3],
+                },
+                TlsaEntry {
+                    is_end_entity: false,
+                    is_sha256: true,
+                    is_spki: true,
+                    data: spki_hash(&certs[1]),
+                },
+            ],
+            has_end_entities: true,
+            has_intermediates: true,
+        };
+        assert_eq!(
+            tlsa.verify_at(&span, "mail.ietf.org", Some(&certs), valid_at),
+            Ok(())
+        );
+
+        // No published record matches
+        assert_eq!(
+            tlsa.verify_at(&span, "mail.ietf.org", Some(&certs[2..]), valid_at),
+            Err(Status::PermanentFailure(Error::DaneError(ErrorDetails {
+                entity: "mail.ietf.org".to_string(),
+                details: "No matching certificates found in TLSA records".to_string()
+            })))
+        );
+    }
+
+    #[test]
+    fn dane_usage() {
+        let certs = load_certs("mail.ietf.org", 4);
+        let valid_at = valid_at(&certs[0]);
+        let expired_at = UNIX_EPOCH + Duration::from_secs(4102444800);
+        let span = tracing::info_span!("test_span");
+        let no_match = Err(Status::PermanentFailure(Error::DaneError(ErrorDetails {
+            entity: "mail.example.org".to_string(),
+            details: "No matching certificates found in TLSA records".to_string(),
+        })));
+
+        // DANE-EE(3) matches ignore the certificate name and expiry
+        let tlsa = Tlsa {
+            entries: vec![TlsaEntry {
+                is_end_entity: true,
+                is_sha256: true,
+                is_spki: true,
+                data: spki_hash(&certs[0]),
+            }],
+            has_end_entities: true,
+            has_intermediates: false,
+        };
+        assert_eq!(
+            tlsa.verify_at(&span, "mail.example.org", Some(&certs), expired_at),
+            Ok(())
+        );
+        assert_eq!(
+            tlsa.verify_at(&span, "mail.example.org", Some(&certs[..1]), valid_at),
+            Ok(())
+        );
+
+        // DANE-TA(2) matches require a valid chain to the trust anchor
+        let tlsa = Tlsa {
+            entries: vec![TlsaEntry {
+                is_end_entity: false,
+                is_sha256: true,
+                is_spki: true,
+                data: spki_hash(&certs[1]),
+            }],
+            has_end_entities: false,
+            has_intermediates: true,
+        };
+        assert_eq!(
+            tlsa.verify_at(&span, "mail.ietf.org", Some(&certs), valid_at),
+            Ok(())
+        );
+        assert_eq!(
+            tlsa.verify_at(&span, "mail.example.org", Some(&certs), valid_at),
+            no_match
+        );
+        assert_eq!(
+            tlsa.verify_at(&span, "mail.example.org", Some(&certs), expired_at),
+            no_match
+        );
+    }
+
+    #[test]
+    fn dane_fallback_pkix() {
+        let certs = load_certs("mail.ietf.org", 3);
+        let valid_at = valid_at(&certs[0]);
+        let span = tracing::info_span!("test_span");
+
+        // Certificates chaining to a web PKI root are accepted
+        assert_eq!(
+            verify_fallback_at(&span, "mail.ietf.org", Some(&certs), valid_at),
+            Ok(())
+        );
+
+        // Name mismatches, missing certificates and untrusted issuers are not
+        assert!(matches!(
+            verify_fallback_at(&span, "mail.example.org", Some(&certs), valid_at),
+            Err(Status::PermanentFailure(Error::TlsError(_)))
+        ));
+        assert!(matches!(
+            verify_fallback_at(&span, "mail.ietf.org", None, valid_at),
+            Err(Status::PermanentFailure(Error::TlsError(_)))
+        ));
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources");
+        path.push("tests");
+        path.push("certs");
+        path.push("tls_cert.pem");
+        let self_signed = rustls_pemfile::certs(&mut BufReader::new(File::open(path).unwrap()))
+            .unwrap()
+            .into_iter()
+            .map(Certificate)
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            verify_fallback_at(
+                &span,
+                "mx.foobar.org",
+                Some(&self_signed),
+                valid_at(&self_signed[0])
+            ),
+            Err(Status::PermanentFailure(Error::TlsError(_)))
+        ));
+    }
+
+    fn load_certs(host: &str, num: usize) -> Vec<Certificate> {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources");
+        path.push("tests");
+        path.push("dane");
+        (0..num)
+            .map(|num| {
+                let mut file = path.clone();
+                file.push(format!("{host}.{num}.cert"));
+                Certificate(fs::read(file).unwrap())
+            })
+            .collect()
+    }
+
+    fn spki_hash(cert: &Certificate) -> Vec<u8> {
+        let (_, cert) = X509Certificate::from_der(cert.as_ref()).unwrap();
+        Sha256::digest(cert.public_key().raw).to_vec()
+    }
+
+    fn valid_at(cert: &Certificate) -> SystemTime {
+        let (_, cert) = X509Certificate::from_der(cert.as_ref()).unwrap();
+        UNIX_EPOCH + Duration::from_secs(cert.validity().not_before.timestamp() as u64 + 86400)
+    }
+
     pub fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
         (0..s.len())
             .step_by(2)
@@ -22,7 +22,7 @@
 */
 
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr},
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -31,25 +31,31 @@ use mail_auth::{
     mta_sts::TlsRpt,
     report::tlsrpt::{FailureDetails, ResultType},
 };
-use mail_send::SmtpClient;
-use smtp_proto::MAIL_REQUIRETLS;
+use rand::Rng;
+use smtp_proto::{Response, MAIL_REQUIRETLS};
 
 use crate::{
-    config::{AggregateFrequency, ServerProtocol, TlsStrategy},
-    core::Core,
+    config::{AggregateFrequency, MxSelection, ServerProtocol, TlsStrategy},
+    core::{throttle::ConcurrencyLimiter, Core, QueueCore},
     queue::ErrorDetails,
     reporting::{tls::TlsRptOptions, PolicyType, TlsEvent},
 };
 
 use super::{
+    dane::verify::verify_fallback,
     lookup::ToRemoteHost,
     mta_sts,
-    session::{read_greeting, say_helo, try_start_tls, SessionParams, StartTlsResult},
+    pool::{ConnectionKey, PooledClient, PooledConnection},
+    session::{
+        connect_staggered, read_greeting, say_helo, try_start_tls, verify_tls_strength, BatchItem,
+        SessionParams, StartTlsResult,
+    },
     RemoteHost,
 };
 use crate::queue::{
-    manager::Queue, throttle, DeliveryAttempt, Domain, Error, Event, OnHold, QueueEnvelope,
-    Schedule, Status, WorkerResult,
+    manager::Queue, throttle, DeliveryAttempt, Domain, Error, Event, HostResponse, OnHold,
+    QueueEnvelope, Recipient, Schedule, Status, WorkerResult, RCPT_STATUS_CHANGED,
+    RCPT_VERIFY_PENDING,
 };
 
 impl DeliveryAttempt {
@@ -75,7 +81,30 @@ impl DeliveryAttempt {
             }
         } else {
             // All message recipients expired, do not re-queue. (DSN has been already sent)
-            self.message.remove().await;
+            self.message.remove(&core.queue.config.spool).await;
+            return;
+        }
+
+        // Enforce global outbound concurrency
+        if let Some(in_flight) = core.queue.concurrency.is_allowed() {
+            self.in_flight.push(in_flight);
+        } else {
+            tracing::info!(
+                parent: &self.span,
+                context = "throttle",
+                event = "too-many-requests",
+                max_concurrent = core.queue.concurrency.max_concurrent,
+                "Outbound concurrency limit exceeded."
+            );
+
+            // Save changes to disk
+            self.message.save_changes().await;
+
+            queue.on_hold(OnHold {
+                next_due: self.message.next_event_after(Instant::now()),
+                limiters: vec![core.queue.concurrency.clone()],
+                message: self.message,
+            });
             return;
         }
 
@@ -113,10 +142,75 @@ impl DeliveryAttempt {
             }
         }
 
+        // Collect messages for the same destination to be delivered over a single connection
+        let batch = if let Some(domain) = self.message.batch_domain() {
+            let max_batch = *core
+                .queue
+                .config
+                .max_batch
+                .eval(&QueueEnvelope {
+                    message: self.message.as_ref(),
+                    domain,
+                    mx: "",
+                    remote_ip: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                    local_ip: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                })
+                .await;
+            let candidates = if max_batch > 1 {
+                queue.next_batch(&self.message, max_batch - 1)
+            } else {
+                Vec::new()
+            };
+
+            // Batched messages are subject to the same sender throttles
+            let mut batch = Vec::with_capacity(candidates.len());
+            'next_candidate: for mut message in candidates {
+                let mut in_flight = Vec::new();
+                for throttle in &core.queue.config.throttle.sender {
+                    if let Err(err) = core
+                        .queue
+                        .is_allowed(throttle, message.as_ref(), &mut in_flight, &self.span)
+                        .await
+                    {
+                        message.save_changes().await;
+                        match err {
+                            throttle::Error::Concurrency { limiter } => {
+                                queue.on_hold(OnHold {
+                                    next_due: message.next_event_after(Instant::now()),
+                                    limiters: vec![limiter],
+                                    message,
+                                });
+                            }
+                            throttle::Error::Rate { retry_at } => {
+                                queue.schedule(Schedule {
+                                    due: retry_at,
+                                    inner: message,
+                                });
+                            }
+                        }
+                        continue 'next_candidate;
+                    }
+                }
+                self.in_flight.extend(in_flight);
+                batch.push(message);
+            }
+            batch
+        } else {
+            Vec::new()
+        };
+
         tokio::spawn(async move {
             let queue_config = &core.queue.config;
             let mut on_hold = Vec::new();
             let no_ip = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+            let mut batch = batch
+                .into_iter()
+                .map(|mut message| BatchItem {
+                    recipients: std::mem::take(&mut message.recipients),
+                    message,
+                    status: None,
+                })
+                .collect::<Vec<_>>();
 
             let mut domains = std::mem::take(&mut self.message.domains);
             let mut recipients = std::mem::take(&mut self.message.recipients);
@@ -145,6 +239,105 @@ impl DeliveryAttempt {
                     local_ip: no_ip,
                 };
 
+                // Bypass the DNS cache if the previous attempt failed
+                let refresh_dns = matches!(&domain.status, Status::TemporaryFailure(_))
+                    && *queue_config.refresh_dns.eval(&envelope).await;
+
+                // Verify recipients that were accepted while address verification was unavailable
+                if let Some(lookup) = queue_config.verify_addresses.eval(&envelope).await {
+                    let mut has_pending = false;
+                    let mut has_unverified = false;
+                    for rcpt in recipients.iter_mut().filter(|rcpt| {
+                        rcpt.domain_idx == domain_idx
+                            && matches!(
+                                &rcpt.status,
+                                Status::Scheduled | Status::TemporaryFailure(_)
+                            )
+                    }) {
+                        if rcpt.has_flag(RCPT_VERIFY_PENDING) {
+                            match lookup.contains(&rcpt.address_lcase).await {
+                                Some(true) => {
+                                    rcpt.flags &= !RCPT_VERIFY_PENDING;
+                                    rcpt.flags |= RCPT_STATUS_CHANGED;
+                                }
+                                Some(false) => {
+                                    tracing::info!(
+                                        parent: &span,
+                                        context = "rcpt",
+                                        event = "verify-failed",
+                                        rcpt = rcpt.address,
+                                        "Mailbox does not exist."
+                                    );
+
+                                    rcpt.flags &= !RCPT_VERIFY_PENDING;
+                                    rcpt.flags |= RCPT_STATUS_CHANGED;
+                                    rcpt.status = Status::PermanentFailure(HostResponse {
+                                        hostname: ErrorDetails {
+                                            entity: queue_config
+                                                .hostname
+                                                .eval(&envelope)
+                                                .await
+                                                .to_string(),
+                                            details: format!("RCPT TO:<{}>", rcpt.address),
+                                        },
+                                        response: Response {
+                                            code: 550,
+                                            esc: [5, 1, 2],
+                                            message: "Mailbox does not exist.".to_string(),
+                                        },
+                                    });
+                                    continue;
+                                }
+                                None => {
+                                    tracing::debug!(
+                                        parent: &span,
+                                        context = "rcpt",
+                                        event = "verify-deferred",
+                                        rcpt = rcpt.address,
+                                        "Temporary address verification failure."
+                                    );
+
+                                    rcpt.flags |= RCPT_STATUS_CHANGED;
+                                    rcpt.status = Status::TemporaryFailure(HostResponse {
+                                        hostname: ErrorDetails {
+                                            entity: queue_config
+                                                .hostname
+                                                .eval(&envelope)
+                                                .await
+                                                .to_string(),
+                                            details: format!("RCPT TO:<{}>", rcpt.address),
+                                        },
+                                        response: Response {
+                                            code: 451,
+                                            esc: [4, 4, 3],
+                                            message: "Unable to verify address at this time."
+                                                .to_string(),
+                                        },
+                                    });
+                                    has_unverified = true;
+                                }
+                            }
+                        }
+                        has_pending = true;
+                    }
+
+                    if has_unverified {
+                        // Unverified recipients are never delivered, try again later
+                        domain.set_status(
+                            Status::TemporaryFailure(Error::Io(
+                                "Unable to verify recipient addresses.".to_string(),
+                            )),
+                            queue_config.retry.eval(&envelope).await,
+                            *queue_config.jitter.eval(&envelope).await,
+                        );
+                        continue 'next_domain;
+                    } else if !has_pending {
+                        // All recipients for this domain failed verification
+                        domain.set_status(Status::Completed(()), &[], Duration::ZERO);
+                        continue 'next_domain;
+                    }
+                }
+
                 // Throttle recipient domain
                 let mut in_flight = Vec::new();
                 for throttle in &queue_config.throttle.rcpt {
@@ -157,6 +350,51 @@ impl DeliveryAttempt {
                         continue 'next_domain;
                     }
                 }
+                if let Some(throttle) = queue_config.throttle.pacing.get(&domain.domain) {
+                    if let Err(err) = core
+                        .queue
+                        .is_allowed(throttle, &envelope, &mut in_flight, &span)
+                        .await
+                    {
+                        domain.set_throttle_error(err, &mut on_hold);
+                        continue 'next_domain;
+                    }
+                }
+
+                // Each batched message takes its own throttle and pacing slots
+                'next_item: for item in std::mem::take(&mut batch) {
+                    let item_envelope = QueueEnvelope {
+                        message: item.message.as_ref(),
+                        domain: &domain.domain,
+                        mx: "",
+                        remote_ip: no_ip,
+                        local_ip: no_ip,
+                    };
+                    let mut item_in_flight = Vec::new();
+                    for throttle in queue_config
+                        .throttle
+                        .rcpt
+                        .iter()
+                        .chain(queue_config.throttle.pacing.get(&domain.domain))
+                    {
+                        if let Err(err) = core
+                            .queue
+                            .is_allowed(throttle, &item_envelope, &mut item_in_flight, &span)
+                            .await
+                        {
+                            let mut message = item.message;
+                            let mut item_on_hold = Vec::new();
+                            message.recipients = item.recipients;
+                            message.domains[0].set_throttle_error(err, &mut item_on_hold);
+                            DeliveryAttempt::from(message)
+                                .finish(&core, item_on_hold)
+                                .await;
+                            continue 'next_item;
+                        }
+                    }
+                    in_flight.extend(item_in_flight);
+                    batch.push(item);
+                }
 
                 // Obtain next hop
                 let (mut remote_hosts, is_smtp) =
@@ -275,7 +513,11 @@ impl DeliveryAttempt {
                                     "Failed to retrieve MTA-STS policy: {}",
                                     err
                                 );
-                                domain.set_status(err, queue_config.retry.eval(&envelope).await);
+                                domain.set_status(
+                                    err,
+                                    queue_config.retry.eval(&envelope).await,
+                                    *queue_config.jitter.eval(&envelope).await,
+                                );
                                 continue 'next_domain;
                             } else {
                                 tracing::debug!(
@@ -298,7 +540,7 @@ impl DeliveryAttempt {
                 let mx_list;
                 if is_smtp {
                     // Lookup MX
-                    mx_list = match core.resolvers.dns.mx_lookup(&domain.domain).await {
+                    mx_list = match core.resolvers.mx_lookup(&domain.domain, refresh_dns).await {
                         Ok(mx) => mx,
                         Err(err) => {
                             tracing::info!(
@@ -307,14 +549,23 @@ impl DeliveryAttempt {
                                 event = "mx-lookup-failed",
                                 reason = %err,
                             );
-                            domain.set_status(err, queue_config.retry.eval(&envelope).await);
+                            domain.set_status(
+                                err,
+                                queue_config.retry.eval(&envelope).await,
+                                *queue_config.jitter.eval(&envelope).await,
+                            );
                             continue 'next_domain;
                         }
                     };
 
-                    if let Some(remote_hosts_) = mx_list
-                        .to_remote_hosts(&domain.domain, *queue_config.max_mx.eval(&envelope).await)
-                    {
+                    let weights = (*queue_config.mx_selection.eval(&envelope).await
+                        == MxSelection::Weighted)
+                        .then_some(&core.queue);
+                    if let Some(remote_hosts_) = mx_list.to_remote_hosts(
+                        &domain.domain,
+                        *queue_config.max_mx.eval(&envelope).await,
+                        weights,
+                    ) {
                         remote_hosts = remote_hosts_;
                     } else {
                         tracing::info!(
@@ -328,6 +579,7 @@ impl DeliveryAttempt {
                                 "Domain does not accept messages (null MX)".to_string(),
                             )),
                             queue_config.retry.eval(&envelope).await,
+                            *queue_config.jitter.eval(&envelope).await,
                         );
                         continue 'next_domain;
                     }
@@ -335,6 +587,13 @@ impl DeliveryAttempt {
 
                 // Try delivering message
                 let max_multihomed = *queue_config.max_multihomed.eval(&envelope).await;
+
+                // Only policies in enforce mode require TLS, policies in testing
+                // mode are reported but do not prevent delivery
+                let mta_sts_enforce = mta_sts_policy
+                    .as_ref()
+                    .map_or(false, |policy| policy.enforce());
+
                 let mut last_status = Status::Scheduled;
                 'next_host: for remote_host in &remote_hosts {
                     // Validate MTA-STS
@@ -364,7 +623,7 @@ impl DeliveryAttempt {
                                 "MX not authorized by policy."
                             );
 
-                            if mta_sts_policy.enforce() {
+                            if mta_sts_enforce {
                                 last_status = Status::PermanentFailure(Error::MtaStsError(
                                     format!("MX {:?} not authorized by policy.", envelope.mx),
                                 ));
@@ -375,7 +634,7 @@ impl DeliveryAttempt {
 
                     // Obtain source and remote IPs
                     let (source_ip, remote_ips) = match core
-                        .resolve_host(remote_host, &envelope, max_multihomed)
+                        .resolve_host(remote_host, &envelope, max_multihomed, refresh_dns)
                         .await
                     {
                         Ok(result) => result,
@@ -395,6 +654,8 @@ impl DeliveryAttempt {
 
                     // Update TLS strategy
                     tls_strategy.dane = *queue_config.tls.dane.eval(&envelope).await;
+                    tls_strategy.dane_fallback =
+                        *queue_config.tls.dane_fallback.eval(&envelope).await;
                     tls_strategy.tls = *queue_config.tls.start.eval(&envelope).await;
 
                     // Lookup DANE policy
@@ -405,7 +666,7 @@ impl DeliveryAttempt {
                             .await
                         {
                             Ok(Some(tlsa)) => {
-                                if tlsa.has_end_entities {
+                                if tlsa.has_end_entities || tlsa.has_intermediates {
                                     tracing::debug!(
                                         parent: &span,
                                         context = "dane",
@@ -537,9 +798,120 @@ impl DeliveryAttempt {
                         None
                     };
 
-                    // Try each IP address
+                    // Reuse an idle connection to the same host
+                    let idle_timeout = *queue_config.pool.idle_timeout.eval(&envelope).await;
+                    let bind_interface = queue_config.bind_interface.eval(&envelope).await;
+                    let pool_key = ConnectionKey {
+                        source_ip,
+                        interface: bind_interface.clone(),
+                        hostname: envelope.mx.to_string(),
+                        port: remote_host.port(),
+                    };
                     envelope.local_ip = source_ip.unwrap_or(no_ip);
-                    'next_ip: for remote_ip in remote_ips {
+                    if let Some(idle_timeout) = idle_timeout {
+                        let require_tls = tls_strategy.is_secure_delivery(
+                            (self.message.flags & MAIL_REQUIRETLS) != 0,
+                            mta_sts_enforce,
+                            dane_policy.is_some(),
+                        );
+                        let require_dane =
+                            dane_policy.is_some() && !tls_strategy.allow_dane_fallback();
+                        while let Some(mut conn) = core.queue.take_connection(
+                            &pool_key,
+                            idle_timeout,
+                            require_tls,
+                            require_dane,
+                        ) {
+                            envelope.remote_ip = conn.remote_ip;
+                            let params =
+                                session_params(&core.queue, remote_host, &envelope, &span, true)
+                                    .await;
+                            if !conn.reset(&params).await {
+                                continue;
+                            }
+
+                            tracing::debug!(
+                                parent: &span,
+                                context = "pool",
+                                event = "reuse",
+                                mx = envelope.mx,
+                                remote_ip = %conn.remote_ip,
+                                messages = conn.messages,
+                            );
+
+                            let mut mx_probe = core.queue.mx_probe(envelope.mx);
+                            let (delivery_result, conn) = conn
+                                .deliver(
+                                    &self.message,
+                                    recipients.iter_mut().filter(|r| r.domain_idx == domain_idx),
+                                    &mut batch,
+                                    &params,
+                                )
+                                .await;
+                            if let Some(conn) = conn {
+                                if conn.messages
+                                    < *queue_config.pool.max_messages.eval(&envelope).await
+                                {
+                                    core.pool_connection(pool_key, conn, idle_timeout);
+                                } else {
+                                    conn.quit().await;
+                                }
+                            }
+
+                            // Update status for the current domain and continue with the next one
+                            mx_probe
+                                .success(!matches!(delivery_result, Status::TemporaryFailure(_)));
+                            let over_quota_retry =
+                                queue_config.over_quota_retry.eval(&envelope).await;
+                            domain.set_status(
+                                delivery_result,
+                                if !over_quota_retry.is_empty()
+                                    && is_over_quota(
+                                        recipients.iter().filter(|r| r.domain_idx == domain_idx),
+                                    )
+                                {
+                                    over_quota_retry
+                                } else {
+                                    queue_config.retry.eval(&envelope).await
+                                },
+                                *queue_config.jitter.eval(&envelope).await,
+                            );
+                            continue 'next_domain;
+                        }
+                    }
+
+                    // Limit concurrent connections per source IP
+                    let mut in_flight_source = match (
+                        source_ip,
+                        *queue_config.source_ip.max_connections.eval(&envelope).await,
+                    ) {
+                        (Some(source_ip), Some(max_connections)) => {
+                            let limiter = core.queue.source_ip_limiter(source_ip, max_connections);
+                            if let Some(in_flight) = limiter.is_allowed() {
+                                Some(in_flight)
+                            } else {
+                                tracing::info!(
+                                    parent: &span,
+                                    context = "throttle",
+                                    event = "too-many-connections",
+                                    source_ip = %source_ip,
+                                    max_concurrent = max_connections,
+                                    "Source IP concurrency limit exceeded."
+                                );
+                                domain.set_throttle_error(
+                                    throttle::Error::Concurrency { limiter },
+                                    &mut on_hold,
+                                );
+                                continue 'next_domain;
+                            }
+                        }
+                        _ => None,
+                    };
+
+                    // Try each IP address
+                    let mut remote_ips = remote_ips;
+                    let connect_delay = *queue_config.connect_delay.eval(&envelope).await;
+                    'next_ip: while let Some(&remote_ip) = remote_ips.first() {
                         // Throttle remote host
                         let mut in_flight_host = Vec::new();
                         envelope.remote_ip = remote_ip;
@@ -554,22 +926,21 @@ impl DeliveryAttempt {
                             }
                         }
 
-                        // Connect
-                        let mut smtp_client = match if let Some(ip_addr) = source_ip {
-                            SmtpClient::connect_using(
-                                ip_addr,
-                                SocketAddr::new(remote_ip, remote_host.port()),
-                                *queue_config.timeout.connect.eval(&envelope).await,
-                            )
-                            .await
-                        } else {
-                            SmtpClient::connect(
-                                SocketAddr::new(remote_ip, remote_host.port()),
-                                *queue_config.timeout.connect.eval(&envelope).await,
-                            )
-                            .await
-                        } {
-                            Ok(smtp_client) => {
+                        // Connect, racing the remaining IPs if the first one is slow to respond
+                        let mut mx_probe = core.queue.mx_probe(envelope.mx);
+                        let (remote_ip, mut smtp_client) = match connect_staggered(
+                            source_ip,
+                            bind_interface.as_deref(),
+                            &mut remote_ips,
+                            remote_host.port(),
+                            *queue_config.timeout.connect.eval(&envelope).await,
+                            connect_delay,
+                            &span,
+                            envelope.mx,
+                        )
+                        .await
+                        {
+                            Ok((remote_ip, smtp_client)) => {
                                 tracing::debug!(
                                     parent: &span,
                                     context = "connect",
@@ -580,42 +951,58 @@ impl DeliveryAttempt {
                                     remote_port = remote_host.port(),
                                 );
 
-                                smtp_client
+                                // Throttle the remote host that won the race
+                                if remote_ip != envelope.remote_ip {
+                                    envelope.remote_ip = remote_ip;
+                                    in_flight_host.clear();
+                                    for throttle in &queue_config.throttle.host {
+                                        if let Err(err) = core
+                                            .queue
+                                            .is_allowed(
+                                                throttle,
+                                                &envelope,
+                                                &mut in_flight_host,
+                                                &span,
+                                            )
+                                            .await
+                                        {
+                                            domain.set_throttle_error(err, &mut on_hold);
+                                            continue 'next_domain;
+                                        }
+                                    }
+                                }
+
+                                (remote_ip, smtp_client)
                             }
                             Err(err) => {
-                                tracing::info!(
-                                    parent: &span,
-                                    context = "connect",
-                                    event = "failed",
-                                    mx = envelope.mx,
-                                    reason = %err,
-                                );
                                 last_status = Status::from_smtp_error(envelope.mx, "", err);
                                 continue 'next_ip;
                             }
                         };
 
                         // Obtail session parameters
-                        let params = SessionParams {
-                            span: &span,
-                            credentials: remote_host.credentials(),
-                            is_smtp: remote_host.is_smtp(),
-                            hostname: envelope.mx,
-                            local_hostname: queue_config.hostname.eval(&envelope).await,
-                            timeout_ehlo: *queue_config.timeout.ehlo.eval(&envelope).await,
-                            timeout_mail: *queue_config.timeout.mail.eval(&envelope).await,
-                            timeout_rcpt: *queue_config.timeout.rcpt.eval(&envelope).await,
-                            timeout_data: *queue_config.timeout.data.eval(&envelope).await,
-                        };
-
-                        // Prepare TLS connector
-                        let tls_connector = if !remote_host.allow_invalid_certs() {
-                            &core.queue.connectors.pki_verify
-                        } else {
-                            &core.queue.connectors.dummy_verify
-                        };
+                        let params = session_params(
+                            &core.queue,
+                            remote_host,
+                            &envelope,
+                            &span,
+                            idle_timeout.is_some(),
+                        )
+                        .await;
+
+                        // Prepare TLS connector, presenting a client certificate if configured.
+                        // When a DANE policy is present, the TLSA records decide whether PKIX
+                        // validation is required.
+                        let client_cert = queue_config.tls.certificate.eval(&envelope).await;
+                        let connectors = client_cert.as_deref().unwrap_or(&core.queue.connectors);
+                        let tls_connector =
+                            if !remote_host.allow_invalid_certs() && dane_policy.is_none() {
+                                &connectors.pki_verify
+                            } else {
+                                &connectors.dummy_verify
+                            };
 
-                        let delivery_result = if !remote_host.implicit_tls() {
+                        let (delivery_result, client) = if !remote_host.implicit_tls() {
                             // Read greeting
                             smtp_client.timeout =
                                 *queue_config.timeout.greeting.eval(&envelope).await;
@@ -650,18 +1037,40 @@ impl DeliveryAttempt {
                                 }
                             };
 
+                            // Limit concurrent TLS handshakes
+                            let handshake = if let Some(permit) =
+                                core.tls_handshakes.acquire().await
+                            {
+                                permit
+                            } else {
+                                tracing::info!(
+                                    parent: &span,
+                                    context = "throttle",
+                                    event = "too-many-handshakes",
+                                    mx = envelope.mx,
+                                    max_concurrent = core.tls_handshakes.max_concurrent,
+                                    "Too many concurrent TLS handshakes."
+                                );
+
+                                last_status = Status::TemporaryFailure(Error::ConcurrencyLimited);
+                                continue 'next_host;
+                            };
+
                             // Try starting TLS
                             smtp_client.timeout = *queue_config.timeout.tls.eval(&envelope).await;
-                            match try_start_tls(
+                            let result = try_start_tls(
                                 smtp_client,
                                 tls_connector,
                                 envelope.mx,
                                 &capabilties,
                             )
-                            .await
-                            {
+                            .await;
+                            drop(handshake);
+
+                            match result {
                                 StartTlsResult::Success { smtp_client } => {
                                     // Verify DANE
+                                    let mut dane_failed = false;
                                     if let Some(dane_policy) = &dane_policy {
                                         if let Err(status) = dane_policy.verify(
                                             &span,
@@ -688,13 +1097,60 @@ impl DeliveryAttempt {
                                                 .await;
                                             }
 
+                                            if tls_strategy.allow_dane_fallback() {
+                                                tracing::info!(
+                                                    parent: &span,
+                                                    context = "dane",
+                                                    event = "fallback",
+                                                    mx = envelope.mx,
+                                                    "DANE verification failed, falling back to opportunistic TLS."
+                                                );
+
+                                                // The handshake skipped PKIX validation in favour of DANE
+                                                if !remote_host.allow_invalid_certs() {
+                                                    if let Err(status) = verify_fallback(
+                                                        &span,
+                                                        envelope.mx,
+                                                        smtp_client
+                                                            .tls_connection()
+                                                            .peer_certificates(),
+                                                    ) {
+                                                        last_status = status;
+                                                        continue 'next_host;
+                                                    }
+                                                }
+                                                dane_failed = true;
+                                            } else {
+                                                last_status = status;
+                                                continue 'next_host;
+                                            }
+                                        }
+                                    }
+
+                                    // Enforce minimum TLS strength on secure deliveries
+                                    if tls_strategy.is_secure_delivery(
+                                        (self.message.flags & MAIL_REQUIRETLS) != 0,
+                                        mta_sts_enforce,
+                                        dane_policy.is_some(),
+                                    ) {
+                                        if let Err(status) = verify_tls_strength(
+                                            &span,
+                                            envelope.mx,
+                                            smtp_client.tls_connection(),
+                                            *queue_config.tls.min_version.eval(&envelope).await,
+                                            *queue_config
+                                                .tls
+                                                .min_cipher_strength
+                                                .eval(&envelope)
+                                                .await,
+                                        ) {
                                             last_status = status;
                                             continue 'next_host;
                                         }
                                     }
 
                                     // Report TLS success
-                                    if let Some(tls_report) = &tls_report {
+                                    if let (Some(tls_report), false) = (&tls_report, dane_failed) {
                                         core.schedule_report(TlsEvent {
                                             policy: (&mta_sts_policy, &dane_policy).into(),
                                             domain: envelope.domain.to_string(),
@@ -706,15 +1162,27 @@ impl DeliveryAttempt {
                                     }
 
                                     // Deliver message over TLS
-                                    self.message
+                                    let (status, client) = self
+                                        .message
                                         .deliver(
                                             smtp_client,
                                             recipients
                                                 .iter_mut()
                                                 .filter(|r| r.domain_idx == domain_idx),
+                                            &mut batch,
                                             params,
                                         )
-                                        .await
+                                        .await;
+                                    (
+                                        status,
+                                        client.map(|(client, capabilities)| {
+                                            (
+                                                PooledClient::Tls(client),
+                                                capabilities,
+                                                dane_policy.is_some() && !dane_failed,
+                                            )
+                                        }),
+                                    )
                                 }
                                 StartTlsResult::Unavailable {
                                     response,
@@ -753,23 +1221,32 @@ impl DeliveryAttempt {
 
                                     if tls_strategy.is_tls_required()
                                         || (self.message.flags & MAIL_REQUIRETLS) != 0
-                                        || mta_sts_policy.is_some()
-                                        || dane_policy.is_some()
+                                        || mta_sts_enforce
+                                        || (dane_policy.is_some()
+                                            && !tls_strategy.allow_dane_fallback())
                                     {
                                         last_status =
                                             Status::from_starttls_error(envelope.mx, response);
                                         continue 'next_host;
                                     } else {
                                         // TLS is not required, proceed in plain-text
-                                        self.message
+                                        let (status, client) = self
+                                            .message
                                             .deliver(
                                                 smtp_client,
                                                 recipients
                                                     .iter_mut()
                                                     .filter(|r| r.domain_idx == domain_idx),
+                                                &mut batch,
                                                 params,
                                             )
-                                            .await
+                                            .await;
+                                        (
+                                            status,
+                                            client.map(|(client, capabilities)| {
+                                                (PooledClient::Plain(client), capabilities, false)
+                                            }),
+                                        )
                                     }
                                 }
                                 StartTlsResult::Error { error } => {
@@ -800,29 +1277,57 @@ impl DeliveryAttempt {
                                         })
                                         .await;
                                     }
-                                    last_status = Status::from_tls_error(envelope.mx, error);
+                                    last_status = Status::from_tls_error(
+                                        envelope.mx,
+                                        error,
+                                        client_cert.is_some(),
+                                    );
                                     continue 'next_host;
                                 }
                             }
                         } else {
+                            // Limit concurrent TLS handshakes
+                            let handshake = if let Some(permit) =
+                                core.tls_handshakes.acquire().await
+                            {
+                                permit
+                            } else {
+                                tracing::info!(
+                                    parent: &span,
+                                    context = "throttle",
+                                    event = "too-many-handshakes",
+                                    mx = envelope.mx,
+                                    max_concurrent = core.tls_handshakes.max_concurrent,
+                                    "Too many concurrent TLS handshakes."
+                                );
+
+                                last_status = Status::TemporaryFailure(Error::ConcurrencyLimited);
+                                continue 'next_host;
+                            };
+
                             // Start TLS
                             smtp_client.timeout = *queue_config.timeout.tls.eval(&envelope).await;
-                            let mut smtp_client =
-                                match smtp_client.into_tls(tls_connector, envelope.mx).await {
-                                    Ok(smtp_client) => smtp_client,
-                                    Err(error) => {
-                                        tracing::info!(
-                                            parent: &span,
-                                            context = "tls",
-                                            event = "failed",
-                                            mx = envelope.mx,
-                                            error = %error,
-                                        );
+                            let result = smtp_client.into_tls(tls_connector, envelope.mx).await;
+                            drop(handshake);
+                            let mut smtp_client = match result {
+                                Ok(smtp_client) => smtp_client,
+                                Err(error) => {
+                                    tracing::info!(
+                                        parent: &span,
+                                        context = "tls",
+                                        event = "failed",
+                                        mx = envelope.mx,
+                                        error = %error,
+                                    );
 
-                                        last_status = Status::from_tls_error(envelope.mx, error);
-                                        continue 'next_host;
-                                    }
-                                };
+                                    last_status = Status::from_tls_error(
+                                        envelope.mx,
+                                        error,
+                                        client_cert.is_some(),
+                                    );
+                                    continue 'next_host;
+                                }
+                            };
 
                             // Read greeting
                             smtp_client.timeout =
@@ -842,92 +1347,178 @@ impl DeliveryAttempt {
                             }
 
                             // Deliver message
-                            self.message
+                            let (status, client) = self
+                                .message
                                 .deliver(
                                     smtp_client,
                                     recipients.iter_mut().filter(|r| r.domain_idx == domain_idx),
+                                    &mut batch,
                                     params,
                                 )
-                                .await
+                                .await;
+                            (
+                                status,
+                                client.map(|(client, capabilities)| {
+                                    (PooledClient::Tls(client), capabilities, false)
+                                }),
+                            )
                         };
 
+                        // Keep the connection open for further deliveries
+                        if let (Some((client, capabilities, dane_verified)), Some(idle_timeout)) =
+                            (client, idle_timeout)
+                        {
+                            in_flight_host.extend(in_flight_source.take());
+                            let mut conn = PooledConnection::new(
+                                client,
+                                capabilities,
+                                remote_ip,
+                                dane_verified,
+                                in_flight_host,
+                            );
+                            conn.messages =
+                                1 + batch.iter().filter(|item| item.status.is_some()).count();
+                            if conn.messages < *queue_config.pool.max_messages.eval(&envelope).await
+                            {
+                                core.pool_connection(pool_key, conn, idle_timeout);
+                            } else {
+                                conn.quit().await;
+                            }
+                        }
+
                         // Update status for the current domain and continue with the next one
-                        domain
-                            .set_status(delivery_result, queue_config.retry.eval(&envelope).await);
+                        mx_probe.success(!matches!(delivery_result, Status::TemporaryFailure(_)));
+                        let over_quota_retry = queue_config.over_quota_retry.eval(&envelope).await;
+                        domain.set_status(
+                            delivery_result,
+                            if !over_quota_retry.is_empty()
+                                && is_over_quota(
+                                    recipients.iter().filter(|r| r.domain_idx == domain_idx),
+                                )
+                            {
+                                over_quota_retry
+                            } else {
+                                queue_config.retry.eval(&envelope).await
+                            },
+                            *queue_config.jitter.eval(&envelope).await,
+                        );
                         continue 'next_domain;
                     }
                 }
 
                 // Update status
-                domain.set_status(last_status, queue_config.retry.eval(&envelope).await);
-            }
-            self.message.domains = domains;
-            self.message.recipients = recipients;
-
-            // Send Delivery Status Notifications
-            core.queue.send_dsn(&mut self).await;
-
-            // Notify queue manager
-            let span = self.span;
-            let result = if !on_hold.is_empty() {
-                // Release quota for completed deliveries
-                self.message.release_quota();
-
-                // Save changes to disk
-                self.message.save_changes().await;
-
-                tracing::info!(
-                    parent: &span,
-                    context = "queue",
-                    event = "requeue",
-                    reason = "concurrency-limited",
-                    "Too many outbound concurrenct connections, message moved to on-hold queue."
+                domain.set_status(
+                    last_status,
+                    queue_config.retry.eval(&envelope).await,
+                    *queue_config.jitter.eval(&envelope).await,
                 );
+            }
+            // Update batched messages
+            for item in batch {
+                let mut message = item.message;
+                message.recipients = item.recipients;
+                if let Some(status) = item.status {
+                    let envelope = QueueEnvelope {
+                        message: message.as_ref(),
+                        domain: &message.domains[0].domain,
+                        mx: "",
+                        remote_ip: no_ip,
+                        local_ip: no_ip,
+                    };
+                    let over_quota_retry = queue_config.over_quota_retry.eval(&envelope).await;
+                    let schedule = if !over_quota_retry.is_empty()
+                        && is_over_quota(message.recipients.iter())
+                    {
+                        over_quota_retry
+                    } else {
+                        queue_config.retry.eval(&envelope).await
+                    };
+                    let jitter = *queue_config.jitter.eval(&envelope).await;
+                    message.domains[0].set_status(status, schedule, jitter);
+                } else if let Some(primary) = domains.first() {
+                    // Not attempted, wait until the next retry of the batch
+                    let domain = &mut message.domains[0];
+                    if primary.retry.due > domain.retry.due {
+                        domain.retry.due = primary.retry.due;
+                        domain.changed = true;
+                    }
+                }
 
-                WorkerResult::OnHold(OnHold {
-                    next_due: self.message.next_event_after(Instant::now()),
-                    limiters: on_hold,
-                    message: self.message,
-                })
-            } else if let Some(due) = self.message.next_event() {
-                // Release quota for completed deliveries
-                self.message.release_quota();
-
-                // Save changes to disk
-                self.message.save_changes().await;
+                DeliveryAttempt::from(message)
+                    .finish(&core, Vec::new())
+                    .await;
+            }
 
-                tracing::info!(
-                    parent: &span,
-                    context = "queue",
-                    event = "requeue",
-                    reason = "delivery-incomplete",
-                    "Delivery was not possible, message re-queued for delivery."
-                );
+            self.message.domains = domains;
+            self.message.recipients = recipients;
+            self.finish(&core, on_hold).await;
+        });
+    }
 
-                WorkerResult::Retry(Schedule {
-                    due,
-                    inner: self.message,
-                })
-            } else {
-                // Delete message from queue
-                self.message.remove().await;
-
-                tracing::info!(
-                    parent: &span,
-                    context = "queue",
-                    event = "completed",
-                    "Delivery completed."
-                );
+    pub async fn finish(mut self, core: &Core, on_hold: Vec<ConcurrencyLimiter>) {
+        // Send Delivery Status Notifications
+        core.queue.send_dsn(&mut self).await;
 
-                WorkerResult::Done
-            };
-            if core.queue.tx.send(Event::Done(result)).await.is_err() {
-                tracing::warn!(
-                    parent: &span,
-                    "Channel closed while trying to notify queue manager."
-                );
-            }
-        });
+        // Notify queue manager
+        let span = self.span;
+        let result = if !on_hold.is_empty() {
+            // Release quota for completed deliveries
+            self.message.release_quota();
+
+            // Save changes to disk
+            self.message.save_changes().await;
+
+            tracing::info!(
+                parent: &span,
+                context = "queue",
+                event = "requeue",
+                reason = "concurrency-limited",
+                "Too many outbound concurrenct connections, message moved to on-hold queue."
+            );
+
+            WorkerResult::OnHold(OnHold {
+                next_due: self.message.next_event_after(Instant::now()),
+                limiters: on_hold,
+                message: self.message,
+            })
+        } else if let Some(due) = self.message.next_event() {
+            // Release quota for completed deliveries
+            self.message.release_quota();
+
+            // Save changes to disk
+            self.message.save_changes().await;
+
+            tracing::info!(
+                parent: &span,
+                context = "queue",
+                event = "requeue",
+                reason = "delivery-incomplete",
+                "Delivery was not possible, message re-queued for delivery."
+            );
+
+            WorkerResult::Retry(Schedule {
+                due,
+                inner: self.message,
+            })
+        } else {
+            // Delete message from queue
+            self.message.remove(&core.queue.config.spool).await;
+
+            tracing::info!(
+                parent: &span,
+                context = "queue",
+                event = "completed",
+                "Delivery completed."
+            );
+
+            WorkerResult::Done
+        };
+        if core.queue.tx.send(Event::Done(result)).await.is_err() {
+            tracing::warn!(
+                parent: &span,
+                "Channel closed while trying to notify queue manager."
+            );
+        }
     }
 
     /// Marks as failed all domains that reached their expiration time
@@ -989,20 +1580,78 @@ impl DeliveryAttempt {
 }
 
 impl Domain {
-    pub fn set_status(&mut self, status: impl Into<Status<(), Error>>, schedule: &[Duration]) {
+    pub fn set_status(
+        &mut self,
+        status: impl Into<Status<(), Error>>,
+        schedule: &[Duration],
+        jitter: Duration,
+    ) {
         self.status = status.into();
         self.changed = true;
         if matches!(
             &self.status,
             Status::TemporaryFailure(_) | Status::Scheduled
         ) {
-            self.retry(schedule);
+            self.retry(schedule, jitter);
         }
     }
 
-    pub fn retry(&mut self, schedule: &[Duration]) {
-        self.retry.due =
-            Instant::now() + schedule[std::cmp::min(self.retry.inner as usize, schedule.len() - 1)];
+    pub fn retry(&mut self, schedule: &[Duration], jitter: Duration) {
+        let mut delay = schedule[std::cmp::min(self.retry.inner as usize, schedule.len() - 1)];
+
+        // Spread out retries of messages that failed at the same time
+        if !jitter.is_zero() {
+            let jitter = jitter.as_millis() as u64;
+            delay = (delay + Duration::from_millis(rand::thread_rng().gen_range(0..=jitter * 2)))
+                .saturating_sub(Duration::from_millis(jitter));
+        }
+
+        self.retry.due = Instant::now() + delay;
         self.retry.inner += 1;
     }
 }
+
+async fn session_params<'x>(
+    queue: &'x QueueCore,
+    remote_host: &'x RemoteHost<'_>,
+    envelope: &QueueEnvelope<'x>,
+    span: &'x tracing::Span,
+    keep_alive: bool,
+) -> SessionParams<'x> {
+    let queue_config = &queue.config;
+    SessionParams {
+        span,
+        credentials: remote_host.credentials(),
+        is_smtp: remote_host.is_smtp(),
+        hostname: envelope.mx,
+        local_hostname: queue_config.hostname.eval(envelope).await,
+        timeout_ehlo: *queue_config.timeout.ehlo.eval(envelope).await,
+        timeout_mail: *queue_config.timeout.mail.eval(envelope).await,
+        timeout_rcpt: *queue_config.timeout.rcpt.eval(envelope).await,
+        timeout_data: *queue_config.timeout.data.eval(envelope).await,
+        pipelining: *queue_config.pipelining.eval(envelope).await
+            && queue.has_pipelining(envelope.mx),
+        keep_alive,
+        retry_over_quota: !queue_config
+            .over_quota_retry
+            .eval(envelope)
+            .await
+            .is_empty(),
+        spool: &queue_config.spool,
+        encryption: queue_config.encryption.as_ref(),
+        queue,
+    }
+}
+
+// Pending recipients that are all over quota are retried using a gentler schedule
+fn is_over_quota<'x>(recipients: impl Iterator<Item = &'x Recipient>) -> bool {
+    let mut has_over_quota = false;
+    for rcpt in recipients {
+        match &rcpt.status {
+            Status::TemporaryFailure(_) if rcpt.is_over_quota() => has_over_quota = true,
+            Status::TemporaryFailure(_) | Status::Scheduled => return false,
+            _ => (),
+        }
+    }
+    has_over_quota
+}
@@ -21,13 +21,17 @@
  * for more details.
 */
 
-use std::net::IpAddr;
+use std::{
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use mail_auth::MX;
+use mail_auth::{common::lru::DnsCache, IpLookupStrategy, MX};
 use rand::{seq::SliceRandom, Rng};
 
 use crate::{
-    core::{Core, Envelope},
+    core::{throttle::ConcurrencyLimiter, Core, Envelope, MxStats, QueueCore, Resolvers},
     queue::{Error, ErrorDetails, Status},
 };
 
@@ -39,62 +43,66 @@ impl Core {
         remote_host: &RemoteHost<'_>,
         envelope: &impl Envelope,
         max_multihomed: usize,
+        refresh_dns: bool,
     ) -> Result<(Option<IpAddr>, Vec<IpAddr>), Status<(), Error>> {
-        let remote_ips = self
-            .resolvers
-            .dns
-            .ip_lookup(
-                remote_host.fqdn_hostname().as_ref(),
-                *self.queue.config.ip_strategy.eval(envelope).await,
-                max_multihomed,
-            )
-            .await
-            .map_err(|err| {
-                if let mail_auth::Error::DnsRecordNotFound(_) = &err {
-                    Status::PermanentFailure(Error::ConnectionError(ErrorDetails {
-                        entity: remote_host.hostname().to_string(),
-                        details: "record not found for MX".to_string(),
-                    }))
-                } else {
-                    Status::TemporaryFailure(Error::ConnectionError(ErrorDetails {
-                        entity: remote_host.hostname().to_string(),
-                        details: format!("lookup error: {err}"),
-                    }))
-                }
-            })?;
+        let hostname = remote_host.fqdn_hostname();
+        let strategy = *self.queue.config.ip_strategy.eval(envelope).await;
+        let remote_ips = if refresh_dns {
+            self.resolvers
+                .ip_lookup_uncached(hostname.as_ref(), strategy, max_multihomed)
+                .await
+        } else {
+            self.resolvers
+                .dns
+                .ip_lookup(hostname.as_ref(), strategy, max_multihomed)
+                .await
+        }
+        .map_err(|err| {
+            if let mail_auth::Error::DnsRecordNotFound(_) = &err {
+                Status::PermanentFailure(Error::ConnectionError(ErrorDetails {
+                    entity: remote_host.hostname().to_string(),
+                    details: "record not found for MX".to_string(),
+                }))
+            } else {
+                Status::TemporaryFailure(Error::ConnectionError(ErrorDetails {
+                    entity: remote_host.hostname().to_string(),
+                    details: format!("lookup error: {err}"),
+                }))
+            }
+        })?;
 
         if let Some(remote_ip) = remote_ips.first() {
-            let mut source_ip = None;
-
-            if remote_ip.is_ipv4() {
-                let source_ips = self.queue.config.source_ip.ipv4.eval(envelope).await;
-                match source_ips.len().cmp(&1) {
-                    std::cmp::Ordering::Equal => {
-                        source_ip = IpAddr::from(*source_ips.first().unwrap()).into();
-                    }
-                    std::cmp::Ordering::Greater => {
-                        source_ip = IpAddr::from(
-                            source_ips[rand::thread_rng().gen_range(0..source_ips.len())],
-                        )
-                        .into();
-                    }
-                    std::cmp::Ordering::Less => (),
-                }
+            let source_ips = if remote_ip.is_ipv4() {
+                self.queue
+                    .config
+                    .source_ip
+                    .ipv4
+                    .eval(envelope)
+                    .await
+                    .iter()
+                    .map(|ip| IpAddr::from(*ip))
+                    .collect::<Vec<_>>()
             } else {
-                let source_ips = self.queue.config.source_ip.ipv6.eval(envelope).await;
-                match source_ips.len().cmp(&1) {
-                    std::cmp::Ordering::Equal => {
-                        source_ip = IpAddr::from(*source_ips.first().unwrap()).into();
-                    }
-                    std::cmp::Ordering::Greater => {
-                        source_ip = IpAddr::from(
-                            source_ips[rand::thread_rng().gen_range(0..source_ips.len())],
-                        )
-                        .into();
-                    }
-                    std::cmp::Ordering::Less => (),
-                }
-            }
+                self.queue
+                    .config
+                    .source_ip
+                    .ipv6
+                    .eval(envelope)
+                    .await
+                    .iter()
+                    .map(|ip| IpAddr::from(*ip))
+                    .collect::<Vec<_>>()
+            };
+            let source_ip = self.queue.select_source_ip(
+                source_ips,
+                *self
+                    .queue
+                    .config
+                    .source_ip
+                    .max_connections
+                    .eval(envelope)
+                    .await,
+            );
 
             Ok((source_ip, remote_ips))
         } else {
@@ -106,11 +114,222 @@ impl Core {
     }
 }
 
+impl Resolvers {
+    pub async fn mx_lookup(
+        &self,
+        domain: &str,
+        refresh_dns: bool,
+    ) -> mail_auth::Result<Arc<Vec<MX>>> {
+        // Fail fast for domains recently found not to exist, unless retrying after a failure
+        if !refresh_dns {
+            if let Some(code) = self.cache.mx_negative.get(domain) {
+                return Err(mail_auth::Error::DnsRecordNotFound(code));
+            }
+        }
+
+        let result = if refresh_dns {
+            self.mx_lookup_uncached(domain).await
+        } else {
+            self.dns.mx_lookup(domain).await
+        };
+        if let Err(mail_auth::Error::DnsRecordNotFound(code)) = &result {
+            self.cache.mx_negative.insert(
+                domain.to_string(),
+                *code,
+                Instant::now() + self.cache.mx_negative_ttl,
+            );
+        }
+
+        result
+    }
+
+    pub async fn mx_lookup_uncached(&self, domain: &str) -> mail_auth::Result<Arc<Vec<MX>>> {
+        #[cfg(any(test, feature = "test"))]
+        if true {
+            return self.dns.mx_lookup(domain).await;
+        }
+
+        let mx_lookup = self.uncached.mx_lookup(domain).await?;
+        let mut records: Vec<MX> = Vec::new();
+        for mx in mx_lookup.iter() {
+            let preference = mx.preference();
+            let exchange = mx.exchange().to_lowercase().to_string();
+            if let Some(record) = records.iter_mut().find(|r| r.preference == preference) {
+                record.exchanges.push(exchange);
+            } else {
+                records.push(MX {
+                    exchanges: vec![exchange],
+                    preference,
+                });
+            }
+        }
+        records.sort_unstable_by(|a, b| a.preference.cmp(&b.preference));
+
+        Ok(Arc::new(records))
+    }
+
+    pub async fn ip_lookup_uncached(
+        &self,
+        hostname: &str,
+        strategy: IpLookupStrategy,
+        max_results: usize,
+    ) -> mail_auth::Result<Vec<IpAddr>> {
+        #[cfg(any(test, feature = "test"))]
+        if true {
+            return self.dns.ip_lookup(hostname, strategy, max_results).await;
+        }
+
+        let (first_v6, fallback) = match strategy {
+            IpLookupStrategy::Ipv4Only => (false, false),
+            IpLookupStrategy::Ipv6Only => (true, false),
+            IpLookupStrategy::Ipv4thenIpv6 => (false, true),
+            IpLookupStrategy::Ipv6thenIpv4 => (true, true),
+        };
+        let result = self
+            .ip_lookup_uncached_(hostname, first_v6, max_results)
+            .await;
+        if fallback && !matches!(&result, Ok(ips) if !ips.is_empty()) {
+            self.ip_lookup_uncached_(hostname, !first_v6, max_results)
+                .await
+        } else {
+            result
+        }
+    }
+
+    async fn ip_lookup_uncached_(
+        &self,
+        hostname: &str,
+        ipv6: bool,
+        max_results: usize,
+    ) -> mail_auth::Result<Vec<IpAddr>> {
+        Ok(if ipv6 {
+            self.uncached
+                .ipv6_lookup(hostname)
+                .await?
+                .iter()
+                .take(max_results)
+                .map(|ip| IpAddr::from(*ip))
+                .collect()
+        } else {
+            self.uncached
+                .ipv4_lookup(hostname)
+                .await?
+                .iter()
+                .take(max_results)
+                .map(|ip| IpAddr::from(*ip))
+                .collect()
+        })
+    }
+}
+
+const MX_STATS_ALPHA: f64 = 0.3;
+const MX_STATS_EXPIRY: Duration = Duration::from_secs(3600);
+const MX_MIN_WEIGHT: f64 = 0.05;
+
+impl QueueCore {
+    pub fn mx_weight(&self, hostname: &str) -> f64 {
+        self.mx_stats
+            .get(hostname)
+            .map_or(1.0, |stats| stats.success_rate / (1.0 + stats.latency))
+            .max(MX_MIN_WEIGHT)
+    }
+
+    pub fn update_mx_stats(&self, hostname: &str, success: bool, latency: Duration) {
+        let success = if success { 1.0 } else { 0.0 };
+        let latency = latency.as_secs_f64();
+        let expires = Instant::now() + MX_STATS_EXPIRY;
+        self.mx_stats
+            .entry(hostname.to_string())
+            .and_modify(|stats| {
+                stats.success_rate += MX_STATS_ALPHA * (success - stats.success_rate);
+                stats.latency += MX_STATS_ALPHA * (latency - stats.latency);
+                stats.expires = expires;
+            })
+            .or_insert(MxStats {
+                success_rate: success,
+                latency,
+                expires,
+            });
+    }
+
+    pub fn has_pipelining(&self, hostname: &str) -> bool {
+        self.no_pipelining
+            .get(hostname)
+            .map_or(true, |expires| *expires <= Instant::now())
+    }
+
+    pub fn disable_pipelining(&self, hostname: &str) {
+        self.no_pipelining
+            .insert(hostname.to_string(), Instant::now() + MX_STATS_EXPIRY);
+    }
+
+    pub fn select_source_ip(
+        &self,
+        mut source_ips: Vec<IpAddr>,
+        max_connections: Option<u64>,
+    ) -> Option<IpAddr> {
+        if source_ips.len() > 1 {
+            source_ips.shuffle(&mut rand::thread_rng());
+        }
+
+        // Prefer source IPs that have not reached their connection limit
+        if let Some(max_connections) = max_connections {
+            if let Some(source_ip) = source_ips.iter().find(|ip| {
+                self.source_ip_limiter(**ip, max_connections)
+                    .check_is_allowed()
+            }) {
+                return Some(*source_ip);
+            }
+        }
+
+        source_ips.first().copied()
+    }
+
+    pub fn source_ip_limiter(&self, source_ip: IpAddr, max_connections: u64) -> ConcurrencyLimiter {
+        let mut limiter = self
+            .source_ips
+            .entry(source_ip)
+            .or_insert_with(|| ConcurrencyLimiter::new(max_connections));
+        limiter.max_concurrent = max_connections;
+        limiter.clone()
+    }
+
+    pub fn mx_probe<'x>(&'x self, hostname: &'x str) -> MxProbe<'x> {
+        MxProbe {
+            core: self,
+            hostname,
+            started: Instant::now(),
+            success: false,
+        }
+    }
+}
+
+pub struct MxProbe<'x> {
+    core: &'x QueueCore,
+    hostname: &'x str,
+    started: Instant,
+    success: bool,
+}
+
+impl MxProbe<'_> {
+    pub fn success(&mut self, success: bool) {
+        self.success = success;
+    }
+}
+
+impl Drop for MxProbe<'_> {
+    fn drop(&mut self) {
+        self.core
+            .update_mx_stats(self.hostname, self.success, self.started.elapsed());
+    }
+}
+
 pub(super) trait ToRemoteHost {
     fn to_remote_hosts<'x, 'y: 'x>(
         &'x self,
         domain: &'y str,
         max_mx: usize,
+        weights: Option<&QueueCore>,
     ) -> Option<Vec<RemoteHost<'_>>>;
 }
 
@@ -119,6 +338,7 @@ impl ToRemoteHost for Vec<MX> {
         &'x self,
         domain: &'y str,
         max_mx: usize,
+        weights: Option<&QueueCore>,
     ) -> Option<Vec<RemoteHost<'_>>> {
         if !self.is_empty() {
             // Obtain max number of MX hosts to process
@@ -127,7 +347,19 @@ impl ToRemoteHost for Vec<MX> {
             'outer: for mx in self.iter() {
                 if mx.exchanges.len() > 1 {
                     let mut slice = mx.exchanges.iter().collect::<Vec<_>>();
-                    slice.shuffle(&mut rand::thread_rng());
+                    if let Some(core) = weights {
+                        // Weighted random order, hosts with a poor track record are still
+                        // tried first once in a while
+                        let mut rng = rand::thread_rng();
+                        let mut keyed = slice
+                            .into_iter()
+                            .map(|host| (rng.gen::<f64>().powf(1.0 / core.mx_weight(host)), host))
+                            .collect::<Vec<_>>();
+                        keyed.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+                        slice = keyed.into_iter().map(|(_, host)| host).collect();
+                    } else {
+                        slice.shuffle(&mut rand::thread_rng());
+                    }
                     for remote_host in slice {
                         remote_hosts.push(RemoteHost::MX(remote_host.as_str()));
                         if remote_hosts.len() == max_mx {
@@ -158,9 +390,13 @@ impl ToRemoteHost for Vec<MX> {
 mod tests {
     use std::time::{Duration, Instant};
 
-    use mail_auth::{IpLookupStrategy, MX};
+    use mail_auth::{trust_dns_resolver::proto::op::ResponseCode, IpLookupStrategy, MX};
 
-    use crate::{config::IfBlock, core::Core, outbound::RemoteHost};
+    use crate::{
+        config::IfBlock,
+        core::{Core, QueueCore},
+        outbound::RemoteHost,
+    };
 
     use super::ToRemoteHost;
 
@@ -198,7 +434,7 @@ mod tests {
         // Ipv4 strategy
         core.queue.config.ip_strategy = IfBlock::new(IpLookupStrategy::Ipv4thenIpv6);
         let (source_ips, remote_ips) = core
-            .resolve_host(&RemoteHost::MX("mx.foobar.org"), &"envelope", 2)
+            .resolve_host(&RemoteHost::MX("mx.foobar.org"), &"envelope", 2, false)
             .await
             .unwrap();
         assert!(ipv4.contains(&match source_ips.unwrap() {
@@ -210,7 +446,7 @@ mod tests {
         // Ipv6 strategy
         core.queue.config.ip_strategy = IfBlock::new(IpLookupStrategy::Ipv6thenIpv4);
         let (source_ips, remote_ips) = core
-            .resolve_host(&RemoteHost::MX("mx.foobar.org"), &"envelope", 2)
+            .resolve_host(&RemoteHost::MX("mx.foobar.org"), &"envelope", 2, false)
             .await
             .unwrap();
         assert!(ipv6.contains(&match source_ips.unwrap() {
@@ -220,6 +456,81 @@ mod tests {
         assert!(remote_ips.contains(&"e:f::a".parse().unwrap()));
     }
 
+    #[tokio::test]
+    async fn lookup_mx_negative() {
+        let core = Core::test();
+        core.resolvers.dns.mx_add(
+            "unknown.org",
+            vec![MX {
+                exchanges: vec!["mx.unknown.org".to_string()],
+                preference: 10,
+            }],
+            Instant::now() + Duration::from_secs(10),
+        );
+        core.resolvers.cache.mx_negative.insert(
+            "unknown.org".to_string(),
+            ResponseCode::NXDomain,
+            Instant::now() + Duration::from_millis(500),
+        );
+
+        // Cached failures do not hit the resolver
+        assert!(matches!(
+            core.resolvers.mx_lookup("unknown.org", false).await,
+            Err(mail_auth::Error::DnsRecordNotFound(ResponseCode::NXDomain))
+        ));
+
+        // Retries after a failure bypass the negative cache
+        assert_eq!(
+            core.resolvers
+                .mx_lookup("unknown.org", true)
+                .await
+                .unwrap()
+                .first()
+                .unwrap()
+                .exchanges,
+            vec!["mx.unknown.org".to_string()]
+        );
+
+        // Negative entries expire
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(
+            core.resolvers
+                .mx_lookup("unknown.org", false)
+                .await
+                .unwrap()
+                .first()
+                .unwrap()
+                .exchanges,
+            vec!["mx.unknown.org".to_string()]
+        );
+    }
+
+    #[test]
+    fn source_ip_limit() {
+        let core = QueueCore::test();
+        let ip1: std::net::IpAddr = "10.0.0.1".parse().unwrap();
+        let ip2: std::net::IpAddr = "10.0.0.2".parse().unwrap();
+
+        // Saturated source IPs are skipped
+        let in_flight = core.source_ip_limiter(ip1, 1).is_allowed().unwrap();
+        for _ in 0..10 {
+            assert_eq!(core.select_source_ip(vec![ip1, ip2], Some(1)), Some(ip2));
+        }
+        let in_flight2 = core.source_ip_limiter(ip2, 1).is_allowed().unwrap();
+        assert!(core.source_ip_limiter(ip2, 1).is_allowed().is_none());
+        assert!(core.select_source_ip(vec![ip1, ip2], Some(1)).is_some());
+
+        // Limits are released once the connection is closed
+        drop(in_flight);
+        assert_eq!(core.select_source_ip(vec![ip1, ip2], Some(1)), Some(ip1));
+        drop(in_flight2);
+        assert!(core.source_ip_limiter(ip2, 1).is_allowed().is_some());
+
+        // No limit
+        assert_eq!(core.select_source_ip(vec![], Some(1)), None);
+        assert_eq!(core.select_source_ip(vec![ip1], None), Some(ip1));
+    }
+
     #[test]
     fn to_remote_hosts() {
         let mx = vec![
@@ -245,7 +556,7 @@ mod tests {
                 preference: 10,
             },
         ];
-        let hosts = mx.to_remote_hosts("domain", 7).unwrap();
+        let hosts = mx.to_remote_hosts("domain", 7, None).unwrap();
         assert_eq!(hosts.len(), 7);
         for host in hosts {
             if let RemoteHost::MX(host) = host {
@@ -256,6 +567,44 @@ mod tests {
             exchanges: vec![".".to_string()],
             preference: 0,
         }];
-        assert!(mx.to_remote_hosts("domain", 10).is_none());
+        assert!(mx.to_remote_hosts("domain", 10, None).is_none());
+    }
+
+    #[test]
+    fn to_remote_hosts_weighted() {
+        let mx = vec![MX {
+            exchanges: vec!["mx1".to_string(), "mx2".to_string(), "mx3".to_string()],
+            preference: 10,
+        }];
+        let core = QueueCore::test();
+        for _ in 0..10 {
+            core.update_mx_stats("mx1", false, Duration::from_secs(30));
+            core.update_mx_stats("mx2", true, Duration::from_millis(100));
+            core.update_mx_stats("mx3", true, Duration::from_secs(5));
+        }
+        assert!(core.mx_weight("mx2") > core.mx_weight("mx3"));
+        assert!(core.mx_weight("mx3") > core.mx_weight("mx1"));
+        assert_eq!(core.mx_weight("mx1"), super::MX_MIN_WEIGHT);
+
+        // Fast and reliable hosts should be preferred, others are still probed
+        let mut first = [0; 3];
+        for _ in 0..1000 {
+            let hosts = mx.to_remote_hosts("domain", 3, Some(&core)).unwrap();
+            assert_eq!(hosts.len(), 3);
+            if let RemoteHost::MX(host) = &hosts[0] {
+                first[(host.as_bytes()[2] - b'1') as usize] += 1;
+            }
+        }
+        assert!(first[1] > first[2], "{first:?}");
+        assert!(first[2] > first[0], "{first:?}");
+        assert!(first[0] > 0, "{first:?}");
+
+        // Probes update the statistics when dropped
+        let mut probe = core.mx_probe("mx4");
+        probe.success(true);
+        drop(probe);
+        assert!(core.mx_stats.get("mx4").unwrap().success_rate == 1.0);
+        drop(core.mx_probe("mx5"));
+        assert!(core.mx_stats.get("mx5").unwrap().success_rate == 0.0);
     }
 }
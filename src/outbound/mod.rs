@@ -35,6 +35,7 @@ pub mod dane;
 pub mod delivery;
 pub mod lookup;
 pub mod mta_sts;
+pub mod pool;
 pub mod session;
 
 impl Status<(), Error> {
@@ -112,7 +113,7 @@ impl Status<(), Error> {
         }
     }
 
-    pub fn from_tls_error(hostname: &str, err: mail_send::Error) -> Self {
+    pub fn from_tls_error(hostname: &str, err: mail_send::Error, client_cert: bool) -> Self {
         match err {
             mail_send::Error::InvalidTLSName => {
                 Status::PermanentFailure(Error::TlsError(ErrorDetails {
@@ -126,7 +127,11 @@ impl Status<(), Error> {
             })),
             mail_send::Error::Tls(err) => Status::TemporaryFailure(Error::TlsError(ErrorDetails {
                 entity: hostname.to_string(),
-                details: format!("Handshake failed: {err}"),
+                details: if client_cert {
+                    format!("Handshake failed while presenting client certificate: {err}")
+                } else {
+                    format!("Handshake failed: {err}")
+                },
             })),
             mail_send::Error::Io(err) => Status::TemporaryFailure(Error::TlsError(ErrorDetails {
                 entity: hostname.to_string(),
@@ -145,6 +150,11 @@ impl Status<(), Error> {
             details: format!("Timeout while {stage}"),
         }))
     }
+
+    pub fn is_closing(&self) -> bool {
+        matches!(self, Status::TemporaryFailure(Error::UnexpectedResponse(response))
+            if response.response.code == 421)
+    }
 }
 
 impl From<mail_auth::Error> for Status<(), Error> {
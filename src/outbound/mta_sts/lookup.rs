@@ -58,14 +58,42 @@ impl Core {
             }
         };
 
-        // Check if the policy has been cached
-        if let Some(value) = self.resolvers.cache.mta_sts.get(domain) {
+        // Check if the policy has been cached, expired policies are never returned
+        // by the cache so they are fetched again even if the id did not change
+        let cached = self.resolvers.cache.mta_sts.get(domain);
+        if let Some(value) = &cached {
             if value.id == record.id {
-                return Ok(value);
+                return Ok(value.clone());
             }
         }
 
-        // Fetch policy
+        // Fetch policy, the cached policy remains in use until it expires
+        // if a new one cannot be obtained
+        match self.fetch_mta_sts_policy(domain, &record.id, timeout).await {
+            Ok(policy) => {
+                let valid_until = Instant::now()
+                    + Duration::from_secs(if (3600..31557600).contains(&policy.max_age) {
+                        policy.max_age
+                    } else {
+                        86400
+                    });
+
+                Ok(self.resolvers.cache.mta_sts.insert(
+                    domain.to_string(),
+                    Arc::new(policy),
+                    valid_until,
+                ))
+            }
+            Err(err) => cached.ok_or(err),
+        }
+    }
+
+    async fn fetch_mta_sts_policy(
+        &self,
+        domain: &str,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<Policy, Error> {
         #[cfg(not(test))]
         let bytes = reqwest::Client::builder()
             .user_agent(crate::USER_AGENT)
@@ -82,23 +110,11 @@ impl Core {
             .lock()
             .clone();
 
-        // Parse policy
-        let policy = Policy::parse(
+        Policy::parse(
             std::str::from_utf8(&bytes).map_err(|err| Error::InvalidPolicy(err.to_string()))?,
-            record.id.clone(),
-        )?;
-        let valid_until = Instant::now()
-            + Duration::from_secs(if (3600..31557600).contains(&policy.max_age) {
-                policy.max_age
-            } else {
-                86400
-            });
-
-        Ok(self
-            .resolvers
-            .cache
-            .mta_sts
-            .insert(domain.to_string(), Arc::new(policy), valid_until))
+            id.to_string(),
+        )
+        .map_err(Error::from)
     }
 
     #[cfg(test)]
@@ -0,0 +1,236 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use mail_send::{smtp::AssertReply, SmtpClient};
+use smtp_proto::EhloResponse;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+use crate::{
+    core::{throttle::InFlight, Core, QueueCore},
+    queue::{Error, Message, Recipient, Status},
+};
+
+use super::session::{quit, BatchItem, SessionParams};
+
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionKey {
+    pub source_ip: Option<IpAddr>,
+    pub interface: Option<String>,
+    pub hostname: String,
+    pub port: u16,
+}
+
+pub enum PooledClient {
+    Plain(SmtpClient<TcpStream>),
+    Tls(SmtpClient<TlsStream<TcpStream>>),
+}
+
+pub struct PooledConnection {
+    pub id: u64,
+    pub client: PooledClient,
+    pub capabilities: EhloResponse<String>,
+    pub remote_ip: IpAddr,
+    pub dane_verified: bool,
+    pub messages: usize,
+    pub idle_since: Instant,
+    pub in_flight: Vec<InFlight>,
+}
+
+impl PooledConnection {
+    pub fn new(
+        client: PooledClient,
+        capabilities: EhloResponse<String>,
+        remote_ip: IpAddr,
+        dane_verified: bool,
+        in_flight: Vec<InFlight>,
+    ) -> Self {
+        PooledConnection {
+            id: CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            client,
+            capabilities,
+            remote_ip,
+            dane_verified,
+            messages: 0,
+            idle_since: Instant::now(),
+            in_flight,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.client, PooledClient::Tls(_))
+    }
+
+    // Resets the session state, which also verifies that the remote host
+    // has not closed the connection while idle.
+    pub async fn reset(&mut self, params: &SessionParams<'_>) -> bool {
+        let result = match &mut self.client {
+            PooledClient::Plain(client) => {
+                client.timeout = params.timeout_mail;
+                client
+                    .cmd(b"RSET\r\n")
+                    .await
+                    .and_then(|r| r.assert_positive_completion())
+            }
+            PooledClient::Tls(client) => {
+                client.timeout = params.timeout_mail;
+                client
+                    .cmd(b"RSET\r\n")
+                    .await
+                    .and_then(|r| r.assert_positive_completion())
+            }
+        };
+
+        if let Err(err) = result {
+            tracing::debug!(
+                parent: params.span,
+                context = "pool",
+                event = "stale",
+                mx = params.hostname,
+                reason = %err,
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    pub async fn deliver(
+        self,
+        message: &Message,
+        recipients: impl Iterator<Item = &mut Recipient>,
+        batch: &mut [BatchItem],
+        params: &SessionParams<'_>,
+    ) -> (Status<(), Error>, Option<Self>) {
+        let (status, client) = match self.client {
+            PooledClient::Plain(client) => {
+                let (status, client) = message
+                    .deliver_transactions(client, self.capabilities, recipients, batch, params)
+                    .await;
+                (
+                    status,
+                    client
+                        .map(|(client, capabilities)| (PooledClient::Plain(client), capabilities)),
+                )
+            }
+            PooledClient::Tls(client) => {
+                let (status, client) = message
+                    .deliver_transactions(client, self.capabilities, recipients, batch, params)
+                    .await;
+                (
+                    status,
+                    client.map(|(client, capabilities)| (PooledClient::Tls(client), capabilities)),
+                )
+            }
+        };
+
+        (
+            status,
+            client.map(|(client, capabilities)| PooledConnection {
+                client,
+                capabilities,
+                messages: self.messages
+                    + 1
+                    + batch.iter().filter(|item| item.status.is_some()).count(),
+                ..self
+            }),
+        )
+    }
+
+    pub async fn quit(self) {
+        match self.client {
+            PooledClient::Plain(client) => quit(client).await,
+            PooledClient::Tls(client) => quit(client).await,
+        }
+    }
+}
+
+impl QueueCore {
+    pub fn take_connection(
+        &self,
+        key: &ConnectionKey,
+        idle_timeout: Duration,
+        require_tls: bool,
+        require_dane: bool,
+    ) -> Option<PooledConnection> {
+        self.take_connection_if(key, |conn| {
+            conn.idle_since.elapsed() < idle_timeout
+                && (!require_tls || conn.is_tls())
+                && (!require_dane || conn.dane_verified)
+        })
+    }
+
+    fn take_connection_if(
+        &self,
+        key: &ConnectionKey,
+        cond: impl Fn(&PooledConnection) -> bool,
+    ) -> Option<PooledConnection> {
+        let conn = {
+            let mut connections = self.connections.get_mut(key)?;
+            let pos = connections.iter().rposition(cond)?;
+            connections.swap_remove(pos)
+        };
+        self.connections
+            .remove_if(key, |_, connections| connections.is_empty());
+        Some(conn)
+    }
+}
+
+impl Core {
+    pub fn pool_connection(
+        self: &Arc<Self>,
+        key: ConnectionKey,
+        mut conn: PooledConnection,
+        idle_timeout: Duration,
+    ) {
+        let id = conn.id;
+        conn.idle_since = Instant::now();
+        self.queue
+            .connections
+            .entry(key.clone())
+            .or_default()
+            .push(conn);
+
+        // Close the connection once the idle timeout elapses
+        let core = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(idle_timeout).await;
+            if let Some(conn) = core.queue.take_connection_if(&key, |conn| {
+                conn.id == id && conn.idle_since.elapsed() >= idle_timeout
+            }) {
+                conn.quit().await;
+            }
+        });
+    }
+}
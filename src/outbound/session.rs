@@ -22,26 +22,40 @@
 */
 
 use mail_send::{smtp::AssertReply, Credentials, SmtpClient};
+use rustls::{CipherSuite, ClientConnection, ProtocolVersion};
 use smtp_proto::{
-    EhloResponse, Response, Severity, EXT_CHUNKING, EXT_DSN, EXT_REQUIRE_TLS, EXT_SIZE,
-    EXT_SMTP_UTF8, EXT_START_TLS, MAIL_REQUIRETLS, MAIL_RET_FULL, MAIL_RET_HDRS, MAIL_SMTPUTF8,
+    response::parser::ResponseReceiver, EhloResponse, Response, Severity, EXT_BINARY_MIME,
+    EXT_CHUNKING, EXT_DSN, EXT_PIPELINING, EXT_REQUIRE_TLS, EXT_SIZE, EXT_SMTP_UTF8, EXT_START_TLS,
+    MAIL_BODY_BINARYMIME, MAIL_REQUIRETLS, MAIL_RET_FULL, MAIL_RET_HDRS, MAIL_SMTPUTF8,
     RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS,
 };
 use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::{
-    fs,
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::TcpStream,
+    net::{TcpSocket, TcpStream},
+    task::JoinSet,
 };
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
 use crate::{
-    config::{RequireOptional, TlsStrategy},
+    config::{RequireOptional, TlsStrategy, TlsVersion},
+    core::QueueCore,
+    inbound::content::encode_binary_mime,
     queue::{ErrorDetails, HostResponse, RCPT_STATUS_CHANGED},
 };
 
-use crate::queue::{Error, Message, Recipient, Status};
+use crate::queue::{
+    dsn::encode_xtext, encryption::SpoolEncryption, spool::Spool, Error, Message, Recipient, Status,
+};
+
+pub struct BatchItem {
+    pub message: Box<Message>,
+    pub recipients: Vec<Recipient>,
+    pub status: Option<Status<(), Error>>,
+}
 
 pub struct SessionParams<'x> {
     pub span: &'x tracing::Span,
@@ -53,6 +67,12 @@ pub struct SessionParams<'x> {
     pub timeout_mail: Duration,
     pub timeout_rcpt: Duration,
     pub timeout_data: Duration,
+    pub pipelining: bool,
+    pub keep_alive: bool,
+    pub retry_over_quota: bool,
+    pub spool: &'x Spool,
+    pub encryption: Option<&'x SpoolEncryption>,
+    pub queue: &'x QueueCore,
 }
 
 impl Message {
@@ -60,8 +80,12 @@ impl Message {
         &self,
         mut smtp_client: SmtpClient<T>,
         recipients: impl Iterator<Item = &mut Recipient>,
+        batch: &mut [BatchItem],
         params: SessionParams<'_>,
-    ) -> Status<(), Error> {
+    ) -> (
+        Status<(), Error>,
+        Option<(SmtpClient<T>, EhloResponse<String>)>,
+    ) {
         // Obtain capabilities
         let mut capabilities = match say_helo(&mut smtp_client, &params).await {
             Ok(capabilities) => capabilities,
@@ -74,7 +98,7 @@ impl Message {
                     reason = %status,
                 );
                 quit(smtp_client).await;
-                return status;
+                return (status, None);
             }
         };
 
@@ -89,7 +113,10 @@ impl Message {
                     reason = %err,
                 );
                 quit(smtp_client).await;
-                return Status::from_smtp_error(params.hostname, "AUTH ...", err);
+                return (
+                    Status::from_smtp_error(params.hostname, "AUTH ...", err),
+                    None,
+                );
             }
 
             // Refresh capabilities
@@ -104,47 +131,212 @@ impl Message {
                         reason = %status,
                     );
                     quit(smtp_client).await;
-                    return status;
+                    return (status, None);
                 }
             };
         }
 
-        // MAIL FROM
-        smtp_client.timeout = params.timeout_mail;
-        let cmd = self.build_mail_from(&capabilities);
-        if let Err(err) = smtp_client
-            .cmd(cmd.as_bytes())
+        self.deliver_transactions(smtp_client, capabilities, recipients, batch, &params)
+            .await
+    }
+
+    pub async fn deliver_transactions<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut smtp_client: SmtpClient<T>,
+        capabilities: EhloResponse<String>,
+        recipients: impl Iterator<Item = &mut Recipient>,
+        batch: &mut [BatchItem],
+        params: &SessionParams<'_>,
+    ) -> (
+        Status<(), Error>,
+        Option<(SmtpClient<T>, EhloResponse<String>)>,
+    ) {
+        // Deliver message
+        let status = match self
+            .send_transaction(&mut smtp_client, &capabilities, recipients, params)
             .await
-            .and_then(|r| r.assert_positive_completion())
         {
+            Ok(status) => status,
+            Err(status) => {
+                quit(smtp_client).await;
+                return (status, None);
+            }
+        };
+
+        // Deliver batched messages over the same connection
+        let mut is_reusable = !status.is_closing();
+        for item in batch {
+            if !is_reusable
+                || smtp_client
+                    .cmd(b"RSET\r\n")
+                    .await
+                    .and_then(|r| r.assert_positive_completion())
+                    .is_err()
+            {
+                is_reusable = false;
+                break;
+            }
+
+            match item
+                .message
+                .send_transaction(
+                    &mut smtp_client,
+                    &capabilities,
+                    item.recipients.iter_mut(),
+                    params,
+                )
+                .await
+            {
+                Ok(status) => {
+                    is_reusable = !status.is_closing();
+                    item.status = status.into();
+                }
+                Err(status) => {
+                    item.status = status.into();
+                    is_reusable = false;
+                    break;
+                }
+            }
+        }
+
+        // Keep the connection open for further deliveries
+        if is_reusable
+            && params.keep_alive
+            && smtp_client
+                .cmd(b"RSET\r\n")
+                .await
+                .and_then(|r| r.assert_positive_completion())
+                .is_ok()
+        {
+            (status, Some((smtp_client, capabilities)))
+        } else {
+            quit(smtp_client).await;
+            (status, None)
+        }
+    }
+
+    pub async fn send_transaction<T: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        smtp_client: &mut SmtpClient<T>,
+        capabilities: &EhloResponse<String>,
+        recipients: impl Iterator<Item = &mut Recipient>,
+        params: &SessionParams<'_>,
+    ) -> Result<Status<(), Error>, Status<(), Error>> {
+        // Skip recipients that do not require delivery
+        let mut total_rcpt = 0;
+        let mut total_completed = 0;
+        let recipients = recipients
+            .filter(|rcpt| {
+                total_rcpt += 1;
+                if matches!(
+                    &rcpt.status,
+                    Status::Completed(_) | Status::PermanentFailure(_)
+                ) {
+                    total_completed += 1;
+                    false
+                } else {
+                    true
+                }
+            })
+            .map(|rcpt| {
+                let cmd = self.build_rcpt_to(rcpt, capabilities);
+                (rcpt, cmd)
+            })
+            .collect::<Vec<_>>();
+        let cmd = self.build_mail_from(capabilities);
+
+        // Send MAIL FROM and all RCPT TO commands in a single batch
+        let mut pipelined = if params.pipelining && capabilities.has_capability(EXT_PIPELINING) {
+            let mut cmds = Vec::with_capacity(recipients.len() + 1);
+            cmds.push(cmd.as_bytes());
+            cmds.extend(recipients.iter().map(|(_, cmd)| cmd.as_bytes()));
+            let result =
+                match tokio::time::timeout(params.timeout_mail, write_chunks(smtp_client, &cmds))
+                    .await
+                {
+                    Ok(Ok(_)) => {
+                        read_pipelined_responses(
+                            smtp_client,
+                            cmds.len(),
+                            params.timeout_mail + params.timeout_rcpt,
+                            params.timeout_rcpt,
+                        )
+                        .await
+                    }
+                    Ok(Err(err)) => Err(err),
+                    Err(_) => Err(mail_send::Error::Timeout),
+                };
+
+            match result {
+                Ok(responses) if responses.len() == cmds.len() => Some(responses.into_iter()),
+                Ok(responses) => {
+                    // The connection is out of sync, retry on a new one without pipelining
+                    tracing::info!(
+                        parent: params.span,
+                        context = "pipelining",
+                        event = "out-of-sync",
+                        mx = params.hostname,
+                        expected = cmds.len(),
+                        received = responses.len(),
+                        "Remote returned fewer responses than pipelined commands, disabling pipelining."
+                    );
+                    params.queue.disable_pipelining(params.hostname);
+                    return Err(Status::TemporaryFailure(Error::ConnectionError(
+                        ErrorDetails {
+                            entity: params.hostname.to_string(),
+                            details: "Pipelined responses out of sync".to_string(),
+                        },
+                    )));
+                }
+                Err(mail_send::Error::Timeout) => {
+                    return Err(Status::timeout(
+                        params.hostname,
+                        "reading pipelined responses",
+                    ));
+                }
+                Err(err) => {
+                    tracing::info!(
+                        parent: params.span,
+                        context = "pipelining",
+                        event = "failed",
+                        mx = params.hostname,
+                        reason = %err,
+                    );
+                    return Err(Status::from_smtp_error(params.hostname, &cmd, err));
+                }
+            }
+        } else {
+            None
+        };
+
+        // MAIL FROM
+        smtp_client.timeout = params.timeout_mail;
+        let response = if let Some(response) = pipelined.as_mut().and_then(|r| r.next()) {
+            Ok(response)
+        } else {
+            smtp_client.cmd(cmd.as_bytes()).await
+        };
+        if let Err(err) = response.and_then(|r| r.assert_positive_completion()) {
             tracing::info!(
                 parent: params.span,
                 context = "sender",
                 event = "rejected",
-                mx = &params.hostname,
+                mx = params.hostname,
                 reason = %err,
             );
-            quit(smtp_client).await;
-            return Status::from_smtp_error(params.hostname, &cmd, err);
+            return Err(Status::from_smtp_error(params.hostname, &cmd, err));
         }
 
         // RCPT TO
-        let mut total_rcpt = 0;
-        let mut total_completed = 0;
         let mut accepted_rcpts = Vec::new();
         smtp_client.timeout = params.timeout_rcpt;
-        for rcpt in recipients {
-            total_rcpt += 1;
-            if matches!(
-                &rcpt.status,
-                Status::Completed(_) | Status::PermanentFailure(_)
-            ) {
-                total_completed += 1;
-                continue;
-            }
-
-            let cmd = self.build_rcpt_to(rcpt, &capabilities);
-            match smtp_client.cmd(cmd.as_bytes()).await {
+        for (rcpt, cmd) in recipients {
+            let response = if let Some(response) = pipelined.as_mut().and_then(|r| r.next()) {
+                Ok(response)
+            } else {
+                smtp_client.cmd(cmd.as_bytes()).await
+            };
+            match response {
                 Ok(response) => match response.severity() {
                     Severity::PositiveCompletion => {
                         accepted_rcpts.push((
@@ -161,7 +353,7 @@ impl Message {
                             context = "rcpt",
                             event = "rejected",
                             rcpt = rcpt.address,
-                            mx = &params.hostname,
+                            mx = params.hostname,
                             reason = %response,
                         );
 
@@ -173,7 +365,9 @@ impl Message {
                             response,
                         };
                         rcpt.flags |= RCPT_STATUS_CHANGED;
-                        rcpt.status = if severity == Severity::PermanentNegativeCompletion {
+                        rcpt.status = if severity == Severity::PermanentNegativeCompletion
+                            && !(params.retry_over_quota && response.is_over_quota())
+                        {
                             total_completed += 1;
                             Status::PermanentFailure(response)
                         } else {
@@ -186,42 +380,45 @@ impl Message {
                         parent: params.span,
                         context = "rcpt",
                         event = "failed",
-                        mx = &params.hostname,
+                        mx = params.hostname,
                         rcpt = rcpt.address,
                         reason = %err,
                     );
 
                     // Something went wrong, abort.
-                    quit(smtp_client).await;
-                    return Status::from_smtp_error(params.hostname, "", err);
+                    return Err(Status::from_smtp_error(params.hostname, "", err));
                 }
             }
         }
 
         // Send message
         if !accepted_rcpts.is_empty() {
-            let bdat_cmd = if capabilities.has_capability(EXT_CHUNKING) {
+            // Binary content is converted to base64 and sent using DATA when
+            // the next hop does not support BINARYMIME
+            let downgrade =
+                self.has_flag(MAIL_BODY_BINARYMIME) && !supports_binary_mime(capabilities);
+            let bdat_cmd = if capabilities.has_capability(EXT_CHUNKING) && !downgrade {
                 format!("BDAT {} LAST\r\n", self.size).into()
             } else {
                 None
             };
 
-            if let Err(status) = send_message(&mut smtp_client, self, &bdat_cmd, &params).await {
+            if let Err(status) = send_message(smtp_client, self, &bdat_cmd, downgrade, params).await
+            {
                 tracing::info!(
                     parent: params.span,
                     context = "message",
                     event = "rejected",
-                    mx = &params.hostname,
+                    mx = params.hostname,
                     reason = %status,
                 );
 
-                quit(smtp_client).await;
-                return status;
+                return Err(status);
             }
 
             if params.is_smtp {
                 // Handle SMTP response
-                match read_smtp_data_respone(&mut smtp_client, params.hostname, &bdat_cmd).await {
+                match read_smtp_data_respone(smtp_client, params.hostname, &bdat_cmd).await {
                     Ok(response) => {
                         // Mark recipients as delivered
                         if response.code() == 250 {
@@ -231,7 +428,7 @@ impl Message {
                                     context = "rcpt",
                                     event = "delivered",
                                     rcpt = rcpt.address,
-                                    mx = &params.hostname,
+                                    mx = params.hostname,
                                     response = %status,
                                 );
 
@@ -244,16 +441,15 @@ impl Message {
                                 parent: params.span,
                                 context = "message",
                                 event = "rejected",
-                                mx = &params.hostname,
+                                mx = params.hostname,
                                 reason = %response,
                             );
 
-                            quit(smtp_client).await;
-                            return Status::from_smtp_error(
+                            return Err(Status::from_smtp_error(
                                 params.hostname,
                                 bdat_cmd.as_deref().unwrap_or("DATA"),
                                 mail_send::Error::UnexpectedReply(response),
-                            );
+                            ));
                         }
                     }
                     Err(status) => {
@@ -261,22 +457,17 @@ impl Message {
                             parent: params.span,
                             context = "message",
                             event = "failed",
-                            mx = &params.hostname,
+                            mx = params.hostname,
                             reason = %status,
                         );
 
-                        quit(smtp_client).await;
-                        return status;
+                        return Err(status);
                     }
                 }
             } else {
                 // Handle LMTP responses
-                match read_lmtp_data_respone(
-                    &mut smtp_client,
-                    params.hostname,
-                    accepted_rcpts.len(),
-                )
-                .await
+                match read_lmtp_data_respone(smtp_client, params.hostname, accepted_rcpts.len())
+                    .await
                 {
                     Ok(responses) => {
                         for ((rcpt, _), response) in accepted_rcpts.into_iter().zip(responses) {
@@ -288,7 +479,7 @@ impl Message {
                                         context = "rcpt",
                                         event = "delivered",
                                         rcpt = rcpt.address,
-                                        mx = &params.hostname,
+                                        mx = params.hostname,
                                         response = %response,
                                     );
 
@@ -304,7 +495,7 @@ impl Message {
                                         context = "rcpt",
                                         event = "rejected",
                                         rcpt = rcpt.address,
-                                        mx = &params.hostname,
+                                        mx = params.hostname,
                                         reason = %response,
                                     );
 
@@ -318,7 +509,9 @@ impl Message {
                                         },
                                         response,
                                     };
-                                    if severity == Severity::PermanentNegativeCompletion {
+                                    if severity == Severity::PermanentNegativeCompletion
+                                        && !(params.retry_over_quota && response.is_over_quota())
+                                    {
                                         total_completed += 1;
                                         Status::PermanentFailure(response)
                                     } else {
@@ -333,22 +526,19 @@ impl Message {
                             parent: params.span,
                             context = "message",
                             event = "rejected",
-                            mx = &params.hostname,
+                            mx = params.hostname,
                             reason = %status,
                         );
 
-                        quit(smtp_client).await;
-                        return status;
+                        return Err(status);
                     }
                 }
             }
         }
-
-        quit(smtp_client).await;
         if total_completed == total_rcpt {
-            Status::Completed(())
+            Ok(Status::Completed(()))
         } else {
-            Status::Scheduled
+            Ok(Status::Scheduled)
         }
     }
 
@@ -364,6 +554,9 @@ impl Message {
         if self.has_flag(MAIL_SMTPUTF8) & capabilities.has_capability(EXT_SMTP_UTF8) {
             mail_from.push_str(" SMTPUTF8");
         }
+        if self.has_flag(MAIL_BODY_BINARYMIME) && supports_binary_mime(capabilities) {
+            mail_from.push_str(" BODY=BINARYMIME");
+        }
         if capabilities.has_capability(EXT_DSN) {
             if self.has_flag(MAIL_RET_FULL) {
                 mail_from.push_str(" RET=FULL");
@@ -371,7 +564,7 @@ impl Message {
                 mail_from.push_str(" RET=HDRS");
             }
             if let Some(env_id) = &self.env_id {
-                let _ = write!(mail_from, " ENVID={env_id}");
+                let _ = write!(mail_from, " ENVID={}", encode_xtext(env_id));
             }
         }
 
@@ -408,6 +601,9 @@ impl Message {
             } else if rcpt.has_flag(RCPT_NOTIFY_NEVER) {
                 rcpt_to.push_str(" NOTIFY=NEVER");
             }
+            if let Some(orcpt) = &rcpt.orcpt {
+                let _ = write!(rcpt_to, " ORCPT=rfc822;{}", encode_xtext(orcpt));
+            }
         }
         rcpt_to.push_str("\r\n");
         rcpt_to
@@ -424,6 +620,16 @@ impl Recipient {
     pub fn has_flag(&self, flag: u64) -> bool {
         (self.flags & flag) != 0
     }
+
+    pub fn is_over_quota(&self) -> bool {
+        matches!(&self.status, Status::TemporaryFailure(response) if response.is_over_quota())
+    }
+}
+
+impl<T> HostResponse<T> {
+    pub fn is_over_quota(&self) -> bool {
+        matches!(self.response.esc, [4 | 5, 2, 2])
+    }
 }
 
 pub enum StartTlsResult {
@@ -470,6 +676,217 @@ pub async fn try_start_tls(
     }
 }
 
+pub fn verify_tls_strength(
+    span: &tracing::Span,
+    hostname: &str,
+    tls_connection: &ClientConnection,
+    min_version: TlsVersion,
+    min_cipher_strength: u32,
+) -> Result<(), Status<(), Error>> {
+    let version = match tls_connection.protocol_version() {
+        Some(ProtocolVersion::TLSv1_3) => Some(TlsVersion::Tls13),
+        Some(ProtocolVersion::TLSv1_2) => Some(TlsVersion::Tls12),
+        _ => None,
+    };
+    let cipher_strength =
+        tls_connection
+            .negotiated_cipher_suite()
+            .map_or(0, |suite| match suite.suite() {
+                CipherSuite::TLS13_AES_128_GCM_SHA256
+                | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+                | CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 => 128,
+                CipherSuite::TLS13_AES_256_GCM_SHA384
+                | CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+                | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+                | CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+                | CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+                | CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256 => 256,
+                _ => 0,
+            });
+
+    let details = if version.map_or(true, |version| version < min_version) {
+        format!(
+            "Negotiated TLS version {:?} is below the required minimum",
+            tls_connection.protocol_version()
+        )
+    } else if cipher_strength < min_cipher_strength {
+        format!(
+            "Negotiated cipher strength of {cipher_strength} bits is below the required {min_cipher_strength}"
+        )
+    } else {
+        return Ok(());
+    };
+
+    tracing::info!(
+        parent: span,
+        context = "tls",
+        event = "weak-cipher",
+        mx = hostname,
+        version = ?tls_connection.protocol_version(),
+        cipher = ?tls_connection.negotiated_cipher_suite().map(|suite| suite.suite()),
+        "{}", details
+    );
+
+    Err(Status::TemporaryFailure(Error::TlsError(ErrorDetails {
+        entity: hostname.to_string(),
+        details,
+    })))
+}
+
+// Connects to the remote IPs in order, starting a new attempt whenever the
+// previous one fails or has not completed after `delay` (RFC 8305). The first
+// connection to be established wins and any pending attempts are aborted.
+#[allow(clippy::too_many_arguments)]
+pub async fn connect_staggered(
+    source_ip: Option<IpAddr>,
+    interface: Option<&str>,
+    remote_ips: &mut Vec<IpAddr>,
+    port: u16,
+    timeout: Duration,
+    delay: Option<Duration>,
+    span: &tracing::Span,
+    hostname: &str,
+) -> Result<(IpAddr, SmtpClient<TcpStream>), mail_send::Error> {
+    let interface = interface.map(Arc::<str>::from);
+    let mut attempts = JoinSet::new();
+    let mut next_ip = 0;
+    let mut last_err = mail_send::Error::Timeout;
+
+    if let Some(&remote_ip) = remote_ips.first() {
+        attempts.spawn(connect(
+            source_ip,
+            interface.clone(),
+            SocketAddr::new(remote_ip, port),
+            timeout,
+        ));
+        next_ip += 1;
+    }
+
+    loop {
+        let result = match (delay, remote_ips.get(next_ip)) {
+            (Some(delay), Some(&remote_ip)) => {
+                tokio::select! {
+                    result = attempts.join_next() => result,
+                    _ = tokio::time::sleep(delay) => {
+                        tracing::debug!(
+                            parent: span,
+                            context = "connect",
+                            event = "stagger",
+                            mx = hostname,
+                            remote_ip = %remote_ip,
+                        );
+                        attempts.spawn(connect(
+                            source_ip,
+                            interface.clone(),
+                            SocketAddr::new(remote_ip, port),
+                            timeout,
+                        ));
+                        next_ip += 1;
+                        continue;
+                    }
+                }
+            }
+            _ => attempts.join_next().await,
+        };
+
+        match result {
+            Some(Ok((remote_ip, Ok(smtp_client)))) => {
+                remote_ips.drain(..next_ip);
+                return Ok((remote_ip, smtp_client));
+            }
+            Some(Ok((remote_ip, Err(err)))) => {
+                tracing::info!(
+                    parent: span,
+                    context = "connect",
+                    event = "failed",
+                    mx = hostname,
+                    remote_ip = %remote_ip,
+                    reason = %err,
+                );
+                last_err = err;
+            }
+            Some(Err(err)) => {
+                last_err =
+                    mail_send::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, err));
+            }
+            None => break,
+        }
+
+        // Start the next attempt right away when one fails
+        if let Some(&remote_ip) = remote_ips.get(next_ip) {
+            attempts.spawn(connect(
+                source_ip,
+                interface.clone(),
+                SocketAddr::new(remote_ip, port),
+                timeout,
+            ));
+            next_ip += 1;
+        }
+    }
+
+    remote_ips.clear();
+    Err(last_err)
+}
+
+async fn connect(
+    source_ip: Option<IpAddr>,
+    interface: Option<Arc<str>>,
+    remote_addr: SocketAddr,
+    timeout: Duration,
+) -> (IpAddr, Result<SmtpClient<TcpStream>, mail_send::Error>) {
+    (
+        remote_addr.ip(),
+        if let Some(interface) = interface {
+            connect_interface(source_ip, &interface, remote_addr, timeout).await
+        } else if let Some(source_ip) = source_ip {
+            SmtpClient::connect_using(source_ip, remote_addr, timeout).await
+        } else {
+            SmtpClient::connect(remote_addr, timeout).await
+        },
+    )
+}
+
+async fn connect_interface(
+    source_ip: Option<IpAddr>,
+    interface: &str,
+    remote_addr: SocketAddr,
+    timeout: Duration,
+) -> Result<SmtpClient<TcpStream>, mail_send::Error> {
+    let socket = if remote_addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }
+    .map_err(mail_send::Error::Io)?;
+
+    bind_device(&socket, interface).map_err(mail_send::Error::Io)?;
+    if let Some(source_ip) = source_ip {
+        socket
+            .bind(SocketAddr::new(source_ip, 0))
+            .map_err(mail_send::Error::Io)?;
+    }
+
+    let stream = tokio::time::timeout(timeout, socket.connect(remote_addr))
+        .await
+        .map_err(|_| mail_send::Error::Timeout)?
+        .map_err(mail_send::Error::Io)?;
+
+    Ok(SmtpClient { stream, timeout })
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> std::io::Result<()> {
+    socket.bind_device(interface.as_bytes().into())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, interface: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("Binding to interface {interface:?} is not supported on this platform"),
+    ))
+}
+
 pub async fn read_greeting<T: AsyncRead + AsyncWrite + Unpin>(
     smtp_client: &mut SmtpClient<T>,
     hostname: &str,
@@ -507,6 +924,48 @@ pub async fn read_lmtp_data_respone<T: AsyncRead + AsyncWrite + Unpin>(
     .map_err(|err| Status::from_smtp_error(hostname, "", err))
 }
 
+pub async fn read_pipelined_responses<T: AsyncRead + AsyncWrite + Unpin>(
+    smtp_client: &mut SmtpClient<T>,
+    num: usize,
+    timeout_first: Duration,
+    timeout_next: Duration,
+) -> Result<Vec<Response<String>>, mail_send::Error> {
+    let mut responses = Vec::with_capacity(num);
+    let mut parser = ResponseReceiver::default();
+    let mut buf = vec![0u8; 1024];
+
+    // Responses are returned in the same order as the pipelined commands,
+    // stop waiting if the remote does not answer all of them in time.
+    while responses.len() < num {
+        let timeout = if responses.is_empty() {
+            timeout_first
+        } else {
+            timeout_next
+        };
+        let br = match tokio::time::timeout(timeout, smtp_client.stream.read(&mut buf)).await {
+            Ok(Ok(br)) if br > 0 => br,
+            Ok(Ok(_)) => return Err(mail_send::Error::UnparseableReply),
+            Ok(Err(err)) => return Err(mail_send::Error::Io(err)),
+            Err(_) if responses.is_empty() => return Err(mail_send::Error::Timeout),
+            Err(_) => break,
+        };
+
+        let mut iter = buf[..br].iter();
+        while responses.len() < num {
+            match parser.parse(&mut iter) {
+                Ok(response) => {
+                    responses.push(response);
+                    parser.reset();
+                }
+                Err(smtp_proto::Error::NeedsMoreData { .. }) => break,
+                Err(_) => return Err(mail_send::Error::UnparseableReply),
+            }
+        }
+    }
+
+    Ok(responses)
+}
+
 pub async fn write_chunks<T: AsyncRead + AsyncWrite + Unpin>(
     smtp_client: &mut SmtpClient<T>,
     chunks: &[&[u8]],
@@ -529,28 +988,31 @@ pub async fn send_message<T: AsyncRead + AsyncWrite + Unpin>(
     smtp_client: &mut SmtpClient<T>,
     message: &Message,
     bdat_cmd: &Option<String>,
+    downgrade: bool,
     params: &SessionParams<'_>,
 ) -> Result<(), Status<(), Error>> {
-    let mut raw_message = vec![0u8; message.size];
-    let mut file = fs::File::open(&message.path).await.map_err(|err| {
-        tracing::error!(parent: params.span,
-                            context = "queue", 
-                            event = "error", 
-                            "Failed to open message file {}: {}", 
-                            message.path.display(),
-                            err);
-        Status::TemporaryFailure(Error::Io("Queue system error.".to_string()))
-    })?;
-    file.read_exact(&mut raw_message).await.map_err(|err| {
-        tracing::error!(parent: params.span,
+    let mut raw_message = params
+        .spool
+        .read(message, message.size, params.encryption)
+        .await
+        .map_err(|err| {
+            tracing::error!(parent: params.span,
                             context = "queue", 
                             event = "error", 
-                            "Failed to read {} bytes file {} from disk: {}", 
-                            message.size,
-                            message.path.display(),
+                            "Failed to read message: {}", 
                             err);
-        Status::TemporaryFailure(Error::Io("Queue system error.".to_string()))
-    })?;
+            Status::TemporaryFailure(Error::Io("Queue system error.".to_string()))
+        })?;
+    if downgrade {
+        if let Some(encoded) = encode_binary_mime(&raw_message) {
+            tracing::debug!(parent: params.span,
+                            context = "message",
+                            event = "binarymime-downgrade",
+                            mx = params.hostname,
+                            "Converted binary content to base64.");
+            raw_message = encoded;
+        }
+    }
     tokio::time::timeout(params.timeout_data, async {
         if let Some(bdat_cmd) = bdat_cmd {
             write_chunks(smtp_client, &[bdat_cmd.as_bytes(), &raw_message]).await
@@ -570,6 +1032,10 @@ pub async fn send_message<T: AsyncRead + AsyncWrite + Unpin>(
     })
 }
 
+fn supports_binary_mime(capabilities: &EhloResponse<String>) -> bool {
+    capabilities.has_capability(EXT_BINARY_MIME) && capabilities.has_capability(EXT_CHUNKING)
+}
+
 pub async fn say_helo<T: AsyncRead + AsyncWrite + Unpin>(
     smtp_client: &mut SmtpClient<T>,
     params: &SessionParams<'_>,
@@ -615,6 +1081,11 @@ impl TlsStrategy {
         matches!(self.dane, RequireOptional::Require)
     }
 
+    #[inline(always)]
+    pub fn allow_dane_fallback(&self) -> bool {
+        self.dane_fallback && matches!(self.dane, RequireOptional::Optional)
+    }
+
     #[inline(always)]
     pub fn try_mta_sts(&self) -> bool {
         matches!(
@@ -634,4 +1105,9 @@ impl TlsStrategy {
             || self.is_dane_required()
             || self.is_mta_sts_required()
     }
+
+    #[inline(always)]
+    pub fn is_secure_delivery(&self, require_tls: bool, mta_sts_enforce: bool, dane: bool) -> bool {
+        self.is_tls_required() || require_tls || mta_sts_enforce || dane
+    }
 }
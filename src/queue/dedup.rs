@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{collections::VecDeque, path::PathBuf};
+
+use ahash::AHashMap;
+use tokio::fs;
+
+use super::{Message, QueueId};
+
+// Bounded index of content hashes for messages that are queued or
+// being delivered, oldest entries are dropped first once full.
+#[derive(Debug)]
+pub struct DedupIndex {
+    max_entries: usize,
+    entries: AHashMap<blake3::Hash, (QueueId, PathBuf)>,
+    order: VecDeque<blake3::Hash>,
+}
+
+impl DedupIndex {
+    pub fn new(max_entries: usize) -> Self {
+        DedupIndex {
+            max_entries,
+            entries: AHashMap::with_capacity(std::cmp::min(max_entries, 1024)),
+            order: VecDeque::with_capacity(std::cmp::min(max_entries, 1024)),
+        }
+    }
+
+    // Returns the id of an undelivered message with the same hash, or
+    // indexes the message if none is found.
+    pub async fn find_or_insert(&mut self, message: &Message) -> Option<QueueId> {
+        let hash = message.dedup_hash?;
+        if let Some((id, path)) = self.entries.get(&hash) {
+            // Spool files are kept until the message is delivered, bounced
+            // or removed, which also covers messages in mid-delivery.
+            if *id != message.id && fs::metadata(path).await.is_ok() {
+                return Some(*id);
+            }
+        }
+
+        if self
+            .entries
+            .insert(hash, (message.id, message.path.clone()))
+            .is_none()
+        {
+            self.order.push_back(hash);
+            while self.order.len() > self.max_entries {
+                if let Some(hash) = self.order.pop_front() {
+                    self.entries.remove(&hash);
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
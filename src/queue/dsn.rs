@@ -31,15 +31,14 @@ use smtp_proto::{
 };
 use std::fmt::Write;
 use std::time::{Duration, Instant};
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
 
-use crate::config::QueueConfig;
+use crate::config::{DsnFormat, QueueConfig};
 use crate::core::QueueCore;
 
 use super::{
     instant_to_timestamp, DeliveryAttempt, Domain, Error, ErrorDetails, HostResponse, Message,
-    Recipient, SimpleEnvelope, Status, RCPT_DSN_SENT, RCPT_STATUS_CHANGED,
+    Recipient, SimpleEnvelope, Status, RCPT_DSN_SENT, RCPT_OVER_QUOTA_NOTIFIED,
+    RCPT_STATUS_CHANGED,
 };
 
 impl QueueCore {
@@ -79,6 +78,12 @@ impl DeliveryAttempt {
         let mut txt_failed = String::new();
         let mut dsn = String::new();
 
+        let mut notify_once = Vec::with_capacity(self.message.domains.len());
+        for domain in &self.message.domains {
+            let envelope = SimpleEnvelope::new(&self.message, &domain.domain);
+            notify_once.push(*config.over_quota_notify_once.eval(&envelope).await);
+        }
+
         for rcpt in &mut self.message.recipients {
             if rcpt.has_flag(RCPT_DSN_SENT | RCPT_NOTIFY_NEVER) {
                 continue;
@@ -95,8 +100,15 @@ impl DeliveryAttempt {
                     response.write_dsn_text(&rcpt.address, &mut txt_success);
                 }
                 Status::TemporaryFailure(response)
-                    if domain.notify.due <= now && rcpt.has_flag(RCPT_NOTIFY_DELAY) =>
+                    if domain.notify.due <= now
+                        && rcpt.has_flag(RCPT_NOTIFY_DELAY)
+                        && !(response.is_over_quota()
+                            && rcpt.has_flag(RCPT_OVER_QUOTA_NOTIFIED)) =>
                 {
+                    // Senders are only notified once about full mailboxes
+                    if notify_once[rcpt.domain_idx] && response.is_over_quota() {
+                        rcpt.flags |= RCPT_OVER_QUOTA_NOTIFIED | RCPT_STATUS_CHANGED;
+                    }
                     rcpt.write_dsn(&mut dsn);
                     rcpt.status.write_dsn(&mut dsn);
                     domain.write_dsn_will_retry_until(&mut dsn);
@@ -157,6 +169,32 @@ impl DeliveryAttempt {
             dsn.push_str("\r\n");
         }
 
+        // Update next delay notification time, even if no delay DSN was requested
+        let mut domains = std::mem::take(&mut self.message.domains);
+        for domain in &mut domains {
+            if matches!(
+                &domain.status,
+                Status::TemporaryFailure(_) | Status::Scheduled
+            ) && domain.notify.due <= now
+            {
+                let envelope = SimpleEnvelope::new(&self.message, &domain.domain);
+
+                if let Some(next_notify) = config
+                    .notify
+                    .eval(&envelope)
+                    .await
+                    .get((domain.notify.inner + 1) as usize)
+                {
+                    domain.notify.inner += 1;
+                    domain.notify.due = Instant::now() + *next_notify;
+                } else {
+                    domain.notify.due = domain.expires + Duration::from_secs(10);
+                }
+                domain.changed = true;
+            }
+        }
+        self.message.domains = domains;
+
         // Build text response
         let txt_len = txt_success.len() + txt_delay.len() + txt_failed.len();
         if txt_len == 0 {
@@ -221,37 +259,19 @@ impl DeliveryAttempt {
             txt.push_str("\r\n");
         }
 
-        // Update next delay notification time
-        if has_delay {
-            let mut domains = std::mem::take(&mut self.message.domains);
-            for domain in &mut domains {
-                if matches!(
-                    &domain.status,
-                    Status::TemporaryFailure(_) | Status::Scheduled
-                ) && domain.notify.due <= now
-                {
-                    let envelope = SimpleEnvelope::new(&self.message, &domain.domain);
-
-                    if let Some(next_notify) = config
-                        .notify
-                        .eval(&envelope)
-                        .await
-                        .get((domain.notify.inner + 1) as usize)
-                    {
-                        domain.notify.inner += 1;
-                        domain.notify.due = Instant::now() + *next_notify;
-                    } else {
-                        domain.notify.due = domain.expires + Duration::from_secs(10);
-                    }
-                    domain.changed = true;
-                }
-            }
-            self.message.domains = domains;
-        }
-
         // Obtain hostname and sender addresses
-        let from_name = config.dsn.name.eval(self.message.as_ref()).await;
-        let from_addr = config.dsn.address.eval(self.message.as_ref()).await;
+        let sender = config
+            .dsn
+            .domains
+            .get(&self.message.return_path_domain.to_lowercase());
+        let from_name = match sender.and_then(|sender| sender.name.as_ref()) {
+            Some(name) => name,
+            None => config.dsn.name.eval(self.message.as_ref()).await,
+        };
+        let from_addr = match sender.and_then(|sender| sender.address.as_ref()) {
+            Some(address) => address,
+            None => config.dsn.address.eval(self.message.as_ref()).await,
+        };
         let reporting_mta = config.hostname.eval(self.message.as_ref()).await;
 
         // Prepare DSN
@@ -260,64 +280,59 @@ impl DeliveryAttempt {
             .write_dsn_headers(&mut dsn_header, reporting_mta);
         let dsn = dsn_header + &dsn;
 
-        // Fetch up to 1024 bytes of message headers
-        let headers = match File::open(&self.message.path).await {
-            Ok(mut file) => {
-                let mut buf = vec![0u8; std::cmp::min(self.message.size, 1024)];
-                match file.read(&mut buf).await {
-                    Ok(br) => {
-                        let mut prev_ch = 0;
-                        let mut last_lf = br;
-                        for (pos, &ch) in buf.iter().enumerate() {
-                            match ch {
-                                b'\n' => {
-                                    last_lf = pos + 1;
-                                    if prev_ch != b'\n' {
-                                        prev_ch = ch;
-                                    } else {
-                                        break;
-                                    }
-                                }
-                                b'\r' => (),
-                                0 => break,
-                                _ => {
+        // Fetch up to 1024 bytes of message headers, if the message was spooled
+        let headers = if self.message.path.as_os_str().is_empty() {
+            String::new()
+        } else {
+            match config
+                .spool
+                .read(
+                    &self.message,
+                    std::cmp::min(self.message.size, 1024),
+                    config.encryption.as_ref(),
+                )
+                .await
+            {
+                Ok(mut buf) => {
+                    let mut prev_ch = 0;
+                    let mut last_lf = buf.len();
+                    for (pos, &ch) in buf.iter().enumerate() {
+                        match ch {
+                            b'\n' => {
+                                last_lf = pos + 1;
+                                if prev_ch != b'\n' {
                                     prev_ch = ch;
+                                } else {
+                                    break;
                                 }
                             }
+                            b'\r' => (),
+                            0 => break,
+                            _ => {
+                                prev_ch = ch;
+                            }
                         }
-                        if last_lf < 1024 {
-                            buf.truncate(last_lf);
-                        }
-                        String::from_utf8(buf).unwrap_or_default()
                     }
-                    Err(err) => {
-                        tracing::error!(
-                            parent: &self.span,
-                            context = "queue",
-                            event = "error",
-                            "Failed to read from {}: {}",
-                            self.message.path.display(),
-                            err
-                        );
-                        String::new()
+                    if last_lf < 1024 {
+                        buf.truncate(last_lf);
                     }
+                    String::from_utf8(buf).unwrap_or_default()
+                }
+                Err(err) => {
+                    tracing::error!(
+                        parent: &self.span,
+                        context = "queue",
+                        event = "error",
+                        "Failed to read message headers: {}",
+                        err
+                    );
+                    String::new()
                 }
-            }
-            Err(err) => {
-                tracing::error!(
-                    parent: &self.span,
-                    context = "queue",
-                    event = "error",
-                    "Failed to open file {}: {}",
-                    self.message.path.display(),
-                    err
-                );
-                String::new()
             }
         };
 
         // Build message
-        MessageBuilder::new()
+        let message = MessageBuilder::new()
             .from((from_name.as_str(), from_addr.as_str()))
             .header(
                 "To",
@@ -325,8 +340,9 @@ impl DeliveryAttempt {
             )
             .header("Auto-Submitted", HeaderType::Text("auto-generated".into()))
             .message_id(format!("<{}@{}>", make_boundary("."), reporting_mta))
-            .subject(subject)
-            .body(MimePart::new(
+            .subject(subject);
+        match config.dsn.format.eval(self.message.as_ref()).await {
+            DsnFormat::Full => message.body(MimePart::new(
                 ContentType::new("multipart/report").attribute("report-type", "delivery-status"),
                 BodyPart::Multipart(vec![
                     MimePart::new(ContentType::new("text/plain"), BodyPart::Text(txt.into())),
@@ -339,10 +355,21 @@ impl DeliveryAttempt {
                         BodyPart::Text(headers.into()),
                     ),
                 ]),
-            ))
-            .write_to_vec()
-            .unwrap_or_default()
-            .into()
+            )),
+            DsnFormat::Simple => {
+                if !headers.is_empty() {
+                    txt.push_str("    ----- Original message headers -----\r\n\r\n");
+                    txt.push_str(&headers);
+                }
+                message.body(MimePart::new(
+                    ContentType::new("text/plain"),
+                    BodyPart::Text(txt.into()),
+                ))
+            }
+        }
+        .write_to_vec()
+        .unwrap_or_default()
+        .into()
     }
 
     fn handle_double_bounce(&mut self) {
@@ -482,6 +509,36 @@ impl Error {
 }
 
 impl Message {
+    pub fn bounce(&mut self, item: Option<&str>) -> bool {
+        let mut bounced = Vec::new();
+        for (domain_idx, domain) in self.domains.iter_mut().enumerate() {
+            if matches!(
+                domain.status,
+                Status::Scheduled | Status::TemporaryFailure(_)
+            ) && item.map_or(true, |item| domain.domain.contains(item))
+            {
+                domain.status = Status::PermanentFailure(Error::Io(
+                    "Delivery canceled by the administrator.".to_string(),
+                ));
+                domain.changed = true;
+                bounced.push(domain_idx);
+            }
+        }
+
+        // Recipients that were deferred become permanent failures
+        for rcpt in &mut self.recipients {
+            if matches!(rcpt.status, Status::TemporaryFailure(_))
+                && bounced.contains(&rcpt.domain_idx)
+            {
+                rcpt.status =
+                    std::mem::replace(&mut rcpt.status, Status::Scheduled).into_permanent();
+                rcpt.flags |= RCPT_STATUS_CHANGED;
+            }
+        }
+
+        !bounced.is_empty()
+    }
+
     fn write_dsn_headers(&self, dsn: &mut String, reporting_mta: &str) {
         let _ = write!(dsn, "Reporting-MTA: dns;{reporting_mta}\r\n");
         dsn.push_str("Arrival-Date: ");
@@ -490,6 +547,13 @@ impl Message {
         if let Some(env_id) = &self.env_id {
             let _ = write!(dsn, "Original-Envelope-Id: {env_id}\r\n");
         }
+        if let Some(metadata) = self
+            .metadata
+            .as_ref()
+            .filter(|m| m.chars().all(|ch| matches!(ch, ' '..='~')))
+        {
+            let _ = write!(dsn, "X-Message-Metadata: {metadata}\r\n");
+        }
         dsn.push_str("\r\n");
     }
 }
@@ -497,9 +561,9 @@ impl Message {
 impl Recipient {
     fn write_dsn(&self, dsn: &mut String) {
         if let Some(orcpt) = &self.orcpt {
-            let _ = write!(dsn, "Original-Recipient: rfc822;{orcpt}\r\n");
+            write_dsn_address(dsn, "Original-Recipient", orcpt);
         }
-        let _ = write!(dsn, "Final-Recipient: rfc822;{}\r\n", self.address);
+        write_dsn_address(dsn, "Final-Recipient", &self.address);
     }
 }
 
@@ -669,3 +733,49 @@ trait WriteDsn {
     fn write_dsn_diagnostic(&self, dsn: &mut String);
     fn write_response(&self, dsn: &mut String);
 }
+
+// RFC 3461 only allows printable US-ASCII in decoded addresses,
+// anything else is reported in its xtext form.
+fn write_dsn_address(dsn: &mut String, field: &str, address: &str) {
+    if address.chars().all(|ch| matches!(ch, ' '..='~')) {
+        let _ = write!(dsn, "{field}: rfc822;{address}\r\n");
+    } else {
+        let _ = write!(dsn, "{field}: rfc822;{}\r\n", encode_xtext(address));
+    }
+}
+
+pub fn encode_xtext(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for &byte in value.as_bytes() {
+        if (b'!'..=b'~').contains(&byte) && byte != b'+' && byte != b'=' {
+            result.push(byte as char);
+        } else {
+            let _ = write!(result, "+{byte:02X}");
+        }
+    }
+    result
+}
+
+pub fn decode_xtext(value: &str) -> Option<String> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut bytes = value.as_bytes().iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'+' => {
+                let mut hex = 0u8;
+                for _ in 0..2 {
+                    hex = (hex << 4)
+                        | match bytes.next()? {
+                            ch @ b'0'..=b'9' => ch - b'0',
+                            ch @ b'A'..=b'F' => ch - b'A' + 10,
+                            _ => return None,
+                        };
+                }
+                result.push(hex);
+            }
+            b'!'..=b'~' if byte != b'=' => result.push(byte),
+            _ => return None,
+        }
+    }
+    String::from_utf8(result).ok()
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use sha2::{Digest, Sha256};
+
+const KEY_ID_LEN: usize = 8;
+pub const ENCRYPTION_OVERHEAD: usize = KEY_ID_LEN + NONCE_LEN + 16;
+
+pub struct SpoolEncryption {
+    keys: Vec<SpoolKey>,
+    rng: SystemRandom,
+}
+
+struct SpoolKey {
+    id: [u8; KEY_ID_LEN],
+    key: LessSafeKey,
+}
+
+impl SpoolEncryption {
+    // The first key is used for encryption, the remaining ones are only
+    // used to decrypt messages spooled before a key rotation.
+    pub fn new(keys: Vec<Vec<u8>>) -> Result<Self, String> {
+        if keys.is_empty() {
+            return Err("At least one spool encryption key is required.".to_string());
+        }
+        let mut spool_keys = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut id = [0u8; KEY_ID_LEN];
+            id.copy_from_slice(&Sha256::digest(&key)[..KEY_ID_LEN]);
+            if spool_keys.iter().any(|k: &SpoolKey| k.id == id) {
+                continue;
+            }
+            spool_keys.push(SpoolKey {
+                id,
+                key: LessSafeKey::new(
+                    UnboundKey::new(&AES_256_GCM, &key)
+                        .map_err(|_| "Spool encryption keys must be 256 bits long.".to_string())?,
+                ),
+            });
+        }
+
+        Ok(SpoolEncryption {
+            keys: spool_keys,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn encrypt(&self, storage_key: &str, chunks: &[&[u8]]) -> Result<Vec<u8>, String> {
+        let key = &self.keys[0];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| "Failed to generate nonce.".to_string())?;
+
+        let mut buf =
+            Vec::with_capacity(chunks.iter().map(|c| c.len()).sum::<usize>() + ENCRYPTION_OVERHEAD);
+        buf.extend_from_slice(&key.id);
+        buf.extend_from_slice(&nonce);
+        for chunk in chunks {
+            buf.extend_from_slice(chunk);
+        }
+        let mut contents = buf.split_off(KEY_ID_LEN + NONCE_LEN);
+        key.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(storage_key.as_bytes()),
+                &mut contents,
+            )
+            .map_err(|_| "Failed to encrypt message.".to_string())?;
+        buf.extend_from_slice(&contents);
+        Ok(buf)
+    }
+
+    pub fn decrypt(&self, storage_key: &str, mut bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+        if bytes.len() < ENCRYPTION_OVERHEAD {
+            return Err("Encrypted message is truncated.".to_string());
+        }
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.id[..] == bytes[..KEY_ID_LEN])
+            .ok_or_else(|| "Message was encrypted with an unknown key.".to_string())?;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&bytes[KEY_ID_LEN..KEY_ID_LEN + NONCE_LEN]);
+
+        let len = key
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(storage_key.as_bytes()),
+                &mut bytes[KEY_ID_LEN + NONCE_LEN..],
+            )
+            .map_err(|_| "Failed to decrypt message.".to_string())?
+            .len();
+        bytes.drain(..KEY_ID_LEN + NONCE_LEN);
+        bytes.truncate(len);
+        Ok(bytes)
+    }
+}
+
+pub fn decode_key(key: &[u8]) -> Option<Vec<u8>> {
+    let key = std::str::from_utf8(key).ok()?.trim();
+    if key.len() != 64 {
+        return None;
+    }
+    (0..key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(key.get(i..i + 2)?, 16).ok())
+        .collect()
+}
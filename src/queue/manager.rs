@@ -23,12 +23,13 @@
 
 use std::{
     collections::BinaryHeap,
+    path::PathBuf,
     sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
 
-use ahash::AHashMap;
-use smtp_proto::Response;
+use ahash::{AHashMap, AHashSet};
+use smtp_proto::{Response, MAIL_REQUIRETLS};
 use tokio::sync::mpsc;
 
 use crate::core::{
@@ -37,17 +38,32 @@ use crate::core::{
 };
 
 use super::{
-    DeliveryAttempt, Event, HostResponse, Message, OnHold, QueueId, Schedule, Status, WorkerResult,
-    RCPT_STATUS_CHANGED,
+    dedup::DedupIndex, DeliveryAttempt, Event, HostResponse, Message, OnHold, QueueId, Schedule,
+    Status, UsedQuota, WorkerResult, RCPT_STATUS_CHANGED, RCPT_VERIFY_PENDING,
 };
 
 #[derive(Debug)]
 pub struct Queue {
     short_wait: Duration,
     long_wait: Duration,
+    pub priority_aging: Option<Duration>,
     pub scheduled: BinaryHeap<Schedule<QueueId>>,
+    ready: BinaryHeap<Ready>,
     pub on_hold: Vec<OnHold<QueueId>>,
     pub messages: AHashMap<QueueId, Box<Message>>,
+    pub max_resident: Option<usize>,
+    load_batch: usize,
+    pub evicted: AHashMap<QueueId, Evicted>,
+    pub dedup: Option<DedupIndex>,
+}
+
+// Scheduled message whose metadata was dropped from memory, it is
+// reloaded from the spool once it becomes due.
+#[derive(Debug)]
+pub struct Evicted {
+    path: PathBuf,
+    size: usize,
+    queue_refs: Vec<UsedQuota>,
 }
 
 impl SpawnQueue for mpsc::Receiver<Event> {
@@ -57,6 +73,7 @@ impl SpawnQueue for mpsc::Receiver<Event> {
                 let result = tokio::time::timeout(queue.wake_up_time(), self.recv()).await;
 
                 // Deliver scheduled messages
+                queue.load_due().await;
                 while let Some(message) = queue.next_due() {
                     DeliveryAttempt::from(message)
                         .try_deliver(core.clone(), &mut queue)
@@ -73,7 +90,20 @@ impl SpawnQueue for mpsc::Receiver<Event> {
                                     .await;
                             }
 
-                            if item.due <= Instant::now() {
+                            // Merge messages identical to an undelivered one
+                            if let Some(original_id) = match &mut queue.dedup {
+                                Some(dedup) => dedup.find_or_insert(&item.inner).await,
+                                None => None,
+                            } {
+                                tracing::info!(
+                                    context = "queue",
+                                    event = "duplicate",
+                                    id = item.inner.id,
+                                    original_id = original_id,
+                                    "Discarding message identical to queued message."
+                                );
+                                item.inner.remove(&core.queue.config.spool).await;
+                            } else if item.due <= Instant::now() {
                                 DeliveryAttempt::from(item.inner)
                                     .try_deliver(core.clone(), &mut queue)
                                     .await;
@@ -98,127 +128,264 @@ impl SpawnQueue for mpsc::Receiver<Event> {
                                 }
                             }
                         }
-                        Event::Manage(request) => match request {
-                            management::QueueRequest::List {
-                                from,
-                                to,
-                                before,
-                                after,
-                                result_tx,
-                            } => {
-                                let mut result = Vec::with_capacity(queue.messages.len());
-                                for message in queue.messages.values() {
-                                    if from.as_ref().map_or(false, |from| {
-                                        !message.return_path_lcase.contains(from)
-                                    }) {
-                                        continue;
-                                    }
-                                    if to.as_ref().map_or(false, |to| {
-                                        !message
-                                            .recipients
-                                            .iter()
-                                            .any(|rcpt| rcpt.address_lcase.contains(to))
-                                    }) {
-                                        continue;
-                                    }
+                        Event::Manage(request) => {
+                            match request {
+                                management::QueueRequest::List {
+                                    from,
+                                    to,
+                                    before,
+                                    after,
+                                    result_tx,
+                                } => {
+                                    let filter = |message: &Message| {
+                                        if from.as_ref().map_or(false, |from| {
+                                            !message.return_path_lcase.contains(from)
+                                        }) {
+                                            return false;
+                                        }
+                                        if to.as_ref().map_or(false, |to| {
+                                            !message
+                                                .recipients
+                                                .iter()
+                                                .any(|rcpt| rcpt.address_lcase.contains(to))
+                                        }) {
+                                            return false;
+                                        }
 
-                                    if (before.is_some() || after.is_some())
-                                        && !message.domains.iter().any(|domain| {
-                                            matches!(
-                                                &domain.status,
-                                                Status::Scheduled | Status::TemporaryFailure(_)
-                                            ) && match (&before, &after) {
-                                                (Some(before), Some(after)) => {
-                                                    domain.retry.due.lt(before)
-                                                        && domain.retry.due.gt(after)
+                                        (before.is_none() && after.is_none())
+                                            || message.domains.iter().any(|domain| {
+                                                matches!(
+                                                    &domain.status,
+                                                    Status::Scheduled | Status::TemporaryFailure(_)
+                                                ) && match (&before, &after) {
+                                                    (Some(before), Some(after)) => {
+                                                        domain.retry.due.lt(before)
+                                                            && domain.retry.due.gt(after)
+                                                    }
+                                                    (Some(before), None) => {
+                                                        domain.retry.due.lt(before)
+                                                    }
+                                                    (None, Some(after)) => {
+                                                        domain.retry.due.gt(after)
+                                                    }
+                                                    (None, None) => false,
                                                 }
-                                                (Some(before), None) => domain.retry.due.lt(before),
-                                                (None, Some(after)) => domain.retry.due.gt(after),
-                                                (None, None) => false,
-                                            }
-                                        })
-                                    {
-                                        continue;
-                                    }
-
-                                    result.push(message.id);
+                                            })
+                                    };
+                                    let mut result = queue
+                                        .messages
+                                        .values()
+                                        .filter(|message| filter(message))
+                                        .map(|message| message.id)
+                                        .collect::<Vec<_>>();
+                                    result.extend(queue.read_evicted(filter).await);
+                                    result.sort_unstable_by_key(|id| *id & 0xFFFFFFFF);
+                                    let _ = result_tx.send(result);
                                 }
-                                result.sort_unstable_by_key(|id| *id & 0xFFFFFFFF);
-                                let _ = result_tx.send(result);
-                            }
-                            management::QueueRequest::Status {
-                                queue_ids,
-                                result_tx,
-                            } => {
-                                let mut result = Vec::with_capacity(queue_ids.len());
-                                for queue_id in queue_ids {
-                                    result.push(
-                                        queue
-                                            .messages
-                                            .get(&queue_id)
-                                            .map(|message| message.as_ref().into()),
-                                    );
+                                management::QueueRequest::Stats { result_tx } => {
+                                    let _ = result_tx.send(queue.stats());
                                 }
-                                let _ = result_tx.send(result);
-                            }
-                            management::QueueRequest::Cancel {
-                                queue_ids,
-                                item,
-                                result_tx,
-                            } => {
-                                let mut result = Vec::with_capacity(queue_ids.len());
-                                for queue_id in &queue_ids {
-                                    let mut found = false;
-                                    if let Some(item) = &item {
-                                        if let Some(message) = queue.messages.get_mut(queue_id) {
-                                            // Cancel delivery for all recipients that match
-                                            for rcpt in &mut message.recipients {
-                                                if rcpt.address_lcase.contains(item) {
-                                                    rcpt.flags |= RCPT_STATUS_CHANGED;
-                                                    rcpt.status = Status::Completed(HostResponse {
-                                                        hostname: String::new(),
-                                                        response: Response {
-                                                            code: 0,
-                                                            esc: [0, 0, 0],
-                                                            message: "Delivery canceled."
-                                                                .to_string(),
-                                                        },
-                                                    });
-                                                    found = true;
+                                management::QueueRequest::Status {
+                                    queue_ids,
+                                    result_tx,
+                                } => {
+                                    queue.load(&queue_ids).await;
+                                    let mut result = Vec::with_capacity(queue_ids.len());
+                                    for queue_id in queue_ids {
+                                        result.push(
+                                            queue
+                                                .messages
+                                                .get(&queue_id)
+                                                .map(|message| message.as_ref().into()),
+                                        );
+                                    }
+                                    let _ = result_tx.send(result);
+                                }
+                                management::QueueRequest::Cancel {
+                                    queue_ids,
+                                    item,
+                                    result_tx,
+                                } => {
+                                    queue.load(&queue_ids).await;
+                                    let mut result = Vec::with_capacity(queue_ids.len());
+                                    for queue_id in &queue_ids {
+                                        let mut found = false;
+                                        if let Some(item) = &item {
+                                            if let Some(message) = queue.messages.get_mut(queue_id)
+                                            {
+                                                // Cancel delivery for all recipients that match
+                                                for rcpt in &mut message.recipients {
+                                                    if rcpt.address_lcase.contains(item) {
+                                                        rcpt.flags |= RCPT_STATUS_CHANGED;
+                                                        rcpt.status =
+                                                            Status::Completed(HostResponse {
+                                                                hostname: String::new(),
+                                                                response: Response {
+                                                                    code: 0,
+                                                                    esc: [0, 0, 0],
+                                                                    message: "Delivery canceled."
+                                                                        .to_string(),
+                                                                },
+                                                            });
+                                                        found = true;
+                                                    }
                                                 }
-                                            }
-                                            if found {
-                                                // Mark as completed domains without any pending deliveries
-                                                for (domain_idx, domain) in
-                                                    message.domains.iter_mut().enumerate()
-                                                {
-                                                    if matches!(
-                                                        domain.status,
-                                                        Status::TemporaryFailure(_)
-                                                            | Status::Scheduled
-                                                    ) {
-                                                        let mut total_rcpt = 0;
-                                                        let mut total_completed = 0;
-
-                                                        for rcpt in &message.recipients {
-                                                            if rcpt.domain_idx == domain_idx {
-                                                                total_rcpt += 1;
-                                                                if matches!(
-                                                                    rcpt.status,
-                                                                    Status::PermanentFailure(_)
-                                                                        | Status::Completed(_)
-                                                                ) {
-                                                                    total_completed += 1;
+                                                if found {
+                                                    // Mark as completed domains without any pending deliveries
+                                                    for (domain_idx, domain) in
+                                                        message.domains.iter_mut().enumerate()
+                                                    {
+                                                        if matches!(
+                                                            domain.status,
+                                                            Status::TemporaryFailure(_)
+                                                                | Status::Scheduled
+                                                        ) {
+                                                            let mut total_rcpt = 0;
+                                                            let mut total_completed = 0;
+
+                                                            for rcpt in &message.recipients {
+                                                                if rcpt.domain_idx == domain_idx {
+                                                                    total_rcpt += 1;
+                                                                    if matches!(
+                                                                        rcpt.status,
+                                                                        Status::PermanentFailure(_)
+                                                                            | Status::Completed(_)
+                                                                    ) {
+                                                                        total_completed += 1;
+                                                                    }
                                                                 }
                                                             }
+
+                                                            if total_rcpt == total_completed {
+                                                                domain.status =
+                                                                    Status::Completed(());
+                                                                domain.changed = true;
+                                                            }
                                                         }
+                                                    }
 
-                                                        if total_rcpt == total_completed {
-                                                            domain.status = Status::Completed(());
-                                                            domain.changed = true;
+                                                    // Delete message if there are no pending deliveries
+                                                    if message.domains.iter().any(|domain| {
+                                                        matches!(
+                                                            domain.status,
+                                                            Status::TemporaryFailure(_)
+                                                                | Status::Scheduled
+                                                        )
+                                                    }) {
+                                                        message.save_changes().await;
+                                                    } else {
+                                                        message
+                                                            .remove(&core.queue.config.spool)
+                                                            .await;
+                                                        queue.messages.remove(queue_id);
+                                                    }
+                                                }
+                                            }
+                                        } else if let Some(message) =
+                                            queue.messages.remove(queue_id)
+                                        {
+                                            message.remove(&core.queue.config.spool).await;
+                                            found = true;
+                                        }
+                                        result.push(found);
+                                    }
+                                    let _ = result_tx.send(result);
+                                }
+                                management::QueueRequest::Retry {
+                                    queue_ids,
+                                    item,
+                                    error,
+                                    time,
+                                    result_tx,
+                                } => {
+                                    // Without explicit ids, an error filter selects the whole queue
+                                    let is_bulk = queue_ids.is_empty() && error.is_some();
+                                    let queue_ids = if let (true, Some(filter)) = (is_bulk, &error)
+                                    {
+                                        let evicted_ids = queue
+                                            .read_evicted(|message| {
+                                                message.domains.iter().any(|domain| {
+                                                    match &domain.status {
+                                                        Status::TemporaryFailure(err) => {
+                                                            err.matches(filter)
                                                         }
+                                                        _ => false,
+                                                    }
+                                                })
+                                            })
+                                            .await;
+                                        queue.load(&evicted_ids).await;
+                                        queue.messages.keys().copied().collect::<Vec<_>>()
+                                    } else {
+                                        queue.load(&queue_ids).await;
+                                        queue_ids
+                                    };
+                                    let mut result = Vec::with_capacity(queue_ids.len());
+                                    for queue_id in &queue_ids {
+                                        let mut found = false;
+                                        if let Some(message) = queue.messages.get_mut(queue_id) {
+                                            for domain in &mut message.domains {
+                                                if match (&domain.status, &error) {
+                                                    (
+                                                        Status::TemporaryFailure(err),
+                                                        Some(filter),
+                                                    ) => err.matches(filter),
+                                                    (Status::Scheduled, None)
+                                                    | (Status::TemporaryFailure(_), None) => true,
+                                                    _ => false,
+                                                } && item.as_ref().map_or(true, |item| {
+                                                    domain.domain.contains(item)
+                                                }) {
+                                                    domain.retry.due = time;
+                                                    if domain.expires > time {
+                                                        domain.expires =
+                                                            time + Duration::from_secs(10);
                                                     }
+                                                    domain.changed = true;
+                                                    found = true;
+                                                }
+                                            }
+
+                                            if found {
+                                                queue.on_hold.retain(|oh| &oh.message != queue_id);
+                                                message.save_changes().await;
+                                                if let Some(next_event) = message.next_event() {
+                                                    queue.scheduled.push(Schedule {
+                                                        due: next_event,
+                                                        inner: *queue_id,
+                                                    });
                                                 }
+                                            }
+                                        }
+                                        if found || !is_bulk {
+                                            result.push(found);
+                                        }
+                                    }
+                                    let _ = result_tx.send(result);
+                                }
+                                management::QueueRequest::Bounce {
+                                    queue_ids,
+                                    item,
+                                    result_tx,
+                                } => {
+                                    queue.load(&queue_ids).await;
+                                    let mut result = Vec::with_capacity(queue_ids.len());
+                                    for queue_id in &queue_ids {
+                                        let mut found = false;
+                                        if let Some(mut message) = queue.messages.remove(queue_id) {
+                                            if message.bounce(item.as_deref()) {
+                                                found = true;
+                                                tracing::info!(
+                                                    context = "queue",
+                                                    event = "bounce",
+                                                    id = *queue_id,
+                                                    filter = item.as_deref().unwrap_or_default(),
+                                                    "Message bounced by the administrator."
+                                                );
+
+                                                let mut attempt = DeliveryAttempt::from(message);
+                                                core.queue.send_dsn(&mut attempt).await;
+                                                message = attempt.message;
 
                                                 // Delete message if there are no pending deliveries
                                                 if message.domains.iter().any(|domain| {
@@ -229,68 +396,70 @@ impl SpawnQueue for mpsc::Receiver<Event> {
                                                     )
                                                 }) {
                                                     message.save_changes().await;
+                                                    queue.messages.insert(*queue_id, message);
                                                 } else {
-                                                    message.remove().await;
-                                                    queue.messages.remove(queue_id);
+                                                    queue
+                                                        .on_hold
+                                                        .retain(|oh| &oh.message != queue_id);
+                                                    message.remove(&core.queue.config.spool).await;
                                                 }
+                                            } else {
+                                                queue.messages.insert(*queue_id, message);
                                             }
                                         }
-                                    } else if let Some(message) = queue.messages.remove(queue_id) {
-                                        message.remove().await;
-                                        found = true;
+                                        result.push(found);
                                     }
-                                    result.push(found);
+                                    let _ = result_tx.send(result);
                                 }
-                                let _ = result_tx.send(result);
-                            }
-                            management::QueueRequest::Retry {
-                                queue_ids,
-                                item,
-                                time,
-                                result_tx,
-                            } => {
-                                let mut result = Vec::with_capacity(queue_ids.len());
-                                for queue_id in &queue_ids {
-                                    let mut found = false;
-                                    if let Some(message) = queue.messages.get_mut(queue_id) {
-                                        for domain in &mut message.domains {
-                                            if matches!(
-                                                domain.status,
-                                                Status::Scheduled | Status::TemporaryFailure(_)
-                                            ) && item
-                                                .as_ref()
-                                                .map_or(true, |item| domain.domain.contains(item))
+                                management::QueueRequest::Edit {
+                                    queue_id,
+                                    edit,
+                                    result_tx,
+                                } => {
+                                    queue.load(&[queue_id]).await;
+
+                                    // Messages being delivered are not resident in the queue
+                                    let result =
+                                        if let Some(message) = queue.messages.get_mut(&queue_id) {
+                                            match message
+                                                .edit_recipients(edit, &core.queue.config)
+                                                .await
                                             {
-                                                domain.retry.due = time;
-                                                if domain.expires > time {
-                                                    domain.expires = time + Duration::from_secs(10);
+                                                Ok(_) => {
+                                                    message.save_metadata().await;
+                                                    if let Some(next_event) = message.next_event() {
+                                                        queue.scheduled.push(Schedule {
+                                                            due: next_event,
+                                                            inner: queue_id,
+                                                        });
+                                                    }
+                                                    Ok(message.as_ref().into())
                                                 }
-                                                domain.changed = true;
-                                                found = true;
+                                                Err(err) => Err(err),
                                             }
-                                        }
-
-                                        if found {
-                                            queue.on_hold.retain(|oh| &oh.message != queue_id);
-                                            message.save_changes().await;
-                                            if let Some(next_event) = message.next_event() {
-                                                queue.scheduled.push(Schedule {
-                                                    due: next_event,
-                                                    inner: *queue_id,
-                                                });
-                                            }
-                                        }
-                                    }
-                                    result.push(found);
+                                        } else {
+                                            Err(format!(
+                                            "Message {queue_id} not found or currently in flight."
+                                        ))
+                                        };
+                                    let _ = result_tx.send(result);
                                 }
-                                let _ = result_tx.send(result);
                             }
-                        },
+                        }
+                        Event::Release { domains, result_tx } => {
+                            let evicted_ids = queue
+                                .read_evicted(|message| message.is_released_by(&domains))
+                                .await;
+                            queue.load(&evicted_ids).await;
+                            let _ = result_tx.send(queue.release(&domains));
+                        }
                         Event::Stop => break,
                     },
                     Ok(None) => break,
                     Err(_) => (),
                 }
+
+                queue.trim();
             }
         });
     }
@@ -302,7 +471,190 @@ impl Queue {
             due: message.due,
             inner: message.inner.id,
         });
-        self.messages.insert(message.inner.id, message.inner);
+        if self
+            .max_resident
+            .map_or(false, |max| self.messages.len() >= max)
+        {
+            self.evict(message.inner);
+        } else {
+            self.messages.insert(message.inner.id, message.inner);
+        }
+    }
+
+    fn evict(&mut self, mut message: Box<Message>) {
+        self.evicted.insert(
+            message.id,
+            Evicted {
+                path: std::mem::take(&mut message.path),
+                size: message.size,
+                queue_refs: std::mem::take(&mut message.queue_refs),
+            },
+        );
+    }
+
+    pub async fn load_due(&mut self) {
+        if self.evicted.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut num_loaded = 0;
+        while num_loaded < self.load_batch
+            && matches!(self.scheduled.peek(), Some(item) if item.due <= now)
+        {
+            let item = self.scheduled.pop().unwrap();
+            if let Some(evicted) = self.evicted.remove(&item.inner) {
+                self.restore(item.inner, evicted).await;
+                num_loaded += 1;
+            }
+            due.push(item);
+        }
+        self.scheduled.extend(due);
+    }
+
+    pub async fn load(&mut self, queue_ids: &[QueueId]) {
+        for queue_id in queue_ids {
+            if let Some(evicted) = self.evicted.remove(queue_id) {
+                self.restore(*queue_id, evicted).await;
+            }
+        }
+    }
+
+    // Reads the metadata of evicted messages without making them resident
+    pub async fn read_evicted(&self, filter: impl Fn(&Message) -> bool) -> Vec<QueueId> {
+        let mut queue_ids = Vec::new();
+        for (queue_id, evicted) in &self.evicted {
+            match Message::from_path(evicted.path.clone()).await {
+                Ok(message) => {
+                    if filter(&message) {
+                        queue_ids.push(*queue_id);
+                    }
+                }
+                Err(err) => {
+                    tracing::error!(
+                        context = "queue",
+                        event = "error",
+                        "Failed to read queued message: {}",
+                        err
+                    );
+                }
+            }
+        }
+        queue_ids
+    }
+
+    async fn restore(&mut self, queue_id: QueueId, evicted: Evicted) {
+        match Message::from_path(evicted.path).await {
+            Ok(mut message) => {
+                message.queue_refs = evicted.queue_refs;
+                self.messages.insert(queue_id, Box::new(message));
+            }
+            Err(err) => {
+                tracing::error!(
+                    context = "queue",
+                    event = "error",
+                    "Failed to reload queued message: {}",
+                    err
+                );
+            }
+        }
+    }
+
+    pub fn trim(&mut self) {
+        let max = match self.max_resident {
+            Some(max) if self.messages.len() > max => max,
+            _ => return,
+        };
+
+        let now = Instant::now();
+        let on_hold = self
+            .on_hold
+            .iter()
+            .map(|on_hold| on_hold.message)
+            .collect::<AHashSet<_>>();
+        let queue_ids = self
+            .scheduled
+            .iter()
+            .filter(|item| {
+                item.due > now
+                    && !on_hold.contains(&item.inner)
+                    && self.messages.contains_key(&item.inner)
+            })
+            .map(|item| item.inner)
+            .collect::<AHashSet<_>>()
+            .into_iter()
+            .take(self.messages.len() - max)
+            .collect::<Vec<_>>();
+        for queue_id in queue_ids {
+            if let Some(message) = self.messages.remove(&queue_id) {
+                self.evict(message);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> management::QueueStats {
+        let now = Instant::now();
+        let mut stats = management::QueueStats::default();
+
+        let on_hold = self
+            .on_hold
+            .iter()
+            .map(|on_hold| on_hold.message)
+            .collect::<AHashSet<_>>();
+        for queue_id in &on_hold {
+            if let Some(message) = self.messages.get(queue_id) {
+                stats.on_hold += 1;
+                stats.size += message.size;
+            }
+        }
+
+        // The scheduled heap may contain stale or repeated entries,
+        // use the earliest one for each message still in the queue.
+        let mut scheduled = AHashMap::with_capacity(self.scheduled.len());
+        for (queue_id, due) in self
+            .scheduled
+            .iter()
+            .map(|item| (item.inner, item.due))
+            .chain(self.ready.iter().map(|item| (item.queue_id, now)))
+            .filter(|(queue_id, _)| !on_hold.contains(queue_id))
+        {
+            scheduled
+                .entry(queue_id)
+                .and_modify(|next_due: &mut Instant| {
+                    if due < *next_due {
+                        *next_due = due;
+                    }
+                })
+                .or_insert(due);
+        }
+        for (queue_id, due) in scheduled {
+            stats.size += if let Some(message) = self.messages.get(&queue_id) {
+                message.size
+            } else if let Some(evicted) = self.evicted.get(&queue_id) {
+                evicted.size
+            } else {
+                continue;
+            };
+
+            let wait = due.saturating_duration_since(now);
+            if wait.is_zero() {
+                stats.retry.now += 1;
+            } else if wait < Duration::from_secs(3600) {
+                stats.retry.hour += 1;
+            } else if wait < Duration::from_secs(86400) {
+                stats.retry.day += 1;
+            } else {
+                stats.retry.later += 1;
+            }
+        }
+
+        stats.total = stats.on_hold
+            + stats.retry.now
+            + stats.retry.hour
+            + stats.retry.day
+            + stats.retry.later;
+        stats
     }
 
     pub fn on_hold(&mut self, message: OnHold<Box<Message>>) {
@@ -315,8 +667,32 @@ impl Queue {
     }
 
     pub fn next_due(&mut self) -> Option<Box<Message>> {
+        if let Some(aging) = self.priority_aging {
+            // Order due messages by their priority, which increases as they wait
+            let now = Instant::now();
+            let mut not_loaded = Vec::new();
+            while matches!(self.scheduled.peek(), Some(item) if item.due <= now) {
+                let item = self.scheduled.pop().unwrap();
+                if let Some(message) = self.messages.get(&item.inner) {
+                    self.ready.push(Ready {
+                        priority: message.aged_priority(aging),
+                        queue_id: item.inner,
+                    });
+                } else if self.evicted.contains_key(&item.inner) {
+                    not_loaded.push(item);
+                }
+            }
+            self.scheduled.extend(not_loaded);
+            while let Some(item) = self.ready.pop() {
+                if let Some(message) = self.messages.remove(&item.queue_id) {
+                    return Some(message);
+                }
+            }
+            return None;
+        }
+
         let item = self.scheduled.peek()?;
-        if item.due <= Instant::now() {
+        if item.due <= Instant::now() && !self.evicted.contains_key(&item.inner) {
             self.scheduled
                 .pop()
                 .and_then(|i| self.messages.remove(&i.inner))
@@ -325,20 +701,110 @@ impl Queue {
         }
     }
 
-    pub fn next_on_hold(&mut self) -> Option<Box<Message>> {
+    pub fn next_batch(&mut self, message: &Message, max_messages: usize) -> Vec<Box<Message>> {
+        let mut batch = Vec::new();
+        let domain = if let Some(domain) = message.batch_domain() {
+            domain
+        } else {
+            return batch;
+        };
+
         let now = Instant::now();
+        let mut skipped = Vec::new();
+        while batch.len() < max_messages {
+            match self.scheduled.peek() {
+                Some(item) if item.due <= now => {
+                    let item = self.scheduled.pop().unwrap();
+                    match self.messages.get(&item.inner) {
+                        Some(candidate)
+                            if candidate.batch_domain() == Some(domain)
+                                && candidate.has_flag(MAIL_REQUIRETLS)
+                                    == message.has_flag(MAIL_REQUIRETLS) =>
+                        {
+                            batch.extend(self.messages.remove(&item.inner));
+                        }
+                        Some(_) => skipped.push(item),
+                        None if self.evicted.contains_key(&item.inner) => skipped.push(item),
+                        None => (),
+                    }
+                }
+                _ => break,
+            }
+        }
+        self.scheduled.extend(skipped);
+
+        if batch.len() < max_messages && !self.ready.is_empty() {
+            let mut skipped = Vec::new();
+            for item in std::mem::take(&mut self.ready)
+                .into_sorted_vec()
+                .into_iter()
+                .rev()
+            {
+                match self.messages.get(&item.queue_id) {
+                    Some(candidate)
+                        if batch.len() < max_messages
+                            && candidate.batch_domain() == Some(domain)
+                            && candidate.has_flag(MAIL_REQUIRETLS)
+                                == message.has_flag(MAIL_REQUIRETLS) =>
+                    {
+                        batch.extend(self.messages.remove(&item.queue_id));
+                    }
+                    Some(_) => skipped.push(item),
+                    None => (),
+                }
+            }
+            self.ready.extend(skipped);
+        }
+
+        batch
+    }
+
+    pub fn release(&mut self, domains: &[String]) -> Vec<Box<Message>> {
+        let queue_ids = self
+            .messages
+            .values()
+            .filter(|message| message.is_released_by(domains))
+            .map(|message| message.id)
+            .collect::<Vec<_>>();
+
+        let messages = queue_ids
+            .into_iter()
+            .filter_map(|queue_id| self.messages.remove(&queue_id))
+            .collect::<Vec<_>>();
         self.on_hold
-            .iter()
-            .position(|o| {
-                o.limiters
-                    .iter()
-                    .any(|l| l.concurrent.load(Ordering::Relaxed) < l.max_concurrent)
-                    || o.next_due.map_or(false, |due| due <= now)
+            .retain(|on_hold| self.messages.contains_key(&on_hold.message));
+        messages
+    }
+
+    pub fn next_on_hold(&mut self) -> Option<Box<Message>> {
+        let now = Instant::now();
+        let mut candidates = self.on_hold.iter().enumerate().filter(|(_, o)| {
+            o.limiters
+                .iter()
+                .any(|l| l.concurrent.load(Ordering::Relaxed) < l.max_concurrent)
+                || o.next_due.map_or(false, |due| due <= now)
+        });
+        let candidate = if let Some(aging) = self.priority_aging {
+            candidates.min_by_key(|(_, o)| {
+                std::cmp::Reverse(
+                    self.messages
+                        .get(&o.message)
+                        .map_or(i64::MIN, |message| message.aged_priority(aging)),
+                )
             })
+        } else {
+            candidates.next()
+        };
+
+        candidate
+            .map(|(pos, _)| pos)
             .and_then(|pos| self.messages.remove(&self.on_hold.remove(pos).message))
     }
 
     pub fn wake_up_time(&self) -> Duration {
+        if !self.ready.is_empty() {
+            return self.short_wait;
+        }
         self.scheduled
             .peek()
             .map(|item| {
@@ -351,6 +817,24 @@ impl Queue {
 }
 
 impl Message {
+    pub fn is_released_by(&self, domains: &[String]) -> bool {
+        self.domains.iter().any(|domain| {
+            matches!(
+                domain.status,
+                Status::Scheduled | Status::TemporaryFailure(_)
+            ) && domains
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&domain.domain))
+        })
+    }
+
+    pub fn aged_priority(&self, aging: Duration) -> i64 {
+        // Equivalent to comparing priority + age / aging, but independent of the current time
+        (self.priority as i64)
+            .saturating_mul(aging.as_secs().max(1) as i64)
+            .saturating_sub(self.created as i64)
+    }
+
     pub fn next_event(&self) -> Option<Instant> {
         let mut next_event = Instant::now();
         let mut has_events = false;
@@ -380,6 +864,27 @@ impl Message {
         }
     }
 
+    pub fn batch_domain(&self) -> Option<&str> {
+        let now = Instant::now();
+        match self.domains.as_slice() {
+            [domain]
+                if matches!(
+                    domain.status,
+                    Status::Scheduled | Status::TemporaryFailure(_)
+                ) && domain.retry.due <= now
+                    && domain.notify.due > now
+                    && domain.expires > now
+                    && !self
+                        .recipients
+                        .iter()
+                        .any(|rcpt| rcpt.has_flag(RCPT_VERIFY_PENDING)) =>
+            {
+                Some(domain.domain.as_str())
+            }
+            _ => None,
+        }
+    }
+
     pub fn next_delivery_event(&self) -> Instant {
         let mut next_delivery = Instant::now();
 
@@ -433,8 +938,14 @@ impl Message {
 
 impl QueueCore {
     pub async fn read_queue(&self) -> Queue {
-        let mut queue = Queue::default();
-        let mut messages = Vec::new();
+        let mut queue = Queue {
+            priority_aging: self.config.priority_aging,
+            max_resident: self.config.max_resident,
+            load_batch: self.config.load_batch,
+            dedup: self.config.dedup.map(DedupIndex::new),
+            ..Default::default()
+        };
+        let mut paths = Vec::new();
 
         for path in self
             .config
@@ -460,10 +971,28 @@ impl QueueCore {
                                         match dir.next_entry().await {
                                             Ok(Some(file)) => {
                                                 let file = file.path();
-                                                if file.extension().map_or(false, |e| e == "msg") {
-                                                    messages.push(tokio::spawn(
-                                                        Message::from_path(file),
-                                                    ));
+                                                if file.extension().map_or(false, |e| {
+                                                    matches!(
+                                                        e.to_str(),
+                                                        Some(
+                                                            "msg"
+                                                                | "meta"
+                                                                | "emsg"
+                                                                | "emeta"
+                                                                | "zmsg"
+                                                                | "zmeta"
+                                                                | "zemsg"
+                                                                | "zemeta"
+                                                        )
+                                                    )
+                                                }) {
+                                                    paths.push(file);
+                                                } else if file
+                                                    .extension()
+                                                    .map_or(false, |e| e == "tmp")
+                                                {
+                                                    // Metadata rewrite interrupted by a crash
+                                                    let _ = tokio::fs::remove_file(&file).await;
                                                 }
                                             }
                                             Ok(None) => break,
@@ -486,8 +1015,25 @@ impl QueueCore {
                                     )
                                 }
                             };
-                        } else if file.extension().map_or(false, |e| e == "msg") {
-                            messages.push(tokio::spawn(Message::from_path(file)));
+                        } else if file.extension().map_or(false, |e| {
+                            matches!(
+                                e.to_str(),
+                                Some(
+                                    "msg"
+                                        | "meta"
+                                        | "emsg"
+                                        | "emeta"
+                                        | "zmsg"
+                                        | "zmeta"
+                                        | "zemsg"
+                                        | "zemeta"
+                                )
+                            )
+                        }) {
+                            paths.push(file);
+                        } else if file.extension().map_or(false, |e| e == "tmp") {
+                            // Metadata rewrite interrupted by a crash
+                            let _ = tokio::fs::remove_file(&file).await;
                         }
                     }
                     Ok(None) => {
@@ -505,37 +1051,43 @@ impl QueueCore {
             }
         }
 
-        // Join all futures
-        for message in messages {
-            match message.await {
-                Ok(Ok(mut message)) => {
-                    // Reserve quota
-                    self.has_quota(&mut message).await;
-
-                    // Schedule message
-                    queue.schedule(Schedule {
-                        due: message.next_event().unwrap_or_else(|| {
-                            tracing::warn!(
-                                context = "queue",
-                                event = "warn",
-                                "No due events found for message {}",
-                                message.path.display()
-                            );
-                            Instant::now()
-                        }),
-                        inner: Box::new(message),
-                    });
-                }
-                Ok(Err(err)) => {
-                    tracing::warn!(
-                        context = "queue",
-                        event = "error",
-                        "Queue startup error: {}",
-                        err
-                    );
-                }
-                Err(err) => {
-                    tracing::error!("Join error while starting queue: {}", err);
+        // Load messages in batches to bound the number of open files
+        for paths in paths.chunks(self.config.load_batch) {
+            let messages = paths
+                .iter()
+                .map(|path| tokio::spawn(Message::from_path(path.clone())))
+                .collect::<Vec<_>>();
+            for message in messages {
+                match message.await {
+                    Ok(Ok(mut message)) => {
+                        // Reserve quota
+                        self.has_quota(&mut message).await;
+
+                        // Schedule message
+                        queue.schedule(Schedule {
+                            due: message.next_event().unwrap_or_else(|| {
+                                tracing::warn!(
+                                    context = "queue",
+                                    event = "warn",
+                                    "No due events found for message {}",
+                                    message.path.display()
+                                );
+                                Instant::now()
+                            }),
+                            inner: Box::new(message),
+                        });
+                    }
+                    Ok(Err(err)) => {
+                        tracing::warn!(
+                            context = "queue",
+                            event = "error",
+                            "Queue startup error: {}",
+                            err
+                        );
+                    }
+                    Err(err) => {
+                        tracing::error!("Join error while starting queue: {}", err);
+                    }
                 }
             }
         }
@@ -544,14 +1096,26 @@ impl QueueCore {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Ready {
+    priority: i64,
+    queue_id: QueueId,
+}
+
 impl Default for Queue {
     fn default() -> Self {
         Queue {
             short_wait: Duration::from_millis(1),
             long_wait: Duration::from_secs(86400 * 365),
+            priority_aging: None,
             scheduled: BinaryHeap::with_capacity(128),
+            ready: BinaryHeap::new(),
             on_hold: Vec::with_capacity(128),
             messages: AHashMap::with_capacity(128),
+            max_resident: None,
+            load_batch: 1024,
+            evicted: AHashMap::new(),
+            dedup: None,
         }
     }
 }
@@ -31,16 +31,24 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 use smtp_proto::Response;
-
-use crate::core::{
-    management,
-    throttle::{ConcurrencyLimiter, InFlight},
-    Envelope,
+use tokio::sync::oneshot;
+
+use crate::{
+    config::DeliveryWindow,
+    core::{
+        management,
+        throttle::{ConcurrencyLimiter, InFlight},
+        Envelope,
+    },
 };
 
+pub mod dedup;
 pub mod dsn;
+pub mod encryption;
 pub mod manager;
 pub mod quota;
+pub mod rejected;
+pub mod s3;
 pub mod serialize;
 pub mod spool;
 pub mod throttle;
@@ -51,6 +59,10 @@ pub type QueueId = u64;
 pub enum Event {
     Queue(Schedule<Box<Message>>),
     Manage(management::QueueRequest),
+    Release {
+        domains: Vec<String>,
+        result_tx: oneshot::Sender<Vec<Box<Message>>>,
+    },
     Done(WorkerResult),
     Stop,
 }
@@ -89,10 +101,12 @@ pub struct Message {
 
     pub flags: u64,
     pub env_id: Option<String>,
+    pub metadata: Option<String>,
     pub priority: i16,
 
     pub size: usize,
     pub queue_refs: Vec<UsedQuota>,
+    pub dedup_hash: Option<blake3::Hash>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -117,6 +131,8 @@ pub struct Recipient {
 
 pub const RCPT_DSN_SENT: u64 = 1 << 32;
 pub const RCPT_STATUS_CHANGED: u64 = 2 << 32;
+pub const RCPT_VERIFY_PENDING: u64 = 4 << 32;
+pub const RCPT_OVER_QUOTA_NOTIFIED: u64 = 8 << 32;
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Status<T, E> {
@@ -220,6 +236,34 @@ impl<T: Default> Schedule<T> {
     }
 }
 
+impl DeliveryWindow {
+    pub fn wait_time(&self, now: u64) -> Duration {
+        let now = now as i64 + self.offset as i64;
+        let today = now - now.rem_euclid(86400);
+        let weekday = (now.div_euclid(86400) + 3).rem_euclid(7);
+        let end = if self.end > self.start {
+            self.end as i64
+        } else {
+            self.end as i64 + 86400
+        };
+
+        // Windows crossing midnight might have started the day before
+        for day in -1..=7 {
+            if self.days & (1 << (weekday + day).rem_euclid(7)) != 0 {
+                let window_start = today + day * 86400 + self.start as i64;
+                let window_end = today + day * 86400 + end;
+                if (window_start..window_end).contains(&now) {
+                    break;
+                } else if window_start > now {
+                    return Duration::from_secs((window_start - now) as u64);
+                }
+            }
+        }
+
+        Duration::ZERO
+    }
+}
+
 pub struct SimpleEnvelope<'x> {
     pub message: &'x Message,
     pub domain: &'x str,
@@ -288,6 +332,14 @@ impl<'x> Envelope for SimpleEnvelope<'x> {
     fn priority(&self) -> i16 {
         self.message.priority
     }
+
+    fn country(&self) -> &str {
+        ""
+    }
+
+    fn asn(&self) -> u32 {
+        0
+    }
 }
 
 pub struct QueueEnvelope<'x> {
@@ -342,6 +394,14 @@ impl<'x> Envelope for QueueEnvelope<'x> {
     fn priority(&self) -> i16 {
         self.message.priority
     }
+
+    fn country(&self) -> &str {
+        ""
+    }
+
+    fn asn(&self) -> u32 {
+        0
+    }
 }
 
 impl Envelope for Message {
@@ -388,6 +448,14 @@ impl Envelope for Message {
     fn priority(&self) -> i16 {
         self.priority
     }
+
+    fn country(&self) -> &str {
+        ""
+    }
+
+    fn asn(&self) -> u32 {
+        0
+    }
 }
 
 impl Envelope for &str {
@@ -434,6 +502,14 @@ impl Envelope for &str {
     fn priority(&self) -> i16 {
         0
     }
+
+    fn country(&self) -> &str {
+        ""
+    }
+
+    fn asn(&self) -> u32 {
+        0
+    }
 }
 
 #[inline(always)]
@@ -480,6 +556,27 @@ impl DomainPart for String {
     }
 }
 
+impl Error {
+    pub fn matches(&self, filter: &str) -> bool {
+        let kind = match self {
+            Error::DnsError(_) => "DnsError",
+            Error::UnexpectedResponse(_) => "UnexpectedResponse",
+            Error::ConnectionError(_) => "ConnectionError",
+            Error::TlsError(_) => "TlsError",
+            Error::DaneError(_) => "DaneError",
+            Error::MtaStsError(_) => "MtaStsError",
+            Error::RateLimited => "RateLimited",
+            Error::ConcurrencyLimited => "ConcurrencyLimited",
+            Error::Io(_) => "Io",
+        };
+        kind.eq_ignore_ascii_case(filter)
+            || self
+                .to_string()
+                .to_lowercase()
+                .contains(&filter.to_lowercase())
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -0,0 +1,214 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{net::IpAddr, path::PathBuf, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite},
+};
+
+use crate::{
+    config::RejectedStore,
+    core::{Core, Session},
+};
+
+use super::{DomainPart, Message, QueueId};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RejectedMessage {
+    pub id: QueueId,
+    pub created: u64,
+    pub remote_ip: IpAddr,
+    pub helo_domain: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(default)]
+    pub authenticated_as: String,
+    pub return_path: String,
+    pub recipients: Vec<String>,
+    pub reason: String,
+    pub size: usize,
+}
+
+impl<T: AsyncWrite + AsyncRead + Unpin> Session<T> {
+    pub async fn store_rejected(&self, raw_message: &[u8], response: &[u8]) {
+        let store = match &self.core.session.config.data.rejected {
+            Some(store) if *store.enable.eval(self).await => store,
+            _ => return,
+        };
+        let id = self.core.queue.queue_id();
+        let rejected = RejectedMessage {
+            id,
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            remote_ip: self.data.remote_ip,
+            helo_domain: self.data.helo_domain.clone(),
+            authenticated_as: self.data.authenticated_as.clone(),
+            return_path: self
+                .data
+                .mail_from
+                .as_ref()
+                .map(|addr| addr.address.clone())
+                .unwrap_or_default(),
+            recipients: self
+                .data
+                .rcpt_to
+                .iter()
+                .map(|rcpt| rcpt.address.clone())
+                .collect(),
+            reason: String::from_utf8_lossy(response).trim_end().to_string(),
+            size: raw_message.len(),
+        };
+
+        let result = match fs::write(store.message_path(id), raw_message).await {
+            Ok(_) => {
+                fs::write(
+                    store.metadata_path(id),
+                    serde_json::to_vec(&rejected).unwrap_or_default(),
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(_) => {
+                tracing::debug!(parent: &self.span,
+                    context = "data",
+                    event = "store-rejected",
+                    id = id,
+                    reason = &rejected.reason);
+            }
+            Err(err) => {
+                tracing::warn!(parent: &self.span,
+                    context = "data",
+                    event = "error",
+                    "Failed to store rejected message in {}: {}",
+                    store.path.display(),
+                    err);
+            }
+        }
+    }
+}
+
+impl Core {
+    pub async fn list_rejected(&self) -> Vec<QueueId> {
+        let mut ids = Vec::new();
+        if let Some(store) = &self.session.config.data.rejected {
+            if let Ok(mut dir) = fs::read_dir(&store.path).await {
+                while let Ok(Some(entry)) = dir.next_entry().await {
+                    if let Some(id) = entry
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| name.strip_suffix(".json"))
+                        .and_then(|id| id.parse().ok())
+                    {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+        ids.sort_unstable();
+        ids
+    }
+
+    pub async fn rejected_status(&self, id: QueueId) -> Option<RejectedMessage> {
+        let store = self.session.config.data.rejected.as_ref()?;
+        serde_json::from_slice(&fs::read(store.metadata_path(id)).await.ok()?).ok()
+    }
+
+    pub async fn replay_rejected(&self, id: QueueId) -> bool {
+        let (store, rejected) = match (
+            &self.session.config.data.rejected,
+            self.rejected_status(id).await,
+        ) {
+            (Some(store), Some(rejected)) if !rejected.recipients.is_empty() => (store, rejected),
+            _ => return false,
+        };
+        let raw_message = match fs::read(store.message_path(id)).await {
+            Ok(raw_message) => raw_message,
+            Err(_) => return false,
+        };
+
+        // Queue the message as received, skipping the rules that rejected it
+        let return_path_lcase = rejected.return_path.to_lowercase();
+        let return_path_domain = return_path_lcase.domain_part().to_string();
+        let mut message =
+            Message::new_boxed(rejected.return_path, return_path_lcase, return_path_domain);
+        for rcpt in rejected.recipients {
+            message.add_recipient(rcpt, &self.queue.config).await;
+        }
+        let span = tracing::info_span!("replay", id = id);
+        if self
+            .queue
+            .queue_message(message, None, &raw_message, &span)
+            .await
+        {
+            tracing::info!(
+                parent: &span,
+                context = "queue",
+                event = "replay",
+                reason = &rejected.reason,
+                "Queued previously rejected message."
+            );
+            let _ = fs::remove_file(store.metadata_path(id)).await;
+            let _ = fs::remove_file(store.message_path(id)).await;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn purge_rejected(&self) {
+        let store = if let Some(store) = &self.session.config.data.rejected {
+            store
+        } else {
+            return;
+        };
+        let now = SystemTime::now();
+        if let Ok(dir) = std::fs::read_dir(&store.path) {
+            for entry in dir.flatten() {
+                if entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+                    .and_then(|modified| now.duration_since(modified).ok())
+                    .map_or(false, |age| age > store.retention)
+                {
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+    }
+}
+
+impl RejectedStore {
+    fn message_path(&self, id: QueueId) -> PathBuf {
+        self.path.join(format!("{id}.eml"))
+    }
+
+    fn metadata_path(&self, id: QueueId) -> PathBuf {
+        self.path.join(format!("{id}.json"))
+    }
+}
@@ -0,0 +1,202 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{fmt::Write, time::SystemTime};
+
+use mail_parser::DateTime;
+use reqwest::{
+    header::{AUTHORIZATION, HOST, RANGE},
+    Method, StatusCode, Url,
+};
+use sha2::{Digest, Sha256};
+
+use crate::lookup::hash::hmac_sha256;
+
+use super::{
+    spool::{SpoolFuture, SpoolStore},
+    Message,
+};
+
+pub struct S3Spool {
+    pub endpoint: Url,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub prefix: String,
+    pub client: reqwest::Client,
+}
+
+impl SpoolStore for S3Spool {
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    fn put<'x>(&'x self, message: &'x Message, chunks: &'x [&'x [u8]]) -> SpoolFuture<'x, ()> {
+        Box::pin(async move {
+            self.request(Method::PUT, message.storage_key(), chunks.concat(), None)
+                .await
+                .map(|_| ())
+        })
+    }
+
+    fn get<'x>(&'x self, message: &'x Message, len: usize) -> SpoolFuture<'x, Vec<u8>> {
+        Box::pin(async move {
+            let key = message.storage_key();
+            let response = self.request(Method::GET, key, vec![], Some(len)).await?;
+            response
+                .bytes()
+                .await
+                .map(|bytes| bytes.to_vec())
+                .map_err(|err| format!("Failed to fetch S3 object {key:?}: {err}"))
+        })
+    }
+
+    fn delete<'x>(&'x self, message: &'x Message) -> SpoolFuture<'x, ()> {
+        Box::pin(async move {
+            self.request(Method::DELETE, message.storage_key(), vec![], None)
+                .await
+                .map(|_| ())
+        })
+    }
+}
+
+impl S3Spool {
+    async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        body: Vec<u8>,
+        max_len: Option<usize>,
+    ) -> Result<reqwest::Response, String> {
+        let path = format!(
+            "{}/{}/{}{}",
+            self.endpoint.path().trim_end_matches('/'),
+            uri_encode(&self.bucket),
+            uri_encode(&self.prefix),
+            uri_encode(key)
+        );
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            _ => return Err(format!("Invalid S3 endpoint {:?}.", self.endpoint.as_str())),
+        };
+        let payload_hash = hex(&Sha256::digest(&body));
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let (amz_date, authorization) =
+            self.sign(method.as_str(), &path, &host, &payload_hash, now);
+
+        let mut request = self
+            .client
+            .request(method.clone(), url)
+            .header(HOST, host)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(AUTHORIZATION, authorization);
+        if let Some(max_len) = max_len.filter(|len| *len > 0) {
+            request = request.header(RANGE, format!("bytes=0-{}", max_len - 1));
+        }
+        if !body.is_empty() {
+            request = request.body(body);
+        }
+
+        let response = request
+            .send()
+            .await
+            .map_err(|err| format!("Failed to {method} S3 object {key:?}: {err}"))?;
+        if response.status().is_success()
+            || (method == Method::DELETE && response.status() == StatusCode::NOT_FOUND)
+        {
+            Ok(response)
+        } else {
+            Err(format!(
+                "Failed to {method} S3 object {key:?}: {}",
+                response.status()
+            ))
+        }
+    }
+
+    pub fn sign(
+        &self,
+        method: &str,
+        path: &str,
+        host: &str,
+        payload_hash: &str,
+        now: u64,
+    ) -> (String, String) {
+        let dt = DateTime::from_timestamp(now as i64);
+        let date = format!("{:04}{:02}{:02}", dt.year, dt.month, dt.day);
+        let amz_date = format!("{}T{:02}{:02}{:02}Z", date, dt.hour, dt.minute, dt.second);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+        let canonical_request = format!(
+            "{method}\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}"
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let mut key = hmac_sha256(
+            format!("AWS4{}", self.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        (
+            amz_date,
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed_headers, signature
+            ),
+        )
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(result, "{byte:02x}");
+    }
+    result
+}
+
+fn uri_encode(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for &ch in value.as_bytes() {
+        if ch.is_ascii_alphanumeric() || matches!(ch, b'-' | b'_' | b'.' | b'~' | b'/') {
+            result.push(ch as char);
+        } else {
+            let _ = write!(result, "%{ch:02X}");
+        }
+    }
+    result
+}
@@ -32,8 +32,8 @@ use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
 use super::{
-    instant_to_timestamp, Domain, DomainPart, Error, ErrorDetails, HostResponse,
-    InstantFromTimestamp, Message, Recipient, Schedule, Status, RCPT_STATUS_CHANGED,
+    instant_to_timestamp, spool::stored_size, Domain, DomainPart, Error, ErrorDetails,
+    HostResponse, InstantFromTimestamp, Message, Recipient, Schedule, Status, RCPT_STATUS_CHANGED,
 };
 
 pub trait QueueSerializer: Sized {
@@ -46,6 +46,7 @@ impl Message {
         let mut buf = String::with_capacity(
             self.return_path.len()
                 + self.env_id.as_ref().map_or(0, |e| e.len())
+                + self.metadata.as_ref().map_or(0, |m| m.len())
                 + (self.domains.len() * 64)
                 + (self.recipients.len() * 64)
                 + 50,
@@ -84,6 +85,12 @@ impl Message {
             rcpt.serialize(idx, &mut buf);
         }
 
+        // Serialize metadata
+        if let Some(metadata) = &self.metadata {
+            buf.push_str("M0 ");
+            metadata.serialize(&mut buf);
+        }
+
         buf.into_bytes()
     }
 
@@ -109,16 +116,17 @@ impl Message {
     }
 
     pub async fn from_path(path: PathBuf) -> Result<Self, String> {
-        let filename = path
+        let (filename, extension) = path
             .file_name()
             .and_then(|f| f.to_str())
             .and_then(|f| f.rsplit_once('.'))
-            .map(|(f, _)| f)
             .ok_or_else(|| format!("Invalid queue file name {}", path.display()))?;
 
         // Decode file name
         let mut id = [0u8; std::mem::size_of::<u64>()];
         let mut size = [0u8; std::mem::size_of::<u32>()];
+        let mut compressed_size = [0u8; std::mem::size_of::<u32>()];
+        let is_compressed = extension.starts_with('z');
 
         for (pos, byte) in Base32Reader::new(filename.as_bytes()).enumerate() {
             match pos {
@@ -128,6 +136,9 @@ impl Message {
                 8..=11 => {
                     size[pos - 8] = byte;
                 }
+                12..=15 if is_compressed => {
+                    compressed_size[pos - 12] = byte;
+                }
                 _ => {
                     return Err(format!("Invalid queue file name {}", path.display()));
                 }
@@ -148,18 +159,37 @@ impl Message {
                 )
             })?
             .len();
-        if size == 0 || size >= file_size {
+        if size == 0 {
             return Err(format!(
                 "Invalid queue file name size {} for {}",
                 size,
                 path.display()
             ));
         }
-        let mut buf = Vec::with_capacity((file_size - size) as usize);
+
+        // Metadata-only files have their body stored in a remote spool
+        let offset = if extension.ends_with("msg") {
+            let size = stored_size(
+                size as usize,
+                is_compressed.then(|| u32::from_le_bytes(compressed_size) as usize),
+                extension.trim_start_matches('z') == "emsg",
+            ) as u64;
+            if size >= file_size {
+                return Err(format!(
+                    "Invalid queue file name size {} for {}",
+                    size,
+                    path.display()
+                ));
+            }
+            size
+        } else {
+            0
+        };
+        let mut buf = Vec::with_capacity((file_size - offset) as usize);
         let mut file = File::open(&path)
             .await
             .map_err(|err| format!("Failed to open queue file {}: {}", path.display(), err))?;
-        file.seek(SeekFrom::Start(size))
+        file.seek(SeekFrom::Start(offset))
             .await
             .map_err(|err| format!("Failed to seek queue file {}: {}", path.display(), err))?;
         file.read_to_end(&mut buf)
@@ -193,12 +223,14 @@ impl Message {
             } else {
                 None
             },
+            metadata: None,
             flags: usize::deserialize(&mut bytes)? as u64,
             priority: i16::deserialize(&mut bytes)?,
             size: 0,
             recipients: vec![],
             domains: vec![],
             queue_refs: vec![],
+            dedup_hash: None,
         };
 
         // Deserialize domains
@@ -268,6 +300,13 @@ impl Message {
                         break;
                     }
                 }
+                b'M' => {
+                    if let Some(metadata) = String::deserialize(&mut bytes) {
+                        message.metadata = Some(metadata).filter(|m| !m.is_empty());
+                    } else {
+                        break;
+                    }
+                }
                 _ => break,
             }
         }
@@ -22,19 +22,45 @@
 */
 
 use crate::queue::DomainPart;
-use mail_auth::common::base32::Base32Writer;
+use mail_auth::common::base32::{Base32Reader, Base32Writer};
 use mail_auth::common::headers::Writer;
+use mail_auth::flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use smtp_proto::{RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS};
+use std::future::Future;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::atomic::Ordering;
 use std::time::Instant;
 use std::time::{Duration, SystemTime};
 use tokio::fs::OpenOptions;
-use tokio::{fs, io::AsyncWriteExt};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
 
 use crate::config::QueueConfig;
+use crate::core::management::RecipientEdit;
 use crate::core::QueueCore;
 
-use super::{Domain, Event, Message, Recipient, Schedule, SimpleEnvelope, Status};
+use super::encryption::{SpoolEncryption, ENCRYPTION_OVERHEAD};
+use super::{
+    Domain, Event, Message, Recipient, Schedule, SimpleEnvelope, Status, RCPT_STATUS_CHANGED,
+};
+
+pub type SpoolFuture<'x, T> = Pin<Box<dyn Future<Output = Result<T, String>> + Send + 'x>>;
+
+pub trait SpoolStore: Send + Sync {
+    fn is_local(&self) -> bool;
+    fn put<'x>(&'x self, message: &'x Message, chunks: &'x [&'x [u8]]) -> SpoolFuture<'x, ()>;
+    fn get<'x>(&'x self, message: &'x Message, len: usize) -> SpoolFuture<'x, Vec<u8>>;
+    fn delete<'x>(&'x self, message: &'x Message) -> SpoolFuture<'x, ()>;
+}
+
+pub struct Spool {
+    store: Box<dyn SpoolStore>,
+}
+
+pub struct LocalSpool;
 
 impl QueueCore {
     pub async fn queue_message(
@@ -52,6 +78,25 @@ impl QueueCore {
             message.size = raw_message.len() + raw_headers.as_ref().map_or(0, |h| h.len());
         }
 
+        // Hash envelope and contents, excluding generated headers
+        if self.config.dedup.is_some() && message.dedup_hash.is_none() {
+            let mut rcpts = message
+                .recipients
+                .iter()
+                .map(|r| r.address_lcase.as_str())
+                .collect::<Vec<_>>();
+            rcpts.sort_unstable();
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(message.return_path_lcase.as_bytes());
+            for rcpt in rcpts {
+                hasher.update(b"\0");
+                hasher.update(rcpt.as_bytes());
+            }
+            hasher.update(b"\0");
+            hasher.update(raw_message);
+            message.dedup_hash = Some(hasher.finalize());
+        }
+
         // Build path
         message.path = self.config.path.eval(message.as_ref()).await.clone();
         let hash = *self.config.hash.eval(message.as_ref()).await;
@@ -60,60 +105,76 @@ impl QueueCore {
         }
         let _ = fs::create_dir(&message.path).await;
 
+        // Compress large bodies
+        let compressed = match self.config.compression {
+            Some(min_size) if message.size >= min_size => {
+                match compress(raw_headers.unwrap_or_default(), raw_message) {
+                    Ok(compressed) => Some(compressed),
+                    Err(err) => {
+                        tracing::warn!(
+                            parent: span,
+                            context = "queue",
+                            event = "error",
+                            "Failed to compress message: {}",
+                            err
+                        );
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let (raw_headers, raw_message) = match &compressed {
+            Some(compressed) => (None, compressed.as_slice()),
+            None => (raw_headers, raw_message),
+        };
+
         // Encode file name
-        let mut encoder = Base32Writer::with_capacity(20);
+        let mut encoder = Base32Writer::with_capacity(26);
         encoder.write(&message.id.to_le_bytes()[..]);
         encoder.write(&(message.size as u32).to_le_bytes()[..]);
+        if compressed.is_some() {
+            encoder.write(&(raw_message.len() as u32).to_le_bytes()[..]);
+        }
         let mut file = encoder.finalize();
-        file.push_str(".msg");
+        file.push('.');
+        if compressed.is_some() {
+            file.push('z');
+        }
+        file.push_str(
+            match (
+                self.config.spool.is_local(),
+                self.config.encryption.is_some(),
+            ) {
+                (true, false) => "msg",
+                (false, false) => "meta",
+                (true, true) => "emsg",
+                (false, true) => "emeta",
+            },
+        );
         message.path.push(file);
 
         // Serialize metadata
         let metadata = message.serialize();
 
         // Save message
-        let mut file = match fs::File::create(&message.path).await {
-            Ok(file) => file,
-            Err(err) => {
-                tracing::error!(
-                    parent: span,
-                    context = "queue",
-                    event = "error",
-                    "Failed to create file {}: {}",
-                    message.path.display(),
-                    err
-                );
-                return false;
-            }
-        };
-
-        let iter = if let Some(raw_headers) = raw_headers {
-            [raw_headers, raw_message, &metadata].into_iter()
-        } else {
-            [raw_message, &metadata, b""].into_iter()
-        };
-
-        for bytes in iter {
-            if !bytes.is_empty() {
-                if let Err(err) = file.write_all(bytes).await {
-                    tracing::error!(
-                        parent: span,
-                        context = "queue",
-                        event = "error",
-                        "Failed to write to file {}: {}",
-                        message.path.display(),
-                        err
-                    );
-                    return false;
-                }
-            }
-        }
-        if let Err(err) = file.flush().await {
+        if let Err(err) = self
+            .config
+            .spool
+            .write(
+                &message,
+                raw_headers,
+                raw_message,
+                &metadata,
+                self.config.encryption.as_ref(),
+            )
+            .await
+        {
             tracing::error!(
                 parent: span,
                 context = "queue",
                 event = "error",
-                "Failed to flush file {}: {}",
+                "Failed to write message {}: {}",
                 message.path.display(),
                 err
             );
@@ -186,12 +247,29 @@ impl Message {
             domains: Vec::with_capacity(1),
             flags: 0,
             env_id: None,
+            metadata: None,
             priority: 0,
             size: 0,
             queue_refs: vec![],
+            dedup_hash: None,
         })
     }
 
+    pub fn max_expires(&self, expires: Instant, max_lifetime: Option<Duration>) -> Instant {
+        if let Some(max_lifetime) = max_lifetime {
+            let age = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+                .saturating_sub(self.created);
+            std::cmp::min(
+                expires,
+                Instant::now() + max_lifetime.saturating_sub(Duration::from_secs(age)),
+            )
+        } else {
+            expires
+        }
+    }
+
     pub async fn add_recipient_parts(
         &mut self,
         rcpt: impl Into<String>,
@@ -209,11 +287,12 @@ impl Message {
                     .expire
                     .eval(&SimpleEnvelope::new(self, &rcpt_domain))
                     .await;
+                let max_expires = self.max_expires(Instant::now() + expires, config.max_lifetime);
                 self.domains.push(Domain {
                     domain: rcpt_domain,
                     retry: Schedule::now(),
                     notify: Schedule::later(expires + Duration::from_secs(10)),
-                    expires: Instant::now() + expires,
+                    expires: max_expires,
                     status: Status::Scheduled,
                     changed: false,
                 });
@@ -237,6 +316,172 @@ impl Message {
             .await;
     }
 
+    pub async fn edit_recipients(
+        &mut self,
+        edit: RecipientEdit,
+        config: &QueueConfig,
+    ) -> Result<(), String> {
+        // Validate all changes before applying them
+        let mut edited = Vec::with_capacity(edit.remove.len() + edit.rewrite.len());
+        for address in edit
+            .remove
+            .iter()
+            .chain(edit.rewrite.iter().map(|rewrite| &rewrite.from))
+        {
+            let address = address.trim().to_lowercase();
+            match self
+                .recipients
+                .iter()
+                .find(|rcpt| rcpt.address_lcase == address)
+            {
+                Some(rcpt)
+                    if matches!(rcpt.status, Status::Scheduled | Status::TemporaryFailure(_)) =>
+                {
+                    if edited.contains(&address) {
+                        return Err(format!("Recipient {address:?} is edited more than once."));
+                    }
+                    edited.push(address);
+                }
+                Some(_) => {
+                    return Err(format!("Recipient {address:?} is not pending delivery."));
+                }
+                None => {
+                    return Err(format!("Recipient {address:?} not found."));
+                }
+            }
+        }
+        let mut added = Vec::with_capacity(edit.rewrite.len());
+        for rewrite in &edit.rewrite {
+            let address = rewrite.to.trim().to_lowercase();
+            if address.domain_part().is_empty() {
+                return Err(format!("Invalid recipient address {address:?}."));
+            } else if added.contains(&address)
+                || self
+                    .recipients
+                    .iter()
+                    .any(|rcpt| rcpt.address_lcase == address)
+            {
+                return Err(format!("Recipient {address:?} already exists."));
+            }
+            added.push(address);
+        }
+        if edit.remove.len() == self.recipients.len() {
+            return Err("Cannot remove all recipients, cancel the message instead.".to_string());
+        }
+
+        for address in &edit.remove {
+            let address = address.trim().to_lowercase();
+            if let Some(idx) = self
+                .recipients
+                .iter()
+                .position(|rcpt| rcpt.address_lcase == address)
+            {
+                self.remove_recipient(idx);
+            }
+        }
+        for rewrite in edit.rewrite {
+            let address = rewrite.from.trim().to_lowercase();
+            if let Some(idx) = self
+                .recipients
+                .iter()
+                .position(|rcpt| rcpt.address_lcase == address)
+            {
+                let old_rcpt = self.remove_recipient(idx);
+                self.add_recipient(rewrite.to.trim(), config).await;
+
+                // Keep the DSN parameters of the original recipient
+                let rcpt = self.recipients.last_mut().unwrap();
+                rcpt.flags = old_rcpt.flags
+                    & (RCPT_NOTIFY_SUCCESS
+                        | RCPT_NOTIFY_FAILURE
+                        | RCPT_NOTIFY_DELAY
+                        | RCPT_NOTIFY_NEVER);
+                rcpt.orcpt = old_rcpt.orcpt.or(Some(old_rcpt.address));
+
+                // Resume delivery to domains that were already completed
+                let domain = &mut self.domains[rcpt.domain_idx];
+                if !matches!(
+                    domain.status,
+                    Status::Scheduled | Status::TemporaryFailure(_)
+                ) {
+                    domain.status = Status::Scheduled;
+                    domain.retry = Schedule::now();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove_recipient(&mut self, idx: usize) -> Recipient {
+        let rcpt = self.recipients.remove(idx);
+
+        // Drop the domain once its last recipient is removed
+        if !self
+            .recipients
+            .iter()
+            .any(|other| other.domain_idx == rcpt.domain_idx)
+        {
+            self.domains.remove(rcpt.domain_idx);
+            for other in &mut self.recipients {
+                if other.domain_idx > rcpt.domain_idx {
+                    other.domain_idx -= 1;
+                }
+            }
+        }
+
+        rcpt
+    }
+
+    pub async fn save_metadata(&mut self) {
+        for domain in &mut self.domains {
+            domain.changed = false;
+        }
+        for rcpt in &mut self.recipients {
+            rcpt.flags &= !RCPT_STATUS_CHANGED;
+        }
+
+        // Copy the body followed by the new metadata to a temporary file,
+        // then atomically replace the queued file with it
+        let offset = if self.has_local_body() {
+            self.stored_size()
+        } else {
+            0
+        } as u64;
+        let metadata = self.serialize();
+        let tmp_path = self
+            .path
+            .with_extension(format!("{}.tmp", self.extension()));
+        let err = match async {
+            let mut tmp_file = fs::File::create(&tmp_path).await?;
+            if offset > 0 {
+                let mut body = fs::File::open(&self.path).await?.take(offset);
+                if tokio::io::copy(&mut body, &mut tmp_file).await? != offset {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "message body is truncated",
+                    ));
+                }
+            }
+            tmp_file.write_all(&metadata).await?;
+            tmp_file.sync_all().await?;
+            fs::rename(&tmp_path, &self.path).await
+        }
+        .await
+        {
+            Ok(_) => return,
+            Err(err) => err,
+        };
+        let _ = fs::remove_file(&tmp_path).await;
+        tracing::error!(
+            context = "queue",
+            event = "error",
+            "Failed to write to {}: {}",
+            self.path.display(),
+            err
+        );
+    }
+
     pub async fn save_changes(&mut self) {
         let buf = self.serialize_changes();
         if !buf.is_empty() {
@@ -257,8 +502,8 @@ impl Message {
         }
     }
 
-    pub async fn remove(&self) {
-        if let Err(err) = fs::remove_file(&self.path).await {
+    pub async fn remove(&self, spool: &Spool) {
+        if let Err(err) = spool.remove(self).await {
             tracing::error!(
                 context = "queue",
                 event = "error",
@@ -268,4 +513,265 @@ impl Message {
             );
         }
     }
+
+    pub fn storage_key(&self) -> &str {
+        self.path
+            .file_stem()
+            .and_then(|f| f.to_str())
+            .unwrap_or_default()
+    }
+
+    fn extension(&self) -> &str {
+        self.path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+    }
+
+    pub fn has_local_body(&self) -> bool {
+        self.path.extension().is_none() || self.extension().ends_with("msg")
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.extension().trim_start_matches('z'), "emsg" | "emeta")
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.extension().starts_with('z')
+    }
+
+    pub fn compressed_size(&self) -> Option<usize> {
+        if self.is_compressed() {
+            let mut size = [0u8; std::mem::size_of::<u32>()];
+            let mut bytes = Base32Reader::new(self.storage_key().as_bytes()).skip(12);
+            for byte in size.iter_mut() {
+                *byte = bytes.next()?;
+            }
+            Some(u32::from_le_bytes(size) as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn stored_size(&self) -> usize {
+        stored_size(self.size, self.compressed_size(), self.is_encrypted())
+    }
+}
+
+pub(crate) fn stored_size(
+    size: usize,
+    compressed_size: Option<usize>,
+    is_encrypted: bool,
+) -> usize {
+    let size = compressed_size.unwrap_or(size);
+    if is_encrypted {
+        size + ENCRYPTION_OVERHEAD
+    } else {
+        size
+    }
+}
+
+fn compress(raw_headers: &[u8], raw_message: &[u8]) -> std::io::Result<Vec<u8>> {
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(
+        Vec::with_capacity((raw_headers.len() + raw_message.len()) / 2),
+        Compression::default(),
+    );
+    encoder.write_all(raw_headers)?;
+    encoder.write_all(raw_message)?;
+    encoder.finish()
+}
+
+fn decompress(bytes: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    use std::io::Read;
+
+    let mut decompressed = Vec::with_capacity(len);
+    GzDecoder::new(bytes)
+        .take(len as u64)
+        .read_to_end(&mut decompressed)
+        .map_err(|err| format!("Failed to decompress message: {err}"))?;
+    Ok(decompressed)
+}
+
+impl Spool {
+    pub fn new(store: impl SpoolStore + 'static) -> Self {
+        Spool {
+            store: Box::new(store),
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.store.is_local()
+    }
+
+    pub async fn write(
+        &self,
+        message: &Message,
+        raw_headers: Option<&[u8]>,
+        raw_message: &[u8],
+        metadata: &[u8],
+        encryption: Option<&SpoolEncryption>,
+    ) -> Result<(), String> {
+        let encrypted;
+        let mut chunks = vec![raw_headers.unwrap_or_default(), raw_message];
+        if let Some(encryption) = encryption {
+            encrypted = encryption.encrypt(message.storage_key(), &chunks)?;
+            chunks = vec![&encrypted[..]];
+        }
+        self.store.put(message, &chunks).await?;
+
+        // Metadata is always stored locally, after the body if there is one
+        let mut options = OpenOptions::new();
+        if self.store.is_local() {
+            options.append(true);
+        } else {
+            options.create(true).write(true).truncate(true);
+        }
+        let mut file = options
+            .open(&message.path)
+            .await
+            .map_err(|err| format!("Failed to create file {}: {}", message.path.display(), err))?;
+        file.write_all(metadata).await.map_err(|err| {
+            format!(
+                "Failed to write to file {}: {}",
+                message.path.display(),
+                err
+            )
+        })?;
+        file.flush()
+            .await
+            .map_err(|err| format!("Failed to flush file {}: {}", message.path.display(), err))
+    }
+
+    pub async fn read(
+        &self,
+        message: &Message,
+        len: usize,
+        encryption: Option<&SpoolEncryption>,
+    ) -> Result<Vec<u8>, String> {
+        // Encrypted and compressed bodies have to be read in full
+        let requested_len = len;
+        let encryption = if message.is_encrypted() {
+            Some(encryption.ok_or_else(|| {
+                format!(
+                    "Message {} is encrypted but no encryption key is configured.",
+                    message.path.display()
+                )
+            })?)
+        } else {
+            None
+        };
+        let is_compressed = message.is_compressed();
+        let len = if encryption.is_some() || is_compressed {
+            message.stored_size()
+        } else {
+            len
+        };
+
+        let bytes = if message.has_local_body() {
+            LocalSpool.get(message, len).await?
+        } else if !self.store.is_local() {
+            self.store.get(message, len).await?
+        } else {
+            return Err(format!(
+                "Message {} is stored remotely but no remote spool is configured.",
+                message.path.display()
+            ));
+        };
+
+        if bytes.len() >= len {
+            let mut bytes = if let Some(encryption) = encryption {
+                encryption.decrypt(message.storage_key(), bytes)?
+            } else {
+                bytes
+            };
+            if is_compressed {
+                decompress(&bytes, requested_len)
+            } else {
+                bytes.truncate(requested_len);
+                Ok(bytes)
+            }
+        } else {
+            Err(format!(
+                "Expected {} bytes but read {} from {}.",
+                len,
+                bytes.len(),
+                message.path.display()
+            ))
+        }
+    }
+
+    pub async fn remove(&self, message: &Message) -> Result<(), String> {
+        let result = if !message.has_local_body() {
+            self.store.delete(message).await
+        } else {
+            Ok(())
+        };
+
+        fs::remove_file(&message.path)
+            .await
+            .map_err(|err| format!("Failed to delete file {}: {}", message.path.display(), err))?;
+
+        result
+    }
+}
+
+impl Default for Spool {
+    fn default() -> Self {
+        Spool::new(LocalSpool)
+    }
+}
+
+impl SpoolStore for LocalSpool {
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn put<'x>(&'x self, message: &'x Message, chunks: &'x [&'x [u8]]) -> SpoolFuture<'x, ()> {
+        Box::pin(async move {
+            let mut file = fs::File::create(&message.path).await.map_err(|err| {
+                format!("Failed to create file {}: {}", message.path.display(), err)
+            })?;
+            for bytes in chunks {
+                if !bytes.is_empty() {
+                    file.write_all(bytes).await.map_err(|err| {
+                        format!(
+                            "Failed to write to file {}: {}",
+                            message.path.display(),
+                            err
+                        )
+                    })?;
+                }
+            }
+            file.flush()
+                .await
+                .map_err(|err| format!("Failed to flush file {}: {}", message.path.display(), err))
+        })
+    }
+
+    fn get<'x>(&'x self, message: &'x Message, len: usize) -> SpoolFuture<'x, Vec<u8>> {
+        Box::pin(async move {
+            let mut bytes = Vec::with_capacity(len);
+            fs::File::open(&message.path)
+                .await
+                .map_err(|err| format!("Failed to open file {}: {}", message.path.display(), err))?
+                .take(len as u64)
+                .read_to_end(&mut bytes)
+                .await
+                .map_err(|err| {
+                    format!(
+                        "Failed to read from file {}: {}",
+                        message.path.display(),
+                        err
+                    )
+                })?;
+            Ok(bytes)
+        })
+    }
+
+    fn delete<'x>(&'x self, _message: &'x Message) -> SpoolFuture<'x, ()> {
+        // The body is removed together with the metadata file
+        Box::pin(async { Ok(()) })
+    }
 }
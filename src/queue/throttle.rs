@@ -21,15 +21,19 @@
  * for more details.
 */
 
-use std::time::Instant;
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
 
 use dashmap::mapref::entry::Entry;
+use tokio::sync::watch;
 
 use crate::{
-    config::Throttle,
+    config::{LoadThrottle, Throttle},
     core::{
         throttle::{ConcurrencyLimiter, InFlight, Limiter, RateLimiter},
-        Envelope, QueueCore,
+        Core, Envelope, QueueCore,
     },
 };
 
@@ -123,3 +127,70 @@ impl Domain {
         self.changed = true;
     }
 }
+
+impl LoadThrottle {
+    pub fn spawn(self, core: Arc<Core>, mut shutdown_rx: watch::Receiver<bool>) {
+        tokio::spawn(async move {
+            let mut max_concurrent = core.queue.concurrency.max_concurrent;
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(self.interval) => {
+                        if let Some(load) = system_load() {
+                            max_concurrent =
+                                self.adjust(&core.queue.concurrency, max_concurrent, load);
+                        }
+                    },
+                    _ = shutdown_rx.changed() => {
+                        break;
+                    }
+                };
+            }
+        });
+    }
+
+    pub fn adjust(&self, limiter: &ConcurrencyLimiter, max_concurrent: u64, load: f64) -> u64 {
+        let reserved = limiter.max_concurrent - max_concurrent;
+        let in_flight = limiter
+            .concurrent
+            .load(Ordering::Relaxed)
+            .saturating_sub(reserved);
+        let new_max_concurrent = if load > self.max_load {
+            (std::cmp::min(max_concurrent, in_flight) / 2).max(self.min_concurrency)
+        } else if load < self.restore_load {
+            max_concurrent.saturating_mul(2)
+        } else {
+            max_concurrent
+        }
+        .min(limiter.max_concurrent);
+
+        if new_max_concurrent != max_concurrent {
+            tracing::info!(
+                context = "throttle",
+                event = "load",
+                load = load,
+                max_concurrent = new_max_concurrent,
+                "Adjusting outbound concurrency."
+            );
+
+            // Reserve the slots of the global outbound limiter that are not available
+            if new_max_concurrent < max_concurrent {
+                limiter
+                    .concurrent
+                    .fetch_add(max_concurrent - new_max_concurrent, Ordering::Relaxed);
+            } else {
+                limiter
+                    .concurrent
+                    .fetch_sub(new_max_concurrent - max_concurrent, Ordering::Relaxed);
+            }
+        }
+
+        new_max_concurrent
+    }
+}
+
+fn system_load() -> Option<f64> {
+    let load = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let load = load.split_ascii_whitespace().next()?.parse::<f64>().ok()?;
+    Some(load / num_cpus::get() as f64)
+}
@@ -64,7 +64,20 @@ pub trait AnalyzeReport {
 impl AnalyzeReport for Arc<Core> {
     fn analyze_report(&self, message: Arc<Vec<u8>>) {
         let core = self.clone();
+        let in_flight =
+            if let Some(in_flight) = self.report.config.analysis.concurrency.is_allowed() {
+                in_flight
+            } else {
+                tracing::info!(
+                    context = "report",
+                    event = "throttle",
+                    max_concurrent = self.report.config.analysis.concurrency.max_concurrent,
+                    "Too many reports are being analyzed, skipping report."
+                );
+                return;
+            };
         self.worker_pool.spawn(move || {
+            let _in_flight = in_flight;
             let message = if let Some(message) = Message::parse(&message) {
                 message
             } else {
@@ -158,11 +171,12 @@ impl AnalyzeReport for Arc<Core> {
                 }
             }
 
+            let max_size = core.report.config.analysis.max_size;
             for report in reports {
                 let data = match report.compression {
                     Compression::None => Cow::Borrowed(report.data),
                     Compression::Gzip => {
-                        let mut file = GzDecoder::new(report.data);
+                        let mut file = GzDecoder::new(report.data).take(max_size as u64 + 1);
                         let mut buf = Vec::new();
                         if let Err(err) = file.read_to_end(&mut buf) {
                             tracing::debug!(
@@ -191,9 +205,13 @@ impl AnalyzeReport for Arc<Core> {
                         let mut buf = Vec::with_capacity(0);
                         for i in 0..archive.len() {
                             match archive.by_index(i) {
-                                Ok(mut file) => {
-                                    buf = Vec::with_capacity(file.compressed_size() as usize);
-                                    if let Err(err) = file.read_to_end(&mut buf) {
+                                Ok(file) => {
+                                    buf = Vec::with_capacity(
+                                        (file.compressed_size() as usize).min(max_size),
+                                    );
+                                    if let Err(err) =
+                                        file.take(max_size as u64 + 1).read_to_end(&mut buf)
+                                    {
                                         tracing::debug!(
                                             context = "report",
                                             from = from,
@@ -216,6 +234,16 @@ impl AnalyzeReport for Arc<Core> {
                         Cow::Owned(buf)
                     }
                 };
+                if data.len() > max_size {
+                    tracing::warn!(
+                        context = "report",
+                        event = "too-large",
+                        from = from,
+                        max_size = max_size,
+                        "Decompressed report exceeds maximum size, discarding."
+                    );
+                    continue;
+                }
 
                 match report.format {
                     Format::Dmarc => match Report::parse_xml(&data) {
@@ -138,9 +138,30 @@ impl Core {
         let from_addr_domain = from_addr_lcase.domain_part().to_string();
         let mut message = Message::new_boxed(from_addr, from_addr_lcase, from_addr_domain);
         for rcpt_ in rcpts {
-            message
-                .add_recipient(rcpt_.as_ref(), &self.queue.config)
-                .await;
+            let rcpt = rcpt_.as_ref();
+
+            // Never send reports to our own domains
+            if let Some(local_domains) = &self.report.config.local_domains {
+                if local_domains
+                    .contains(rcpt.to_lowercase().domain_part())
+                    .await
+                    .unwrap_or(false)
+                {
+                    tracing::warn!(
+                        parent: span,
+                        context = "report",
+                        event = "local-recipient",
+                        rcpt = rcpt,
+                        "Refusing to send report to a local domain."
+                    );
+                    continue;
+                }
+            }
+
+            message.add_recipient(rcpt, &self.queue.config).await;
+        }
+        if message.recipients.is_empty() {
+            return;
         }
 
         // Sign message
@@ -0,0 +1,192 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use ahash::AHashSet;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    config::{ConfigContext, IfBlock},
+    core::{Core, ServerInstance, Session, SessionData, SessionParameters, State},
+    lookup::Lookup,
+    queue::{Event, Message},
+    tests::{session::VerifyResponse, ParseTestConfig},
+};
+
+#[tokio::test]
+async fn atrn() {
+    let mut core = Core::test();
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "odmr".to_string(),
+        Arc::new(Lookup::Local(AHashSet::from_iter([
+            "john:foobar.org,example.org".to_string(),
+        ]))),
+    );
+    core.session.config.extensions.atrn = r"[{if = 'remote-ip', eq = '10.0.0.1', then = 'odmr'},
+    {else = false}]"
+        .parse_if::<Option<String>>(&ctx)
+        .map_if_block(&ctx.lookup, "", "")
+        .unwrap();
+
+    // ATRN should not be available to 10.0.0.2
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.2".parse().unwrap();
+    session.data.authenticated_as = "john".to_string();
+    session.eval_session_params().await;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_not_contains("ATRN");
+    session.cmd("ATRN foobar.org", "502 5.5.1").await;
+
+    // ATRN requires authentication
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.data.authenticated_as = String::new();
+    session.eval_session_params().await;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_not_contains("ATRN");
+    session.cmd("ATRN foobar.org", "530 5.7.0").await;
+
+    // Only authorized domains can be dequeued
+    session.data.authenticated_as = "john".to_string();
+    session.ehlo("mx.foobar.org").await.assert_contains("ATRN");
+    session
+        .cmd("ATRN foobar.org,otherdomain.org", "450 4.7.1")
+        .await;
+    session.data.authenticated_as = "jane".to_string();
+    session.cmd("ATRN example.org", "450 4.7.1").await;
+
+    // No queued messages for the requested domains
+    session.data.authenticated_as = "john".to_string();
+    session.cmd("ATRN foobar.org", "453").await;
+    session.cmd("ATRN foobar.org,example.org", "453").await;
+}
+
+#[tokio::test]
+async fn atrn_deliver() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_atrn_deliver_test");
+    core.session.config.extensions.atrn =
+        IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+            "john:foobar.org".to_string(),
+        ])))));
+    let core = Arc::new(core);
+
+    // Queue a message for foobar.org
+    let mut message = Message::new_boxed("bill@example.org", "bill@example.org", "example.org");
+    message
+        .add_recipient("jane@foobar.org", &core.queue.config)
+        .await;
+    assert!(
+        core.queue
+            .queue_message(
+                message,
+                None,
+                b"Subject: ATRN test\r\n\r\nHello Jane!\r\n",
+                &tracing::info_span!("test")
+            )
+            .await
+    );
+    let message = qr.read_event().await.unwrap_message();
+    let path = message.path.clone();
+    assert!(path.exists());
+
+    // Open a connection from the on-demand relay client
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut session = Session {
+        state: State::default(),
+        instance: Arc::new(ServerInstance::test()),
+        core: core.clone(),
+        span: tracing::info_span!("test"),
+        stream,
+        data: SessionData::new("127.0.0.1".parse().unwrap(), "10.0.0.1".parse().unwrap()),
+        params: SessionParameters::default(),
+        in_flight: vec![],
+        connection: None,
+    };
+    session.data.authenticated_as = "john".to_string();
+    session.data.helo_domain = "mx.foobar.org".to_string();
+    session.eval_session_params().await;
+    let atrn = tokio::spawn(async move { session.handle_atrn(vec![]).await });
+
+    // The queue manager hands over the messages for the requested domains
+    match qr.read_event().await {
+        Event::Release { domains, result_tx } => {
+            assert_eq!(domains, vec!["foobar.org".to_string()]);
+            result_tx.send(vec![message]).unwrap();
+        }
+        _ => panic!("Expected a release event."),
+    }
+
+    // The client turns around and accepts the queued message
+    let (reader, mut writer) = client.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut data = Vec::new();
+    let mut in_data = false;
+    while let Some(line) = lines.next_line().await.unwrap() {
+        let response = if in_data {
+            if line == "." {
+                in_data = false;
+                "250 2.0.0 Message queued.\r\n"
+            } else {
+                data.push(line);
+                continue;
+            }
+        } else if line.starts_with("250 2.0.0 OK now reversing") {
+            "220 mx.foobar.org ESMTP\r\n"
+        } else if line.starts_with("EHLO ") {
+            "250 mx.foobar.org\r\n"
+        } else if line.starts_with("MAIL FROM:<bill@example.org>") {
+            "250 2.1.0 OK\r\n"
+        } else if line.starts_with("RCPT TO:<jane@foobar.org>") {
+            "250 2.1.5 OK\r\n"
+        } else if line == "DATA" {
+            in_data = true;
+            "354 Start mail input.\r\n"
+        } else if line == "QUIT" {
+            writer.write_all(b"221 2.0.0 Bye.\r\n").await.unwrap();
+            break;
+        } else {
+            panic!("Unexpected command {line:?}");
+        };
+        writer.write_all(response.as_bytes()).await.unwrap();
+    }
+    assert!(data.iter().any(|line| line == "Hello Jane!"));
+
+    // The connection is closed and the message is removed from the queue
+    assert!(atrn.await.unwrap().is_err());
+    qr.read_event().await.unwrap_done();
+    assert!(!path.exists());
+    qr.assert_empty_queue();
+}
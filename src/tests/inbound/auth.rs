@@ -21,16 +21,30 @@
  * for more details.
 */
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use ahash::AHashSet;
-use smtp_proto::{AUTH_LOGIN, AUTH_PLAIN};
+use mail_builder::encoders::base64::base64_encode;
+use mail_parser::decoders::base64::base64_decode;
+use sha2::{Digest, Sha256};
+use smtp_proto::{
+    AUTH_CRAM_MD5, AUTH_EXTERNAL, AUTH_LOGIN, AUTH_OAUTHBEARER, AUTH_PLAIN, AUTH_SCRAM_SHA_256,
+};
 
 use crate::{
-    config::ConfigContext,
+    config::{Config, ConfigContext, IfBlock, TravelAction},
     core::{Core, Session, State},
-    lookup::Lookup,
-    tests::{session::VerifyResponse, ParseTestConfig},
+    inbound::scram::salted_password,
+    lookup::{
+        geoip::GeoIp,
+        hash::{hmac_md5, hmac_sha256},
+        Lookup,
+    },
+    tests::{
+        add_test_certs,
+        session::{DummyIo, VerifyResponse},
+        ParseTestConfig,
+    },
 };
 
 #[tokio::test]
@@ -150,3 +164,450 @@ async fn auth() {
         .cmd("AUTH PLAIN AGpvaG4Ac2VjcmV0", "503 5.5.1")
         .await;
 }
+
+#[tokio::test]
+async fn auth_travel() {
+    let mut core = Core::test();
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "plain".to_string(),
+        Arc::new(Lookup::Local(AHashSet::from_iter([
+            "john:secret".to_string()
+        ]))),
+    );
+
+    let config = &mut core.session.config.auth;
+    config.lookup = "'plain'"
+        .parse_if::<Option<String>>(&ctx)
+        .map_if_block(&ctx.lookup, "", "")
+        .unwrap();
+    config.mechanisms = IfBlock::new(AUTH_PLAIN);
+    config.travel_action = IfBlock::new(TravelAction::Defer);
+    let core = Arc::new(core);
+
+    for (country, expected_code) in [
+        ("ES", "235 2.7.0"),
+        ("JP", "451 4.7.1"),
+        ("ES", "235 2.7.0"),
+    ] {
+        let mut session = Session::test(core.clone());
+        session.data.remote_ip = "10.0.0.1".parse().unwrap();
+        session.data.geoip = GeoIp {
+            country: country.to_string(),
+            asn: 0,
+        }
+        .into();
+        session.eval_session_params().await;
+        session.stream.tls = true;
+        session.ehlo("mx.foobar.org").await;
+        session
+            .cmd("AUTH PLAIN AGpvaG4Ac2VjcmV0", expected_code)
+            .await;
+    }
+
+    // Origins are shared across sessions
+    let origin = core.session.auth_origins.get("john").unwrap();
+    assert_eq!(origin.country, "ES");
+}
+
+#[tokio::test]
+async fn auth_oauth_expiry() {
+    let valid_token = concat!(
+        "n,a=john,\u{1}auth=Bearer eyJhbGciOiJIUzI1NiJ9.",
+        "eyJzdWIiOiJqb2huIiwiZXhwIjo0MTAyNDQ0ODAwLCJpYXQiOjE2MDAwMDAwMDB9.c2ln\u{1}\u{1}"
+    );
+    let expired_token = concat!(
+        "n,a=john,\u{1}auth=Bearer eyJhbGciOiJIUzI1NiJ9.",
+        "eyJzdWIiOiJqb2huIiwiZXhwIjoxMDAwMDAwMDAwfQ.c2ln\u{1}\u{1}"
+    );
+    let valid_response = concat!(
+        "bixhPWpvaG4sAWF1dGg9QmVhcmVyIGV5SmhiR2NpT2lKSVV6STFOaUo5LmV5SnpkV0lpT2lKcWIyaHVJaXdp",
+        "Wlhod0lqbzBNVEF5TkRRME9EQXdMQ0pwWVhRaU9qRTJNREF3TURBd01EQjkuYzJsbgEB"
+    );
+    let expired_response = concat!(
+        "bixhPWpvaG4sAWF1dGg9QmVhcmVyIGV5SmhiR2NpT2lKSVV6STFOaUo5LmV5SnpkV0lpT2lKcWIyaHVJaXdp",
+        "Wlhod0lqb3hNREF3TURBd01EQXdmUS5jMmxuAQE="
+    );
+
+    let mut core = Core::test();
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "oauth".to_string(),
+        Arc::new(Lookup::Local(AHashSet::from_iter([
+            valid_token.to_string(),
+            expired_token.to_string(),
+        ]))),
+    );
+    let config = &mut core.session.config.auth;
+    config.lookup = "'oauth'"
+        .parse_if::<Option<String>>(&ctx)
+        .map_if_block(&ctx.lookup, "", "")
+        .unwrap();
+    config.mechanisms = IfBlock::new(AUTH_OAUTHBEARER);
+    config.errors_max = IfBlock::new(10);
+    config.errors_wait = IfBlock::new(Duration::from_millis(1));
+    config.oauth_check_expiry = r"[{if = 'remote-ip', eq = '10.0.0.1', then = true},
+    {else = false}]"
+        .parse_if(&ctx);
+    config.oauth_max_age = r"[{if = 'remote-ip', eq = '10.0.0.3', then = '1d'},
+    {else = false}]"
+        .parse_if(&ctx);
+    let core = Arc::new(core);
+
+    // Expired tokens are accepted by the backend when the check is disabled
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.2".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .cmd(&format!("AUTH OAUTHBEARER {expired_response}"), "235 2.7.0")
+        .await;
+
+    // Expired tokens are rejected locally with an error challenge
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .cmd(
+            &format!("AUTH OAUTHBEARER {expired_response}"),
+            "334 eyJzdGF0dXMiOiJpbnZhbGlkX3Rva2VuIiwic2NoZW1lcyI6ImJlYXJlciJ9",
+        )
+        .await;
+    session
+        .cmd("AQ==", "535 5.7.8 Authentication token expired")
+        .await;
+    assert!(session.data.authenticated_as.is_empty());
+    session
+        .cmd(&format!("AUTH OAUTHBEARER {valid_response}"), "235 2.7.0")
+        .await;
+
+    // Tokens issued too long ago are rejected
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.3".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .cmd(&format!("AUTH OAUTHBEARER {valid_response}"), "334 ")
+        .await;
+    session.cmd("AQ==", "535 5.7.8").await;
+}
+
+#[tokio::test]
+async fn auth_max_sessions() {
+    let mut core = Core::test();
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "plain".to_string(),
+        Arc::new(Lookup::Local(AHashSet::from_iter([
+            "john:secret".to_string()
+        ]))),
+    );
+
+    let config = &mut core.session.config.auth;
+    config.lookup = "'plain'"
+        .parse_if::<Option<String>>(&ctx)
+        .map_if_block(&ctx.lookup, "", "")
+        .unwrap();
+    config.mechanisms = IfBlock::new(AUTH_PLAIN);
+    config.max_sessions = IfBlock::new(Some(2));
+    let core = Arc::new(core);
+
+    let mut sessions = Vec::new();
+    for _ in 0..3 {
+        let mut session = Session::test(core.clone());
+        session.data.remote_ip = "10.0.0.1".parse().unwrap();
+        session.eval_session_params().await;
+        session.stream.tls = true;
+        session.ehlo("mx.foobar.org").await;
+        sessions.push(session);
+    }
+
+    // Third session for the same account is refused
+    for session in sessions.iter_mut().take(2) {
+        session
+            .cmd("AUTH PLAIN AGpvaG4Ac2VjcmV0", "235 2.7.0")
+            .await;
+    }
+    let mut session = sessions.pop().unwrap();
+    session
+        .ingest(b"AUTH PLAIN AGpvaG4Ac2VjcmV0\r\n")
+        .await
+        .unwrap_err();
+    session.response().assert_code("421 4.7.0");
+
+    // Ending a session releases its slot
+    sessions.pop();
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.stream.tls = true;
+    session.ehlo("mx.foobar.org").await;
+    session
+        .cmd("AUTH PLAIN AGpvaG4Ac2VjcmV0", "235 2.7.0")
+        .await;
+}
+
+#[tokio::test]
+async fn auth_scram() {
+    let mut core = Core::test();
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "scram".to_string(),
+        Arc::new(Lookup::Local(AHashSet::from_iter([
+            "john:secret".to_string(),
+            concat!(
+                "jane:SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==$",
+                "WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:",
+                "wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU="
+            )
+            .to_string(),
+        ]))),
+    );
+    let config = &mut core.session.config.auth;
+    config.lookup = "'scram'"
+        .parse_if::<Option<String>>(&ctx)
+        .map_if_block(&ctx.lookup, "", "")
+        .unwrap();
+    config.mechanisms = IfBlock::new(AUTH_PLAIN | AUTH_SCRAM_SHA_256);
+    config.errors_max = IfBlock::new(10);
+    config.errors_wait = IfBlock::new(Duration::from_millis(1));
+
+    // SCRAM-SHA-256 should be advertised without TLS
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.stream.tls = false;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains(" SCRAM-SHA-256")
+        .assert_not_contains(" PLAIN");
+
+    // Plain text secrets
+    scram_exchange(&mut session, "john", "secret", true).await;
+    session.cmd("", "235 2.7.0").await;
+    assert_eq!(session.data.authenticated_as, "john");
+
+    // Secrets stored in RFC 5803 format
+    session.data.authenticated_as.clear();
+    scram_exchange(&mut session, "jane", "pencil", true).await;
+    session.cmd("", "235 2.7.0").await;
+    assert_eq!(session.data.authenticated_as, "jane");
+
+    // Invalid passwords and unknown accounts fail at the last step
+    session.data.authenticated_as.clear();
+    scram_exchange(&mut session, "jane", "pen", false).await;
+    let salt = scram_exchange(&mut session, "bill", "secret", false).await;
+    assert!(session.data.authenticated_as.is_empty());
+
+    // Unknown accounts are always offered the same salt
+    assert_eq!(
+        scram_exchange(&mut session, "bill", "secret", false).await,
+        salt
+    );
+    assert_ne!(
+        scram_exchange(&mut session, "mike", "secret", false).await,
+        salt
+    );
+
+    // Channel binding is not supported
+    session.cmd("AUTH SCRAM-SHA-256", "334").await;
+    session
+        .cmd(&encode("p=tls-unique,,n=john,r=abcdef"), "504 5.5.4")
+        .await;
+}
+
+#[tokio::test]
+async fn auth_cram_md5() {
+    // Test vector from RFC 2195
+    assert_eq!(
+        hmac_md5(
+            b"tanstaaftanstaaf",
+            b"<1896.697170952@postoffice.reston.mci.net>"
+        )
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>(),
+        "b913a602c7eda7a495b4e6e7334d3890"
+    );
+
+    let mut core = Core::test();
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "cram".to_string(),
+        Arc::new(Lookup::Local(AHashSet::from_iter([
+            "john:secret".to_string()
+        ]))),
+    );
+    let config = &mut core.session.config.auth;
+    config.lookup = "'cram'"
+        .parse_if::<Option<String>>(&ctx)
+        .map_if_block(&ctx.lookup, "", "")
+        .unwrap();
+    config.mechanisms = IfBlock::new(AUTH_CRAM_MD5);
+    config.errors_max = IfBlock::new(10);
+    config.errors_wait = IfBlock::new(Duration::from_millis(1));
+
+    // CRAM-MD5 should be advertised when configured
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.stream.tls = false;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains(" CRAM-MD5");
+
+    // Invalid digests and unknown accounts should be rejected
+    for (username, secret) in [("john", "wrong"), ("jane", "secret")] {
+        let response = cram_md5_response(&mut session, username, secret).await;
+        session.cmd(&response, "535 5.7.8").await;
+    }
+
+    // Successful authentication
+    let response = cram_md5_response(&mut session, "john", "secret").await;
+    session.cmd(&response, "235 2.7.0").await;
+    assert_eq!(session.data.authenticated_as, "john");
+}
+
+#[tokio::test]
+async fn auth_external() {
+    let mut core = Core::test();
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "certs".to_string(),
+        Arc::new(Lookup::Local(AHashSet::from_iter(
+            ["localhost".to_string()],
+        ))),
+    );
+    let config = &mut core.session.config.auth;
+    config.lookup = "'certs'"
+        .parse_if::<Option<String>>(&ctx)
+        .map_if_block(&ctx.lookup, "", "")
+        .unwrap();
+    config.mechanisms = IfBlock::new(AUTH_PLAIN | AUTH_EXTERNAL);
+    config.errors_max = IfBlock::new(10);
+    config.errors_wait = IfBlock::new(Duration::from_millis(1));
+    let certificate = Config::parse(&add_test_certs(
+        "[certificate.tls]\ncert = 'file://{CERT}'\n",
+    ))
+    .unwrap()
+    .rustls_certificate("tls")
+    .unwrap()
+    .remove(0);
+
+    // EXTERNAL should not be advertised nor accepted without a client certificate
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.stream.tls = true;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains(" PLAIN")
+        .assert_not_contains("EXTERNAL");
+    session.cmd("AUTH EXTERNAL =", "535 5.7.8").await;
+
+    // The authorization identity must match the certificate subject
+    session.data.peer_certificate = certificate.into();
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains(" EXTERNAL");
+    session
+        .cmd(&format!("AUTH EXTERNAL {}", encode("john")), "535 5.7.8")
+        .await;
+
+    // Successful authentication after an empty challenge
+    session.cmd("AUTH EXTERNAL", "334").await;
+    session.cmd("=", "235 2.7.0").await;
+    assert_eq!(session.data.authenticated_as, "localhost");
+}
+
+async fn cram_md5_response(session: &mut Session<DummyIo>, username: &str, secret: &str) -> String {
+    let challenge = session.cmd("AUTH CRAM-MD5", "334").await.pop().unwrap();
+    let challenge = base64_decode(challenge.strip_prefix("334 ").unwrap().as_bytes()).unwrap();
+    assert!(challenge.starts_with(b"<") && challenge.ends_with(b">"));
+    encode(&format!(
+        "{username} {}",
+        hmac_md5(secret.as_bytes(), &challenge)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    ))
+}
+
+async fn scram_exchange(
+    session: &mut Session<DummyIo>,
+    username: &str,
+    password: &str,
+    success: bool,
+) -> Vec<u8> {
+    let client_first_bare = format!("n={username},r=fyko+d2lbbFgONRv9qkxdawL");
+    session.cmd("AUTH SCRAM-SHA-256", "334").await;
+    let server_first = session
+        .cmd(&encode(&format!("n,,{client_first_bare}")), "334")
+        .await
+        .pop()
+        .unwrap();
+    let server_first = String::from_utf8(
+        base64_decode(server_first.strip_prefix("334 ").unwrap().as_bytes()).unwrap(),
+    )
+    .unwrap();
+
+    let mut nonce = None;
+    let mut salt = None;
+    let mut iterations = None;
+    for attribute in server_first.split(',') {
+        let (name, value) = attribute.split_once('=').unwrap();
+        match name {
+            "r" => nonce = value.to_string().into(),
+            "s" => salt = base64_decode(value.as_bytes()),
+            "i" => iterations = value.parse::<u32>().ok(),
+            _ => unreachable!(),
+        }
+    }
+    let nonce = nonce.unwrap();
+    assert!(nonce.starts_with("fyko+d2lbbFgONRv9qkxdawL"));
+
+    let salt = salt.unwrap();
+    let salted_password = salted_password(password.as_bytes(), &salt, iterations.unwrap());
+    let client_key = hmac_sha256(&salted_password, b"Client Key");
+    let stored_key: [u8; 32] = Sha256::digest(client_key).into();
+    let server_key = hmac_sha256(&salted_password, b"Server Key");
+    let without_proof = format!("c=biws,r={nonce}");
+    let auth_message = format!("{client_first_bare},{server_first},{without_proof}");
+    let client_signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+    let proof = client_key
+        .iter()
+        .zip(client_signature.iter())
+        .map(|(k, s)| k ^ s)
+        .collect::<Vec<_>>();
+
+    let client_final = encode(&format!(
+        "{without_proof},p={}",
+        String::from_utf8(base64_encode(&proof).unwrap()).unwrap()
+    ));
+    if success {
+        let server_final = session.cmd(&client_final, "334").await.pop().unwrap();
+        assert_eq!(
+            String::from_utf8(
+                base64_decode(server_final.strip_prefix("334 ").unwrap().as_bytes()).unwrap()
+            )
+            .unwrap(),
+            format!(
+                "v={}",
+                String::from_utf8(
+                    base64_encode(&hmac_sha256(&server_key, auth_message.as_bytes())).unwrap()
+                )
+                .unwrap()
+            )
+        );
+    } else {
+        session.cmd(&client_final, "535 5.7.8").await;
+    }
+
+    salt
+}
+
+fn encode(value: &str) -> String {
+    String::from_utf8(base64_encode(value.as_bytes()).unwrap()).unwrap()
+}
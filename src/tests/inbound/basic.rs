@@ -21,9 +21,13 @@
  * for more details.
 */
 
+use std::sync::atomic::Ordering;
+
 use crate::{
+    config::{ConfigContext, IfBlock},
     core::{Core, Session},
-    tests::session::VerifyResponse,
+    inbound::session::fold_response,
+    tests::{session::VerifyResponse, ParseTestConfig},
 };
 
 #[tokio::test]
@@ -39,6 +43,19 @@ async fn basic_commands() {
     assert!(!session.ingest(b"STARTTLS\r\n").await.unwrap());
     session.response().assert_contains("220 2.0.0");
 
+    // Commands pipelined after STARTTLS should be discarded
+    let mut injected = Session::test(Core::test());
+    injected.ehlo("mx.foobar.org").await;
+    assert!(injected
+        .ingest(b"STARTTLS\r\nMAIL FROM:<x>\r\n")
+        .await
+        .is_err());
+    injected
+        .response()
+        .assert_contains("220 2.0.0")
+        .assert_not_contains("250");
+    assert!(injected.data.mail_from.is_none());
+
     // STARTTLS should not be offered on TLS connections
     session.stream.tls = true;
     session
@@ -56,6 +73,11 @@ async fn basic_commands() {
     // Test HELP
     session.cmd("HELP QUIT", "250").await;
 
+    // BURL requires an authenticated session with an IMAP host configured
+    session
+        .cmd("BURL imap://foobar.org/INBOX/;uid=20 LAST", "503 5.5.1")
+        .await;
+
     // Test LHLO on SMTP channel
     session.cmd("LHLO domain.org", "502").await;
 
@@ -63,3 +85,153 @@ async fn basic_commands() {
     session.ingest(b"QUIT\r\n").await.unwrap_err();
     session.response().assert_code("221");
 }
+
+#[tokio::test]
+async fn draining() {
+    let mut core = Core::test();
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    let mut session = Session::test(core);
+    session.ehlo("mx.foobar.org").await;
+
+    // Transactions in progress are completed while draining
+    session.mail_from("john@foobar.org", "250").await;
+    session.core.draining.store(true, Ordering::Relaxed);
+    session.rcpt_to("bill@foobar.org", "250").await;
+    session.rset().await;
+
+    // New transactions are refused
+    assert!(session
+        .ingest(b"MAIL FROM:<john@foobar.org>\r\n")
+        .await
+        .is_err());
+    session.response().assert_code("421 4.3.2");
+}
+
+#[tokio::test]
+async fn chunking() {
+    let mut core = Core::test();
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    let config = &mut core.session.config.extensions;
+    config.require_chunking = r"[{if = 'remote-ip', eq = '10.0.0.1', then = true},
+    {else = false}]"
+        .parse_if(&ConfigContext::default());
+    config.chunking = r"[{if = 'remote-ip', eq = '10.0.0.2', then = false},
+    {else = true}]"
+        .parse_if(&ConfigContext::default());
+
+    // DATA should be rejected when CHUNKING is required
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains("CHUNKING");
+    session.mail_from("john@foobar.org", "250").await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+    session.cmd("DATA", "503 5.5.1").await;
+    session.ingest(b"BDAT 4\r\ntest").await.unwrap();
+    session.response().assert_code("250 2.6.0");
+
+    // DATA should be rejected for BINARYMIME messages
+    session.data.remote_ip = "10.0.0.3".parse().unwrap();
+    session.eval_session_params().await;
+    session.rset().await;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains("BINARYMIME");
+    session
+        .mail_from("<john@foobar.org> BODY=BINARYMIME", "250")
+        .await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+    session.cmd("DATA", "503 5.5.1").await;
+    session.ingest(b"BDAT 4\r\ntest").await.unwrap();
+    session.response().assert_code("250 2.6.0");
+
+    // BDAT and BINARYMIME should be rejected when CHUNKING is disabled
+    session.data.remote_ip = "10.0.0.2".parse().unwrap();
+    session.eval_session_params().await;
+    session.rset().await;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_not_contains("CHUNKING")
+        .assert_not_contains("BINARYMIME");
+    session
+        .mail_from("<john@foobar.org> BODY=BINARYMIME", "501 5.5.4")
+        .await;
+    session.mail_from("john@foobar.org", "250").await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+    session.ingest(b"BDAT 4 LAST\r\ntest").await.unwrap();
+    session.response().assert_code("503 5.5.1");
+    session.ingest(b"DATA\r\n").await.unwrap();
+    session.response().assert_code("354");
+}
+
+#[tokio::test]
+async fn chunking_too_large() {
+    let mut core = Core::test();
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    let mut session = Session::test(core);
+    session.eval_session_params().await;
+    session.params.max_message_size = 25 * 1024 * 1024;
+    session.ehlo("mx.foobar.org").await;
+    session.mail_from("john@foobar.org", "250").await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+
+    // Oversized chunks should be rejected before their contents are received
+    let chunk_size = 30 * 1024 * 1024;
+    session
+        .ingest(format!("BDAT {chunk_size} LAST\r\n").as_bytes())
+        .await
+        .unwrap();
+    session.response().assert_code("552 5.3.4");
+
+    // The announced chunk is still consumed to keep the session in sync
+    let data = vec![b'A'; 1024 * 1024];
+    for _ in 0..30 {
+        session.ingest(&data).await.unwrap();
+        assert!(session.stream.tx_buf.is_empty());
+    }
+    session.cmd("NOOP", "250").await;
+}
+
+#[test]
+fn fold_long_responses() {
+    assert_eq!(fold_response(b"250 2.0.0 OK\r\n"), None);
+
+    let reason = "word ".repeat(200);
+    let response = format!("550 5.7.1 {}\r\n", reason.trim_end());
+    let folded = String::from_utf8(fold_response(response.as_bytes()).unwrap()).unwrap();
+    let lines = folded.split_terminator("\r\n").collect::<Vec<_>>();
+    assert!(lines.len() > 1);
+    for (pos, line) in lines.iter().enumerate() {
+        assert!(line.len() <= 510, "{line}");
+        if pos < lines.len() - 1 {
+            assert!(line.starts_with("550-5.7.1 word"), "{line}");
+        } else {
+            assert!(line.starts_with("550 5.7.1 word"), "{line}");
+        }
+    }
+    assert_eq!(
+        lines
+            .iter()
+            .map(|line| &line[10..])
+            .collect::<Vec<_>>()
+            .join(" "),
+        reason.trim_end()
+    );
+
+    // Lines without whitespace are split at the maximum length
+    let response = format!("250-{}\r\n250 OK\r\n", "a".repeat(600));
+    let folded = String::from_utf8(fold_response(response.as_bytes()).unwrap()).unwrap();
+    assert_eq!(
+        folded,
+        format!(
+            "250-{}\r\n250-{}\r\n250 OK\r\n",
+            "a".repeat(506),
+            "a".repeat(94)
+        )
+    );
+}
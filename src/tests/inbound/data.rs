@@ -25,11 +25,19 @@ use std::sync::Arc;
 
 use ahash::AHashSet;
 
+use rustls::ServerName;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::server::TlsStream;
+
 use crate::{
     config::{ConfigContext, IfBlock},
-    core::{Core, Session, SessionAddress},
+    core::{Core, ServerInstance, Session, SessionAddress, SessionData, SessionParameters, State},
     lookup::Lookup,
     tests::{
+        lookup::dummy_tls_acceptor,
         session::{load_test_message, DummyIo, VerifyResponse},
         ParseTestConfig,
     },
@@ -64,6 +72,10 @@ async fn data() {
     config.data.add_received = config.data.add_auth_results.clone();
     config.data.add_return_path = config.data.add_auth_results.clone();
     config.data.add_received_spf = config.data.add_auth_results.clone();
+    config.data.add_list_unsubscribe =
+        r"[{if = 'remote-ip', eq = '10.0.0.3', then = 'https://unsubscribe.foobar.org'},
+    {else = false}]"
+            .parse_if(&ConfigContext::default());
     config.data.max_received_headers = IfBlock::new(3);
     config.data.max_messages = r"[{if = 'remote-ip', eq = '10.0.0.1', then = 1},
     {else = 100}]"
@@ -151,7 +163,9 @@ async fn data() {
         .assert_contains("Return-Path: ")
         .assert_contains("Received: ")
         .assert_contains("Authentication-Results: ")
-        .assert_contains("Received-SPF: ");
+        .assert_contains("Received-SPF: ")
+        .assert_contains("List-Unsubscribe: <https://unsubscribe.foobar.org>")
+        .assert_contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click");
 
     // Only one message is allowed in the queue from john@doe.org
     let mut queued_messages = vec![];
@@ -272,3 +286,272 @@ impl Session<DummyIo> {
         }
     }
 }
+
+#[tokio::test]
+async fn data_metadata() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_data_metadata_test");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.session.config.data.metadata_header = IfBlock::new(Some("X-Tracking-Id".to_string()));
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    let message = concat!(
+        "From: john@doe.org\r\n",
+        "To: bill@foobar.org\r\n",
+        "X-Tracking-Id: abc-123\r\n",
+        "Subject: test\r\n",
+        "\r\n",
+        "test"
+    );
+
+    // Metadata should not be extracted from unauthenticated sessions
+    session
+        .send_message("john@doe.org", &["bill@foobar.org"], message, "250")
+        .await;
+    let message_ = qr.read_event().await.unwrap_message();
+    assert_eq!(message_.metadata, None);
+    message_
+        .read_lines()
+        .assert_contains("X-Tracking-Id: abc-123");
+
+    // Metadata is stored with the message and removed from its contents
+    session.data.authenticated_as = "john".to_string();
+    session
+        .send_message("john@doe.org", &["bill@foobar.org"], message, "250")
+        .await;
+    let message_ = qr.read_event().await.unwrap_message();
+    assert_eq!(message_.metadata.as_deref(), Some("abc-123"));
+    message_
+        .read_lines()
+        .assert_not_contains("X-Tracking-Id")
+        .assert_contains("Subject: test");
+}
+
+#[tokio::test]
+async fn data_rcpt_mismatch() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_data_rcpt_mismatch_test");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.session.config.data.max_rcpt_ratio = IfBlock::new(Some(2.0));
+    core.session.config.data.rcpt_ratio_action =
+        r"[{if = 'remote-ip', eq = '10.0.0.2', then = 'reject'},
+    {else = 'tag'}]"
+            .parse_if(&ConfigContext::default());
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    let message = concat!(
+        "From: john@doe.org\r\n",
+        "To: bill@foobar.org\r\n",
+        "Subject: test\r\n",
+        "\r\n",
+        "test"
+    );
+
+    // Messages within the ratio are not tagged
+    session
+        .send_message(
+            "john@doe.org",
+            &["bill@foobar.org", "jane@foobar.org"],
+            message,
+            "250",
+        )
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_not_contains("X-Recipient-Mismatch");
+
+    // Messages exceeding the ratio are tagged
+    session
+        .send_message(
+            "john@doe.org",
+            &["bill@foobar.org", "jane@foobar.org", "mike@foobar.org"],
+            message,
+            "250",
+        )
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_contains("X-Recipient-Mismatch: envelope=3; header=1");
+
+    // Or rejected
+    session.data.remote_ip = "10.0.0.2".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .send_message(
+            "john@doe.org",
+            &["bill@foobar.org", "jane@foobar.org", "mike@foobar.org"],
+            message,
+            "550 5.7.1",
+        )
+        .await;
+    qr.assert_empty_queue();
+}
+
+#[tokio::test]
+async fn data_display_name_spoofing() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_data_display_name_test");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.session.config.rcpt.lookup_domains =
+        IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+            "foobar.org".to_string(),
+        ])))));
+    core.session.config.data.protected_names = IfBlock::new(vec!["Bill Foobar".to_string()]);
+    core.session.config.data.display_name_action =
+        r"[{if = 'remote-ip', eq = '10.0.0.2', then = 'reject'},
+    {else = 'tag'}]"
+            .parse_if(&ConfigContext::default());
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    let spoofed = concat!(
+        "From: \"Bill Foobar\" <bill@evil.org>\r\n",
+        "To: jane@foobar.org\r\n",
+        "Subject: wire transfer\r\n",
+        "\r\n",
+        "test"
+    );
+
+    // Protected names from local domains are not tagged
+    session
+        .send_message(
+            "bill@foobar.org",
+            &["jane@foobar.org"],
+            &spoofed.replace("bill@evil.org", "bill@foobar.org"),
+            "250",
+        )
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_not_contains("X-Display-Name-Spoofing");
+
+    // Protected names from external domains are tagged
+    session
+        .send_message("bill@evil.org", &["jane@foobar.org"], spoofed, "250")
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_contains("X-Display-Name-Spoofing: name=\"bill foobar\"; address=bill@evil.org");
+
+    // Or rejected
+    session.data.remote_ip = "10.0.0.2".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .send_message("bill@evil.org", &["jane@foobar.org"], spoofed, "550 5.7.1")
+        .await;
+    qr.assert_empty_queue();
+}
+
+#[tokio::test]
+async fn data_received_tls() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_data_received_tls_test");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.session.config.data.add_received_cipher =
+        r"[{if = 'remote-ip', eq = '10.0.0.2', then = false},
+    {else = true}]"
+            .parse_if(&ConfigContext::default());
+    let core = Arc::new(core);
+
+    // Establish a TLS connection
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let (stream, client) = tokio::join!(
+        dummy_tls_acceptor().accept(stream),
+        core.queue
+            .connectors
+            .dummy_verify
+            .connect(ServerName::try_from("mx.example.org").unwrap(), client)
+    );
+    let mut client = BufReader::new(client.unwrap());
+    let mut session = Session {
+        state: State::default(),
+        instance: Arc::new(ServerInstance::test()),
+        core,
+        span: tracing::info_span!("test"),
+        stream: stream.unwrap(),
+        data: SessionData::new("127.0.0.1".parse().unwrap(), "10.0.0.1".parse().unwrap()),
+        params: SessionParameters::default(),
+        in_flight: vec![],
+        connection: None,
+    };
+    session.eval_session_params().await;
+    session.cmd(&mut client, "EHLO mx.doe.org\r\n", "250").await;
+    let message = concat!(
+        "From: john@doe.org\r\n",
+        "To: bill@foobar.org\r\n",
+        "Subject: test\r\n",
+        "\r\n",
+        "test\r\n.\r\n"
+    );
+
+    // The negotiated protocol and cipher are recorded in the Received header
+    for (remote_ip, include_cipher) in [("10.0.0.1", true), ("10.0.0.2", false)] {
+        session.data.remote_ip = remote_ip.parse().unwrap();
+        session.eval_session_params().await;
+        session
+            .cmd(&mut client, "MAIL FROM:<john@doe.org>\r\n", "250")
+            .await;
+        session
+            .cmd(&mut client, "RCPT TO:<bill@foobar.org>\r\n", "250")
+            .await;
+        session.cmd(&mut client, "DATA\r\n", "354").await;
+        session.cmd(&mut client, message, "250").await;
+
+        let lines = qr.read_event().await.unwrap_message().read_lines();
+        let received = lines
+            .iter()
+            .skip_while(|line| !line.starts_with("Received: "))
+            .take_while(|line| !line.contains(';'))
+            .map(|line| line.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if include_cipher {
+            assert!(
+                received.contains("(using TLSv1.3 with cipher TLS13_"),
+                "{received}"
+            );
+        } else {
+            assert!(received.contains("(using TLSv1.3)"), "{received}");
+        }
+    }
+}
+
+impl Session<TlsStream<TcpStream>> {
+    async fn cmd(
+        &mut self,
+        client: &mut BufReader<tokio_rustls::client::TlsStream<TcpStream>>,
+        cmd: &str,
+        expected_code: &str,
+    ) {
+        self.ingest(cmd.as_bytes()).await.unwrap();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            client.read_line(&mut line).await.unwrap();
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+        assert!(line.starts_with(expected_code), "{line}");
+    }
+}
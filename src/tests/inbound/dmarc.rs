@@ -296,3 +296,239 @@ async fn dmarc() {
         .assert_contains("dmarc=pass")
         .assert_contains("Received-SPF: pass");
 }
+
+#[tokio::test]
+async fn dkim_require() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_dkim_require_test");
+
+    core.resolvers.dns.txt_add(
+        "ed._domainkey.example.com",
+        DomainKey::parse(
+            concat!(
+                "v=DKIM1; k=ed25519; ",
+                "p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+            )
+            .as_bytes(),
+        )
+        .unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    core.resolvers.dns.txt_add(
+        "default._domainkey.example.com",
+        DomainKey::parse(
+            concat!(
+                "v=DKIM1; t=s; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQ",
+                "KBgQDwIRP/UC3SBsEmGqZ9ZJW3/DkMoGeLnQg1fWn7/zYt",
+                "IxN2SnFCjxOCKG9v3b4jYfcTNh5ijSsq631uBItLa7od+v",
+                "/RtdC2UzJ1lWT947qR+Rcac2gbto/NMqJ0fzfVjH4OuKhi",
+                "tdY9tf6mcwGjaNBcWToIMmPSPDdQPNUYckcQ2QIDAQAB",
+            )
+            .as_bytes(),
+        )
+        .unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+
+    let mut config = &mut core.session.config.rcpt;
+    config.lookup_domains = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "example.com".to_string(),
+    ])))));
+    config.lookup_addresses = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "jdoe@example.com".to_string(),
+    ])))));
+
+    let mut config = &mut core.mail_auth;
+    config.spf.verify_ehlo = IfBlock::new(VerifyStrategy::Disable);
+    config.spf.verify_mail_from = IfBlock::new(VerifyStrategy::Disable);
+    config.dmarc.verify = IfBlock::new(VerifyStrategy::Disable);
+    config.arc.verify = IfBlock::new(VerifyStrategy::Disable);
+    config.dkim.verify = IfBlock::new(VerifyStrategy::Disable);
+    config.dkim.require = "[{if = 'sender-domain', eq = 'example.com', then = 'example.com'},
+    {if = 'sender-domain', eq = 'partner.com', then = 'partner.com'},
+    { else = false }]"
+        .parse_if(&ConfigContext::default());
+
+    let core = Arc::new(core);
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.com").await;
+
+    // Messages without a signature from the required domain are rejected
+    session
+        .send_message(
+            "bill@example.com",
+            &["jdoe@example.com"],
+            "test:no_dkim",
+            "550 5.7.20",
+        )
+        .await;
+    session
+        .send_message(
+            "bill@example.com",
+            &["jdoe@example.com"],
+            "test:invalid_dkim",
+            "550 5.7.20",
+        )
+        .await;
+    session
+        .send_message(
+            "bill@partner.com",
+            &["jdoe@example.com"],
+            "test:dkim",
+            "550 5.7.20",
+        )
+        .await;
+    qr.assert_empty_queue();
+
+    // Valid signatures from the required domain are accepted
+    session
+        .send_message(
+            "bill@example.com",
+            &["jdoe@example.com"],
+            "test:dkim",
+            "250",
+        )
+        .await;
+    qr.read_event().await.unwrap_message();
+
+    // Other sender domains are not affected
+    session
+        .send_message("joe@test.net", &["jdoe@example.com"], "test:no_dkim", "250")
+        .await;
+    qr.read_event().await.unwrap_message();
+}
+
+#[tokio::test]
+async fn spf_softfail_no_dkim() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_spf_softfail_no_dkim_test");
+
+    core.resolvers.dns.txt_add(
+        "example.com",
+        Spf::parse(b"v=spf1 ip4:10.0.0.2 ~all").unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    core.resolvers.dns.txt_add(
+        "softfail.net",
+        Spf::parse(b"v=spf1 ip4:10.0.0.2 ~all").unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    core.resolvers.dns.txt_add(
+        "test.net",
+        Spf::parse(b"v=spf1 ip4:10.0.0.1 -all").unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    core.resolvers.dns.txt_add(
+        "ed._domainkey.example.com",
+        DomainKey::parse(
+            concat!(
+                "v=DKIM1; k=ed25519; ",
+                "p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+            )
+            .as_bytes(),
+        )
+        .unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+    core.resolvers.dns.txt_add(
+        "default._domainkey.example.com",
+        DomainKey::parse(
+            concat!(
+                "v=DKIM1; t=s; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQ",
+                "KBgQDwIRP/UC3SBsEmGqZ9ZJW3/DkMoGeLnQg1fWn7/zYt",
+                "IxN2SnFCjxOCKG9v3b4jYfcTNh5ijSsq631uBItLa7od+v",
+                "/RtdC2UzJ1lWT947qR+Rcac2gbto/NMqJ0fzfVjH4OuKhi",
+                "tdY9tf6mcwGjaNBcWToIMmPSPDdQPNUYckcQ2QIDAQAB",
+            )
+            .as_bytes(),
+        )
+        .unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+
+    let mut config = &mut core.session.config.rcpt;
+    config.lookup_domains = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "example.com".to_string(),
+    ])))));
+    config.lookup_addresses = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "jdoe@example.com".to_string(),
+    ])))));
+
+    let mut config = &mut core.mail_auth;
+    config.spf.verify_ehlo = IfBlock::new(VerifyStrategy::Disable);
+    config.spf.verify_mail_from = IfBlock::new(VerifyStrategy::Relaxed);
+    config.dmarc.verify = IfBlock::new(VerifyStrategy::Disable);
+    config.arc.verify = IfBlock::new(VerifyStrategy::Disable);
+    config.dkim.verify = IfBlock::new(VerifyStrategy::Disable);
+    config.spf.softfail_no_dkim = "[{if = 'sender-domain', eq = 'example.com', then = 'reject'},
+    {if = 'listener', eq = 'smtp', then = 'tag'},
+    { else = false }]"
+        .parse_if(&ConfigContext::default());
+
+    let core = Arc::new(core);
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.com").await;
+
+    // SPF softfail without a valid DKIM signature is rejected
+    session
+        .send_message(
+            "bill@example.com",
+            &["jdoe@example.com"],
+            "test:no_dkim",
+            "550 5.7.23",
+        )
+        .await;
+    session
+        .send_message(
+            "bill@example.com",
+            &["jdoe@example.com"],
+            "test:invalid_dkim",
+            "550 5.7.23",
+        )
+        .await;
+    qr.assert_empty_queue();
+
+    // A valid DKIM signature is enough to accept a softfail
+    session
+        .send_message(
+            "bill@example.com",
+            &["jdoe@example.com"],
+            "test:dkim",
+            "250",
+        )
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_not_contains("X-SPF-Softfail-No-DKIM");
+
+    // Other senders on the listener are tagged
+    session
+        .send_message(
+            "joe@softfail.net",
+            &["jdoe@example.com"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_contains("X-SPF-Softfail-No-DKIM: smtp.mailfrom=joe@softfail.net");
+
+    // SPF passes are not affected
+    session
+        .send_message("joe@test.net", &["jdoe@example.com"], "test:no_dkim", "250")
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_not_contains("X-SPF-Softfail-No-DKIM");
+}
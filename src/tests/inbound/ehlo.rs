@@ -58,6 +58,9 @@ async fn ehlo() {
     core.mail_auth.spf.verify_ehlo = r"[{if = 'remote-ip', eq = '10.0.0.2', then = 'strict'},
     {else = 'relaxed'}]"
         .parse_if(&ConfigContext::default());
+    config.extensions.custom =
+        r"[{if = 'remote-ip', eq = '10.0.0.1', then = ['X-INTERNAL-FOO', 'X-BAR 1']}]"
+            .parse_if(&ConfigContext::default());
     config.ehlo.reject_non_fqdn = IfBlock::new(true);
 
     // Reject non-FQDN domains
@@ -74,7 +77,9 @@ async fn ehlo() {
         .assert_contains("SIZE 1024")
         .assert_contains("MT-PRIORITY NSEP")
         .assert_contains("FUTURERELEASE 3600")
-        .assert_contains("STARTTLS");
+        .assert_contains("STARTTLS")
+        .assert_contains("250-X-INTERNAL-FOO")
+        .assert_contains("250 X-BAR 1");
 
     // SPF should be a Pass for 10.0.0.1
     assert_eq!(
@@ -102,5 +107,56 @@ async fn ehlo() {
         .assert_contains("SIZE 2048")
         .assert_not_contains("MT-PRIORITY")
         .assert_not_contains("FUTURERELEASE")
-        .assert_not_contains("STARTTLS");
+        .assert_not_contains("STARTTLS")
+        .assert_not_contains("X-INTERNAL-FOO");
+
+    // Sender dependent limits advertise the largest size at EHLO
+    let mut core = Core::test();
+    core.session.config.data.max_message_size =
+        r"[{if = 'sender-domain', eq = 'foobar.org', then = 4096},
+    {else = 1024}]"
+            .parse_if(&ConfigContext::default());
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .cmd("EHLO mx1.foobar.org", "250")
+        .await
+        .assert_contains("SIZE 4096");
+
+    // The stricter limit is enforced once the sender is known
+    session
+        .cmd("MAIL FROM:<bill@example.org> SIZE=2048", "552 5.3.4")
+        .await;
+    session
+        .cmd("MAIL FROM:<bill@foobar.org> SIZE=2048", "250")
+        .await;
+    assert_eq!(session.params.max_message_size, 4096);
+
+    // Anonymous limits are advertised until the client authenticates
+    let mut core = Core::test();
+    core.session.config.data.max_message_size = r"[{if = 'authenticated-as', ne = '', then = 8192},
+    {else = 1024}]"
+        .parse_if(&ConfigContext::default());
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .cmd("EHLO mx1.foobar.org", "250")
+        .await
+        .assert_contains("SIZE 1024");
+    session
+        .cmd("MAIL FROM:<bill@foobar.org> SIZE=4096", "552 5.3.4")
+        .await;
+
+    // The authenticated limit is advertised and enforced after authentication
+    session.data.authenticated_as = "john".to_string();
+    session
+        .cmd("EHLO mx1.foobar.org", "250")
+        .await
+        .assert_contains("SIZE 8192");
+    session
+        .cmd("MAIL FROM:<bill@foobar.org> SIZE=4096", "250")
+        .await;
+    assert_eq!(session.params.max_message_size, 8192);
 }
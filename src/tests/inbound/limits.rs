@@ -21,13 +21,16 @@
  * for more details.
 */
 
-use std::time::{Duration, Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use tokio::sync::watch;
 
 use crate::{
-    config::ConfigContext,
-    core::{Core, Session},
+    config::{ConfigContext, ConnectionOverflow},
+    core::{throttle::ConnectionLimiter, Core, Session, SessionEnd},
     tests::{session::VerifyResponse, ParseTestConfig},
 };
 
@@ -44,6 +47,9 @@ async fn limits() {
     config.duration = r"[{if = 'remote-ip', eq = '10.0.0.3', then = '500ms'},
     {else = '60m'}]"
         .parse_if(&ConfigContext::default());
+    config.command_limit = r"[{if = 'remote-ip', eq = '10.0.0.4', then = 3},
+    {else = 0}]"
+        .parse_if(&ConfigContext::default());
     let (_tx, rx) = watch::channel(true);
 
     // Exceed max line length
@@ -62,7 +68,10 @@ async fn limits() {
     // Exceed transfer quota
     session.eval_session_params().await;
     session.write_rx("MAIL FROM:<this_is_a_long@command_over_10_chars.com>\r\n");
-    session.handle_conn_(rx.clone()).await;
+    assert!(matches!(
+        session.handle_conn_(rx.clone()).await,
+        SessionEnd::ClosedByServer
+    ));
     session.response().assert_code("451 4.7.28");
 
     // Loitering
@@ -71,7 +80,10 @@ async fn limits() {
     session.eval_session_params().await;
     tokio::time::sleep(Duration::from_millis(600)).await;
     session.write_rx("MAIL FROM:<this_is_a_long@command_over_10_chars.com>\r\n");
-    session.handle_conn_(rx.clone()).await;
+    assert!(matches!(
+        session.handle_conn_(rx.clone()).await,
+        SessionEnd::ClosedByServer
+    ));
     session.response().assert_code("453 4.3.2");
 
     // Timeout
@@ -79,6 +91,95 @@ async fn limits() {
     session.data.valid_until = Instant::now();
     session.eval_session_params().await;
     session.write_rx("MAIL FROM:<this_is_a_long@command_over_10_chars.com>\r\n");
-    session.handle_conn_(rx.clone()).await;
+    assert!(matches!(
+        session.handle_conn_(rx.clone()).await,
+        SessionEnd::ClosedByServer
+    ));
     session.response().assert_code("221 2.0.0");
+
+    // Exceed command limit
+    session.data.remote_ip = "10.0.0.4".parse().unwrap();
+    session.data.commands = 0;
+    session.eval_session_params().await;
+    session.ingest(b"NOOP\r\nRSET\r\nNOOP\r\n").await.unwrap();
+    session.response().assert_code("250 2.0.0");
+    assert!(session.ingest(b"NOOP\r\n").await.is_err());
+    session.response().assert_code("421 4.3.2");
+}
+
+#[tokio::test]
+async fn connection_limit() {
+    // New connections are rejected once the limit is reached
+    let limiter = Arc::new(ConnectionLimiter::new(2, ConnectionOverflow::RejectNew));
+    let conn1 = limiter.admit().unwrap();
+    let conn2 = limiter.admit().unwrap();
+    conn1.set_idle(true);
+    assert!(limiter.admit().is_none());
+    drop(conn2);
+    let _conn2 = limiter.admit().unwrap();
+    assert_eq!(limiter.len(), 2);
+
+    // Busy connections are never evicted
+    let limiter = Arc::new(ConnectionLimiter::new(
+        2,
+        ConnectionOverflow::EvictOldestIdle,
+    ));
+    let conn1 = limiter.admit().unwrap();
+    let conn2 = limiter.admit().unwrap();
+    assert!(limiter.admit().is_none());
+
+    // The connection that has been idle the longest is evicted
+    conn2.set_idle(true);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    conn1.set_idle(true);
+    let _conn3 = limiter.admit().unwrap();
+    assert_eq!(limiter.len(), 2);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), conn2.evict.notified())
+            .await
+            .is_ok()
+    );
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), conn1.evict.notified())
+            .await
+            .is_err()
+    );
+    drop(conn2);
+    assert_eq!(limiter.len(), 2);
+
+    // Sessions in the middle of a transaction are not idle
+    let (_tx, rx) = watch::channel(true);
+    let limiter = Arc::new(ConnectionLimiter::new(
+        1,
+        ConnectionOverflow::EvictOldestIdle,
+    ));
+    let mut session = Session::test(Core::test());
+    session.connection = limiter.admit();
+    session.eval_session_params().await;
+    session.ehlo("mx.foobar.org").await;
+    session.mail_from("bill@foobar.org", "250").await;
+    session.write_rx("NOOP\r\n");
+    tokio::time::timeout(Duration::from_millis(100), session.handle_conn_(rx.clone()))
+        .await
+        .unwrap_err();
+    session.response().assert_code("250");
+    assert!(limiter.admit().is_none());
+
+    // Idle sessions are closed once evicted
+    session.rset().await;
+    session.write_rx("NOOP\r\n");
+    tokio::time::timeout(Duration::from_millis(100), session.handle_conn_(rx.clone()))
+        .await
+        .unwrap_err();
+    session.response().assert_code("250");
+    let _conn = limiter.admit().unwrap();
+    session.write_rx("NOOP\r\n");
+    assert!(matches!(
+        session.handle_conn_(rx.clone()).await,
+        SessionEnd::ClosedByServer
+    ));
+    session
+        .response()
+        .assert_contains("250")
+        .assert_contains("421 4.3.2");
 }
@@ -0,0 +1,237 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::Arc, time::Duration};
+
+use ahash::AHashSet;
+use tokio::sync::mpsc;
+
+use crate::{
+    config::{ConfigContext, IfBlock},
+    core::{Core, Session},
+    lookup::{Event, Item, Lookup, LookupResult},
+    tests::{session::VerifyResponse, ParseTestConfig},
+};
+
+#[tokio::test]
+async fn list_expansion() {
+    let mut core = Core::test();
+
+    // Create temp dir for queue
+    let mut qr = core.init_test_queue("smtp_lists_test");
+
+    let config = &mut core.session.config.rcpt;
+    config.relay = IfBlock::new(true);
+    config.lookup_lists = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "team@foobar.org".to_string(),
+        "team@foobar.org:jane@foobar.org,staff@foobar.org,bill@example.org".to_string(),
+        "staff@foobar.org".to_string(),
+        "staff@foobar.org:team@foobar.org,mike@example.net,JANE@foobar.org".to_string(),
+    ])))));
+    config.lists_max_members = r"[{if = 'sender', eq = 'max@doe.org', then = 1},
+    {else = 100}]"
+        .parse_if(&ConfigContext::default());
+    config.lists_max_depth = r"[{if = 'sender', eq = 'depth@doe.org', then = 1},
+    {else = 3}]"
+        .parse_if(&ConfigContext::default());
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+
+    // Nested lists are expanded, loops and duplicates are skipped
+    session
+        .send_message("john@doe.org", &["team@foobar.org"], "test:no_dkim", "250")
+        .await;
+    assert_eq!(
+        sorted_rcpts(qr.read_event().await.unwrap_message()),
+        vec!["bill@example.org", "jane@foobar.org", "mike@example.net"]
+    );
+    qr.assert_empty_queue();
+
+    // Regular recipients are queued right away, list members in a separate message
+    session
+        .send_message(
+            "john@doe.org",
+            &["team@foobar.org", "direct@example.com"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    assert_eq!(
+        sorted_rcpts(qr.read_event().await.unwrap_message()),
+        vec!["direct@example.com"]
+    );
+    assert_eq!(
+        sorted_rcpts(qr.read_event().await.unwrap_message()),
+        vec!["bill@example.org", "jane@foobar.org", "mike@example.net"]
+    );
+
+    // Lists exceeding the maximum number of members are bounced
+    session
+        .send_message("max@doe.org", &["team@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let dsn = qr.read_event().await.unwrap_message();
+    dsn.read_lines()
+        .assert_contains(
+            "<team@foobar.org> (host 'mx.example.org' rejected command 'RCPT TO:<team@foobar.org>'",
+        )
+        .assert_contains("Status: 5.5.3");
+    assert_eq!(sorted_rcpts(dsn), vec!["max@doe.org"]);
+    qr.assert_empty_queue();
+
+    // Nested lists deeper than the maximum depth are skipped
+    session
+        .send_message("depth@doe.org", &["team@foobar.org"], "test:no_dkim", "250")
+        .await;
+    assert_eq!(
+        sorted_rcpts(qr.read_event().await.unwrap_message()),
+        vec!["bill@example.org", "jane@foobar.org"]
+    );
+
+    // The list address itself is never queued
+    session.cmd("MAIL FROM:<john@doe.org>", "250").await;
+    session.cmd("RCPT TO:<staff@foobar.org>", "250").await;
+    assert_eq!(
+        session.data.rcpt_lists,
+        vec!["staff@foobar.org".to_string()]
+    );
+    session.cmd("RSET", "250").await;
+    assert!(session.data.rcpt_lists.is_empty());
+    qr.assert_empty_queue();
+}
+
+#[tokio::test]
+async fn list_expansion_failure() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_lists_failure_test");
+
+    // Expanding broken@foobar.org always fails temporarily, flaky@foobar.org only once
+    let (tx, mut rx) = mpsc::channel(128);
+    tokio::spawn(async move {
+        let mut flaky_attempts = 0;
+        while let Some(Event::Lookup(lookup)) = rx.recv().await {
+            match lookup.item {
+                Item::IsAccount(list) => {
+                    let _ = lookup.result.send(
+                        if ["team@foobar.org", "broken@foobar.org", "flaky@foobar.org"]
+                            .contains(&list.as_str())
+                        {
+                            LookupResult::True
+                        } else {
+                            LookupResult::False
+                        },
+                    );
+                }
+                Item::Expand(list) if list == "team@foobar.org" => {
+                    let _ = lookup
+                        .result
+                        .send(LookupResult::Values(vec!["jane@foobar.org".to_string()]));
+                }
+                Item::Expand(list) if list == "flaky@foobar.org" => {
+                    flaky_attempts += 1;
+                    if flaky_attempts > 1 {
+                        let _ = lookup
+                            .result
+                            .send(LookupResult::Values(vec!["mike@example.net".to_string()]));
+                    }
+                }
+                _ => (),
+            }
+        }
+    });
+    let config = &mut core.session.config.rcpt;
+    config.relay = IfBlock::new(true);
+    config.lookup_lists = IfBlock::new(Some(Arc::new(Lookup::Remote(tx.into()))));
+    core.queue.config.retry = IfBlock::new(vec![Duration::from_millis(100)]);
+    core.queue.config.expire = IfBlock::new(Duration::from_millis(350));
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+
+    // Temporary failures are retried
+    session
+        .send_message("john@doe.org", &["flaky@foobar.org"], "test:no_dkim", "250")
+        .await;
+    qr.assert_empty_queue();
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(
+        sorted_rcpts(qr.read_event().await.unwrap_message()),
+        vec!["mike@example.net"]
+    );
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    qr.assert_empty_queue();
+
+    // The sender is notified once the list expires without being expanded
+    session
+        .send_message(
+            "john@doe.org",
+            &["broken@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    qr.assert_empty_queue();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let dsn = qr.read_event().await.unwrap_message();
+    dsn.read_lines()
+        .assert_contains("<broken@foobar.org> (queue error: Failed to expand mailing list.)");
+    assert_eq!(sorted_rcpts(dsn), vec!["john@doe.org"]);
+    qr.assert_empty_queue();
+
+    // Lists that were expanded are delivered right away
+    session
+        .send_message(
+            "john@doe.org",
+            &["team@foobar.org", "broken@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    assert_eq!(
+        sorted_rcpts(qr.read_event().await.unwrap_message()),
+        vec!["jane@foobar.org"]
+    );
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_contains("<broken@foobar.org> (queue error: Failed to expand mailing list.)")
+        .assert_not_contains("<team@foobar.org>");
+    qr.assert_empty_queue();
+}
+
+fn sorted_rcpts(message: Box<crate::queue::Message>) -> Vec<String> {
+    let mut rcpts = message
+        .recipients
+        .into_iter()
+        .map(|r| r.address)
+        .collect::<Vec<_>>();
+    rcpts.sort();
+    rcpts
+}
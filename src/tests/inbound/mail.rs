@@ -26,12 +26,13 @@ use std::{
     time::{Duration, Instant, SystemTime},
 };
 
-use mail_auth::{common::parse::TxtRecordParser, spf::Spf, IprevResult, SpfResult};
+use mail_auth::{common::parse::TxtRecordParser, spf::Spf, IprevResult, SpfResult, MX};
 use smtp_proto::{MAIL_BY_NOTIFY, MAIL_BY_RETURN, MAIL_REQUIRETLS};
 
 use crate::{
-    config::{ConfigContext, IfBlock, VerifyStrategy},
+    config::{AddressStrictness, ConfigContext, IfBlock, VerifyStrategy},
     core::{Core, Session},
+    inbound::mail::normalize_address,
     tests::{session::VerifyResponse, ParseTestConfig},
 };
 
@@ -305,3 +306,151 @@ async fn mail() {
     session.response().assert_code("501 5.5.4");
     session.rset().await;
 }
+
+#[test]
+fn address_strictness() {
+    for (address, lenient, strict) in [
+        (
+            "john@foobar.org",
+            Some("john@foobar.org"),
+            Some("john@foobar.org"),
+        ),
+        ("", Some(""), Some("")),
+        (
+            "@mx1.foobar.org,@mx2.foobar.org:john@foobar.org",
+            Some("john@foobar.org"),
+            None,
+        ),
+        ("\"john\"@foobar.org", Some("john@foobar.org"), None),
+        (
+            "\"john doe\"@foobar.org",
+            Some("\"john doe\"@foobar.org"),
+            None,
+        ),
+        ("john..doe@foobar.org", Some("john..doe@foobar.org"), None),
+        (
+            "john@[10.0.0.1]",
+            Some("john@[10.0.0.1]"),
+            Some("john@[10.0.0.1]"),
+        ),
+        ("john@-foobar.org", Some("john@-foobar.org"), None),
+        ("john", Some("john"), None),
+    ] {
+        assert_eq!(
+            normalize_address(address.to_string(), AddressStrictness::Lenient).as_deref(),
+            lenient,
+            "{address}"
+        );
+        assert_eq!(
+            normalize_address(address.to_string(), AddressStrictness::Strict).as_deref(),
+            strict,
+            "{address}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn mail_require_tls() {
+    let mut core = Core::test();
+    core.session.config.mail.require_tls = IfBlock::new(true);
+
+    // Plain text transactions are refused
+    let mut session = Session::test(core);
+    session.stream.tls = false;
+    session.eval_session_params().await;
+    session
+        .ehlo("mx.foobar.org")
+        .await
+        .assert_contains("STARTTLS");
+    session
+        .cmd("MAIL FROM:<john@foobar.org>", "530 5.7.10")
+        .await;
+
+    // STARTTLS is still allowed, after which the transaction is accepted
+    assert!(!session.ingest(b"STARTTLS\r\n").await.unwrap());
+    session.response().assert_contains("220 2.0.0");
+    session.stream.tls = true;
+    session.ehlo("mx.foobar.org").await;
+    session.cmd("MAIL FROM:<john@foobar.org>", "250").await;
+}
+
+#[tokio::test]
+async fn mail_verify_domain() {
+    let mut core = Core::test();
+    let expires = Instant::now() + Duration::from_secs(10);
+    core.mail_auth.spf.verify_ehlo = IfBlock::new(VerifyStrategy::Disable);
+    core.mail_auth.spf.verify_mail_from = IfBlock::new(VerifyStrategy::Disable);
+    core.mail_auth.iprev.verify = IfBlock::new(VerifyStrategy::Disable);
+
+    // foobar.org resolves through its MX and example.org through its A record
+    core.resolvers.dns.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx.foobar.org".to_string()],
+            preference: 10,
+        }],
+        expires,
+    );
+    core.resolvers.dns.mx_add("example.org", vec![], expires);
+    core.resolvers
+        .dns
+        .ipv4_add("example.org", vec!["10.0.0.1".parse().unwrap()], expires);
+
+    // nowhere.org has neither MX nor address records
+    core.resolvers.dns.mx_add("nowhere.org", vec![], expires);
+    core.resolvers.dns.ipv4_add("nowhere.org", vec![], expires);
+    core.resolvers.dns.ipv6_add("nowhere.org", vec![], expires);
+
+    core.session.config.mail.verify_domain =
+        r"[{if = 'remote-ip', eq = '10.0.0.1', then = 'disable'},
+    {if = 'remote-ip', eq = '10.0.0.2', then = 'permanent'},
+    {else = 'temporary'}]"
+            .parse_if(&ConfigContext::default());
+    core.session.config.mail.verify_domain_timeout =
+        r"[{if = 'remote-ip', eq = '10.0.0.4', then = '1ms'},
+    {else = '10s'}]"
+            .parse_if(&ConfigContext::default());
+
+    // Verification disabled
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    session.cmd("MAIL FROM:<john@nowhere.org>", "250").await;
+    session.rset().await;
+
+    // Permanent failure
+    session.data.remote_ip = "10.0.0.2".parse().unwrap();
+    session.eval_session_params().await;
+    session.cmd("MAIL FROM:<john@foobar.org>", "250").await;
+    session.rset().await;
+    session.cmd("MAIL FROM:<john@example.org>", "250").await;
+    session.rset().await;
+    session
+        .cmd("MAIL FROM:<john@nowhere.org>", "550 5.1.8")
+        .await;
+
+    // Null senders and authenticated users are not verified
+    session.cmd("MAIL FROM:<>", "250").await;
+    session.rset().await;
+    session.data.authenticated_as = "john".to_string();
+    session.cmd("MAIL FROM:<john@nowhere.org>", "250").await;
+    session.rset().await;
+    session.data.authenticated_as.clear();
+
+    // Temporary failure
+    session.data.remote_ip = "10.0.0.3".parse().unwrap();
+    session.eval_session_params().await;
+    session.cmd("MAIL FROM:<john@foobar.org>", "250").await;
+    session.rset().await;
+    session
+        .cmd("MAIL FROM:<john@nowhere.org>", "450 4.1.8")
+        .await;
+
+    // Lookups that do not complete in time are retried later
+    session.data.remote_ip = "10.0.0.4".parse().unwrap();
+    session.eval_session_params().await;
+    session
+        .cmd("MAIL FROM:<john@unknown.org>", "451 4.4.3")
+        .await;
+}
@@ -32,6 +32,7 @@ use crate::{
 
 use super::{QueueReceiver, ReportReceiver};
 
+pub mod atrn;
 pub mod auth;
 pub mod basic;
 pub mod data;
@@ -39,6 +40,7 @@ pub mod dmarc;
 pub mod dnsrbl;
 pub mod ehlo;
 pub mod limits;
+pub mod lists;
 pub mod mail;
 pub mod rcpt;
 pub mod scripts;
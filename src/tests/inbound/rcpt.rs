@@ -21,15 +21,23 @@
  * for more details.
 */
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use ahash::AHashSet;
-use smtp_proto::{RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_SUCCESS};
+use ahash::{AHashMap, AHashSet};
+use smtp_proto::{RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS};
+use tokio::sync::mpsc;
 
 use crate::{
-    config::{ConfigContext, IfBlock},
-    core::{Core, Session, State},
-    lookup::Lookup,
+    config::{CatchAllAction, ConfigContext, IfBlock},
+    core::{CatchAllDomain, Core, Session, State},
+    lookup::{Event, Item, Lookup, LookupResult},
+    queue::{manager::Queue, DeliveryAttempt, Status, RCPT_VERIFY_PENDING},
     tests::{session::VerifyResponse, ParseTestConfig},
 };
 
@@ -44,11 +52,18 @@ async fn rcpt() {
         "john@foobar.org".to_string(),
     ]));
     let list_domains = Lookup::Local(AHashSet::from_iter(["foobar.org".to_string()]));
+    let list_created = Lookup::Local(AHashSet::from_iter([
+        "jane@foobar.org:2020-01-01T00:00:00Z".to_string(),
+        "bill@foobar.org:1577836800".to_string(),
+    ]));
 
     let mut config = &mut core.session.config.rcpt;
     let mut config_ext = &mut core.session.config.extensions;
     config.lookup_domains = IfBlock::new(Some(Arc::new(list_domains)));
     config.lookup_addresses = IfBlock::new(Some(Arc::new(list_addresses)));
+    config.lookup_created = IfBlock::new(Some(Arc::new(list_created)));
+    config.domain_aliases =
+        AHashMap::from_iter([("old-foobar.org".to_string(), "foobar.org".to_string())]);
     config.max_recipients = r"[{if = 'remote-ip', eq = '10.0.0.1', then = 3},
     {else = 5}]"
         .parse_if(&ConfigContext::default());
@@ -56,6 +71,9 @@ async fn rcpt() {
     {else = true}]"
         .parse_if(&ConfigContext::default());
     config_ext.dsn = r"[{if = 'remote-ip', eq = '10.0.0.1', then = false},
+    {else = true}]"
+        .parse_if(&ConfigContext::default());
+    config_ext.rrvs = r"[{if = 'remote-ip', eq = '10.0.0.1', then = false},
     {else = true}]"
         .parse_if(&ConfigContext::default());
     config.errors_max = r"[{if = 'remote-ip', eq = '10.0.0.1', then = 3},
@@ -89,6 +107,13 @@ async fn rcpt() {
         .unwrap();
     session.response().assert_code("501 5.5.4");
 
+    // RRVS is disabled for 10.0.0.1
+    session
+        .ingest(b"RCPT TO:<jane@foobar.org> RRVS=2021-01-01T00:00:00Z\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("501 5.5.4");
+
     // Send to non-existing user
     session.rcpt_to("tom@foobar.org", "550 5.1.2").await;
 
@@ -132,6 +157,14 @@ async fn rcpt() {
     session.mail_from("john@example.net", "250").await;
     session.rcpt_to("external@domain.com", "250").await;
 
+    // Aliased domains are verified after rewriting
+    session.rcpt_to("tom@old-foobar.org", "550 5.1.2").await;
+    session.rcpt_to("Bill@Old-FooBar.org", "250").await;
+    let rcpt = session.data.rcpt_to.last().unwrap();
+    assert_eq!(rcpt.address, "Bill@foobar.org");
+    assert_eq!(rcpt.address_lcase, "bill@foobar.org");
+    assert_eq!(rcpt.domain, "foobar.org");
+
     // DSN is enabled for 10.0.0.2
     session
         .ingest(b"RCPT TO:<jane@foobar.org> NOTIFY=SUCCESS,FAILURE,DELAY ORCPT=rfc822;Jane.Doe@Foobar.org\r\n")
@@ -141,4 +174,276 @@ async fn rcpt() {
     let rcpt = session.data.rcpt_to.last().unwrap();
     assert!((rcpt.flags & (RCPT_NOTIFY_DELAY | RCPT_NOTIFY_SUCCESS | RCPT_NOTIFY_FAILURE)) != 0);
     assert_eq!(rcpt.dsn_info.as_ref().unwrap(), "Jane.Doe@Foobar.org");
+    session
+        .ingest(b"RCPT TO:<john@foobar.org> ORCPT=rfc822;John+20Doe+2Btag@Foobar.org\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    assert_eq!(
+        session
+            .data
+            .rcpt_to
+            .last()
+            .unwrap()
+            .dsn_info
+            .as_ref()
+            .unwrap(),
+        "John Doe+tag@Foobar.org"
+    );
+
+    // RRVS is enabled for 10.0.0.2
+    session
+        .ingest(b"RCPT TO:<jane@foobar.org> RRVS=2021-01-01T00:00:00Z\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    session
+        .ingest(b"RCPT TO:<bill@foobar.org> RRVS=2019-01-01T00:00:00Z\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("550 5.7.17");
+    session
+        .ingest(b"RCPT TO:<mike@foobar.org> RRVS=2019-01-01T00:00:00Z\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("550 5.7.19");
+    session
+        .ingest(b"RCPT TO:<mike@foobar.org> RRVS=2019-01-01T00:00:00Z;C\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+}
+
+#[tokio::test]
+async fn rcpt_temp_fail() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_rcpt_temp_fail_test");
+
+    // Simulate a lookup backend outage
+    let (tx, rx) = mpsc::channel(1);
+    drop(rx);
+    let mut config = &mut core.session.config.rcpt;
+    config.lookup_domains = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "foobar.org".to_string(),
+    ])))));
+    config.lookup_addresses = IfBlock::new(Some(Arc::new(Lookup::Remote(tx.into()))));
+    config.lookup_fail_action = r"[{if = 'remote-ip', eq = '10.0.0.2', then = 'accept'},
+    {else = 'reject'}]"
+        .parse_if(&ConfigContext::default());
+
+    // Delivery time verification is only available for senders at doe.org
+    let is_online = Arc::new(AtomicBool::new(false));
+    let is_online_ = is_online.clone();
+    let (tx, mut rx) = mpsc::channel(128);
+    tokio::spawn(async move {
+        while let Some(Event::Lookup(lookup)) = rx.recv().await {
+            if let (Item::IsAccount(address), true) =
+                (lookup.item, is_online_.load(Ordering::Relaxed))
+            {
+                let _ = lookup.result.send(if address == "jane@foobar.org" {
+                    LookupResult::True
+                } else {
+                    LookupResult::False
+                });
+            }
+        }
+    });
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "remote/verify".to_string(),
+        Arc::new(Lookup::Remote(tx.into())),
+    );
+    core.queue.config.verify_addresses =
+        r"[{if = 'sender-domain', eq = 'doe.org', then = 'remote/verify'},
+    {else = false}]"
+            .parse_if::<Option<String>>(&ctx)
+            .map_if_block(&ctx.lookup, "", "")
+            .unwrap();
+
+    // Temporary failures are rejected by default
+    let core = Arc::new(core);
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.doe.org").await;
+    session.mail_from("john@doe.org", "250").await;
+    session.rcpt_to("bill@foobar.org", "451 4.4.3").await;
+
+    // Recipients that cannot be verified at delivery time are rejected
+    session.data.remote_ip = "10.0.0.2".parse().unwrap();
+    session.eval_session_params().await;
+    session.cmd("RSET", "250").await;
+    session.mail_from("john@example.net", "250").await;
+    session.rcpt_to("bill@foobar.org", "451 4.4.3").await;
+    session.cmd("RSET", "250").await;
+
+    // Recipients are accepted and verified at delivery time
+    session
+        .send_message("john@doe.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    let message = qr.read_event().await.unwrap_message();
+    assert!(message.recipients[0].has_flag(RCPT_VERIFY_PENDING));
+
+    // Delivery is postponed while verification is unavailable
+    DeliveryAttempt::from(message)
+        .try_deliver(core.clone(), &mut Queue::default())
+        .await;
+    let mut message = qr.read_event().await.unwrap_retry().inner;
+    assert!(message.recipients[0].has_flag(RCPT_VERIFY_PENDING));
+    assert!(matches!(
+        message.recipients[0].status,
+        Status::TemporaryFailure(_)
+    ));
+    assert!(matches!(
+        message.domains[0].status,
+        Status::TemporaryFailure(_)
+    ));
+
+    // Unknown recipients are bounced
+    is_online.store(true, Ordering::Relaxed);
+    message.domains[0].retry.due = Instant::now();
+    DeliveryAttempt::from(message)
+        .try_deliver(core.clone(), &mut Queue::default())
+        .await;
+    let dsn = qr.read_event().await.unwrap_message();
+    assert_eq!(dsn.recipients[0].address, "john@doe.org");
+    dsn.read_lines()
+        .assert_contains("<bill@foobar.org> (host '")
+        .assert_contains("5.1.2");
+    qr.read_event().await.unwrap_done();
+    qr.assert_empty_queue();
+}
+
+#[tokio::test]
+async fn rcpt_min_delay() {
+    let mut core = Core::test();
+    let mut config = &mut core.session.config.rcpt;
+    config.relay = IfBlock::new(true);
+    config.min_delay = IfBlock::new(Some(Duration::from_millis(50)));
+    config.min_delay_wait = IfBlock::new(Duration::from_millis(200));
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.cmd("HELO mx.foobar.org", "250").await;
+
+    // Clients that do not wait for the MAIL FROM response are tarpitted
+    session.cmd("MAIL FROM:<bill@foobar.org>", "250").await;
+    let time = Instant::now();
+    session.cmd("RCPT TO:<jane@example.org>", "250").await;
+    assert!(time.elapsed() >= Duration::from_millis(200));
+
+    // Subsequent recipients are not delayed
+    let time = Instant::now();
+    session.cmd("RCPT TO:<john@example.org>", "250").await;
+    assert!(time.elapsed() < Duration::from_millis(200));
+
+    // Clients that wait are not delayed
+    session.cmd("RSET", "250").await;
+    session.cmd("MAIL FROM:<bill@foobar.org>", "250").await;
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let time = Instant::now();
+    session.cmd("RCPT TO:<jane@example.org>", "250").await;
+    assert!(time.elapsed() < Duration::from_millis(200));
+
+    // Pipelining clients are not delayed
+    session.ehlo("mx.foobar.org").await;
+    session.cmd("MAIL FROM:<bill@foobar.org>", "250").await;
+    let time = Instant::now();
+    session.cmd("RCPT TO:<jane@example.org>", "250").await;
+    assert!(time.elapsed() < Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn rcpt_catch_all() {
+    let mut core = Core::test();
+    let mut config = &mut core.session.config.rcpt;
+    config.lookup_domains = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "foobar.org".to_string(),
+        "catchall.org".to_string(),
+    ])))));
+    config.lookup_addresses = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "jane@foobar.org".to_string(),
+        "jane@catchall.org".to_string(),
+    ])))));
+    config.catch_all_detect = IfBlock::new(true);
+    core.session.catch_all.insert(
+        "catchall.org".to_string(),
+        CatchAllDomain {
+            is_catch_all: true,
+            expires: Instant::now() + Duration::from_secs(60),
+        },
+    );
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.foobar.org").await;
+    session.mail_from("john@example.net", "250").await;
+
+    // Domains are probed once and the result is cached
+    session
+        .ingest(b"RCPT TO:<jane@foobar.org> NOTIFY=FAILURE\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    assert!(session.data.rcpt_to.last().unwrap().flags & RCPT_NOTIFY_FAILURE != 0);
+    assert!(
+        !session
+            .core
+            .session
+            .catch_all
+            .get("foobar.org")
+            .unwrap()
+            .is_catch_all
+    );
+
+    // Bounces are suppressed for recipients at catch-all domains
+    session
+        .ingest(b"RCPT TO:<jane@catchall.org> NOTIFY=FAILURE\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    let flags = session.data.rcpt_to.last().unwrap().flags;
+    assert_eq!(flags & RCPT_NOTIFY_FAILURE, 0);
+    assert_ne!(flags & RCPT_NOTIFY_NEVER, 0);
+    session.rcpt_to("tom@catchall.org", "550 5.1.2").await;
+
+    // Recipients are accepted as usual when the action is "accept"
+    session.rset().await;
+    session.mail_from("john@example.net", "250").await;
+    session.params.rcpt_catch_all = Some(CatchAllAction::Accept);
+    session
+        .ingest(b"RCPT TO:<jane@catchall.org> NOTIFY=FAILURE\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    assert!(session.data.rcpt_to.last().unwrap().flags & RCPT_NOTIFY_FAILURE != 0);
+
+    // Expired entries are probed again
+    session.params.rcpt_catch_all = Some(CatchAllAction::NoBounce);
+    session
+        .core
+        .session
+        .catch_all
+        .get_mut("catchall.org")
+        .unwrap()
+        .expires = Instant::now() - Duration::from_secs(1);
+    session.rset().await;
+    session.mail_from("john@example.net", "250").await;
+    session
+        .ingest(b"RCPT TO:<jane@catchall.org> NOTIFY=FAILURE\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    assert!(session.data.rcpt_to.last().unwrap().flags & RCPT_NOTIFY_FAILURE != 0);
+    assert!(
+        !session
+            .core
+            .session
+            .catch_all
+            .get("catchall.org")
+            .unwrap()
+            .is_catch_all
+    );
 }
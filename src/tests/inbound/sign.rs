@@ -28,7 +28,7 @@ use std::{
 
 use ahash::AHashSet;
 use mail_auth::{
-    common::{parse::TxtRecordParser, verify::DomainKey},
+    common::{headers::HeaderWriter, parse::TxtRecordParser, verify::DomainKey},
     spf::Spf,
 };
 
@@ -36,7 +36,10 @@ use crate::{
     config::{Config, ConfigContext, IfBlock, VerifyStrategy},
     core::{Core, Session},
     lookup::Lookup,
-    tests::{session::VerifyResponse, ParseTestConfig},
+    tests::{
+        session::{load_test_message, VerifyResponse},
+        ParseTestConfig,
+    },
 };
 
 const SIGNATURES: &str = "
@@ -90,6 +93,18 @@ canonicalization = 'relaxed/simple'
 set-body-length = false
 ";
 
+const BODY_LENGTH_SIGNATURE: &str = "
+[signature.ed]
+public-key = '11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo='
+private-key = 'nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A='
+domain = 'example.com'
+selector = 'ed'
+headers = ['From', 'To', 'Subject']
+algorithm = 'ed25519-sha256'
+canonicalization = 'relaxed/relaxed'
+set-body-length = true
+";
+
 #[tokio::test]
 async fn sign_and_seal() {
     let mut core = Core::test();
@@ -203,6 +218,130 @@ async fn sign_and_seal() {
         );
 }
 
+#[tokio::test]
+async fn dkim_body_length() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_dkim_body_length_test");
+
+    core.resolvers.dns.txt_add(
+        "ed._domainkey.example.com",
+        DomainKey::parse(
+            concat!(
+                "v=DKIM1; k=ed25519; ",
+                "p=11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo="
+            )
+            .as_bytes(),
+        )
+        .unwrap(),
+        Instant::now() + Duration::from_secs(5),
+    );
+
+    // Sign a message including the l= tag
+    let mut ctx = ConfigContext::default();
+    Config::parse(BODY_LENGTH_SIGNATURE)
+        .unwrap()
+        .parse_signatures(&mut ctx)
+        .unwrap();
+    let message = load_test_message("no_dkim", "messages");
+    let mut signed_message = Vec::new();
+    ctx.signers
+        .get("ed")
+        .unwrap()
+        .sign_chained(&[message.as_bytes()])
+        .unwrap()
+        .write_header(&mut signed_message);
+    signed_message.extend_from_slice(message.as_bytes());
+    let signed_message = String::from_utf8(signed_message).unwrap();
+    assert!(signed_message.contains(" l="), "{signed_message}");
+
+    let mut config = &mut core.session.config.rcpt;
+    config.lookup_domains = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "example.com".to_string(),
+    ])))));
+    config.lookup_addresses = IfBlock::new(Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "jdoe@example.com".to_string(),
+    ])))));
+    core.session.config.data.add_auth_results = IfBlock::new(true);
+
+    let mut config = &mut core.mail_auth;
+    config.spf.verify_ehlo = IfBlock::new(VerifyStrategy::Disable);
+    config.spf.verify_mail_from = IfBlock::new(VerifyStrategy::Disable);
+    config.dmarc.verify = IfBlock::new(VerifyStrategy::Disable);
+    config.arc.verify = IfBlock::new(VerifyStrategy::Disable);
+    config.dkim.verify = IfBlock::new(VerifyStrategy::Relaxed);
+    config.dkim.body_length = "[{if = 'sender-domain', eq = 'reject.org', then = 'reject'},
+    {if = 'sender-domain', eq = 'ignore.org', then = 'ignore'},
+    {if = 'sender-domain', eq = 'flag.org', then = 'flag'},
+    {else = 'allow'}]"
+        .parse_if(&ConfigContext::default());
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.example.com").await;
+
+    // Signatures with body length limits are rejected
+    session
+        .send_message(
+            "bill@reject.org",
+            &["jdoe@example.com"],
+            &signed_message,
+            "550 5.7.20",
+        )
+        .await;
+    qr.assert_empty_queue();
+
+    // Signatures with body length limits are treated as unsigned
+    session
+        .send_message(
+            "bill@ignore.org",
+            &["jdoe@example.com"],
+            &signed_message,
+            "250",
+        )
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_contains(
+            "dkim=policy (body length limit, signature ignored) header.d=example.com header.s=ed",
+        )
+        .assert_not_contains("dkim=pass");
+
+    // Signatures with body length limits are flagged
+    session
+        .send_message(
+            "bill@flag.org",
+            &["jdoe@example.com"],
+            &signed_message,
+            "250",
+        )
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_contains("dkim=pass")
+        .assert_contains("dkim=policy (body length limit) header.d=example.com header.s=ed");
+
+    // Signatures with body length limits are accepted by default
+    session
+        .send_message(
+            "bill@example.org",
+            &["jdoe@example.com"],
+            &signed_message,
+            "250",
+        )
+        .await;
+    qr.read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_contains("dkim=pass")
+        .assert_not_contains("dkim=policy");
+}
+
 impl ConfigContext {
     pub fn parse_signatures(mut self) -> Self {
         Config::parse(SIGNATURES)
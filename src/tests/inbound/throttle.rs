@@ -21,12 +21,25 @@
  * for more details.
 */
 
-use std::time::Duration;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rustls::ServerName;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
 
 use crate::{
-    config::ConfigContext,
-    core::{Core, Session, SessionAddress},
-    tests::ParseTestConfig,
+    config::{ConfigContext, IfBlock, Rate, Tarpit, TarpitCurve},
+    core::{
+        throttle::{HandshakeLimiter, LogLimiter},
+        Core, ServerInstance, Session, SessionAddress, SessionData, SessionEnd, SessionParameters,
+        State,
+    },
+    tests::{lookup::dummy_tls_acceptor, session::VerifyResponse, ParseTestConfig},
 };
 
 #[tokio::test]
@@ -111,3 +124,213 @@ async fn throttle_inbound() {
     session.data.remote_ip = "10.0.0.2".parse().unwrap();
     assert!(session.is_allowed().await, "Rate limiter too strict.");
 }
+
+#[tokio::test]
+async fn throttle_tarpit() {
+    let mut core = Core::test();
+    core.session.config.throttle.connect = r"[[throttle]]
+    key = 'remote-ip'
+    rate = '4/1s'
+    tarpit = {threshold = 50, max-delay = '400ms', curve = 'linear'}
+    "
+    .parse_throttle(&ConfigContext::default());
+
+    // Requests below the threshold are not delayed
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    let time = Instant::now();
+    assert!(session.is_allowed().await);
+    assert!(session.is_allowed().await);
+    assert!(time.elapsed() < Duration::from_millis(100));
+
+    // Requests above the threshold are delayed proportionally
+    let time = Instant::now();
+    assert!(session.is_allowed().await);
+    assert!(time.elapsed() >= Duration::from_millis(150));
+
+    // Escalation curves
+    for (curve, expected) in [
+        (TarpitCurve::Linear, [0, 0, 500, 1000]),
+        (TarpitCurve::Quadratic, [0, 0, 250, 1000]),
+        (TarpitCurve::Exponential, [0, 0, 30, 1000]),
+    ] {
+        let tarpit = Tarpit {
+            threshold: 50,
+            max_delay: Duration::from_secs(1),
+            curve,
+        };
+        for (utilization, expected) in [0.25, 0.5, 0.75, 1.0].into_iter().zip(expected) {
+            assert_eq!(
+                tarpit.delay(utilization).unwrap_or_default().as_millis() as u64,
+                expected,
+                "{curve:?} {utilization}"
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn throttle_snapshot() {
+    let build_core = || {
+        let mut core = Core::test();
+        core.session.config.throttle.mail_from = r"[[throttle]]
+        key = 'sender'
+        rate = '2/1s'
+        "
+        .parse_throttle(&ConfigContext::default());
+        core
+    };
+    let sender = SessionAddress {
+        address: "sender@test.org".to_string(),
+        address_lcase: "sender@test.org".to_string(),
+        domain: "test.org".to_string(),
+        flags: 0,
+        dsn_info: None,
+    };
+
+    // Exhaust the rate limiter
+    let mut session = Session::test(build_core());
+    session.data.mail_from = sender.clone().into();
+    assert!(session.is_allowed().await, "Rate limiter too strict.");
+    assert!(session.is_allowed().await, "Rate limiter too strict.");
+    assert!(!session.is_allowed().await, "Rate limiter failed.");
+
+    // Restore the snapshot into a new instance
+    let snapshot = session.core.serialize_throttle();
+    let core = build_core();
+    assert_eq!(core.restore_throttle(&snapshot), 1);
+    let mut session = Session::test(core);
+    session.data.mail_from = sender.into();
+    assert!(
+        !session.is_allowed().await,
+        "Rate limiter state was not restored."
+    );
+}
+
+#[tokio::test]
+async fn log_limiter() {
+    // Without a rate every event is logged
+    let limiter = LogLimiter::default();
+    for _ in 0..10 {
+        assert_eq!(limiter.is_allowed("rcpt-relay"), Some(0));
+    }
+
+    // Events are sampled per type and suppressed ones are counted
+    let limiter = LogLimiter::new(Some(Rate {
+        requests: 2,
+        period: Duration::from_millis(500),
+    }));
+    assert_eq!(limiter.is_allowed("rcpt-relay"), Some(0));
+    assert_eq!(limiter.is_allowed("rcpt-relay"), Some(0));
+    assert_eq!(limiter.is_allowed("rcpt-relay"), None);
+    assert_eq!(limiter.is_allowed("rcpt-relay"), None);
+    assert_eq!(limiter.is_allowed("invalid-command"), Some(0));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(limiter.is_allowed("rcpt-relay"), Some(2));
+    assert_eq!(limiter.is_allowed("rcpt-relay"), None);
+}
+
+#[tokio::test]
+async fn throttle_abandoned() {
+    let mut core = Core::test();
+    let mut config = &mut core.session.config.connect.abandoned;
+    config.threshold = IfBlock::new(Some(2));
+    config.delay = IfBlock::new(Duration::from_millis(100));
+    config.block = IfBlock::new(Some(4));
+
+    let mut session = Session::test(core);
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+
+    // Abandoned sessions below the threshold are not penalized
+    assert!(session.init_conn(b"220 mx.example.org\r\n").await);
+    session.response().assert_code("220");
+    session.track_abandoned().await;
+    assert!(session.is_abandon_allowed().await);
+
+    // Delays escalate once the threshold is reached
+    session.track_abandoned().await;
+    let time = Instant::now();
+    assert!(session.is_abandon_allowed().await);
+    assert!(time.elapsed() >= Duration::from_millis(100));
+    session.track_abandoned().await;
+    let time = Instant::now();
+    assert!(session.is_abandon_allowed().await);
+    assert!(time.elapsed() >= Duration::from_millis(200));
+
+    // Clients are blocked after too many abandoned sessions
+    session.track_abandoned().await;
+    assert!(!session.is_abandon_allowed().await);
+    assert!(!session.init_conn(b"220 mx.example.org\r\n").await);
+    session.response().assert_code("421 4.7.0");
+
+    // Other addresses are not affected
+    session.data.remote_ip = "10.0.0.2".parse().unwrap();
+    assert!(session.is_abandon_allowed().await);
+
+    // Sessions closed by the server are not abandoned
+    let (_tx, rx) = watch::channel(false);
+    session.params.timeout = Duration::from_millis(50);
+    assert!(matches!(
+        session.handle_conn_(rx).await,
+        SessionEnd::ClosedByServer
+    ));
+    session.response().assert_code("221 2.0.0");
+    assert!(session
+        .core
+        .session
+        .abandoned
+        .get(&session.data.remote_ip)
+        .is_none());
+}
+
+#[tokio::test]
+async fn throttle_tls_handshakes() {
+    let mut core = Core::test();
+    core.tls_handshakes = HandshakeLimiter::new(1, Duration::from_millis(100));
+    let core = Arc::new(core);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // Connections are dropped while all handshake slots are taken
+    let permit = core.tls_handshakes.acquire().await.unwrap();
+    assert!(core.tls_handshakes.acquire().await.is_none());
+    let _client = TcpStream::connect(addr).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let time = Instant::now();
+    assert!(tcp_session(core.clone(), stream)
+        .into_tls((*dummy_tls_acceptor()).clone())
+        .await
+        .is_err());
+    assert!(time.elapsed() >= Duration::from_millis(100));
+
+    // Once a slot is released the handshake proceeds
+    drop(permit);
+    let client = TcpStream::connect(addr).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let (session, client) = tokio::join!(
+        tcp_session(core.clone(), stream).into_tls((*dummy_tls_acceptor()).clone()),
+        core.queue
+            .connectors
+            .dummy_verify
+            .connect(ServerName::try_from("mx.example.org").unwrap(), client)
+    );
+    assert!(session.is_ok());
+    assert!(client.is_ok());
+
+    // And the slot is returned after the handshake completes
+    assert!(core.tls_handshakes.acquire().await.is_some());
+}
+
+fn tcp_session(core: Arc<Core>, stream: TcpStream) -> Session<TcpStream> {
+    Session {
+        state: State::default(),
+        instance: Arc::new(ServerInstance::test()),
+        core,
+        span: tracing::info_span!("test"),
+        stream,
+        data: SessionData::new("127.0.0.1".parse().unwrap(), "10.0.0.1".parse().unwrap()),
+        params: SessionParameters::default(),
+        in_flight: vec![],
+        connection: None,
+    }
+}
@@ -0,0 +1,347 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use mail_send::Credentials;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    config::{Config, ConfigContext},
+    lookup::{
+        ldap::{ber, ber_int, encode_filter, escape_filter_value, parse_message, BerReader},
+        Item, LookupResult,
+    },
+};
+
+use super::dummy_tls_acceptor;
+
+const REMOTE: &str = "
+[remote.ldap]
+address = 127.0.0.1
+port = 9997
+concurrency = 5
+protocol = 'ldap'
+
+[remote.ldap.auth]
+username = 'cn=admin,dc=example,dc=org'
+secret = 'admin'
+
+[remote.ldap.ldap]
+base-dn = 'dc=example,dc=org'
+filter = {account = '(&(objectClass=person)(mail=%s))', auth = '(uid=%s)'}
+
+[remote.ldap.limits]
+errors = 3
+requests = 5
+
+[remote.ldap.cache]
+entries = 500
+ttl = {positive = '10s', negative = '5s'}
+
+[remote.ldap.tls]
+implicit = true
+allow-invalid-certs = true
+";
+
+const USERS: &[(&str, &str, &str, &str)] = &[
+    (
+        "uid=john,ou=people,dc=example,dc=org",
+        "john",
+        "john@example.org",
+        "12345",
+    ),
+    (
+        "uid=jdoe,ou=people,dc=example,dc=org",
+        "jdoe",
+        "jdoe@example.org",
+        "12345",
+    ),
+    (
+        "uid=jdoe,ou=staff,dc=example,dc=org",
+        "jdoe",
+        "jdoe@example.net",
+        "12345",
+    ),
+];
+
+#[tokio::test]
+async fn lookup_ldap() {
+    // Spawn mock LDAP server
+    let shutdown = spawn_mock_ldap_server();
+
+    // Spawn lookup client
+    let mut ctx = ConfigContext::default();
+    let config = Config::parse(REMOTE).unwrap();
+    config.parse_remote_hosts(&mut ctx).unwrap();
+    let lookup = ctx.hosts.remove("ldap").unwrap().spawn(&config);
+
+    for (item, expected) in [
+        (
+            Item::IsAccount("john@example.org".to_string()),
+            LookupResult::True,
+        ),
+        (
+            Item::IsAccount("jane@example.org".to_string()),
+            LookupResult::False,
+        ),
+        (Item::IsAccount("*".to_string()), LookupResult::False),
+        (
+            Item::Verify("john@example.org".to_string()),
+            LookupResult::Values(vec!["john@example.org".to_string()]),
+        ),
+        (
+            Item::Authenticate(Credentials::Plain {
+                username: "john".to_string(),
+                secret: "12345".to_string(),
+            }),
+            LookupResult::True,
+        ),
+        (
+            Item::Authenticate(Credentials::Plain {
+                username: "john".to_string(),
+                secret: "54321".to_string(),
+            }),
+            LookupResult::False,
+        ),
+        (
+            Item::Authenticate(Credentials::Plain {
+                username: "john".to_string(),
+                secret: "".to_string(),
+            }),
+            LookupResult::False,
+        ),
+        (
+            Item::Authenticate(Credentials::Plain {
+                username: "jdoe".to_string(),
+                secret: "12345".to_string(),
+            }),
+            LookupResult::False,
+        ),
+    ] {
+        assert_eq!(
+            lookup.lookup(item.clone()).await,
+            Some(expected),
+            "Failed for {item:?}"
+        );
+    }
+
+    // Shutdown
+    shutdown.send(false).ok();
+    TcpStream::connect("127.0.0.1:9997").await.unwrap_err();
+
+    // Cached results are still available, connection failures are temporary
+    assert_eq!(
+        lookup
+            .lookup(Item::IsAccount("john@example.org".to_string()))
+            .await,
+        Some(LookupResult::True)
+    );
+    assert_eq!(
+        lookup
+            .lookup(Item::IsAccount("bill@example.org".to_string()))
+            .await,
+        None
+    );
+}
+
+#[test]
+fn ldap_filter() {
+    assert_eq!(
+        escape_filter_value("a*(b)\\c"),
+        "a\\2a\\28b\\29\\5cc".to_string()
+    );
+
+    for (filter, expected) in [
+        (
+            "(mail=john@example.org)",
+            vec![
+                0xa3, 0x18, 0x04, 0x04, b'm', b'a', b'i', b'l', 0x04, 0x10, b'j', b'o', b'h', b'n',
+                b'@', b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'o', b'r', b'g',
+            ],
+        ),
+        (
+            "cn=a\\2ab",
+            vec![
+                0xa3, 0x09, 0x04, 0x02, b'c', b'n', 0x04, 0x03, b'a', b'*', b'b',
+            ],
+        ),
+        ("(cn=*)", vec![0x87, 0x02, b'c', b'n']),
+        (
+            "(cn=a*b*)",
+            vec![
+                0xa4, 0x0c, 0x04, 0x02, b'c', b'n', 0x30, 0x06, 0x80, 0x01, b'a', 0x81, 0x01, b'b',
+            ],
+        ),
+        (
+            "(&(uid>=a)(!(cn~=b)))",
+            vec![
+                0xa0, 0x15, 0xa5, 0x08, 0x04, 0x03, b'u', b'i', b'd', 0x04, 0x01, b'a', 0xa2, 0x09,
+                0xa8, 0x07, 0x04, 0x02, b'c', b'n', 0x04, 0x01, b'b',
+            ],
+        ),
+    ] {
+        assert_eq!(encode_filter(filter), Some(expected), "{filter}");
+    }
+
+    for invalid in [
+        "(mail=john",
+        "(mail=(john))",
+        "((mail=john))",
+        "(mail=john)(uid=john)",
+        "(=john)",
+        "(mail~=jo*hn)",
+        "(mail=jo\\zzhn)",
+        "(&(mail=john)",
+    ] {
+        assert_eq!(encode_filter(invalid), None, "{invalid}");
+    }
+}
+
+pub fn spawn_mock_ldap_server() -> watch::Sender<bool> {
+    let (tx, mut rx) = watch::channel(true);
+
+    tokio::spawn(async move {
+        let listener = TcpListener::bind("127.0.0.1:9997")
+            .await
+            .unwrap_or_else(|e| {
+                panic!("Failed to bind mock LDAP server to 127.0.0.1:9997: {e}");
+            });
+        let acceptor = dummy_tls_acceptor();
+        loop {
+            tokio::select! {
+                stream = listener.accept() => {
+                    match stream {
+                        Ok((stream, _)) => {
+                            tokio::spawn(accept_ldap(stream, acceptor.clone()));
+                        }
+                        Err(err) => {
+                            panic!("Something went wrong: {err}" );
+                        }
+                    }
+                },
+                _ = rx.changed() => {
+                    break;
+                }
+            };
+        }
+    });
+
+    tx
+}
+
+async fn accept_ldap(stream: TcpStream, acceptor: Arc<TlsAcceptor>) {
+    let mut stream = acceptor.accept(stream).await.unwrap();
+    let mut buf = Vec::new();
+    let mut buf_u8 = vec![0u8; 4096];
+
+    loop {
+        match stream.read(&mut buf_u8).await {
+            Ok(br) if br > 0 => {
+                buf.extend_from_slice(&buf_u8[..br]);
+            }
+            _ => break,
+        }
+        let (id, tag, request) = if let Some(message) = parse_message(&buf) {
+            message
+        } else {
+            continue;
+        };
+        let mut request = BerReader::new(request);
+        let mut response = Vec::new();
+
+        match tag {
+            0x60 => {
+                request.expect(0x02).unwrap();
+                let dn = std::str::from_utf8(request.expect(0x04).unwrap()).unwrap();
+                let secret = std::str::from_utf8(request.expect(0x80).unwrap()).unwrap();
+                let success = (dn == "cn=admin,dc=example,dc=org" && secret == "admin")
+                    || USERS
+                        .iter()
+                        .any(|(user_dn, _, _, pass)| *user_dn == dn && *pass == secret);
+                response.extend(ldap_message(
+                    id,
+                    0x61,
+                    &ldap_result(if success { 0 } else { 49 }),
+                ));
+            }
+            0x63 => {
+                assert_eq!(request.expect(0x04).unwrap(), b"dc=example,dc=org");
+                for _ in 0..5 {
+                    request.next().unwrap();
+                }
+                let (filter_tag, filter) = request.next().unwrap();
+                let mut raw_filter = Vec::new();
+                ber(&mut raw_filter, filter_tag, filter);
+
+                for (dn, uid, mail, _) in USERS {
+                    if encode_filter(&format!("(&(objectClass=person)(mail={mail}))")).unwrap()
+                        == raw_filter
+                        || encode_filter(&format!("(uid={uid})")).unwrap() == raw_filter
+                    {
+                        let mut values = Vec::new();
+                        ber(&mut values, 0x04, mail.as_bytes());
+                        let mut attribute = Vec::new();
+                        ber(&mut attribute, 0x04, b"mail");
+                        ber(&mut attribute, 0x31, &values);
+                        let mut attributes = Vec::new();
+                        ber(&mut attributes, 0x30, &attribute);
+                        let mut entry = Vec::new();
+                        ber(&mut entry, 0x04, dn.as_bytes());
+                        ber(&mut entry, 0x30, &attributes);
+                        response.extend(ldap_message(id, 0x64, &entry));
+                    }
+                }
+                response.extend(ldap_message(id, 0x65, &ldap_result(0)));
+            }
+            0x42 => break,
+            _ => panic!("Unexpected LDAP operation {tag:#x}"),
+        }
+        buf.clear();
+
+        stream.write_all(&response).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+}
+
+fn ldap_message(id: i64, tag: u8, operation: &[u8]) -> Vec<u8> {
+    let mut message = Vec::new();
+    ber_int(&mut message, 0x02, id);
+    ber(&mut message, tag, operation);
+    let mut packet = Vec::new();
+    ber(&mut packet, 0x30, &message);
+    packet
+}
+
+fn ldap_result(code: i64) -> Vec<u8> {
+    let mut result = Vec::new();
+    ber_int(&mut result, 0x0a, code);
+    ber(&mut result, 0x04, b"");
+    ber(&mut result, 0x04, b"");
+    result
+}
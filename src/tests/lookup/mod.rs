@@ -21,16 +21,17 @@
  * for more details.
 */
 
-use std::{io::BufReader, sync::Arc};
+use std::{io::BufReader, sync::Arc, time::Duration};
 
 use mail_send::Credentials;
 use rustls::{Certificate, PrivateKey, ServerConfig};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use tokio_rustls::TlsAcceptor;
 
-use crate::lookup::{Item, LookupResult};
+use crate::lookup::{cache::LookupCache, Item, LookupResult};
 
 pub mod imap;
+pub mod ldap;
 pub mod smtp;
 pub mod sql;
 
@@ -119,6 +120,10 @@ XzVV5pwOxkIDBWDIqMUfwJDChBKfpw==
 ";
 
 pub fn dummy_tls_acceptor() -> Arc<TlsAcceptor> {
+    Arc::new(TlsAcceptor::from(Arc::new(dummy_tls_server_config())))
+}
+
+pub fn dummy_tls_server_config() -> ServerConfig {
     // Init server config builder with safe defaults
     let config = ServerConfig::builder()
         .with_safe_defaults()
@@ -145,9 +150,7 @@ pub fn dummy_tls_acceptor() -> Arc<TlsAcceptor> {
         panic!("Could not locate PKCS 8 private keys.");
     }
 
-    Arc::new(TlsAcceptor::from(Arc::new(
-        config.with_single_cert(cert_chain, keys.remove(0)).unwrap(),
-    )))
+    config.with_single_cert(cert_chain, keys.remove(0)).unwrap()
 }
 
 impl Item {
@@ -192,3 +195,24 @@ impl LookupResult {
         }
     }
 }
+
+#[test]
+fn lookup_cache() {
+    let mut cache = LookupCache::new(10, Duration::from_millis(400), Duration::from_millis(100));
+    cache.insert_pos("john@example.org".to_string());
+    cache.insert_neg("unknown@example.org".to_string());
+
+    assert_eq!(cache.get("john@example.org"), Some(true));
+    assert_eq!(cache.get("unknown@example.org"), Some(false));
+    assert_eq!(cache.get("jane@example.org"), None);
+    assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+    // Negative entries expire independently of positive ones
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(cache.get("unknown@example.org"), None);
+    assert_eq!(cache.get("john@example.org"), Some(true));
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(cache.get("john@example.org"), None);
+    assert_eq!((cache.hits(), cache.misses()), (3, 3));
+}
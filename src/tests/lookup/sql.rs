@@ -28,7 +28,7 @@ use smtp_proto::{AUTH_LOGIN, AUTH_PLAIN};
 use crate::{
     config::{Config, ConfigContext, IfBlock},
     core::{Core, Session},
-    lookup::SqlDatabase,
+    lookup::{hash::verify_secret, SqlDatabase},
     tests::{make_temp_dir, session::VerifyResponse, ParseTestConfig},
 };
 
@@ -85,6 +85,8 @@ async fn lookup_sql() {
             "INSERT INTO users (email, secret) VALUES ('jane@foobar.org', 's3cr3tp4ss');",
             "INSERT INTO users (email, secret) VALUES ('john@foobar.org', 'mypassword');",
             "INSERT INTO users (email, secret) VALUES ('bill@foobar.org', '123456');",
+            "INSERT INTO users (email, secret) VALUES ('mike@foobar.org', '{SSHA256}B/lzSONrcvmzg6RSS+YQ9oIYps7pXYmWOsNt5yFngBVzYWx0c2FsdA==');",
+            "INSERT INTO users (email, secret) VALUES ('lisa@foobar.org', '$2b$05$abcdefghijklmnopqrstuuOQiyCxlgf/oeuTqixKmWdcYUh4Hjl0a');",
             "INSERT INTO mailing_lists (id, member) VALUES ('sales@foobar.org', 'jane@foobar.org');",
             "INSERT INTO mailing_lists (id, member) VALUES ('sales@foobar.org', 'john@foobar.org');",
             "INSERT INTO mailing_lists (id, member) VALUES ('sales@foobar.org', 'bill@foobar.org');",
@@ -197,4 +199,84 @@ async fn lookup_sql() {
             "235 2.7.0",
         )
         .await;
+
+    // Test AUTH with hashed secrets
+    for (fail, success) in [
+        (
+            "AUTH PLAIN AG1pa2VAZm9vYmFyLm9yZwA2NTQzMjE=",
+            "AUTH PLAIN AG1pa2VAZm9vYmFyLm9yZwAxMjM0NTY=",
+        ),
+        (
+            "AUTH PLAIN AGxpc2FAZm9vYmFyLm9yZwB3cm9uZw==",
+            "AUTH PLAIN AGxpc2FAZm9vYmFyLm9yZwBzZWNyZXQ=",
+        ),
+    ] {
+        let mut session = Session::test(session.core.clone());
+        session.data.remote_ip = "10.0.0.1".parse().unwrap();
+        session.eval_session_params().await;
+        session.stream.tls = true;
+        session.ehlo("mx1.foobar.org").await;
+        session.cmd(fail, "535 5.7.8").await;
+        session.cmd(success, "235 2.7.0").await;
+    }
+}
+
+#[test]
+fn sql_secret_hashes() {
+    for (stored, secret, expected) in [
+        ("s3cr3tp4ss", "s3cr3tp4ss", true),
+        ("s3cr3tp4ss", "S3cr3tp4ss", false),
+        ("{PLAIN}secret", "secret", true),
+        ("{SHA}kd/Z3bQZiv/FwZTNjObTOP3kcOI=", "mypassword", true),
+        ("{sha}kd/Z3bQZiv/FwZTNjObTOP3kcOI=", "mypassw0rd", false),
+        ("{SHA256}K7gNU3sdo+OL0wNhqoVWhr3g6s1xYv72ol/pe/Unols=", "secret", true),
+        (
+            "{SSHA256}B/lzSONrcvmzg6RSS+YQ9oIYps7pXYmWOsNt5yFngBVzYWx0c2FsdA==",
+            "123456",
+            true,
+        ),
+        (
+            "{SSHA512}aCu7JRc+kLsuEmFs1zTY+AiP7DSGnjjG+dH28Dp+E5usqoAixeTPihKqZmkWal4mUfp63tqvCAkFV1LKTDFH6XNhbHRzYWx0",
+            "secret",
+            true,
+        ),
+        ("{SMD5}VAfQ6nCkaw9o3u+x706wnXNhbHRzYWx0", "secret", true),
+        ("{SMD5}VAfQ6nCkaw9o3u+x706wnXNhbHRzYWx0", "Secret", false),
+        ("{SHA256}K7gNU3sdo+OL0wNhqoVWhr3g6s1x", "secret", false),
+        ("{CRYPT}abcdef", "abcdef", false),
+        ("$2b$12$abcdefghijklmnopqrstuv", "$2b$12$abcdefghijklmnopqrstuv", false),
+        (
+            "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW",
+            "U*U",
+            true,
+        ),
+        (
+            "$2b$05$abcdefghijklmnopqrstuuOQiyCxlgf/oeuTqixKmWdcYUh4Hjl0a",
+            "secret",
+            true,
+        ),
+        (
+            "$2b$05$abcdefghijklmnopqrstuuOQiyCxlgf/oeuTqixKmWdcYUh4Hjl0a",
+            "Secret",
+            false,
+        ),
+        (
+            "{CRYPT}$2b$05$abcdefghijklmnopqrstuuOQiyCxlgf/oeuTqixKmWdcYUh4Hjl0a",
+            "secret",
+            true,
+        ),
+        (
+            "$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5knV8wiOQSpT0Y77vwPZN.Pq.H91p5hVO1",
+            "secret",
+            true,
+        ),
+        (
+            "$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5knV8wiOQSpT0Y77vwPZN.Pq.H91p5hVO1",
+            "secre7",
+            false,
+        ),
+        ("$1$saltsalt$qjXMvbEw8oaL.CzflDugX/", "secret", false),
+    ] {
+        assert_eq!(verify_secret(stored, secret), expected, "{stored}");
+    }
 }
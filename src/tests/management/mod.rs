@@ -24,6 +24,7 @@
 use std::time::Duration;
 
 use hyper::header::AUTHORIZATION;
+use reqwest::Method;
 use serde::{de::DeserializeOwned, Deserialize};
 
 pub mod queue;
@@ -43,13 +44,30 @@ pub async fn send_manage_request<T: DeserializeOwned>(query: &str) -> Result<Res
 }
 
 pub async fn send_manage_request_raw(query: &str) -> Result<String, String> {
+    send_http_request(Method::GET, query, String::new()).await
+}
+
+pub async fn send_manage_post_request<T: DeserializeOwned>(
+    query: &str,
+    body: &str,
+) -> Result<Response<T>, String> {
+    send_http_request(Method::POST, query, body.to_string())
+        .await
+        .map(|result| {
+            serde_json::from_str::<Response<T>>(&result)
+                .unwrap_or_else(|err| panic!("{err}: {result}"))
+        })
+}
+
+async fn send_http_request(method: Method, query: &str, body: String) -> Result<String, String> {
     reqwest::Client::builder()
         .timeout(Duration::from_millis(500))
         .danger_accept_invalid_certs(true)
         .build()
         .unwrap()
-        .get(format!("https://127.0.0.1:9980{query}"))
+        .request(method, format!("https://127.0.0.1:9980{query}"))
         .header(AUTHORIZATION, "Basic YWRtaW46c2VjcmV0")
+        .body(body)
         .send()
         .await
         .map_err(|err| err.to_string())?
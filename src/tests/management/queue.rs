@@ -22,7 +22,7 @@
 */
 
 use std::{
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
 
@@ -33,13 +33,19 @@ use mail_parser::DateTime;
 
 use crate::{
     config::{IfBlock, ServerProtocol},
-    core::{management::Message, Core, Session},
+    core::{
+        management::{Message, QueueStats, RetryStats},
+        Core, Session,
+    },
     lookup::Lookup,
     queue::{
         manager::{Queue, SpawnQueue},
         QueueId, Status,
     },
-    tests::{management::send_manage_request, outbound::start_test_server},
+    tests::{
+        management::{send_manage_post_request, send_manage_request},
+        outbound::start_test_server,
+    },
 };
 
 #[tokio::test]
@@ -244,6 +250,30 @@ async fn manage_queue() {
     }
     assert_eq!(id_map.len(), 6);
 
+    // Queue statistics, all messages are due for retry within the hour
+    let stats = send_manage_request::<QueueStats>("/queue/stats")
+        .await
+        .unwrap()
+        .unwrap_data();
+    assert_eq!(
+        stats,
+        QueueStats {
+            total: 6,
+            on_hold: 0,
+            retry: RetryStats {
+                now: 0,
+                hour: 6,
+                day: 0,
+                later: 0,
+            },
+            size: get_messages(&id_map.values().copied().collect::<Vec<_>>())
+                .await
+                .into_iter()
+                .map(|message| message.unwrap().size)
+                .sum(),
+        }
+    );
+
     // Test list search
     for (query, expected_ids) in [
         ("/queue/list?from=bill1@foobar.net".to_string(), vec!["a"]),
@@ -338,6 +368,36 @@ async fn manage_queue() {
         }
     }
 
+    // Bulk retry messages by error state, only 'f' has a temporary failure
+    for (filter, expected) in [
+        ("ConcurrencyLimited", vec![]),
+        ("unexpectedresponse", vec![true]),
+        ("mx1.foobar.org", vec![true]),
+    ] {
+        assert_eq!(
+            send_manage_request::<Vec<bool>>(&format!("/queue/retry?error={filter}"))
+                .await
+                .unwrap()
+                .unwrap_data(),
+            expected,
+            "failed for {filter}"
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(
+        get_messages(&[*id_map.get("f").unwrap()])
+            .await
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap()
+            .domains
+            .first()
+            .unwrap()
+            .retry_num,
+        4
+    );
+
     // Cancel deliveries
     for (id, filter) in [
         ("a", "example2.org"),
@@ -419,6 +479,87 @@ async fn manage_queue() {
         }
     }
 
+    // Edit recipients
+    let id_c = *id_map.get("c").unwrap();
+    for (query, body, expected) in [
+        (
+            format!("/queue/edit?id={id_c}"),
+            r#"{"remove": ["rcpt6@example2.com"]}"#,
+            "is not pending delivery",
+        ),
+        (
+            format!("/queue/edit?id={id_c}"),
+            r#"{"rewrite": [{"from": "rcpt7@example2.com", "to": "rcpt9@example4.com"}]}"#,
+            "already exists",
+        ),
+        (
+            format!("/queue/edit?id={}", id_map.get("b").unwrap()),
+            r#"{"remove": ["rcpt3@example1.net"]}"#,
+            "not found",
+        ),
+    ] {
+        let (_, details) = send_manage_post_request::<Message>(&query, body)
+            .await
+            .unwrap()
+            .unwrap_error();
+        assert!(details.contains(expected), "{details}");
+    }
+    let message = send_manage_post_request::<Message>(
+        &format!("/queue/edit?id={id_c}"),
+        r#"{"remove": ["rcpt5@example1.com"],
+            "rewrite": [{"from": "RCPT8@example3.com", "to": "rcpt10@example5.com"}]}"#,
+    )
+    .await
+    .unwrap()
+    .unwrap_data();
+    assert_eq!(
+        message
+            .domains
+            .iter()
+            .map(|domain| domain.name.as_str())
+            .collect::<Vec<_>>(),
+        vec!["example2.com", "example4.com", "example5.com"]
+    );
+    let rcpt = &message.domains.last().unwrap().recipients[0];
+    assert_eq!(rcpt.address, "rcpt10@example5.com");
+    assert_eq!(rcpt.orcpt.as_deref(), Some("rcpt8@example3.com"));
+    assert_eq!(
+        get_messages(&[id_c])
+            .await
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap()
+            .domains
+            .len(),
+        3
+    );
+
+    // Health checks are available without authentication
+    for (path, draining, expected_status) in [
+        ("/health", false, StatusCode::OK),
+        ("/ready", false, StatusCode::OK),
+        ("/health", true, StatusCode::OK),
+        ("/ready", true, StatusCode::SERVICE_UNAVAILABLE),
+    ] {
+        core.draining.store(draining, Ordering::Relaxed);
+        assert_eq!(
+            reqwest::Client::builder()
+                .timeout(Duration::from_millis(500))
+                .danger_accept_invalid_certs(true)
+                .build()
+                .unwrap()
+                .get(format!("https://127.0.0.1:9980{path}"))
+                .send()
+                .await
+                .unwrap()
+                .status(),
+            expected_status,
+            "{path} (draining: {draining})"
+        );
+    }
+    core.draining.store(false, Ordering::Relaxed);
+
     // Test authentication error
     assert_eq!(
         reqwest::Client::builder()
@@ -27,7 +27,10 @@ use ahash::{AHashMap, AHashSet};
 use dashmap::DashMap;
 use mail_auth::{
     common::lru::{DnsCache, LruCache},
-    trust_dns_resolver::config::{ResolverConfig, ResolverOpts},
+    trust_dns_resolver::{
+        config::{ResolverConfig, ResolverOpts},
+        AsyncResolver,
+    },
     IpLookupStrategy, Resolver,
 };
 use mail_send::smtp::tls::build_tls_connector;
@@ -37,19 +40,20 @@ use tokio::sync::mpsc;
 
 use crate::{
     config::{
-        utils::ParseValues, AggregateReport, ArcAuthConfig, Auth, Config, ConfigContext, Connect,
-        Data, DkimAuthConfig, DmarcAuthConfig, DnsBlConfig, Dsn, Ehlo, EnvelopeKey, Extensions,
-        IfBlock, IpRevAuthConfig, Mail, MailAuthConfig, QueueConfig, QueueOutboundSourceIp,
-        QueueOutboundTimeout, QueueOutboundTls, QueueQuotas, QueueThrottle, Rcpt, Report,
-        ReportAnalysis, ReportConfig, SessionConfig, SessionThrottle, SpfAuthConfig, Throttle,
-        VerifyStrategy,
+        utils::ParseValues, Abandoned, AggregateReport, ArcAuthConfig, Auth, Config, ConfigContext,
+        Connect, ContentAction, Data, DkimAuthConfig, DmarcAuthConfig, DnsBlConfig, Dsn, DsnFormat,
+        Ehlo, EnvelopeKey, Extensions, IfBlock, IpRevAuthConfig, Mail, MailAuthConfig, QueueConfig,
+        QueueOutboundPool, QueueOutboundSourceIp, QueueOutboundTimeout, QueueOutboundTls,
+        QueueQuotas, QueueThrottle, Rcpt, Report, ReportAnalysis, ReportConfig, SessionConfig,
+        SessionThrottle, SpfAuthConfig, Throttle, TravelAction, VerifyDomain, VerifyStrategy,
     },
     core::{
-        throttle::{ConcurrencyLimiter, ThrottleKeyHasherBuilder},
+        throttle::{ConcurrencyLimiter, HandshakeLimiter, LogLimiter, ThrottleKeyHasherBuilder},
         Core, QueueCore, ReportCore, Resolvers, SessionCore, SieveConfig, SieveCore, TlsConnectors,
     },
     lookup::Lookup,
     outbound::dane::DnssecResolver,
+    queue::spool::Spool,
 };
 
 pub mod inbound;
@@ -59,6 +63,7 @@ pub mod outbound;
 pub mod queue;
 pub mod reporting;
 pub mod session;
+pub mod syslog;
 
 pub trait ParseTestConfig {
     fn parse_if<T: Default + ParseValues>(&self, ctx: &ConfigContext) -> IfBlock<T>;
@@ -135,6 +140,7 @@ impl Core {
                 .num_threads(num_cpus::get())
                 .build()
                 .unwrap(),
+            tls_handshakes: HandshakeLimiter::new(100, Duration::from_secs(1)),
             session: SessionCore::test(),
             queue: QueueCore::test(),
             resolvers: Resolvers {
@@ -144,14 +150,22 @@ impl Core {
                     ResolverOpts::default(),
                 )
                 .unwrap(),
+                uncached: AsyncResolver::tokio(
+                    ResolverConfig::cloudflare(),
+                    ResolverOpts::default(),
+                )
+                .unwrap(),
                 cache: crate::core::DnsCache {
                     tlsa: LruCache::with_capacity(100),
                     mta_sts: LruCache::with_capacity(100),
+                    mx_negative: LruCache::with_capacity(100),
+                    mx_negative_ttl: Duration::from_secs(300),
                 },
             },
             mail_auth: MailAuthConfig::test(),
             report: ReportCore::test(),
             sieve: SieveCore::test(),
+            draining: false.into(),
         }
     }
 }
@@ -166,6 +180,11 @@ impl SessionCore {
                 ThrottleKeyHasherBuilder::default(),
                 16,
             ),
+            auth_origins: DashMap::new(),
+            auth_sessions: DashMap::new(),
+            abandoned: DashMap::new(),
+            catch_all: DashMap::new(),
+            log_limiter: LogLimiter::default(),
         }
     }
 }
@@ -176,6 +195,7 @@ impl SessionConfig {
             timeout: IfBlock::new(Duration::from_secs(10)),
             duration: IfBlock::new(Duration::from_secs(10)),
             transfer_limit: IfBlock::new(1024 * 1024),
+            command_limit: IfBlock::new(0),
             throttle: SessionThrottle {
                 connect: vec![],
                 mail_from: vec![],
@@ -183,6 +203,12 @@ impl SessionConfig {
             },
             connect: Connect {
                 script: IfBlock::new(None),
+                abandoned: Abandoned {
+                    threshold: IfBlock::default(),
+                    window: IfBlock::new(Duration::from_secs(3600)),
+                    delay: IfBlock::new(Duration::from_secs(5)),
+                    block: IfBlock::default(),
+                },
             },
             ehlo: Ehlo {
                 script: IfBlock::new(None),
@@ -192,11 +218,16 @@ impl SessionConfig {
             extensions: Extensions {
                 pipelining: IfBlock::new(true),
                 chunking: IfBlock::new(true),
+                require_chunking: IfBlock::new(false),
                 requiretls: IfBlock::new(true),
                 no_soliciting: IfBlock::new("domain.org".to_string().into()),
                 future_release: IfBlock::new(None),
                 deliver_by: IfBlock::new(None),
                 mt_priority: IfBlock::new(None),
+                burl: IfBlock::default(),
+                rrvs: IfBlock::default(),
+                atrn: IfBlock::default(),
+                custom: IfBlock::default(),
                 dsn: IfBlock::new(true),
             },
             auth: Auth {
@@ -205,9 +236,19 @@ impl SessionConfig {
                 require: IfBlock::new(false),
                 errors_max: IfBlock::new(10),
                 errors_wait: IfBlock::new(Duration::from_secs(1)),
+                travel_action: IfBlock::new(TravelAction::Disable),
+                travel_window: IfBlock::new(Duration::from_secs(3600)),
+                max_sessions: IfBlock::default(),
+                oauth_check_expiry: IfBlock::new(false),
+                oauth_max_age: IfBlock::default(),
+                scram_secret: b"secret".to_vec(),
             },
             mail: Mail {
                 script: IfBlock::new(None),
+                verify_domain: IfBlock::new(VerifyDomain::Disable),
+                verify_domain_timeout: IfBlock::new(Duration::from_secs(10)),
+                address_strictness: IfBlock::default(),
+                require_tls: IfBlock::default(),
             },
             rcpt: Rcpt {
                 script: IfBlock::new(None),
@@ -216,23 +257,52 @@ impl SessionConfig {
                 lookup_addresses: IfBlock::new(None),
                 lookup_expn: IfBlock::new(None),
                 lookup_vrfy: IfBlock::new(None),
+                lookup_lists: IfBlock::new(None),
+                lookup_created: IfBlock::new(None),
+                lookup_fail_action: IfBlock::default(),
+                domain_aliases: AHashMap::new(),
+                catch_all_detect: IfBlock::new(false),
+                catch_all_action: IfBlock::default(),
+                catch_all_ttl: Duration::from_secs(86400),
                 errors_max: IfBlock::new(3),
                 errors_wait: IfBlock::new(Duration::from_secs(1)),
+                min_delay: IfBlock::default(),
+                min_delay_wait: IfBlock::new(Duration::from_secs(1)),
                 max_recipients: IfBlock::new(3),
+                lists_max_members: IfBlock::new(10000),
+                lists_max_depth: IfBlock::new(3),
             },
             data: Data {
                 script: IfBlock::new(None),
                 max_messages: IfBlock::new(10),
                 max_message_size: IfBlock::new(1024 * 1024),
                 max_received_headers: IfBlock::new(10),
+                max_urls: IfBlock::default(),
+                urls_action: IfBlock::new(ContentAction::Reject),
+                urls_max_scan_size: IfBlock::new(1024 * 1024),
+                verify_encoding: IfBlock::new(false),
+                encoding_action: IfBlock::new(ContentAction::Reject),
+                verify_line_length: IfBlock::new(false),
+                max_line_length: IfBlock::new(1000),
+                line_length_action: IfBlock::new(ContentAction::Reject),
+                max_rcpt_ratio: IfBlock::default(),
+                rcpt_ratio_action: IfBlock::new(ContentAction::Tag),
+                protected_names: IfBlock::new(vec![]),
+                display_name_action: IfBlock::new(ContentAction::Reject),
+                rejected: None,
+                metadata_header: IfBlock::default(),
                 add_received: IfBlock::new(true),
+                add_received_cipher: IfBlock::new(true),
                 add_received_spf: IfBlock::new(true),
                 add_return_path: IfBlock::new(true),
                 add_auth_results: IfBlock::new(true),
                 add_message_id: IfBlock::new(true),
                 add_date: IfBlock::new(true),
+                add_geoip: IfBlock::new(false),
+                add_list_unsubscribe: IfBlock::new(None),
                 pipe_commands: vec![],
             },
+            geoip: None,
         }
     }
 }
@@ -241,6 +311,7 @@ impl QueueCore {
     pub fn test() -> Self {
         Self {
             config: QueueConfig::test(),
+            concurrency: ConcurrencyLimiter::new(100),
             throttle: DashMap::with_capacity_and_hasher_and_shard_amount(
                 10,
                 ThrottleKeyHasherBuilder::default(),
@@ -251,6 +322,10 @@ impl QueueCore {
                 ThrottleKeyHasherBuilder::default(),
                 16,
             ),
+            mx_stats: DashMap::new(),
+            no_pipelining: DashMap::new(),
+            source_ips: DashMap::new(),
+            connections: DashMap::new(),
             tx: mpsc::channel(1024).0,
             id_seq: 0.into(),
             connectors: TlsConnectors {
@@ -266,27 +341,57 @@ impl QueueConfig {
         Self {
             path: Default::default(),
             hash: IfBlock::new(10),
+            spool: Spool::default(),
+            encryption: None,
+            compression: None,
+            dedup: None,
             retry: IfBlock::new(vec![Duration::from_secs(10)]),
+            jitter: IfBlock::new(Duration::ZERO),
             notify: IfBlock::new(vec![Duration::from_secs(20)]),
+            over_quota_retry: IfBlock::new(Vec::new()),
+            over_quota_notify_once: IfBlock::new(false),
             expire: IfBlock::new(Duration::from_secs(10)),
+            window: IfBlock::new(None),
+            max_lifetime: None,
+            priority_aging: None,
+            max_resident: None,
+            load_batch: 1024,
             hostname: IfBlock::new("mx.example.org".to_string()),
             next_hop: Default::default(),
+            verify_addresses: IfBlock::new(None),
             max_mx: IfBlock::new(5),
             max_multihomed: IfBlock::new(5),
+            max_batch: IfBlock::new(1),
+            pipelining: IfBlock::new(true),
             source_ip: QueueOutboundSourceIp {
                 ipv4: IfBlock::new(vec![]),
                 ipv6: IfBlock::new(vec![]),
+                max_connections: IfBlock::new(None),
+            },
+            pool: QueueOutboundPool {
+                idle_timeout: IfBlock::new(None),
+                max_messages: IfBlock::new(100),
             },
             ip_strategy: IfBlock::new(IpLookupStrategy::Ipv4thenIpv6),
+            connect_delay: IfBlock::new(Some(Duration::from_millis(250))),
+            bind_interface: IfBlock::default(),
+            mx_selection: IfBlock::default(),
+            refresh_dns: IfBlock::new(false),
             tls: QueueOutboundTls {
                 dane: IfBlock::new(crate::config::RequireOptional::Optional),
+                dane_fallback: IfBlock::new(false),
                 mta_sts: IfBlock::new(crate::config::RequireOptional::Optional),
                 start: IfBlock::new(crate::config::RequireOptional::Optional),
+                min_version: IfBlock::new(crate::config::TlsVersion::Tls12),
+                min_cipher_strength: IfBlock::new(0),
+                certificate: IfBlock::default(),
             },
             dsn: Dsn {
                 name: IfBlock::new("Mail Delivery Subsystem".to_string()),
                 address: IfBlock::new("MAILER-DAEMON@example.org".to_string()),
                 sign: IfBlock::default(),
+                format: IfBlock::new(DsnFormat::Full),
+                domains: AHashMap::new(),
             },
             timeout: QueueOutboundTimeout {
                 connect: IfBlock::new(Duration::from_secs(1)),
@@ -302,6 +407,7 @@ impl QueueConfig {
                 sender: vec![],
                 rcpt: vec![],
                 host: vec![],
+                pacing: AHashMap::new(),
             },
             quota: QueueQuotas {
                 sender: vec![],
@@ -319,6 +425,8 @@ impl MailAuthConfig {
             dkim: DkimAuthConfig {
                 verify: IfBlock::new(VerifyStrategy::Relaxed),
                 sign: IfBlock::default(),
+                require: IfBlock::default(),
+                body_length: IfBlock::default(),
             },
             arc: ArcAuthConfig {
                 verify: IfBlock::new(VerifyStrategy::Relaxed),
@@ -327,6 +435,7 @@ impl MailAuthConfig {
             spf: SpfAuthConfig {
                 verify_ehlo: IfBlock::new(VerifyStrategy::Relaxed),
                 verify_mail_from: IfBlock::new(VerifyStrategy::Relaxed),
+                softfail_no_dkim: IfBlock::default(),
             },
             dmarc: DmarcAuthConfig {
                 verify: IfBlock::new(VerifyStrategy::Relaxed),
@@ -339,6 +448,7 @@ impl MailAuthConfig {
                 ip_lookup: vec![],
                 domain_lookup: vec![],
             },
+            policy: IfBlock::default(),
         }
     }
 }
@@ -358,11 +468,14 @@ impl ReportConfig {
             path: Default::default(),
             hash: IfBlock::new(10),
             submitter: IfBlock::new("example.org".to_string()),
+            local_domains: None,
             analysis: ReportAnalysis {
                 addresses: vec![],
                 forward: true,
                 store: None,
                 report_id: 0.into(),
+                max_size: 25 * 1024 * 1024,
+                concurrency: ConcurrencyLimiter::new(4),
             },
             dkim: Report::test(),
             spf: Report::test(),
@@ -0,0 +1,229 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::outbound::session::connect_staggered;
+
+#[tokio::test]
+async fn connect_staggered_attempts() {
+    let port = 9927;
+    let live_ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let blackhole_ip: IpAddr = "127.0.0.2".parse().unwrap();
+
+    // Accept connections on the live IP
+    let listener = TcpListener::bind(SocketAddr::new(live_ip, port))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+
+    // Black-hole the other IP by filling up the accept queue of a listener
+    // that never accepts, so that further SYNs are silently dropped
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(SocketAddr::new(blackhole_ip, port)).unwrap();
+    let _blackhole = socket.listen(0).unwrap();
+    let mut backlog = Vec::new();
+    for _ in 0..4 {
+        if let Ok(Ok(stream)) = tokio::time::timeout(
+            Duration::from_millis(100),
+            TcpStream::connect(SocketAddr::new(blackhole_ip, port)),
+        )
+        .await
+        {
+            backlog.push(stream);
+        }
+    }
+
+    // Without a delay, the black-holed IP has to time out first
+    let span = tracing::info_span!("connect");
+    let mut remote_ips = vec![blackhole_ip, live_ip];
+    let time = Instant::now();
+    let (remote_ip, _) = connect_staggered(
+        None,
+        None,
+        &mut remote_ips,
+        port,
+        Duration::from_millis(500),
+        None,
+        &span,
+        "mx.foobar.org",
+    )
+    .await
+    .unwrap();
+    assert_eq!(remote_ip, live_ip);
+    assert!(time.elapsed() >= Duration::from_millis(500));
+    assert!(remote_ips.is_empty());
+
+    // Staggered attempts succeed as soon as the live IP answers
+    let mut remote_ips = vec![blackhole_ip, live_ip];
+    let time = Instant::now();
+    let (remote_ip, _) = connect_staggered(
+        None,
+        None,
+        &mut remote_ips,
+        port,
+        Duration::from_secs(5),
+        Some(Duration::from_millis(50)),
+        &span,
+        "mx.foobar.org",
+    )
+    .await
+    .unwrap();
+    assert_eq!(remote_ip, live_ip);
+    assert!(time.elapsed() < Duration::from_secs(1));
+
+    // The first IP wins when it answers before the delay expires
+    let mut remote_ips = vec![live_ip, blackhole_ip];
+    let (remote_ip, _) = connect_staggered(
+        None,
+        None,
+        &mut remote_ips,
+        port,
+        Duration::from_secs(5),
+        Some(Duration::from_millis(500)),
+        &span,
+        "mx.foobar.org",
+    )
+    .await
+    .unwrap();
+    assert_eq!(remote_ip, live_ip);
+    assert_eq!(remote_ips, vec![blackhole_ip]);
+
+    // Errors are returned once every attempt has failed
+    let mut remote_ips = vec![blackhole_ip];
+    assert!(connect_staggered(
+        None,
+        None,
+        &mut remote_ips,
+        port,
+        Duration::from_millis(200),
+        Some(Duration::from_millis(50)),
+        &span,
+        "mx.foobar.org",
+    )
+    .await
+    .is_err());
+    assert!(remote_ips.is_empty());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn connect_bind_interface() {
+    let port = 9928;
+    let live_ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let listener = TcpListener::bind(SocketAddr::new(live_ip, port))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+
+    // Connections egress through the requested interface
+    let span = tracing::info_span!("connect");
+    let mut remote_ips = vec![live_ip];
+    let (remote_ip, _) = connect_staggered(
+        None,
+        Some("lo"),
+        &mut remote_ips,
+        port,
+        Duration::from_secs(1),
+        None,
+        &span,
+        "mx.foobar.org",
+    )
+    .await
+    .unwrap();
+    assert_eq!(remote_ip, live_ip);
+
+    // Binding to an unknown interface fails the attempt
+    let mut remote_ips = vec![live_ip];
+    assert!(matches!(
+        connect_staggered(
+            None,
+            Some("invalid0"),
+            &mut remote_ips,
+            port,
+            Duration::from_secs(1),
+            None,
+            &span,
+            "mx.foobar.org",
+        )
+        .await,
+        Err(mail_send::Error::Io(_))
+    ));
+}
+
+#[tokio::test]
+async fn connect_after_failure() {
+    let port = 9929;
+    let live_ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let refused_ip: IpAddr = "127.0.0.3".parse().unwrap();
+    let listener = TcpListener::bind(SocketAddr::new(live_ip, port))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+
+    // The next IP is tried right away when the first one refuses the connection
+    let span = tracing::info_span!("connect");
+    let mut interfaces = vec![None];
+    if cfg!(target_os = "linux") {
+        interfaces.push(Some("lo"));
+    }
+    for interface in interfaces {
+        let mut remote_ips = vec![refused_ip, live_ip];
+        let time = Instant::now();
+        let (remote_ip, _) = connect_staggered(
+            None,
+            interface,
+            &mut remote_ips,
+            port,
+            Duration::from_secs(5),
+            Some(Duration::from_secs(5)),
+            &span,
+            "mx.foobar.org",
+        )
+        .await
+        .unwrap();
+        assert_eq!(remote_ip, live_ip);
+        assert!(time.elapsed() < Duration::from_secs(1));
+        assert!(remote_ips.is_empty());
+    }
+}
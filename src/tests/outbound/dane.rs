@@ -34,12 +34,12 @@ use mail_auth::{
 };
 
 use crate::{
-    config::{AggregateFrequency, IfBlock, RequireOptional, ServerProtocol},
+    config::{AggregateFrequency, ConfigContext, IfBlock, ServerProtocol},
     core::{Core, Session},
     outbound::dane::{Tlsa, TlsaEntry},
     queue::{manager::Queue, DeliveryAttempt},
     reporting::PolicyType,
-    tests::{outbound::start_test_server, session::VerifyResponse},
+    tests::{outbound::start_test_server, session::VerifyResponse, ParseTestConfig},
 };
 
 #[tokio::test]
@@ -83,7 +83,10 @@ async fn dane_verify() {
     let mut local_qr = core.init_test_queue("smtp_dane_local");
     let mut rr = core.init_test_report();
     core.session.config.rcpt.relay = IfBlock::new(true);
-    core.queue.config.tls.dane = IfBlock::new(RequireOptional::Require);
+    core.queue.config.tls.dane = r"[{if = 'sender', eq = 'jane@test.org', then = 'optional'},
+    {else = 'require'}]"
+        .parse_if(&ConfigContext::default());
+    core.queue.config.tls.dane_fallback = IfBlock::new(true);
     core.report.config.tls.send = IfBlock::new(AggregateFrequency::Weekly);
 
     let core = Arc::new(core);
@@ -164,6 +167,26 @@ async fn dane_verify() {
     );
     remote_qr.assert_empty_queue();
 
+    // Opportunistic DANE falls back to regular TLS
+    session
+        .send_message("jane@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    DeliveryAttempt::from(local_qr.read_event().await.unwrap_message())
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+    local_qr.read_event().await.unwrap_done();
+    remote_qr
+        .read_event()
+        .await
+        .unwrap_message()
+        .read_lines()
+        .assert_contains("using TLSv1.3 with cipher");
+    let report = rr.read_report().await.unwrap_tls();
+    assert_eq!(
+        report.failure.as_ref().unwrap().result_type,
+        ResultType::ValidationFailure
+    );
+
     // DANE successful delivery
     let tlsa = Arc::new(Tlsa {
         entries: vec![TlsaEntry {
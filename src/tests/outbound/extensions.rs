@@ -27,13 +27,15 @@ use std::{
 };
 
 use mail_auth::MX;
-use smtp_proto::{MAIL_REQUIRETLS, MAIL_RET_HDRS, MAIL_SMTPUTF8, RCPT_NOTIFY_NEVER};
+use smtp_proto::{
+    MAIL_BODY_BINARYMIME, MAIL_REQUIRETLS, MAIL_RET_HDRS, MAIL_SMTPUTF8, RCPT_NOTIFY_NEVER,
+};
 
 use crate::{
-    config::{IfBlock, ServerProtocol},
+    config::{ConfigContext, IfBlock, ServerProtocol},
     core::{Core, Session},
     queue::{manager::Queue, DeliveryAttempt},
-    tests::{outbound::start_test_server, session::VerifyResponse},
+    tests::{outbound::start_test_server, session::VerifyResponse, ParseTestConfig},
 };
 
 #[tokio::test]
@@ -75,6 +77,10 @@ async fn extensions() {
     let mut local_qr = core.init_test_queue("smtp_ext_local");
     core.session.config.rcpt.relay = IfBlock::new(true);
     core.session.config.extensions.dsn = IfBlock::new(true);
+    core.queue.config.hostname =
+        r"[{if = 'rcpt-domain', eq = 'foobar.org', then = 'mx.partner.example.org'},
+    {else = 'mx.example.org'}]"
+            .parse_if(&ConfigContext::default());
     let core = Arc::new(core);
     let mut queue = Queue::default();
     let mut session = Session::test(core.clone());
@@ -107,7 +113,8 @@ async fn extensions() {
         .await
         .unwrap_message()
         .read_lines()
-        .assert_contains("using TLSv1.3 with cipher");
+        .assert_contains("using TLSv1.3 with cipher")
+        .assert_contains("from mx.partner.example.org");
 
     // Test SIZE extension
     session
@@ -147,4 +154,21 @@ async fn extensions() {
     assert!((message.flags & MAIL_REQUIRETLS) != 0);
     assert!((message.flags & MAIL_SMTPUTF8) != 0);
     assert!((message.recipients.last().unwrap().flags & RCPT_NOTIFY_NEVER) != 0);
+
+    // BINARYMIME is relayed to hosts that support CHUNKING
+    session
+        .mail_from("<john@test.org> BODY=BINARYMIME", "250")
+        .await;
+    session.rcpt_to("bill@foobar.org", "250").await;
+    session
+        .ingest(b"BDAT 32 LAST\r\nSubject: binary\r\n\r\n\x00\x01\xff bare\nlf\r\n")
+        .await
+        .unwrap();
+    session.response().assert_code("250");
+    DeliveryAttempt::from(local_qr.read_event().await.unwrap_message())
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+    local_qr.read_event().await.unwrap_done();
+    let message = remote_qr.read_event().await.unwrap_message();
+    assert!((message.flags & MAIL_BODY_BINARYMIME) != 0);
 }
@@ -29,7 +29,7 @@ use std::{
 use crate::{
     config::{Config, ConfigContext, IfBlock, ServerProtocol},
     core::{Core, Session},
-    queue::{manager::Queue, DeliveryAttempt, Event, WorkerResult},
+    queue::{manager::Queue, DeliveryAttempt, Event, Schedule, Status, WorkerResult},
     tests::{outbound::start_test_server, session::VerifyResponse, ParseTestConfig},
 };
 
@@ -134,7 +134,7 @@ async fn lmtp_delivery() {
                 WorkerResult::OnHold(_) => unreachable!(),
             },
             None | Some(Event::Stop) => break,
-            Some(Event::Manage(_)) => unreachable!(),
+            Some(Event::Manage(_) | Event::Release { .. }) => unreachable!(),
         }
 
         if !queue.scheduled.is_empty() {
@@ -193,3 +193,107 @@ async fn lmtp_delivery() {
     );
     remote_qr.assert_empty_queue();
 }
+
+#[tokio::test]
+#[serial_test::serial]
+async fn lmtp_partial_delivery() {
+    // Start test server
+    let mut core = Core::test();
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    let mut remote_qr = core.init_test_queue("lmtp_partial_remote");
+    let _rx = start_test_server(core.into(), &[ServerProtocol::Lmtp]);
+
+    // Add mock DNS entries
+    let mut core = Core::test();
+    core.resolvers.dns.ipv4_add(
+        "lmtp.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    let mut local_qr = core.init_test_queue("lmtp_partial_local");
+
+    let mut ctx = ConfigContext::default();
+    let config = Config::parse(REMOTE).unwrap();
+    config.parse_remote_hosts(&mut ctx).unwrap();
+    core.queue.config.next_hop = "[{if = 'rcpt-domain', eq = 'foobar.org', then = 'lmtp'},
+    {else = false}]"
+        .parse_if::<Option<String>>(&ctx)
+        .into_relay_host(&ctx)
+        .unwrap();
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.session.config.rcpt.max_recipients = IfBlock::new(100);
+    let mut config = &mut core.queue.config;
+    config.retry = IfBlock::new(vec![Duration::from_millis(100)]);
+    config.notify = IfBlock::new(vec![Duration::from_secs(86400)]);
+    config.expire = IfBlock::new(Duration::from_secs(86400));
+
+    let core = Arc::new(core);
+    let mut queue = Queue::default();
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &[
+                "<bill@foobar.org>",
+                "<delay@foobar.org>",
+                "<jane@foobar.org>",
+            ],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    DeliveryAttempt::from(local_qr.read_event().await.unwrap_message())
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+
+    for attempt in 0..2 {
+        // Only the deferred recipient should be pending
+        let message = match local_qr.read_event().await {
+            Event::Done(WorkerResult::Retry(retry)) => retry.inner,
+            _ => unreachable!(),
+        };
+        for rcpt in &message.recipients {
+            if rcpt.address == "delay@foobar.org" {
+                assert!(
+                    matches!(rcpt.status, Status::TemporaryFailure(_)),
+                    "{:?}",
+                    rcpt.status
+                );
+            } else {
+                assert!(
+                    matches!(rcpt.status, Status::Completed(_)),
+                    "{:?}",
+                    rcpt.status
+                );
+            }
+        }
+
+        // Delivered recipients must not be delivered again
+        if attempt == 0 {
+            assert_eq!(
+                remote_qr
+                    .read_event()
+                    .await
+                    .unwrap_message()
+                    .recipients
+                    .into_iter()
+                    .map(|r| r.address)
+                    .collect::<Vec<_>>(),
+                vec!["bill@foobar.org".to_string(), "jane@foobar.org".to_string()]
+            );
+        }
+        remote_qr.assert_empty_queue();
+
+        queue.schedule(Schedule {
+            due: message.next_delivery_event(),
+            inner: message,
+        });
+        tokio::time::sleep(queue.wake_up_time()).await;
+        DeliveryAttempt::from(queue.next_due().unwrap())
+            .try_deliver(core.clone(), &mut queue)
+            .await;
+    }
+}
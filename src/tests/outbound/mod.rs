@@ -32,12 +32,14 @@ use crate::{
 
 use super::add_test_certs;
 
+pub mod connect;
 pub mod dane;
 pub mod extensions;
 pub mod lmtp;
 pub mod mta_sts;
 pub mod smtp;
 pub mod throttle;
+pub mod tls;
 
 const SERVER: &str = "
 [server]
@@ -92,7 +94,7 @@ pub fn start_test_server(core: Arc<Core>, protocols: &[ServerProtocol]) -> watch
                 ServerProtocol::Http => server
                     .spawn_management(core.clone(), shutdown_rx.clone())
                     .unwrap(),
-                ServerProtocol::Imap => unreachable!(),
+                ServerProtocol::Imap | ServerProtocol::Ldap => unreachable!(),
             };
         }
     }
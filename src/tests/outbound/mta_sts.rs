@@ -27,7 +27,7 @@ use std::{
 };
 
 use mail_auth::{
-    common::parse::TxtRecordParser,
+    common::{lru::DnsCache, parse::TxtRecordParser},
     mta_sts::{MtaSts, ReportUri, TlsRpt},
     report::tlsrpt::ResultType,
     MX,
@@ -193,6 +193,43 @@ async fn mta_sts_verify() {
     );
     remote_qr.assert_empty_queue();
 
+    // MTA-STS policies in testing mode are reported but not enforced
+    core.resolvers.dns.txt_add(
+        "_mta-sts.foobar.org",
+        MtaSts::parse(b"v=STSv1; id=policy_testing;").unwrap(),
+        Instant::now() + Duration::from_secs(10),
+    );
+    let policy = concat!(
+        "version: STSv1\n",
+        "mode: testing\n",
+        "mx: mail.foobar.net\n",
+        "max_age: 604800\n"
+    );
+    STS_TEST_POLICY.lock().clear();
+    STS_TEST_POLICY.lock().extend_from_slice(policy.as_bytes());
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    DeliveryAttempt::from(local_qr.read_event().await.unwrap_message())
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+    local_qr.read_event().await.unwrap_done();
+    remote_qr.read_event().await.unwrap_message();
+
+    // Expect TLS failure report
+    let report = rr.read_report().await.unwrap_tls();
+    assert_eq!(
+        report.policy,
+        PolicyType::Sts(
+            Arc::new(Policy::parse(policy, "policy_testing".to_string()).unwrap()).into()
+        )
+    );
+    assert_eq!(
+        report.failure.as_ref().unwrap().result_type,
+        ResultType::ValidationFailure
+    );
+    rr.read_report().await.unwrap_tls();
+
     // MTA-STS successful validation
     core.resolvers.dns.txt_add(
         "_mta-sts.foobar.org",
@@ -231,3 +268,66 @@ async fn mta_sts_verify() {
     );
     assert!(report.failure.is_none());
 }
+
+#[tokio::test]
+#[serial_test::serial]
+async fn mta_sts_policy_refresh() {
+    let core = Core::test();
+    core.resolvers.dns.txt_add(
+        "_mta-sts.foobar.org",
+        MtaSts::parse(b"v=STSv1; id=policy_refresh;").unwrap(),
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Cache a policy that is about to expire
+    let policy = concat!(
+        "version: STSv1\n",
+        "mode: enforce\n",
+        "mx: mx1.foobar.org\n",
+        "max_age: 604800\n"
+    );
+    core.resolvers.cache.mta_sts.insert(
+        "foobar.org".to_string(),
+        Arc::new(Policy::parse(policy, "policy_refresh".to_string()).unwrap()),
+        Instant::now() + Duration::from_millis(200),
+    );
+    let policy = concat!(
+        "version: STSv1\n",
+        "mode: enforce\n",
+        "mx: mx2.foobar.org\n",
+        "max_age: 604800\n"
+    );
+    STS_TEST_POLICY.lock().clear();
+    STS_TEST_POLICY.lock().extend_from_slice(policy.as_bytes());
+
+    // The cached policy is used while it has not expired
+    let timeout = Duration::from_secs(1);
+    let policy = core
+        .lookup_mta_sts_policy("foobar.org", timeout)
+        .await
+        .unwrap();
+    assert!(policy.verify("mx1.foobar.org"));
+
+    // Expired policies are fetched again even if the id did not change
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let policy = core
+        .lookup_mta_sts_policy("foobar.org", timeout)
+        .await
+        .unwrap();
+    assert!(policy.verify("mx2.foobar.org"));
+    assert!(!policy.verify("mx1.foobar.org"));
+
+    // Failed fetches keep using the cached policy
+    core.resolvers.dns.txt_add(
+        "_mta-sts.foobar.org",
+        MtaSts::parse(b"v=STSv1; id=policy_refresh_failed;").unwrap(),
+        Instant::now() + Duration::from_secs(10),
+    );
+    STS_TEST_POLICY.lock().clear();
+    let policy = core
+        .lookup_mta_sts_policy("foobar.org", timeout)
+        .await
+        .unwrap();
+    assert_eq!(policy.id, "policy_refresh");
+    assert!(policy.verify("mx2.foobar.org"));
+}
@@ -22,19 +22,44 @@
 */
 
 use std::{
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
 
+use ahash::AHashSet;
 use mail_auth::MX;
+use smtp_proto::AUTH_PLAIN;
+
+use mail_send::SmtpClient;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
 
 use crate::{
-    config::{ConfigContext, IfBlock, ServerProtocol},
+    config::{Config, ConfigContext, IfBlock, ServerProtocol, Throttle, THROTTLE_MX},
     core::{Core, Session},
-    queue::{manager::Queue, DeliveryAttempt, Event, WorkerResult},
+    lookup::Lookup,
+    outbound::{pool::ConnectionKey, session::read_pipelined_responses},
+    queue::{manager::Queue, DeliveryAttempt, Error, Event, Status, WorkerResult},
     tests::{outbound::start_test_server, session::VerifyResponse, ParseTestConfig},
 };
 
+const SMARTHOST: &str = "
+[remote.smarthost]
+address = smarthost.foobar.net
+port = 9925
+protocol = 'smtp'
+
+[remote.smarthost.auth]
+username = john
+secret = secret
+
+[remote.smarthost.tls]
+implicit = false
+allow-invalid-certs = true
+";
+
 #[tokio::test]
 #[serial_test::serial]
 async fn smtp_delivery() {
@@ -159,7 +184,7 @@ async fn smtp_delivery() {
                 WorkerResult::OnHold(_) => unreachable!(),
             },
             None | Some(Event::Stop) => break,
-            Some(Event::Manage(_)) => unreachable!(),
+            Some(Event::Manage(_) | Event::Release { .. }) => unreachable!(),
         }
 
         if !queue.scheduled.is_empty() {
@@ -240,3 +265,495 @@ async fn smtp_delivery() {
 
     remote_qr.assert_empty_queue();
 }
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smtp_delivery_over_quota() {
+    // Start test server
+    let mut core = Core::test();
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    let mut remote_qr = core.init_test_queue("smtp_over_quota_remote");
+    let _rx = start_test_server(core.into(), &[ServerProtocol::Smtp]);
+
+    // Add mock DNS entries
+    let mut core = Core::test();
+    core.resolvers.dns.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx1.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.resolvers.dns.ipv4_add(
+        "mx1.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Full mailboxes are retried less often and notified only once
+    let mut local_qr = core.init_test_queue("smtp_over_quota_local");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.session.config.extensions.dsn = IfBlock::new(true);
+    let mut config = &mut core.queue.config;
+    config.retry = IfBlock::new(vec![Duration::from_millis(100)]);
+    config.over_quota_retry = IfBlock::new(vec![Duration::from_millis(300)]);
+    config.over_quota_notify_once = IfBlock::new(true);
+    config.notify = IfBlock::new(vec![Duration::from_millis(100); 10]);
+    config.expire = IfBlock::new(Duration::from_millis(1100));
+
+    let core = Arc::new(core);
+    let mut queue = Queue::default();
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["<full@foobar.org> NOTIFY=DELAY,FAILURE"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    DeliveryAttempt::from(local_qr.read_event().await.unwrap_message())
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+    let mut dsn = Vec::new();
+    let mut retries = 0;
+    loop {
+        match local_qr.try_read_event().await {
+            Some(Event::Queue(message)) => {
+                dsn.push(message.inner);
+            }
+            Some(Event::Done(wr)) => match wr {
+                WorkerResult::Done => {
+                    break;
+                }
+                WorkerResult::Retry(retry) => {
+                    let domain = &retry.inner.domains[0];
+                    assert!(
+                        domain.retry.due >= Instant::now() + Duration::from_millis(200),
+                        "{domain:?}"
+                    );
+                    retries = domain.retry.inner;
+                    queue.schedule(retry);
+                }
+                WorkerResult::OnHold(_) => unreachable!(),
+            },
+            None | Some(Event::Stop) => break,
+            Some(Event::Manage(_) | Event::Release { .. }) => unreachable!(),
+        }
+
+        if !queue.scheduled.is_empty() {
+            tokio::time::sleep(queue.wake_up_time()).await;
+            DeliveryAttempt::from(queue.next_due().unwrap())
+                .try_deliver(core.clone(), &mut queue)
+                .await;
+        }
+    }
+    assert!((2..=4).contains(&retries), "retries {retries}");
+
+    // A single delay notification followed by the final failure
+    assert_eq!(dsn.len(), 2);
+    let mut dsn = dsn.into_iter();
+    dsn.next()
+        .unwrap()
+        .read_lines()
+        .assert_contains("<full@foobar.org> (host ")
+        .assert_contains("Action: delayed");
+    dsn.next()
+        .unwrap()
+        .read_lines()
+        .assert_contains("<full@foobar.org> (host ")
+        .assert_contains("Action: failed");
+    remote_qr.assert_empty_queue();
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smtp_delivery_pool() {
+    // Start test server
+    let mut core = Core::test();
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    let mut remote_qr = core.init_test_queue("smtp_pool_remote");
+    let _rx = start_test_server(core.into(), &[ServerProtocol::Smtp]);
+
+    // Add mock DNS entries
+    let mut core = Core::test();
+    core.resolvers.dns.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx1.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.resolvers.dns.ipv4_add(
+        "mx1.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    // Enable connection pooling
+    let mut local_qr = core.init_test_queue("smtp_pool_local");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.queue.config.pool.idle_timeout = IfBlock::new(Some(Duration::from_millis(500)));
+    core.queue.config.pool.max_messages = IfBlock::new(2);
+    core.queue.config.throttle.host = vec![Throttle {
+        conditions: Default::default(),
+        keys: THROTTLE_MX,
+        concurrency: 5.into(),
+        rate: None,
+        tarpit: None,
+    }];
+
+    let core = Arc::new(core);
+    let mut queue = Queue::default();
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    let key = ConnectionKey {
+        source_ip: None,
+        interface: None,
+        hostname: "mx1.foobar.org".to_string(),
+        port: 9925,
+    };
+
+    for (messages, in_flight) in [(Some(1), 1), (None, 0), (Some(1), 1)] {
+        session
+            .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+            .await;
+        DeliveryAttempt::from(local_qr.read_event().await.unwrap_message())
+            .try_deliver(core.clone(), &mut queue)
+            .await;
+        local_qr.read_event().await.unwrap_done();
+        remote_qr.read_event().await.unwrap_message();
+
+        // Idle connections are kept open until the maximum number of messages is reached
+        assert_eq!(
+            core.queue
+                .connections
+                .get(&key)
+                .and_then(|conns| conns.first().map(|conn| conn.messages)),
+            messages
+        );
+        assert_eq!(host_in_flight(&core), in_flight);
+    }
+
+    // Idle connections are closed after the timeout
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert!(core.queue.connections.get(&key).is_none());
+    assert_eq!(host_in_flight(&core), 0);
+    remote_qr.assert_empty_queue();
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smtp_delivery_smarthost() {
+    // Start test server requiring authentication for relaying
+    let mut core = Core::test();
+    let mut ctx = ConfigContext::default();
+    ctx.lookup.insert(
+        "plain".to_string(),
+        Arc::new(Lookup::Local(AHashSet::from_iter([
+            "john:secret".to_string()
+        ]))),
+    );
+    core.session.config.auth.lookup = "'plain'"
+        .parse_if::<Option<String>>(&ctx)
+        .map_if_block(&ctx.lookup, "", "")
+        .unwrap();
+    core.session.config.auth.mechanisms = IfBlock::new(AUTH_PLAIN);
+    core.session.config.rcpt.relay = r"[{if = 'authenticated-as', ne = '', then = true},
+    {else = false}]"
+        .parse_if(&ctx);
+    let mut remote_qr = core.init_test_queue("smtp_smarthost_remote");
+    let _rx = start_test_server(core.into(), &[ServerProtocol::Smtp]);
+
+    // The MX of foobar.org is unreachable, only the smarthost resolves
+    let mut core = Core::test();
+    core.resolvers.dns.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx1.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.resolvers.dns.ipv4_add(
+        "smarthost.foobar.net",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let mut local_qr = core.init_test_queue("smtp_smarthost_local");
+    let mut ctx = ConfigContext::default();
+    let config = Config::parse(SMARTHOST).unwrap();
+    config.parse_remote_hosts(&mut ctx).unwrap();
+    core.queue.config.next_hop = "[{if = 'rcpt-domain', eq = 'foobar.org', then = 'smarthost'},
+    {else = false}]"
+        .parse_if::<Option<String>>(&ctx)
+        .into_relay_host(&ctx)
+        .unwrap();
+    core.session.config.rcpt.relay = IfBlock::new(true);
+
+    let core = Arc::new(core);
+    let mut queue = Queue::default();
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message("john@test.org", &["bill@foobar.org"], "test:no_dkim", "250")
+        .await;
+    DeliveryAttempt::from(local_qr.read_event().await.unwrap_message())
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+    local_qr.read_event().await.unwrap_done();
+    assert_eq!(
+        remote_qr
+            .read_event()
+            .await
+            .unwrap_message()
+            .recipients
+            .into_iter()
+            .map(|r| r.address)
+            .collect::<Vec<_>>(),
+        vec!["bill@foobar.org".to_string()]
+    );
+    remote_qr.assert_empty_queue();
+}
+
+#[tokio::test]
+#[serial_test::serial]
+async fn smtp_delivery_pipelining() {
+    // Start a server that accepts some recipients and rejects others, ignoring
+    // the commands pipelined after MAIL FROM on the first connection
+    let listener = TcpListener::bind("127.0.0.1:9925").await.unwrap();
+    tokio::spawn(async move {
+        let mut is_first = true;
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut ignore_rcpts = if std::mem::take(&mut is_first) { 3 } else { 0 };
+            tokio::spawn(async move {
+                let mut buf = vec![0u8; 4096];
+                let mut in_data = false;
+                stream
+                    .write_all(b"220 mx1.foobar.org ESMTP\r\n")
+                    .await
+                    .unwrap();
+                while let Ok(br) = stream.read(&mut buf).await {
+                    if br == 0 {
+                        break;
+                    }
+                    let chunk = String::from_utf8_lossy(&buf[..br]).into_owned();
+                    if in_data {
+                        if chunk.ends_with("\r\n.\r\n") {
+                            in_data = false;
+                            stream.write_all(b"250 2.0.0 Queued\r\n").await.unwrap();
+                        }
+                        continue;
+                    }
+                    let mut response = String::new();
+                    for line in chunk.split_terminator("\r\n") {
+                        let line = line.to_ascii_uppercase();
+                        if line.starts_with("EHLO") {
+                            response.push_str("250-mx1.foobar.org\r\n250 PIPELINING\r\n");
+                        } else if line.starts_with("MAIL FROM") {
+                            response.push_str("250 2.1.0 OK\r\n");
+                        } else if line.starts_with("RCPT TO") && ignore_rcpts > 0 {
+                            ignore_rcpts -= 1;
+                        } else if line.starts_with("RCPT TO:<JANE@") {
+                            response.push_str("550 5.1.1 Mailbox does not exist\r\n");
+                        } else if line.starts_with("RCPT TO:<JOHN@") {
+                            response.push_str("452 4.2.2 Mailbox full\r\n");
+                        } else if line.starts_with("RCPT TO") {
+                            response.push_str("250 2.1.5 OK\r\n");
+                        } else if line.starts_with("DATA") {
+                            response.push_str("354 Start mail input\r\n");
+                            in_data = true;
+                        } else if line.starts_with("QUIT") {
+                            response.push_str("221 Bye\r\n");
+                        } else {
+                            response.push_str("250 OK\r\n");
+                        }
+                    }
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+
+    // Add mock DNS entries
+    let mut core = Core::test();
+    core.resolvers.dns.mx_add(
+        "foobar.org",
+        vec![MX {
+            exchanges: vec!["mx1.foobar.org".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.resolvers.dns.ipv4_add(
+        "mx1.foobar.org",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+    let mut local_qr = core.init_test_queue("smtp_pipelining_local");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.queue.config.timeout.rcpt = IfBlock::new(Duration::from_millis(200));
+
+    let core = Arc::new(core);
+    let mut queue = Queue::default();
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["bill@foobar.org", "jane@foobar.org", "john@foobar.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    DeliveryAttempt::from(local_qr.read_event().await.unwrap_message())
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+
+    // Missing responses leave the connection out of sync, the next attempt does not pipeline
+    let mut retry = loop {
+        match local_qr.read_event().await {
+            Event::Done(WorkerResult::Retry(retry)) => break retry,
+            Event::Queue(_) => (),
+            event => panic!("Unexpected event {event:?}"),
+        }
+    };
+    assert!(
+        matches!(
+            &retry.inner.domains[0].status,
+            Status::TemporaryFailure(Error::ConnectionError(err))
+                if err.details == "Pipelined responses out of sync"
+        ),
+        "{:?}",
+        retry.inner.domains[0].status
+    );
+    assert!(retry
+        .inner
+        .recipients
+        .iter()
+        .all(|r| matches!(r.status, Status::Scheduled)));
+    assert!(!core.queue.has_pipelining("mx1.foobar.org"));
+    retry.inner.domains[0].retry.due = Instant::now();
+    DeliveryAttempt::from(retry.inner)
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+
+    // Each recipient is updated from its own response
+    let retry = loop {
+        match local_qr.read_event().await {
+            Event::Done(WorkerResult::Retry(retry)) => break retry,
+            Event::Queue(_) => (),
+            event => panic!("Unexpected event {event:?}"),
+        }
+    };
+    for (address, status) in [
+        ("bill@foobar.org", 250),
+        ("jane@foobar.org", 550),
+        ("john@foobar.org", 452),
+    ] {
+        let rcpt = retry
+            .inner
+            .recipients
+            .iter()
+            .find(|r| r.address == address)
+            .unwrap();
+        match (&rcpt.status, status) {
+            (Status::Completed(response), 250) => assert_eq!(response.response.code(), 250),
+            (Status::PermanentFailure(response), 550) => {
+                assert_eq!(response.response.code(), 550)
+            }
+            (Status::TemporaryFailure(response), 452) => {
+                assert_eq!(response.response.code(), 452)
+            }
+            _ => panic!("Unexpected status for {address}: {rcpt:?}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn smtp_pipelined_responses() {
+    let (client, mut server) = tokio::io::duplex(1024);
+    let mut smtp_client = SmtpClient {
+        stream: client,
+        timeout: Duration::from_secs(1),
+    };
+
+    // Responses are correlated in order, even when split across reads
+    server
+        .write_all(b"250 2.1.0 OK\r\n250 2.1.5 OK\r\n550-5.1.1 No such\r\n550 5.1.1 user\r\n45")
+        .await
+        .unwrap();
+    let reader = tokio::spawn(async move {
+        let responses = read_pipelined_responses(
+            &mut smtp_client,
+            4,
+            Duration::from_secs(1),
+            Duration::from_millis(500),
+        )
+        .await
+        .unwrap();
+        (smtp_client, responses)
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    server.write_all(b"2 4.2.2 Mailbox full\r\n").await.unwrap();
+    let (mut smtp_client, responses) = reader.await.unwrap();
+    assert_eq!(
+        responses.iter().map(|r| r.code()).collect::<Vec<_>>(),
+        vec![250, 250, 550, 452]
+    );
+
+    // Fewer responses than commands are returned after the timeout
+    server
+        .write_all(b"250 2.1.0 OK\r\n250 2.1.5 OK\r\n")
+        .await
+        .unwrap();
+    let responses = read_pipelined_responses(
+        &mut smtp_client,
+        4,
+        Duration::from_secs(1),
+        Duration::from_millis(100),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        responses.iter().map(|r| r.code()).collect::<Vec<_>>(),
+        vec![250, 250]
+    );
+
+    // No responses at all is a timeout
+    assert!(matches!(
+        read_pipelined_responses(
+            &mut smtp_client,
+            2,
+            Duration::from_millis(100),
+            Duration::from_millis(100),
+        )
+        .await,
+        Err(mail_send::Error::Timeout)
+    ));
+}
+
+fn host_in_flight(core: &Core) -> u64 {
+    core.queue
+        .throttle
+        .iter()
+        .filter_map(|limiter| {
+            limiter
+                .concurrency
+                .as_ref()
+                .map(|limiter| limiter.concurrent.load(Ordering::Relaxed))
+        })
+        .sum()
+}
@@ -23,16 +23,16 @@
 
 use std::{
     net::{IpAddr, Ipv4Addr},
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
 
 use mail_auth::MX;
 
 use crate::{
-    config::{ConfigContext, IfBlock},
-    core::{Core, Session},
-    queue::{manager::Queue, DeliveryAttempt, Message, QueueEnvelope},
+    config::{ConfigContext, IfBlock, LoadThrottle, Rate},
+    core::{throttle::ConcurrencyLimiter, Core, Session},
+    queue::{manager::Queue, DeliveryAttempt, Message, QueueEnvelope, Schedule},
     tests::{queue::manager::new_message, ParseTestConfig},
 };
 
@@ -306,3 +306,136 @@ impl<'x> QueueEnvelope<'x> {
         }
     }
 }
+
+#[test]
+fn throttle_load() {
+    let limiter = ConcurrencyLimiter::new(64);
+    let load_throttle = LoadThrottle {
+        max_load: 2.0,
+        restore_load: 1.5,
+        min_concurrency: 4,
+        interval: Duration::from_secs(10),
+    };
+    let _in_flight = (0..10)
+        .map(|_| limiter.is_allowed().unwrap())
+        .collect::<Vec<_>>();
+
+    // High load halves the number of deliveries in flight
+    let max_concurrent = load_throttle.adjust(&limiter, 64, 3.0);
+    assert_eq!(max_concurrent, 5);
+    assert_eq!(limiter.concurrent.load(Ordering::Relaxed), 10 + 59);
+    assert!(limiter.is_allowed().is_none());
+
+    // Concurrency never drops below the configured minimum
+    let max_concurrent = load_throttle.adjust(&limiter, max_concurrent, 3.0);
+    assert_eq!(max_concurrent, 4);
+    assert_eq!(limiter.concurrent.load(Ordering::Relaxed), 10 + 60);
+
+    // Concurrency is kept between both thresholds
+    let max_concurrent = load_throttle.adjust(&limiter, max_concurrent, 1.8);
+    assert_eq!(max_concurrent, 4);
+
+    // Concurrency is restored once the load drops
+    let mut max_concurrent = max_concurrent;
+    for expected in [8, 16, 32, 64, 64] {
+        max_concurrent = load_throttle.adjust(&limiter, max_concurrent, 0.5);
+        assert_eq!(max_concurrent, expected);
+    }
+    assert_eq!(limiter.concurrent.load(Ordering::Relaxed), 10);
+    assert!(limiter.is_allowed().is_some());
+}
+
+#[tokio::test]
+async fn throttle_pacing() {
+    let core = Core::test();
+    let throttle = r#"
+[queue.outbound.pacing."example.org"]
+concurrency = 2
+messages-per-minute = 45
+
+[queue.outbound.pacing."example.net"]
+messages-per-minute = 60
+"#
+    .parse_queue_throttle(&ConfigContext::default());
+
+    // Rates are spread over the shortest whole-second period
+    let pacing = throttle.pacing.get("example.org").unwrap();
+    assert_eq!(pacing.concurrency, Some(2));
+    assert_eq!(
+        pacing.rate,
+        Some(Rate {
+            requests: 3,
+            period: Duration::from_secs(4)
+        })
+    );
+    assert!(!throttle.pacing.contains_key("example.com"));
+
+    // Expect the second delivery within the same second to be rate limited
+    let test_message = new_message(0);
+    let pacing = throttle.pacing.get("example.net").unwrap();
+    let envelope = QueueEnvelope::test(&test_message, "example.net", "");
+    let span = tracing::info_span!("test");
+    let mut in_flight = vec![];
+    core.queue
+        .is_allowed(pacing, &envelope, &mut in_flight, &span)
+        .await
+        .unwrap();
+    assert!(core
+        .queue
+        .is_allowed(pacing, &envelope, &mut in_flight, &span)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn throttle_batch() {
+    let mut core = Core::test();
+    let mut local_qr = core.init_test_queue("smtp_throttle_batch");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.queue.config.max_batch = IfBlock::new(5);
+    core.queue.config.throttle = r#"
+[queue.outbound.pacing."example.net"]
+messages-per-minute = 1
+"#
+    .parse_queue_throttle(&ConfigContext::default());
+    core.resolvers.dns.mx_add(
+        "example.net",
+        vec![MX {
+            exchanges: vec!["mx.example.net".to_string()],
+            preference: 10,
+        }],
+        Instant::now() + Duration::from_secs(10),
+    );
+    core.resolvers.dns.ipv4_add(
+        "mx.example.net",
+        vec!["127.0.0.1".parse().unwrap()],
+        Instant::now() + Duration::from_secs(10),
+    );
+
+    let core = Arc::new(core);
+    let mut queue = Queue::default();
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    for rcpt in ["jane@example.net", "bill@example.net"] {
+        session
+            .send_message("john@test.org", &[rcpt], "test:no_dkim", "250")
+            .await;
+    }
+    let message = local_qr.read_event().await.unwrap_message();
+    let batched = local_qr.read_event().await.unwrap_message();
+    let batched_id = batched.id;
+    queue.schedule(Schedule {
+        due: Instant::now(),
+        inner: batched,
+    });
+
+    // The batched message does not bypass the pacing limit of the first one
+    DeliveryAttempt::from(message)
+        .try_deliver(core.clone(), &mut queue)
+        .await;
+    let retry = local_qr.read_event().await.unwrap_retry();
+    assert_eq!(retry.inner.id, batched_id);
+    assert!(retry.due > Instant::now() + Duration::from_secs(30));
+}
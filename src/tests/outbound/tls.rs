@@ -0,0 +1,195 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use rustls::{
+    cipher_suite::{
+        TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    },
+    version::{TLS12, TLS13},
+    ClientConfig, ClientConnection, ServerConnection, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
+
+use crate::{
+    config::{
+        certificate::DummyVerifier, Config, ConfigContext, EnvelopeKey, RequireOptional,
+        TlsStrategy, TlsVersion,
+    },
+    outbound::session::verify_tls_strength,
+    tests::{add_test_certs, lookup::dummy_tls_server_config},
+};
+
+#[test]
+fn client_certificate() {
+    let keys = [EnvelopeKey::RecipientDomain, EnvelopeKey::Mx];
+    let config = Config::parse(&add_test_certs(
+        r#"[queue.outbound.tls]
+certificate = [ { if = "rcpt-domain", eq = "partner.org", then = "client" },
+                { if = "mx", eq = "mx.partner.org", then = "client" },
+                { else = false } ]
+
+[certificate.client]
+cert = "file://{CERT}"
+private-key = "file://{PK}"
+"#,
+    ))
+    .unwrap();
+    let certificate = config
+        .parse_queue_client_certificate(&ConfigContext::default(), &keys)
+        .unwrap();
+    let first = certificate.if_then[0].then.as_ref().unwrap();
+    let second = certificate.if_then[1].then.as_ref().unwrap();
+    assert!(Arc::ptr_eq(first, second));
+    assert!(certificate.default.is_none());
+
+    // No client certificate by default
+    let certificate = Config::parse("")
+        .unwrap()
+        .parse_queue_client_certificate(&ConfigContext::default(), &keys)
+        .unwrap();
+    assert!(certificate.if_then.is_empty() && certificate.default.is_none());
+
+    // Unknown certificates are rejected
+    assert!(
+        Config::parse("[queue.outbound.tls]\ncertificate = \"missing\"\n")
+            .unwrap()
+            .parse_queue_client_certificate(&ConfigContext::default(), &keys)
+            .is_err()
+    );
+}
+
+#[test]
+fn tls_strength() {
+    let span = tracing::info_span!("test");
+    let tls12_128 = handshake(&TLS12, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256);
+    let tls12_256 = handshake(&TLS12, TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384);
+    let tls13_128 = handshake(&TLS13, TLS13_AES_128_GCM_SHA256);
+    let tls13_256 = handshake(&TLS13, TLS13_AES_256_GCM_SHA384);
+
+    let optional = TlsStrategy {
+        dane: RequireOptional::Optional,
+        dane_fallback: false,
+        mta_sts: RequireOptional::Optional,
+        tls: RequireOptional::Optional,
+    };
+    let require_tls = TlsStrategy {
+        tls: RequireOptional::Require,
+        ..optional
+    };
+    let require_dane = TlsStrategy {
+        dane: RequireOptional::Require,
+        ..optional
+    };
+    let require_mta_sts = TlsStrategy {
+        mta_sts: RequireOptional::Require,
+        ..optional
+    };
+
+    for (strategy, require_tls, mta_sts_enforce, dane, is_secure) in [
+        // Opportunistic TLS is never rejected
+        (&optional, false, false, false, false),
+        // STARTTLS, DANE or MTA-STS required by policy
+        (&require_tls, false, false, false, true),
+        (&require_dane, false, false, false, true),
+        (&require_mta_sts, false, false, false, true),
+        // REQUIRETLS requested by the sender
+        (&optional, true, false, false, true),
+        // MTA-STS policy in enforce mode
+        (&optional, false, true, false, true),
+        // TLSA records published for the MX
+        (&optional, false, false, true, true),
+    ] {
+        assert_eq!(
+            strategy.is_secure_delivery(require_tls, mta_sts_enforce, dane),
+            is_secure
+        );
+        if !is_secure {
+            continue;
+        }
+
+        for (conn, min_version, min_strength, expect_ok) in [
+            (&tls12_128, TlsVersion::Tls12, 0, true),
+            (&tls12_128, TlsVersion::Tls12, 128, true),
+            (&tls12_128, TlsVersion::Tls12, 256, false),
+            (&tls12_256, TlsVersion::Tls12, 256, true),
+            (&tls12_256, TlsVersion::Tls13, 0, false),
+            (&tls13_128, TlsVersion::Tls13, 128, true),
+            (&tls13_128, TlsVersion::Tls13, 256, false),
+            (&tls13_256, TlsVersion::Tls13, 256, true),
+            (&tls13_256, TlsVersion::Tls12, 256, true),
+        ] {
+            let result =
+                verify_tls_strength(&span, "mx.foobar.org", conn, min_version, min_strength);
+            assert_eq!(
+                result.is_ok(),
+                expect_ok,
+                "version {:?}, cipher {:?}, min version {min_version:?}, min strength {min_strength}: {result:?}",
+                conn.protocol_version(),
+                conn.negotiated_cipher_suite().map(|suite| suite.suite()),
+            );
+        }
+    }
+}
+
+fn handshake(
+    version: &'static SupportedProtocolVersion,
+    suite: SupportedCipherSuite,
+) -> ClientConnection {
+    let config = ClientConfig::builder()
+        .with_cipher_suites(&[suite])
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[version])
+        .unwrap()
+        .with_custom_certificate_verifier(Arc::new(DummyVerifier))
+        .with_no_client_auth();
+    let mut client =
+        ClientConnection::new(Arc::new(config), "mx.foobar.org".try_into().unwrap()).unwrap();
+    let mut server = ServerConnection::new(Arc::new(dummy_tls_server_config())).unwrap();
+
+    // Exchange handshake records in memory
+    while client.is_handshaking() || server.is_handshaking() {
+        let mut buf = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut buf).unwrap();
+        }
+        if !buf.is_empty() {
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets().unwrap();
+        }
+
+        let mut buf = Vec::new();
+        while server.wants_write() {
+            server.write_tls(&mut buf).unwrap();
+        }
+        if !buf.is_empty() {
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets().unwrap();
+        }
+    }
+
+    assert_eq!(client.negotiated_cipher_suite(), Some(suite));
+    client
+}
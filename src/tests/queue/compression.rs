@@ -0,0 +1,154 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{config::Config, core::Core, queue::Message};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+#[tokio::test]
+async fn queue_compression() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_queue_compression_test");
+    core.queue.config.compression =
+        Config::parse("[queue.compression]\nenable = true\nmin-size = 100\n")
+            .unwrap()
+            .parse_queue_compression()
+            .unwrap();
+    assert_eq!(core.queue.config.compression, Some(100));
+
+    let headers = b"From: sender@foobar.org\r\nSubject: compressed\r\n\r\n";
+    let body = "compress me please ".repeat(100);
+    let expected = [&headers[..], body.as_bytes()].concat();
+
+    for encrypt in [false, true] {
+        if encrypt {
+            core.queue.config.encryption =
+                Config::parse(&format!("[queue.encryption]\nkey = \"{KEY}\"\n"))
+                    .unwrap()
+                    .parse_queue_encryption()
+                    .unwrap();
+        }
+        let encryption = core.queue.config.encryption.as_ref();
+
+        // Large messages are compressed
+        let mut message =
+            Message::new_boxed("sender@foobar.org", "sender@foobar.org", "foobar.org");
+        message
+            .add_recipient("rcpt@example.org", &core.queue.config)
+            .await;
+        assert!(
+            core.queue
+                .queue_message(
+                    message,
+                    (&headers[..]).into(),
+                    body.as_bytes(),
+                    &tracing::info_span!("hi")
+                )
+                .await
+        );
+        let mut message = qr.read_event().await.unwrap_message();
+        assert!(message.is_compressed());
+        assert_eq!(message.is_encrypted(), encrypt);
+        assert_eq!(message.size, expected.len());
+        let compressed_size = message.compressed_size().unwrap();
+        assert!(compressed_size < message.size);
+        let contents = std::fs::read(&message.path).unwrap();
+        assert!(contents.len() < message.size);
+        assert!(!contents.windows(11).any(|w| w == b"compress me"));
+
+        // Metadata is stored uncompressed after the body
+        assert!(contents[message.stored_size()..]
+            .windows(17)
+            .any(|w| w == b"sender@foobar.org"));
+
+        // Bodies are decompressed transparently
+        assert_eq!(
+            core.queue
+                .config
+                .spool
+                .read(&message, message.size, encryption)
+                .await
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            core.queue
+                .config
+                .spool
+                .read(&message, 10, encryption)
+                .await
+                .unwrap(),
+            &expected[..10]
+        );
+
+        // Metadata updates and reloads preserve the compressed body
+        message.recipients[0].address = "other@example.org".to_string();
+        message.save_metadata().await;
+        let mut tmp_path = message.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        assert!(!std::path::Path::new(&tmp_path).exists());
+        let loaded = Message::from_path(message.path.clone()).await.unwrap();
+        assert_eq!(loaded.size, message.size);
+        assert_eq!(loaded.compressed_size(), Some(compressed_size));
+        assert_eq!(loaded.recipients[0].address, "other@example.org");
+        assert_eq!(
+            core.queue
+                .config
+                .spool
+                .read(&loaded, loaded.size, encryption)
+                .await
+                .unwrap(),
+            expected
+        );
+        message.remove(&core.queue.config.spool).await;
+    }
+
+    // Small messages are not compressed
+    let mut message = Message::new_boxed("sender@foobar.org", "sender@foobar.org", "foobar.org");
+    message
+        .add_recipient("rcpt@example.org", &core.queue.config)
+        .await;
+    assert!(
+        core.queue
+            .queue_message(
+                message,
+                None,
+                b"Subject: small\r\n\r\nhi",
+                &tracing::info_span!("hi")
+            )
+            .await
+    );
+    let message = qr.read_event().await.unwrap_message();
+    assert!(!message.is_compressed());
+    assert_eq!(message.compressed_size(), None);
+    message.remove(&core.queue.config.spool).await;
+
+    // Compression is disabled by default
+    assert_eq!(
+        Config::parse("[queue.compression]\nmin-size = 100\n")
+            .unwrap()
+            .parse_queue_compression()
+            .unwrap(),
+        None
+    );
+}
@@ -0,0 +1,124 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{
+    config::Config,
+    core::Core,
+    queue::{dedup::DedupIndex, Message},
+};
+
+#[tokio::test]
+async fn queue_dedup() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_queue_dedup_test");
+    core.queue.config.dedup = Config::parse("[queue.dedup]\nenable = true\nmax-entries = 2\n")
+        .unwrap()
+        .parse_queue_dedup()
+        .unwrap();
+    assert_eq!(core.queue.config.dedup, Some(2));
+    let mut dedup = DedupIndex::new(core.queue.config.dedup.unwrap());
+
+    // Generated headers and recipient order are not part of the hash
+    assert!(
+        queue(
+            &core,
+            &["a@example.org", "b@example.org"],
+            b"Received: 1\r\n"
+        )
+        .await
+    );
+    let original = qr.read_event().await.unwrap_message();
+    assert!(original.dedup_hash.is_some());
+    assert_eq!(dedup.find_or_insert(&original).await, None);
+    assert!(
+        queue(
+            &core,
+            &["b@example.org", "a@example.org"],
+            b"Received: 2\r\n"
+        )
+        .await
+    );
+    let duplicate = qr.read_event().await.unwrap_message();
+    assert_eq!(duplicate.dedup_hash, original.dedup_hash);
+    assert_eq!(dedup.find_or_insert(&duplicate).await, Some(original.id));
+    duplicate.remove(&core.queue.config.spool).await;
+
+    // Messages being delivered are still matched, as their spool file
+    // is only removed once delivery completes
+    assert!(queue(&core, &["a@example.org", "b@example.org"], b"").await);
+    let duplicate = qr.read_event().await.unwrap_message();
+    assert_eq!(dedup.find_or_insert(&duplicate).await, Some(original.id));
+    duplicate.remove(&core.queue.config.spool).await;
+
+    // Delivered messages no longer match
+    original.remove(&core.queue.config.spool).await;
+    assert!(queue(&core, &["a@example.org", "b@example.org"], b"").await);
+    let resubmitted = qr.read_event().await.unwrap_message();
+    assert_eq!(dedup.find_or_insert(&resubmitted).await, None);
+    assert_eq!(dedup.len(), 1);
+
+    // Different recipients produce a different hash
+    assert!(queue(&core, &["a@example.org"], b"").await);
+    let other = qr.read_event().await.unwrap_message();
+    assert_ne!(other.dedup_hash, resubmitted.dedup_hash);
+    assert_eq!(dedup.find_or_insert(&other).await, None);
+    assert_eq!(dedup.len(), 2);
+
+    // The oldest entry is dropped once the index is full
+    assert!(queue(&core, &["b@example.org"], b"").await);
+    let newest = qr.read_event().await.unwrap_message();
+    assert_eq!(dedup.find_or_insert(&newest).await, None);
+    assert_eq!(dedup.len(), 2);
+    assert!(queue(&core, &["a@example.org", "b@example.org"], b"").await);
+    let evicted = qr.read_event().await.unwrap_message();
+    assert_eq!(dedup.find_or_insert(&evicted).await, None);
+    for message in [resubmitted, other, newest, evicted] {
+        message.remove(&core.queue.config.spool).await;
+    }
+
+    // Hashes are not computed when deduplication is disabled
+    core.queue.config.dedup = Config::parse("[queue.dedup]\nmax-entries = 2\n")
+        .unwrap()
+        .parse_queue_dedup()
+        .unwrap();
+    assert_eq!(core.queue.config.dedup, None);
+    assert!(queue(&core, &["a@example.org"], b"").await);
+    let message = qr.read_event().await.unwrap_message();
+    assert_eq!(message.dedup_hash, None);
+    message.remove(&core.queue.config.spool).await;
+}
+
+async fn queue(core: &Core, rcpts: &[&str], headers: &[u8]) -> bool {
+    let mut message = Message::new_boxed("sender@foobar.org", "sender@foobar.org", "foobar.org");
+    for rcpt in rcpts {
+        message.add_recipient(*rcpt, &core.queue.config).await;
+    }
+    core.queue
+        .queue_message(
+            message,
+            Some(headers),
+            b"From: sender@foobar.org\r\nSubject: dedup\r\n\r\nhi",
+            &tracing::info_span!("hi"),
+        )
+        .await
+}
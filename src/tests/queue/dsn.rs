@@ -27,15 +27,18 @@ use std::{
     time::{Duration, Instant, SystemTime},
 };
 
-use smtp_proto::{Response, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_SUCCESS};
+use smtp_proto::{
+    Response, RCPT_NOTIFY_DELAY, RCPT_NOTIFY_FAILURE, RCPT_NOTIFY_NEVER, RCPT_NOTIFY_SUCCESS,
+};
 use tokio::{fs::File, io::AsyncReadExt};
 
 use crate::{
-    config::ConfigContext,
+    config::{ConfigContext, DsnFormat, DsnSender, IfBlock},
     core::Core,
     queue::{
+        dsn::{decode_xtext, encode_xtext},
         DeliveryAttempt, Domain, Error, ErrorDetails, HostResponse, Message, Recipient, Schedule,
-        Status,
+        Status, RCPT_DSN_SENT,
     },
     tests::ParseTestConfig,
 };
@@ -91,9 +94,11 @@ async fn generate_dsn() {
         }],
         flags: 0,
         env_id: None,
+        metadata: None,
         priority: 0,
 
         queue_refs: vec![],
+        dedup_hash: None,
     });
     let mut attempt = DeliveryAttempt {
         span: tracing::span!(tracing::Level::INFO, "hi"),
@@ -142,6 +147,7 @@ async fn generate_dsn() {
     compare_dsn(qr.read_event().await.unwrap_message(), "success.eml").await;
 
     // Delay DSN
+    attempt.message.domains[0].notify.due = Instant::now();
     attempt.message.recipients.push(Recipient {
         domain_idx: 0,
         address: "john.doe@example.org".to_string(),
@@ -164,6 +170,422 @@ async fn generate_dsn() {
     // Load queue
     let queue = core.queue.read_queue().await;
     assert_eq!(queue.scheduled.len(), 4);
+
+    // Simplified DSN
+    core.queue.config.dsn.format = IfBlock::new(DsnFormat::Simple);
+    attempt.message.recipients[0].flags = flags;
+    core.queue.send_dsn(&mut attempt).await;
+    let message = qr.read_event().await.unwrap_message();
+    let mut bytes = vec![0u8; message.size];
+    File::open(&message.path)
+        .await
+        .unwrap()
+        .read_exact(&mut bytes)
+        .await
+        .unwrap();
+    let dsn = String::from_utf8(bytes).unwrap();
+    assert!(dsn.contains("Content-Type: text/plain"), "{dsn}");
+    assert!(dsn.contains("<foobar@example.org> (host 'mx.example.org' rejected"));
+    assert!(dsn.contains("----- Original message headers -----"));
+    assert!(!dsn.contains("multipart/report"));
+    assert!(!dsn.contains("message/delivery-status"));
+
+    // Per-domain DSN sender
+    core.queue.config.dsn.domains.insert(
+        "foobar.org".to_string(),
+        DsnSender {
+            name: "FooBar Postmaster".to_string().into(),
+            address: "postmaster@foobar.org".to_string().into(),
+        },
+    );
+    attempt.message.recipients[0].flags = flags;
+    core.queue.send_dsn(&mut attempt).await;
+    let message = qr.read_event().await.unwrap_message();
+    let mut bytes = vec![0u8; message.size];
+    File::open(&message.path)
+        .await
+        .unwrap()
+        .read_exact(&mut bytes)
+        .await
+        .unwrap();
+    let dsn = String::from_utf8(bytes).unwrap();
+    assert!(dsn.contains("FooBar Postmaster"), "{dsn}");
+    assert!(dsn.contains("<postmaster@foobar.org>"), "{dsn}");
+}
+
+#[tokio::test]
+async fn delay_notify_schedule() {
+    let mut core = Core::test();
+    core.queue.config.notify = IfBlock::new(vec![
+        Duration::from_secs(10),
+        Duration::from_secs(60),
+        Duration::from_secs(120),
+    ]);
+    let mut qr = core.init_test_queue("smtp_dsn_notify_test");
+
+    let mut attempt = DeliveryAttempt {
+        span: tracing::span!(tracing::Level::INFO, "hi"),
+        message: Box::new(Message {
+            size: 0,
+            id: 0,
+            path: PathBuf::new(),
+            created: 0,
+            return_path: "sender@foobar.org".to_string(),
+            return_path_lcase: "sender@foobar.org".to_string(),
+            return_path_domain: "foobar.org".to_string(),
+            recipients: vec![Recipient {
+                domain_idx: 0,
+                address: "john@example.org".to_string(),
+                address_lcase: "john@example.org".to_string(),
+                status: Status::Scheduled,
+                flags: RCPT_NOTIFY_FAILURE,
+                orcpt: None,
+            }],
+            domains: vec![Domain {
+                domain: "example.org".to_string(),
+                retry: Schedule::now(),
+                notify: Schedule::now(),
+                expires: Instant::now() + Duration::from_secs(600),
+                status: Status::TemporaryFailure(Error::ConnectionError(ErrorDetails {
+                    entity: "mx.example.org".to_string(),
+                    details: "Connection timeout".to_string(),
+                })),
+                changed: false,
+            }],
+            flags: 0,
+            env_id: None,
+            metadata: None,
+            priority: 0,
+            queue_refs: vec![],
+            dedup_hash: None,
+        }),
+        in_flight: vec![],
+    };
+
+    // Recipients that did not request delay notifications should not receive
+    // a DSN, but the notification schedule must still advance.
+    core.queue.send_dsn(&mut attempt).await;
+    qr.assert_empty_queue();
+    let domain = &attempt.message.domains[0];
+    assert_eq!(domain.notify.inner, 1);
+    assert!(domain.notify.due > Instant::now() + Duration::from_secs(50));
+    assert!(attempt.message.next_event().unwrap() > Instant::now());
+
+    // Not due yet
+    core.queue.send_dsn(&mut attempt).await;
+    qr.assert_empty_queue();
+    assert_eq!(attempt.message.domains[0].notify.inner, 1);
+
+    // Delay DSNs are sent at each notification interval
+    attempt.message.recipients[0].flags = RCPT_NOTIFY_FAILURE | RCPT_NOTIFY_DELAY;
+    attempt.message.domains[0].notify.due = Instant::now();
+    core.queue.send_dsn(&mut attempt).await;
+    let dsn = qr.read_event().await.unwrap_message();
+    assert_eq!(dsn.recipients[0].address, "sender@foobar.org");
+    let domain = &attempt.message.domains[0];
+    assert_eq!(domain.notify.inner, 2);
+    assert!(domain.notify.due > Instant::now() + Duration::from_secs(110));
+
+    // No more intervals left, next notification is after expiration
+    attempt.message.domains[0].notify.due = Instant::now();
+    core.queue.send_dsn(&mut attempt).await;
+    qr.read_event().await.unwrap_message();
+    let domain = &attempt.message.domains[0];
+    assert_eq!(domain.notify.inner, 2);
+    assert!(domain.notify.due > domain.expires);
+}
+
+#[tokio::test]
+async fn delay_dsn_recipients() {
+    let mut core = Core::test();
+    core.queue.config.notify = IfBlock::new(vec![Duration::from_secs(10)]);
+    let mut qr = core.init_test_queue("smtp_dsn_delay_test");
+
+    let mut attempt = DeliveryAttempt::from(Box::new(Message {
+        size: 0,
+        id: 0,
+        path: PathBuf::new(),
+        created: 0,
+        return_path: "sender@foobar.org".to_string(),
+        return_path_lcase: "sender@foobar.org".to_string(),
+        return_path_domain: "foobar.org".to_string(),
+        recipients: [
+            ("john@example.org", RCPT_NOTIFY_DELAY | RCPT_NOTIFY_FAILURE),
+            ("jane@example.org", RCPT_NOTIFY_NEVER),
+            ("bill@example.org", RCPT_NOTIFY_FAILURE),
+        ]
+        .into_iter()
+        .map(|(address, flags)| Recipient {
+            domain_idx: 0,
+            address: address.to_string(),
+            address_lcase: address.to_string(),
+            status: Status::Scheduled,
+            flags,
+            orcpt: None,
+        })
+        .collect(),
+        domains: vec![Domain {
+            domain: "example.org".to_string(),
+            retry: Schedule::later(Duration::from_secs(600)),
+            notify: Schedule::now(),
+            expires: Instant::now() + Duration::from_secs(600),
+            status: Status::TemporaryFailure(Error::ConnectionError(ErrorDetails {
+                entity: "mx.example.org".to_string(),
+                details: "Connection timeout".to_string(),
+            })),
+            changed: false,
+        }],
+        flags: 0,
+        env_id: None,
+        metadata: None,
+        priority: 0,
+        queue_refs: vec![],
+        dedup_hash: None,
+    }));
+
+    // Only recipients that requested delay notifications are included
+    core.queue.send_dsn(&mut attempt).await;
+    let dsn = qr.read_event().await.unwrap_message().read_message();
+    assert!(dsn.contains("Action: delayed"), "{dsn}");
+    assert!(
+        dsn.contains("Final-Recipient: rfc822;john@example.org"),
+        "{dsn}"
+    );
+    assert!(!dsn.contains("jane@example.org"), "{dsn}");
+    assert!(!dsn.contains("bill@example.org"), "{dsn}");
+    assert!(attempt.message.domains[0].notify.due > Instant::now());
+
+    // Delay notifications do not prevent the final DSN
+    assert!(attempt
+        .message
+        .recipients
+        .iter()
+        .all(|rcpt| !rcpt.has_flag(RCPT_DSN_SENT)));
+    assert!(attempt.message.bounce(None));
+    core.queue.send_dsn(&mut attempt).await;
+    let dsn = qr.read_event().await.unwrap_message().read_message();
+    assert!(dsn.contains("Action: failed"), "{dsn}");
+    assert!(
+        dsn.contains("Final-Recipient: rfc822;john@example.org"),
+        "{dsn}"
+    );
+    assert!(
+        dsn.contains("Final-Recipient: rfc822;bill@example.org"),
+        "{dsn}"
+    );
+    assert!(!dsn.contains("jane@example.org"), "{dsn}");
+    qr.assert_empty_queue();
+}
+
+#[tokio::test]
+async fn bounce_message() {
+    let core = Core::test();
+    let mut qr = core.init_test_queue("smtp_dsn_bounce_test");
+
+    let mut message = Box::new(Message {
+        size: 0,
+        id: 0,
+        path: PathBuf::new(),
+        created: 0,
+        return_path: "sender@foobar.org".to_string(),
+        return_path_lcase: "sender@foobar.org".to_string(),
+        return_path_domain: "foobar.org".to_string(),
+        recipients: vec![
+            Recipient {
+                domain_idx: 0,
+                address: "john@example.org".to_string(),
+                address_lcase: "john@example.org".to_string(),
+                status: Status::Scheduled,
+                flags: RCPT_NOTIFY_FAILURE,
+                orcpt: None,
+            },
+            Recipient {
+                domain_idx: 0,
+                address: "jane@example.org".to_string(),
+                address_lcase: "jane@example.org".to_string(),
+                status: Status::TemporaryFailure(HostResponse {
+                    hostname: ErrorDetails {
+                        entity: "mx.example.org".to_string(),
+                        details: "RCPT TO:<jane@example.org>".to_string(),
+                    },
+                    response: Response {
+                        code: 452,
+                        esc: [4, 2, 2],
+                        message: "Mailbox full".to_string(),
+                    },
+                }),
+                flags: RCPT_NOTIFY_FAILURE,
+                orcpt: None,
+            },
+            Recipient {
+                domain_idx: 1,
+                address: "bill@example.net".to_string(),
+                address_lcase: "bill@example.net".to_string(),
+                status: Status::Scheduled,
+                flags: RCPT_NOTIFY_FAILURE,
+                orcpt: None,
+            },
+        ],
+        domains: vec![
+            Domain {
+                domain: "example.org".to_string(),
+                retry: Schedule::now(),
+                notify: Schedule::later(Duration::from_secs(600)),
+                expires: Instant::now() + Duration::from_secs(600),
+                status: Status::TemporaryFailure(Error::ConnectionError(ErrorDetails {
+                    entity: "mx.example.org".to_string(),
+                    details: "Connection timeout".to_string(),
+                })),
+                changed: false,
+            },
+            Domain {
+                domain: "example.net".to_string(),
+                retry: Schedule::now(),
+                notify: Schedule::later(Duration::from_secs(600)),
+                expires: Instant::now() + Duration::from_secs(600),
+                status: Status::Scheduled,
+                changed: false,
+            },
+        ],
+        flags: 0,
+        env_id: None,
+        metadata: None,
+        priority: 0,
+        queue_refs: vec![],
+        dedup_hash: None,
+    });
+
+    // Filters that do not match any pending domain are ignored
+    assert!(!message.bounce(Some("example.com")));
+    assert!(message.domains.iter().all(|d| !d.changed));
+
+    // Bounce a single domain
+    assert!(message.bounce(Some("example.org")));
+    assert!(matches!(
+        message.domains[0].status,
+        Status::PermanentFailure(Error::Io(_))
+    ));
+    assert_eq!(message.domains[1].status, Status::Scheduled);
+    assert!(matches!(
+        message.recipients[1].status,
+        Status::PermanentFailure(_)
+    ));
+    assert_eq!(message.recipients[2].status, Status::Scheduled);
+
+    let mut attempt = DeliveryAttempt::from(message);
+    core.queue.send_dsn(&mut attempt).await;
+    let dsn = qr.read_event().await.unwrap_message().read_message();
+    assert!(
+        dsn.contains("Delivery canceled by the administrator."),
+        "{dsn}"
+    );
+    assert!(dsn.contains("Mailbox full"), "{dsn}");
+    assert!(!dsn.contains("bill@example.net"), "{dsn}");
+    qr.assert_empty_queue();
+
+    // Bounce the remaining domains
+    assert!(attempt.message.bounce(None));
+    assert!(!attempt.message.bounce(None));
+    core.queue.send_dsn(&mut attempt).await;
+    let dsn = qr.read_event().await.unwrap_message().read_message();
+    assert!(dsn.contains("bill@example.net"), "{dsn}");
+    assert!(!dsn.contains("john@example.org"), "{dsn}");
+}
+
+#[test]
+fn xtext_codec() {
+    for (decoded, encoded) in [
+        ("jdoe@example.org", "jdoe@example.org"),
+        ("john doe@example.org", "john+20doe@example.org"),
+        ("jdoe+tag@example.org", "jdoe+2Btag@example.org"),
+        ("a=b@example.org", "a+3Db@example.org"),
+        ("\"john\tdoe\"@example.org", "\"john+09doe\"@example.org"),
+        ("jöhn@example.org", "j+C3+B6hn@example.org"),
+    ] {
+        assert_eq!(encode_xtext(decoded), encoded);
+        assert_eq!(decode_xtext(encoded).unwrap(), decoded);
+    }
+
+    for invalid in ["john doe@example.org", "a=b", "a+2", "a+2b", "a+ZZ", "a+FF"] {
+        assert!(decode_xtext(invalid).is_none(), "{invalid:?}");
+    }
+}
+
+#[tokio::test]
+async fn dsn_recipient_xtext() {
+    let core = Core::test();
+    let mut qr = core.init_test_queue("smtp_dsn_xtext_test");
+
+    let mut attempt = DeliveryAttempt::from(Box::new(Message {
+        size: 0,
+        id: 0,
+        path: PathBuf::new(),
+        created: 0,
+        return_path: "sender@foobar.org".to_string(),
+        return_path_lcase: "sender@foobar.org".to_string(),
+        return_path_domain: "foobar.org".to_string(),
+        recipients: ["John Doe+tag@example.org", "jöhn+tag@exämple.org"]
+            .into_iter()
+            .map(|orcpt| Recipient {
+                domain_idx: 0,
+                address: orcpt.replace("+tag", ""),
+                address_lcase: orcpt.replace("+tag", "").to_lowercase(),
+                status: Status::PermanentFailure(HostResponse {
+                    hostname: ErrorDetails {
+                        entity: "mx.example.org".to_string(),
+                        details: "RCPT TO:<john@example.org>".to_string(),
+                    },
+                    response: Response {
+                        code: 550,
+                        esc: [5, 1, 1],
+                        message: "User does not exist".to_string(),
+                    },
+                }),
+                flags: RCPT_NOTIFY_FAILURE,
+                orcpt: orcpt.to_string().into(),
+            })
+            .collect(),
+        domains: vec![Domain {
+            domain: "example.org".to_string(),
+            retry: Schedule::now(),
+            notify: Schedule::later(Duration::from_secs(600)),
+            expires: Instant::now() + Duration::from_secs(600),
+            status: Status::Scheduled,
+            changed: false,
+        }],
+        flags: 0,
+        env_id: None,
+        metadata: None,
+        priority: 0,
+        queue_refs: vec![],
+        dedup_hash: None,
+    }));
+    core.queue.send_dsn(&mut attempt).await;
+    let dsn = qr.read_event().await.unwrap_message().read_message();
+
+    // Printable US-ASCII addresses are written decoded
+    assert!(
+        dsn.contains("Original-Recipient: rfc822;John Doe+tag@example.org\r\n"),
+        "{dsn}"
+    );
+    assert!(
+        dsn.contains("Final-Recipient: rfc822;John Doe@example.org\r\n"),
+        "{dsn}"
+    );
+
+    // Anything else is xtext encoded and decodes back to the original value
+    for (field, expected) in [
+        ("Original-Recipient: rfc822;", "jöhn+tag@exämple.org"),
+        ("Final-Recipient: rfc822;", "jöhn@exämple.org"),
+    ] {
+        let encoded = dsn
+            .lines()
+            .filter_map(|line| line.strip_prefix(field))
+            .find(|value| value.contains("+C3"))
+            .unwrap_or_else(|| panic!("{field} missing: {dsn}"));
+        assert!(encoded.is_ascii(), "{encoded}");
+        assert_eq!(decode_xtext(encoded).unwrap(), expected);
+    }
 }
 
 async fn compare_dsn(message: Box<Message>, test: &str) {
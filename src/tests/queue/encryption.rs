@@ -0,0 +1,128 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{config::Config, core::Core, queue::Message};
+
+const KEY_1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const KEY_2: &str = "f0e0d0c0b0a090807060504030201000f1e1d1c1b1a191817161514131211101";
+
+#[tokio::test]
+async fn queue_encryption() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_queue_encryption_test");
+    core.queue.config.encryption =
+        Config::parse(&format!("[queue.encryption]\nkey = \"{KEY_1}\"\n"))
+            .unwrap()
+            .parse_queue_encryption()
+            .unwrap();
+
+    // Queue an encrypted message
+    let mut message = Message::new_boxed("sender@foobar.org", "sender@foobar.org", "foobar.org");
+    message
+        .add_recipient("rcpt@example.org", &core.queue.config)
+        .await;
+    assert!(
+        core.queue
+            .queue_message(
+                message,
+                (&b"From: sender@foobar.org\r\n"[..]).into(),
+                b"Subject: secret\r\n\r\ntop secret",
+                &tracing::info_span!("hi")
+            )
+            .await
+    );
+    let message = qr.read_event().await.unwrap_message();
+    assert!(message.is_encrypted());
+    let contents = std::fs::read(&message.path).unwrap();
+    assert!(!contents.windows(10).any(|w| w == b"top secret"));
+
+    // Bodies are decrypted transparently
+    let expected = b"From: sender@foobar.org\r\nSubject: secret\r\n\r\ntop secret";
+    let encryption = core.queue.config.encryption.as_ref();
+    assert_eq!(
+        core.queue
+            .config
+            .spool
+            .read(&message, message.size, encryption)
+            .await
+            .unwrap(),
+        expected
+    );
+    assert_eq!(
+        core.queue
+            .config
+            .spool
+            .read(&message, 10, encryption)
+            .await
+            .unwrap(),
+        &expected[..10]
+    );
+    assert!(core
+        .queue
+        .config
+        .spool
+        .read(&message, message.size, None)
+        .await
+        .is_err());
+
+    // Metadata can still be read from disk
+    let loaded = Message::from_path(message.path.clone()).await.unwrap();
+    assert_eq!(loaded.size, message.size);
+    assert_eq!(loaded.recipients[0].address, "rcpt@example.org");
+
+    // Rotated keys can still decrypt older messages
+    let rotated = Config::parse(&format!(
+        "[queue.encryption]\nkey = \"{KEY_2}\"\nprevious-keys = [\"{KEY_1}\"]\n"
+    ))
+    .unwrap()
+    .parse_queue_encryption()
+    .unwrap();
+    assert_eq!(
+        core.queue
+            .config
+            .spool
+            .read(&message, message.size, rotated.as_ref())
+            .await
+            .unwrap(),
+        expected
+    );
+    let unknown = Config::parse(&format!("[queue.encryption]\nkey = \"{KEY_2}\"\n"))
+        .unwrap()
+        .parse_queue_encryption()
+        .unwrap();
+    assert!(core
+        .queue
+        .config
+        .spool
+        .read(&message, message.size, unknown.as_ref())
+        .await
+        .is_err());
+
+    // Invalid keys are rejected
+    assert!(Config::parse("[queue.encryption]\nkey = \"abcd\"\n")
+        .unwrap()
+        .parse_queue_encryption()
+        .is_err());
+
+    message.remove(&core.queue.config.spool).await;
+}
@@ -21,11 +21,18 @@
  * for more details.
 */
 
-use std::time::{Duration, Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use mail_auth::trust_dns_resolver::proto::op::ResponseCode;
 
-use crate::queue::{manager::Queue, Domain, Message, Schedule, Status};
+use crate::{
+    config::IfBlock,
+    core::{Core, Session},
+    queue::{manager::Queue, Domain, Error, Message, Schedule, Status},
+};
 
 #[test]
 fn queue_due() {
@@ -66,6 +73,116 @@ fn queue_due() {
     assert!(queue.next_due().is_none());
 }
 
+#[test]
+fn queue_batch() {
+    let mut queue = Queue::default();
+
+    for (id, domains) in [
+        (0, &["a"][..]),
+        (1, &["b"][..]),
+        (2, &["a"][..]),
+        (3, &["a", "b"][..]),
+        (4, &["a"][..]),
+    ] {
+        let mut message = new_message(id);
+        for name in domains {
+            message.domains.push(domain(name, 0, 5, 10));
+        }
+        queue.schedule(Schedule {
+            due: message.next_delivery_event(),
+            inner: message,
+        });
+    }
+
+    let mut message = new_message(5);
+    message.domains.push(domain("a", 0, 5, 10));
+
+    let mut batch = queue.next_batch(&message, 2);
+    batch.extend(queue.next_batch(&message, 5));
+    let mut ids = batch.iter().map(|m| m.id).collect::<Vec<_>>();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![0, 2, 4]);
+    assert_eq!(queue.scheduled.len(), 2);
+
+    // Messages with multiple domains are not batched
+    message.domains.push(domain("b", 0, 5, 10));
+    assert!(queue.next_batch(&message, 5).is_empty());
+    assert_eq!(queue.scheduled.len(), 2);
+}
+
+#[tokio::test]
+async fn queue_memory_limit() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("queue_memory_limit");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    core.queue.config.max_resident = Some(2);
+    core.queue.config.load_batch = 1;
+    let core = Arc::new(core);
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    for rcpt in ["a@foobar.org", "b@foobar.org", "c@foobar.org"] {
+        session
+            .send_message("john@test.org", &[rcpt], "test:no_dkim", "250")
+            .await;
+        qr.read_event().await.unwrap_message();
+    }
+
+    // Only the metadata of two messages is kept in memory on startup
+    let mut queue = core.queue.read_queue().await;
+    assert_eq!(queue.messages.len(), 2);
+    assert_eq!(queue.evicted.len(), 1);
+    assert_eq!(queue.scheduled.len(), 3);
+
+    // Evicted messages are reloaded from the spool once due
+    let mut messages = Vec::new();
+    for _ in 0..5 {
+        queue.load_due().await;
+        while let Some(message) = queue.next_due() {
+            messages.push(message);
+        }
+    }
+    let mut rcpts = messages
+        .iter()
+        .map(|message| message.recipients[0].address.as_str())
+        .collect::<Vec<_>>();
+    rcpts.sort_unstable();
+    assert_eq!(rcpts, ["a@foobar.org", "b@foobar.org", "c@foobar.org"]);
+    assert!(queue.evicted.is_empty());
+
+    // Messages exceeding the limit are evicted when scheduled
+    let total_size = messages.iter().map(|message| message.size).sum::<usize>();
+    let due = Instant::now() + Duration::from_secs(3600);
+    for message in messages {
+        queue.schedule(Schedule {
+            due,
+            inner: message,
+        });
+    }
+    assert_eq!(queue.messages.len(), 2);
+    assert_eq!(queue.evicted.len(), 1);
+    queue.max_resident = Some(1);
+    queue.trim();
+    assert_eq!(queue.messages.len(), 1);
+    assert_eq!(queue.evicted.len(), 2);
+
+    // Statistics include evicted messages without restoring them
+    let stats = queue.stats();
+    assert_eq!(stats.total, 3);
+    assert_eq!(stats.size, total_size);
+    assert_eq!(queue.evicted.len(), 2);
+
+    // Management requests only restore the messages they refer to
+    let evicted_ids = queue.read_evicted(|_| true).await;
+    assert_eq!(evicted_ids.len(), 2);
+    assert_eq!(queue.evicted.len(), 2);
+    queue.load(&evicted_ids[..1]).await;
+    assert_eq!(queue.messages.len(), 2);
+    assert_eq!(queue.evicted.len(), 1);
+    assert!(queue.messages.contains_key(&evicted_ids[0]));
+}
+
 #[test]
 fn delivery_events() {
     let mut message = new_message(0);
@@ -109,6 +226,7 @@ fn delivery_events() {
     message.domain_mut("a").set_status(
         mail_auth::Error::DnsRecordNotFound(ResponseCode::BADCOOKIE),
         &[],
+        Duration::ZERO,
     );
     assert_eq!(message.next_event().unwrap(), message.domain("b").retry.due);
     assert_eq!(message.next_delivery_event(), message.domain("b").retry.due);
@@ -116,6 +234,7 @@ fn delivery_events() {
     message.domain_mut("b").set_status(
         mail_auth::Error::DnsRecordNotFound(ResponseCode::BADCOOKIE),
         &[],
+        Duration::ZERO,
     );
     assert_eq!(message.next_event().unwrap(), message.domain("c").retry.due);
     assert_eq!(message.next_delivery_event(), message.domain("c").retry.due);
@@ -123,10 +242,113 @@ fn delivery_events() {
     message.domain_mut("c").set_status(
         mail_auth::Error::DnsRecordNotFound(ResponseCode::BADCOOKIE),
         &[],
+        Duration::ZERO,
     );
     assert!(message.next_event().is_none());
 }
 
+#[test]
+fn retry_jitter() {
+    let schedule = [Duration::from_secs(60)];
+    let jitter = Duration::from_secs(10);
+
+    // Domains failing at the same time should be retried at different times
+    let now = Instant::now();
+    let mut retries = Vec::new();
+    for name in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+        let mut domain = domain(name, 0, 0, 0);
+        domain.set_status(
+            Status::TemporaryFailure(Error::ConcurrencyLimited),
+            &schedule,
+            jitter,
+        );
+        let delay = domain.retry.due - now;
+        assert!(
+            delay >= schedule[0] - jitter && delay <= schedule[0] + jitter + Duration::from_secs(1),
+            "{delay:?}"
+        );
+        retries.push(domain.retry.due);
+    }
+    assert!(
+        *retries.iter().max().unwrap() - *retries.iter().min().unwrap() > Duration::from_secs(1)
+    );
+
+    // No jitter
+    let now = Instant::now();
+    let mut domain = domain("a", 0, 0, 0);
+    domain.set_status(
+        Status::TemporaryFailure(Error::ConcurrencyLimited),
+        &schedule,
+        Duration::ZERO,
+    );
+    let delay = domain.retry.due - now;
+    assert!(delay >= schedule[0] && delay < schedule[0] + Duration::from_secs(1));
+}
+
+#[test]
+fn queue_release() {
+    let mut queue = Queue::default();
+
+    for (id, domains) in [
+        (0, vec!["foobar.org"]),
+        (1, vec!["example.org", "foobar.org"]),
+        (2, vec!["example.org"]),
+        (3, vec!["foobar.org"]),
+    ] {
+        let mut message = new_message(id);
+        for name in domains {
+            message.domains.push(domain(name, 1, 2, 3));
+        }
+        if id == 3 {
+            message.domains[0].status = Status::Completed(());
+        }
+        queue.schedule(Schedule {
+            due: message.next_delivery_event(),
+            inner: message,
+        });
+    }
+
+    let mut released = queue
+        .release(&["FooBar.org".to_string()])
+        .into_iter()
+        .map(|message| message.id)
+        .collect::<Vec<_>>();
+    released.sort_unstable();
+    assert_eq!(released, vec![0, 1]);
+    assert_eq!(queue.messages.len(), 2);
+    assert!(queue.release(&["foobar.org".to_string()]).is_empty());
+}
+
+#[test]
+fn queue_priority_aging() {
+    let mut queue = Queue {
+        priority_aging: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    // Fresh high priority mail is delivered before fresh low priority mail,
+    // but low priority mail that has been waiting long enough goes first.
+    for (id, priority, age) in [(0, 0, 0), (1, 10, 0), (2, 0, 3600), (3, -10, 0)] {
+        let mut message = new_message(id);
+        message.priority = priority;
+        message.created = now - age;
+        message.domains.push(domain("a", 0, 10, 20));
+        queue.schedule(Schedule {
+            due: Instant::now(),
+            inner: message,
+        });
+    }
+    let mut order = Vec::new();
+    while let Some(message) = queue.next_due() {
+        order.push(message.id);
+    }
+    assert_eq!(order, vec![2, 1, 0, 3]);
+    assert!(queue.messages.is_empty());
+}
+
 pub fn new_message(id: u64) -> Box<Message> {
     Box::new(Message {
         size: 0,
@@ -140,8 +362,10 @@ pub fn new_message(id: u64) -> Box<Message> {
         domains: vec![],
         flags: 0,
         env_id: None,
+        metadata: None,
         priority: 0,
         queue_refs: vec![],
+        dedup_hash: None,
     })
 }
 
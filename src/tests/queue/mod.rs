@@ -21,7 +21,13 @@
  * for more details.
 */
 
+pub mod compression;
+pub mod dedup;
 pub mod dsn;
+pub mod encryption;
 pub mod manager;
+pub mod rejected;
 pub mod retry;
+pub mod s3;
 pub mod serialize;
+pub mod window;
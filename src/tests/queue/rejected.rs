@@ -0,0 +1,78 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    config::{IfBlock, RejectedStore},
+    core::{Core, Session},
+    tests::make_temp_dir,
+};
+
+#[tokio::test]
+async fn rejected_store() {
+    let mut core = Core::test();
+    let store_dir = make_temp_dir("smtp_rejected_test", true);
+    let mut qr = core.init_test_queue("smtp_rejected_queue_test");
+    core.session.config.rcpt.relay = IfBlock::new(true);
+    let mut config = &mut core.session.config.data;
+    config.max_urls = IfBlock::new(Some(0));
+    config.rejected = RejectedStore {
+        path: store_dir.temp_dir.clone(),
+        retention: Duration::from_secs(3600),
+        enable: IfBlock::new(true),
+    }
+    .into();
+
+    // Rejected messages are stored along with their envelope
+    let core = Arc::new(core);
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["bill@foobar.org"],
+            "From: john@test.org\r\nSubject: Offer\r\n\r\nVisit https://spam.example.org/",
+            "550 5.7.1",
+        )
+        .await;
+    qr.assert_empty_queue();
+
+    let ids = core.list_rejected().await;
+    assert_eq!(ids.len(), 1);
+    let rejected = core.rejected_status(ids[0]).await.unwrap();
+    assert_eq!(rejected.return_path, "john@test.org");
+    assert_eq!(rejected.recipients, vec!["bill@foobar.org".to_string()]);
+    assert_eq!(rejected.helo_domain, "mx.test.org");
+    assert!(rejected.reason.starts_with("550 5.7.1"), "{rejected:?}");
+
+    // Replaying queues the message and removes it from the store
+    assert!(core.replay_rejected(ids[0]).await);
+    let message = qr.read_event().await.unwrap_message();
+    assert_eq!(message.return_path, "john@test.org");
+    assert_eq!(message.recipients[0].address, "bill@foobar.org");
+    assert!(core.list_rejected().await.is_empty());
+    assert!(!core.replay_rejected(ids[0]).await);
+}
@@ -121,7 +121,7 @@ async fn queue_retry() {
                 WorkerResult::OnHold(_) => unreachable!(),
             },
             None | Some(Event::Stop) => break,
-            Some(Event::Manage(_)) => unreachable!(),
+            Some(Event::Manage(_) | Event::Release { .. }) => unreachable!(),
         }
 
         if !queue.scheduled.is_empty() {
@@ -231,3 +231,91 @@ async fn queue_retry() {
             .as_secs()
     ));
 }
+
+#[tokio::test]
+async fn queue_max_lifetime() {
+    let mut core = Core::test();
+
+    // Create temp dir for queue
+    let mut qr = core.init_test_queue("smtp_queue_max_lifetime_test");
+
+    let config = &mut core.session.config.rcpt;
+    config.relay = IfBlock::new(true);
+    let config = &mut core.queue.config;
+    config.retry = IfBlock::new(vec![Duration::from_millis(100)]);
+    config.notify = IfBlock::new(vec![Duration::from_secs(86400)]);
+    config.expire = IfBlock::new(Duration::from_secs(86400));
+    config.max_lifetime = Some(Duration::from_millis(450));
+
+    // Create test message
+    let core = Arc::new(core);
+    let mut queue = Queue::default();
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+    session
+        .send_message(
+            "john@test.org",
+            &["jane@_dns_error.org"],
+            "test:no_dkim",
+            "250",
+        )
+        .await;
+    let now = Instant::now();
+    let attempt = DeliveryAttempt::from(qr.read_event().await.unwrap_message());
+    assert!(
+        attempt
+            .message
+            .domains
+            .first()
+            .unwrap()
+            .expires
+            .duration_since(now)
+            <= Duration::from_millis(450)
+    );
+
+    // Expect a failed DSN once the message lifetime is exceeded,
+    // well before the configured expiration time.
+    let path = attempt.message.path.clone();
+    let mut dsn = Vec::new();
+    let mut num_retries = 0;
+    attempt.try_deliver(core.clone(), &mut queue).await;
+    loop {
+        match qr.try_read_event().await {
+            Some(Event::Queue(message)) => {
+                dsn.push(message.inner);
+            }
+            Some(Event::Done(wr)) => match wr {
+                WorkerResult::Done => break,
+                WorkerResult::Retry(retry) => {
+                    queue.schedule(retry);
+                    num_retries += 1;
+                }
+                WorkerResult::OnHold(_) => unreachable!(),
+            },
+            None | Some(Event::Stop) => break,
+            Some(Event::Manage(_) | Event::Release { .. }) => unreachable!(),
+        }
+
+        if !queue.scheduled.is_empty() {
+            tokio::time::sleep(queue.wake_up_time()).await;
+            DeliveryAttempt::from(queue.next_due().unwrap())
+                .try_deliver(core.clone(), &mut queue)
+                .await;
+        }
+    }
+    assert!(queue.scheduled.is_empty());
+    assert!(num_retries > 0);
+    assert!(now.elapsed() < Duration::from_secs(5));
+    assert_eq!(dsn.len(), 1);
+    assert!(!path.exists());
+
+    dsn.into_iter()
+        .next()
+        .unwrap()
+        .read_lines()
+        .assert_contains("<jane@_dns_error.org> (failed to lookup '_dns_error.org'")
+        .assert_contains("Final-Recipient: rfc822;jane@_dns_error.org")
+        .assert_contains("Action: failed");
+}
@@ -0,0 +1,73 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::config::Config;
+
+#[test]
+fn s3_spool() {
+    let config = Config::parse(
+        r#"[queue.spool]
+type = "s3"
+
+[queue.spool.s3]
+endpoint = "https://s3.example.org"
+bucket = "spool"
+region = "eu-west-1"
+access-key = "AKIDEXAMPLE"
+secret-key = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY"
+prefix = "queue/"
+"#,
+    )
+    .unwrap();
+    assert!(!config.parse_queue_spool().unwrap().is_local());
+    let s3 = config.parse_s3_spool().unwrap();
+    assert_eq!(s3.bucket, "spool");
+    assert_eq!(s3.prefix, "queue/");
+
+    let (amz_date, authorization) = s3.sign(
+        "GET",
+        "/spool/queue/ABCD",
+        "s3.example.org",
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        1440938160,
+    );
+    assert_eq!(amz_date, "20150830T123600Z");
+    assert_eq!(
+        authorization,
+        concat!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/eu-west-1/s3/aws4_request, ",
+            "SignedHeaders=host;x-amz-content-sha256;x-amz-date, ",
+            "Signature=277bf3095b682dc3cc80cc255a5bb89580aab264cc75c9176ca53cbfc406ee1d"
+        )
+    );
+
+    assert!(Config::parse("")
+        .unwrap()
+        .parse_queue_spool()
+        .unwrap()
+        .is_local());
+    assert!(Config::parse("[queue.spool]\ntype = \"nfs\"\n")
+        .unwrap()
+        .parse_queue_spool()
+        .is_err());
+}
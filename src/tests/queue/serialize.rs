@@ -90,9 +90,11 @@ async fn queue_serialize() {
         ],
         flags: MAIL_REQUIRETLS | MAIL_SMTPUTF8,
         env_id: "hello".to_string().into(),
+        metadata: "tracking-id 1234".to_string().into(),
         priority: -1,
 
         queue_refs: vec![],
+        dedup_hash: None,
     };
 
     // Queue message
@@ -107,6 +109,15 @@ async fn queue_serialize() {
             .await
     );
     let mut message = qr.read_event().await.unwrap_message();
+    assert_eq!(
+        core.queue
+            .config
+            .spool
+            .read(&message, message.size, None)
+            .await
+            .unwrap(),
+        b"From: test@foobar.org\r\nSubject: test\r\n\n\ntest"
+    );
 
     // Deserialize
     assert_msg_eq(
@@ -170,7 +181,7 @@ async fn queue_serialize() {
     );
 
     // Remove
-    message.remove().await;
+    message.remove(&core.queue.config.spool).await;
     assert!(!message.path.exists());
 }
 
@@ -194,6 +205,7 @@ fn assert_msg_eq(msg: &Message, other: &Message) {
     }
     assert_eq!(msg.flags, other.flags);
     assert_eq!(msg.env_id, other.env_id);
+    assert_eq!(msg.metadata, other.metadata);
     assert_eq!(msg.priority, other.priority);
     assert_eq!(msg.size, other.size);
 }
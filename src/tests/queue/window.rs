@@ -0,0 +1,75 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::time::Duration;
+
+use crate::config::{DeliveryWindow, ParseValue};
+
+#[test]
+fn queue_window() {
+    // Monday to Friday, 08:00 to 18:00 UTC
+    let window = DeliveryWindow::parse_value("test", "mon-fri 08:00-18:00 +00:00").unwrap();
+    assert_eq!(
+        window,
+        DeliveryWindow {
+            days: 0b0011111,
+            start: 8 * 3600,
+            end: 18 * 3600,
+            offset: 0,
+        }
+    );
+
+    // 2023-01-02 is a Monday
+    let monday = 1672617600;
+    for (now, expected) in [
+        (monday + 9 * 3600, 0),
+        (monday + 7 * 3600, 3600),
+        (monday + 18 * 3600, 14 * 3600),
+        (monday + 4 * 86400 + 19 * 3600, 2 * 86400 + 13 * 3600),
+    ] {
+        assert_eq!(
+            window.wait_time(now),
+            Duration::from_secs(expected),
+            "now {now}"
+        );
+    }
+
+    // Windows crossing midnight with a timezone offset
+    let window = DeliveryWindow::parse_value("test", "sat,sun 22:00-06:00 -02:00").unwrap();
+    assert_eq!(window.days, 0b1100000);
+    let saturday = monday + 5 * 86400;
+    for (now, expected) in [
+        (saturday + 23 * 3600, 3600),
+        (saturday + 20 * 3600, 4 * 3600),
+        (monday + 7 * 86400 + 5 * 3600, 0),
+        (monday + 7 * 86400 + 9 * 3600, 5 * 86400 + 15 * 3600),
+    ] {
+        assert_eq!(
+            window.wait_time(now),
+            Duration::from_secs(expected),
+            "now {now}"
+        );
+    }
+
+    assert!(DeliveryWindow::parse_value("test", "mon-fri 25:00-18:00").is_err());
+    assert!(DeliveryWindow::parse_value("test", "someday 08:00-18:00").is_err());
+}
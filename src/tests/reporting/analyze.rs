@@ -95,3 +95,41 @@ async fn report_analyze() {
         .await;
     qr.read_event().await.unwrap_message();
 }
+
+#[tokio::test]
+async fn report_analyze_max_size() {
+    let mut core = Core::test();
+
+    // Create temp dir for queue
+    let mut qr = core.init_test_queue("smtp_analyze_report_size_test");
+    let report_dir = make_temp_dir("smtp_report_incoming_size", true);
+
+    let mut config = &mut core.session.config.rcpt;
+    config.relay = IfBlock::new(true);
+    let mut config = &mut core.report.config.analysis;
+    config.addresses = vec![AddressMatch::StartsWith("reports@".to_string())];
+    config.forward = false;
+    config.store = report_dir.temp_dir.clone().into();
+    config.max_size = 128;
+
+    let core = Arc::new(core);
+    let mut session = Session::test(core.clone());
+    session.data.remote_ip = "10.0.0.1".parse().unwrap();
+    session.eval_session_params().await;
+    session.ehlo("mx.test.org").await;
+
+    // Compressed reports that expand beyond the limit are discarded
+    for test in ["dmarc1", "tls1"] {
+        session
+            .send_message(
+                "john@test.org",
+                &["reports@foobar.org"],
+                &format!("report:{test}"),
+                "250",
+            )
+            .await;
+        qr.assert_empty_queue();
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(fs::read_dir(&report_dir.temp_dir).unwrap().count(), 0);
+}
@@ -0,0 +1,65 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use ahash::AHashSet;
+
+use crate::{config::IfBlock, core::Core, lookup::Lookup, tests::session::VerifyResponse};
+
+#[tokio::test]
+async fn report_local_domains() {
+    let mut core = Core::test();
+    let mut qr = core.init_test_queue("smtp_report_local_domains_test");
+    core.report.config.local_domains = Some(Arc::new(Lookup::Local(AHashSet::from_iter([
+        "foobar.org".to_string(),
+    ]))));
+    let span = tracing::info_span!("test");
+
+    // Local recipients should be removed from reports
+    core.send_report(
+        "reports@foobar.org",
+        ["rua@foobar.org", "rua@example.org"].iter(),
+        b"Subject: report\r\n\r\ntest".to_vec(),
+        &IfBlock::default(),
+        &span,
+        true,
+    )
+    .await;
+    let message = qr.read_event().await.unwrap_message();
+    assert_eq!(message.recipients.len(), 1);
+    assert_eq!(message.recipients[0].address, "rua@example.org");
+    message.read_lines().assert_contains("Subject: report");
+
+    // Reports addressed only to local domains are not queued
+    core.send_report(
+        "reports@foobar.org",
+        ["rua@FOOBAR.ORG"].iter(),
+        b"Subject: report\r\n\r\ntest".to_vec(),
+        &IfBlock::default(),
+        &span,
+        true,
+    )
+    .await;
+    qr.assert_empty_queue();
+}
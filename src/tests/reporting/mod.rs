@@ -23,5 +23,6 @@
 
 pub mod analyze;
 pub mod dmarc;
+pub mod local_domains;
 pub mod scheduler;
 pub mod tls;
@@ -82,7 +82,7 @@ impl IsTls for DummyIo {
         self.tls
     }
 
-    fn write_tls_header(&self, _headers: &mut Vec<u8>) {}
+    fn write_tls_header(&self, _headers: &mut Vec<u8>, _include_cipher: bool) {}
 }
 
 impl Unpin for DummyIo {}
@@ -102,6 +102,7 @@ impl Session<DummyIo> {
             data: SessionData::new("127.0.0.1".parse().unwrap(), "127.0.0.1".parse().unwrap()),
             params: SessionParameters::default(),
             in_flight: vec![],
+            connection: None,
         }
     }
 
@@ -254,6 +255,7 @@ impl ServerInstance {
             is_smtp: true,
             hostname: "mx.example.org".to_string(),
             greeting: b"220 mx.example.org at your service.\r\n".to_vec(),
+            connections: None,
         }
     }
 }
@@ -0,0 +1,88 @@
+/*
+ * Copyright (c) 2023 Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart SMTP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{net::UdpSocket, time::Duration};
+
+use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, Registry};
+
+use crate::config::Config;
+
+#[test]
+fn syslog_rfc5424() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Duration::from_secs(5).into())
+        .unwrap();
+    let layer = Config::parse(&format!(
+        concat!(
+            "[global.tracing.syslog]\n",
+            "endpoint = \"udp://{}\"\n",
+            "facility = \"local3\"\n",
+            "hostname = \"mx.example.org\"\n"
+        ),
+        socket.local_addr().unwrap()
+    ))
+    .unwrap()
+    .build_syslog_layer()
+    .unwrap()
+    .unwrap();
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let span = tracing::info_span!("session", remote_ip = "10.0.0.1");
+        let _guard = span.enter();
+        tracing::warn!(
+            context = "throttle",
+            event = "rate-limit-exceeded",
+            max_requests = 5,
+            "Rate \"limit\" exceeded."
+        );
+    });
+
+    let mut buf = vec![0u8; 2048];
+    let len = socket.recv(&mut buf).unwrap();
+    let message = std::str::from_utf8(&buf[..len]).unwrap();
+    assert!(message.starts_with("<156>1 "), "{message}");
+    assert!(
+        message.ends_with(&format!(
+            concat!(
+                " mx.example.org stalwart-smtp {} rate-limit-exceeded ",
+                "[stalwart@32473 context=\"throttle\" event=\"rate-limit-exceeded\" ",
+                "max_requests=\"5\" remote_ip=\"10.0.0.1\"] Rate \"limit\" exceeded."
+            ),
+            std::process::id()
+        )),
+        "{message}"
+    );
+
+    // Invalid settings
+    for config in [
+        "[global.tracing.syslog]\nendpoint = \"http://127.0.0.1:514\"\n",
+        "[global.tracing.syslog]\nendpoint = \"udp://127.0.0.1\"\n",
+        "[global.tracing.syslog]\nendpoint = \"udp://127.0.0.1:514\"\nfacility = \"local8\"\n",
+    ] {
+        assert!(
+            Config::parse(config).unwrap().build_syslog_layer().is_err(),
+            "{config}"
+        );
+    }
+}